@@ -64,7 +64,7 @@ impl BenchDb for BenchAdapter {
 	}
 
 	fn commit<I: IntoIterator<Item=(Key, Option<Value>)>>(&self, tx: I) {
-		self.0.commit(tx.into_iter().map(|(k, v)| (0, k, v))).unwrap()
+		self.0.commit(tx.into_iter().map(|(k, v)| (0, k, v))).unwrap();
 	}
 }
 
@@ -55,6 +55,29 @@ pub fn run() -> Result<(), String> {
 	options.stats = cli.shared().with_stats;
 	println!("Options {:?}, {:?}", cli, options);
 	match cli.subcommand {
+		SubCommand::Stats(stat) if stat.history => {
+			let mut path = db_path.clone();
+			path.push("stats.ndjson");
+			let file = std::fs::File::open(&path)
+				.map_err(|e| format!("Error opening stats history {:?}: {:?}", path, e))?;
+			let history = axia_db::DbStats::read_history(std::io::BufReader::new(file))
+				.map_err(|e| format!("Invalid stats history: {:?}", e))?;
+			for snapshot in history {
+				println!("timestamp={} uptime={}ms last_enacted={}", snapshot.timestamp, snapshot.uptime, snapshot.last_enacted);
+				for c in snapshot.columns.iter().filter(|c| stat.column.map_or(true, |col| col == c.col)) {
+					println!(
+						"  col{}: values={} bytes={} commits={} queries_miss={} reindexes={}",
+						c.col, c.total_values, c.total_bytes, c.commits, c.queries_miss, c.reindex_history.len(),
+					);
+					for r in c.reindex_history.iter() {
+						println!(
+							"    reindex records {}-{}: {}ms, {} entries, {} bytes",
+							r.start_record, r.end_record, r.duration, r.entries, r.bytes,
+						);
+					}
+				}
+			}
+		},
 		SubCommand::Stats(stat) => {
 			let db = axia_db::Db::open_read_only(&options)
 				.map_err(|e| format!("Invalid db: {:?}", e))?;
@@ -107,6 +130,26 @@ pub fn run() -> Result<(), String> {
 			db.check_from_index(check_param)
 				.map_err(|e| format!("Check error: {:?}", e))?;
 		},
+		SubCommand::DumpValues(dump) => {
+			let db = axia_db::Db::open_read_only(&options)
+				.map_err(|e| format!("Invalid db: {:?}", e))?;
+			let entries = db.iter_values_raw(dump.column)
+				.map_err(|e| format!("Invalid column: {:?}", e))?;
+			for entry in entries {
+				let entry = entry.map_err(|e| format!("Error reading value table: {:?}", e))?;
+				let len = dump.display_value_max.map_or(entry.value.len(), |max| std::cmp::min(max as usize, entry.value.len()));
+				println!(
+					"tier={} offset={} rc={} compressed={} partial_key={} len={} value={}",
+					entry.tier,
+					entry.offset,
+					entry.rc,
+					entry.compressed,
+					to_hex(&entry.partial_key),
+					entry.value.len(),
+					to_hex(&entry.value[.. len]),
+				);
+			}
+		},
 		SubCommand::Flush(_flush) => {
 			let _db = axia_db::Db::open(&options)
 				.map_err(|e| format!("Invalid db: {:?}", e))?;
@@ -187,6 +230,8 @@ pub enum SubCommand {
 	Flush(Flush),
 	/// Check db content.
 	Check(Check),
+	/// Print the raw entries of the value tables of a column, without using the index.
+	DumpValues(DumpValues),
 	/// Stress tests.
 	Stress(bench::Stress),
 }
@@ -206,6 +251,9 @@ impl Cli {
 			SubCommand::Check(check) => {
 				&check.shared
 			},
+			SubCommand::DumpValues(dump) => {
+				&dump.shared
+			},
 			SubCommand::Stress(bench) => {
 				&bench.shared
 			},
@@ -226,6 +274,10 @@ pub struct Stats {
 	/// Clear current stats.
 	#[structopt(long)]
 	pub clear: bool,
+
+	/// Show the stats history recorded in `stats.ndjson` instead of current stats.
+	#[structopt(long)]
+	pub history: bool,
 }
 
 /// Migrate db (update version or change column options).
@@ -280,14 +332,14 @@ pub struct Check {
 	pub index_value: bool,
 
 	/// Start range for operation.
-	/// Index start chunk in db.
-	#[structopt(long)]
-	pub range_start: Option<u64>,
+	/// Hex encoded key, a key prefix for columns with uniform keys.
+	#[structopt(long, parse(try_from_str = parse_hex))]
+	pub range_start: Option<HexKey>,
 
-	/// End range for operation.
-	/// Index end chunk in db.
-	#[structopt(long)]
-	pub range_end: Option<u64>,
+	/// End range for operation, exclusive.
+	/// Hex encoded key, a key prefix for columns with uniform keys.
+	#[structopt(long, parse(try_from_str = parse_hex))]
+	pub range_end: Option<HexKey>,
 
 	/// When active, display parsed index and value content.
 	#[structopt(long)]
@@ -297,3 +349,35 @@ pub struct Check {
 	#[structopt(long)]
 	pub display_value_max: Option<u64>,
 }
+
+/// Print value table entries.
+#[derive(Debug, StructOpt)]
+pub struct DumpValues {
+	#[structopt(flatten)]
+	pub shared: Shared,
+
+	/// Column to dump.
+	#[structopt(long)]
+	pub column: u8,
+
+	/// Max length of values to display.
+	#[structopt(long)]
+	pub display_value_max: Option<u64>,
+}
+
+// Not spelled as `Vec<u8>` so that structopt parses a single value rather than a list.
+type HexKey = Vec<u8>;
+
+fn parse_hex(s: &str) -> Result<HexKey, String> {
+	let s = s.strip_prefix("0x").unwrap_or(s);
+	if s.len() % 2 != 0 {
+		return Err(format!("Odd number of hex digits: {}", s));
+	}
+	(0 .. s.len()).step_by(2)
+		.map(|i| u8::from_str_radix(&s[i .. i + 2], 16).map_err(|e| format!("Invalid hex {}: {}", s, e)))
+		.collect()
+}
+
+fn to_hex(data: &[u8]) -> String {
+	data.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,57 @@
+// Copyright 2015-2020 AXIA Technologies (UK) Ltd.
+// This file is part of AXIA.
+
+// AXIA is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// AXIA is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with AXIA.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Commit throughput with several threads queueing single key commits.
+//!
+//! Run with `cargo bench --bench commit_queue -- [threads] [commits per thread]`.
+//! Commits are timed until they are queued, including waits for queue space. Writing
+//! the queued commits on drop is not timed.
+
+use std::time::Instant;
+
+fn main() {
+	let mut args = std::env::args().skip(1).filter(|a| !a.starts_with("--"));
+	let threads: u32 = args.next().and_then(|a| a.parse().ok()).unwrap_or(4);
+	let commits: u32 = args.next().and_then(|a| a.parse().ok()).unwrap_or(100_000);
+
+	let dir = tempfile::tempdir().unwrap();
+	let db = axia_db::Db::with_columns(dir.path(), 1).unwrap();
+	let value = vec![0u8; 32];
+	let start = Instant::now();
+	std::thread::scope(|s| {
+		for t in 0 .. threads {
+			let (db, value) = (&db, &value);
+			s.spawn(move || {
+				for i in 0 .. commits {
+					let mut key = [0u8; 8];
+					key[.. 4].copy_from_slice(&t.to_le_bytes());
+					key[4 ..].copy_from_slice(&i.to_le_bytes());
+					db.commit(vec![(0, key, Some(value.clone()))]).unwrap();
+				}
+			});
+		}
+	});
+	let elapsed = start.elapsed();
+	let total = threads as u64 * commits as u64;
+	println!(
+		"{} threads, {} commits in {:.2?}: {:.0} commits/s, {:.2?} per commit",
+		threads,
+		total,
+		elapsed,
+		total as f64 / elapsed.as_secs_f64(),
+		elapsed * threads / total as u32,
+	);
+}
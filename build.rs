@@ -0,0 +1,40 @@
+// Copyright 2015-2020 AXIA Technologies (UK) Ltd.
+// This file is part of AXIA.
+
+// AXIA is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// AXIA is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with AXIA.  If not, see <http://www.gnu.org/licenses/>.
+
+fn main() {
+	println!("cargo:rerun-if-changed=build.rs");
+	#[cfg(feature = "ffi")]
+	generate_header();
+}
+
+/// Generate the C header for the `ffi` module into `include/axiadb.h`.
+#[cfg(feature = "ffi")]
+fn generate_header() {
+	println!("cargo:rerun-if-changed=src/ffi.rs");
+	println!("cargo:rerun-if-changed=cbindgen.toml");
+	let dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+	let config = cbindgen::Config::from_file(format!("{}/cbindgen.toml", dir))
+		.expect("Error reading cbindgen.toml");
+	match cbindgen::Builder::new()
+		.with_src(format!("{}/src/ffi.rs", dir))
+		.with_config(config)
+		.generate() {
+		Ok(bindings) => {
+			bindings.write_to_file(format!("{}/include/axiadb.h", dir));
+		}
+		Err(e) => println!("cargo:warning=Error generating C header: {}", e),
+	}
+}
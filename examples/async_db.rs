@@ -0,0 +1,49 @@
+// Copyright 2015-2020 AXIA Technologies (UK) Ltd.
+// This file is part of AXIA.
+
+// AXIA is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// AXIA is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with AXIA.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Using the database from tokio tasks with `AsyncDb`.
+//!
+//! Run with `cargo run --example async_db --features async`.
+
+use axia_db::{AsyncDb, Db};
+
+#[tokio::main]
+async fn main() -> axia_db::Result<()> {
+	let dir = tempfile::tempdir()?;
+	let db = AsyncDb::new(Db::with_columns(dir.path(), 1)?)?;
+
+	// Tasks commit concurrently. Waiting for queue space doesn't block the runtime.
+	let tasks: Vec<_> = (0 .. 4u8).map(|t| {
+		let db = db.clone();
+		tokio::spawn(async move {
+			for i in 0 .. 100u8 {
+				db.commit(vec![(0, [t, i], Some(vec![i; 64]))]).await?;
+			}
+			// Wait for the last commit to be written to the log.
+			db.commit_durable(vec![(0, [t, 0xff], Some(b"done".to_vec()))]).await
+		})
+	}).collect();
+	for task in tasks {
+		task.await.expect("Task panicked")?;
+	}
+
+	for t in 0 .. 4u8 {
+		assert_eq!(db.get(0, &[t, 0xff]).await?, Some(b"done".to_vec()));
+		assert_eq!(db.get(0, &[t, 42]).await?, Some(vec![42; 64]));
+	}
+	println!("Committed {} values", 4 * 101);
+	Ok(())
+}
@@ -0,0 +1,185 @@
+#ifndef AXIADB_H
+#define AXIADB_H
+
+/* Generated with cbindgen from src/ffi.rs. Do not edit. */
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+/*
+ Operation completed successfully.
+ */
+#define AXIADB_OK 0
+
+/*
+ The key was not found.
+ */
+#define AXIADB_NOT_FOUND 1
+
+/*
+ Invalid argument, e.g. a null pointer or an out of range column.
+ */
+#define AXIADB_INVALID_ARGUMENT 2
+
+/*
+ IO error.
+ */
+#define AXIADB_IO 3
+
+/*
+ Database files are corrupted.
+ */
+#define AXIADB_CORRUPTION 4
+
+/*
+ Options don't match the database or are invalid.
+ */
+#define AXIADB_INVALID_CONFIGURATION 5
+
+/*
+ A background worker has failed. The database must be closed.
+ */
+#define AXIADB_BACKGROUND 6
+
+/*
+ The database is opened by another process.
+ */
+#define AXIADB_LOCKED 7
+
+/*
+ Any other error.
+ */
+#define AXIADB_OTHER 8
+
+/*
+ Opaque database handle.
+ */
+typedef struct AxiaDb AxiaDb;
+
+/*
+ Column options. See `ColumnOptions` for details.
+ */
+typedef struct AxiaDbColumnOptions {
+  bool preimage;
+  bool uniform;
+  bool ref_counted;
+  /*
+   0 for none, 1 for lz4, 2 for snappy.
+   */
+  uint8_t compression;
+} AxiaDbColumnOptions;
+
+/*
+ Database options. Null pointers passed for options select the defaults.
+ */
+typedef struct AxiaDbOptions {
+  /*
+   Create the database if it does not exist.
+   */
+  bool create;
+  bool sync_wal;
+  bool sync_data;
+  bool stats;
+  /*
+   Column options, an array with an entry for each column. May be null.
+   */
+  const struct AxiaDbColumnOptions *columns;
+} AxiaDbOptions;
+
+/*
+ Called with the value found by `axiadb_get`. The value buffer is only valid
+ during the call.
+ */
+typedef void (*AxiaDbValueCallback)(void *context, const uint8_t *value, size_t value_len);
+
+/*
+ A single change for `axiadb_commit`.
+ */
+typedef struct AxiaDbOp {
+  uint8_t col;
+  const uint8_t *key;
+  size_t key_len;
+  /*
+   Value to set, or null to remove the key.
+   */
+  const uint8_t *value;
+  size_t value_len;
+} AxiaDbOp;
+
+/*
+ Open a database with `num_columns` columns at `path`. Returns null on failure.
+ The reason is written to `error`, unless it is null.
+
+ # Safety
+
+ `path` must be a null-terminated string. `opts` must be null or point to valid
+ options, with `columns` being null or pointing to `num_columns` entries.
+ `error` must be null or valid for writes.
+ */
+struct AxiaDb *axiadb_open(const char *path,
+                           uint8_t num_columns,
+                           const struct AxiaDbOptions *opts,
+                           int *error);
+
+/*
+ Close the database and release the handle.
+
+ # Safety
+
+ `handle` must be null or returned by `axiadb_open` and not closed yet.
+ */
+void axiadb_close(struct AxiaDb *handle);
+
+/*
+ Look up a value and pass it to `callback`. Returns `AXIADB_NOT_FOUND` without
+ calling `callback` if there is no value for the key.
+
+ # Safety
+
+ `handle` must be a valid handle. `key` must point to `key_len` bytes.
+ */
+int axiadb_get(const struct AxiaDb *handle,
+               uint8_t col,
+               const uint8_t *key,
+               size_t key_len,
+               AxiaDbValueCallback callback,
+               void *context);
+
+/*
+ Look up a value and return a copy in `value` and `value_len`. The copy must
+ be released with `axiadb_free_value`. Returns `AXIADB_NOT_FOUND` and sets
+ `value` to null if there is no value for the key.
+
+ # Safety
+
+ `handle` must be a valid handle. `key` must point to `key_len` bytes.
+ `value` and `value_len` must be valid for writes.
+ */
+int axiadb_get_copy(const struct AxiaDb *handle,
+                    uint8_t col,
+                    const uint8_t *key,
+                    size_t key_len,
+                    uint8_t **value,
+                    size_t *value_len);
+
+/*
+ Release a value returned by `axiadb_get_copy`.
+
+ # Safety
+
+ `value` and `value_len` must be null or returned by `axiadb_get_copy` and not released yet.
+ */
+void axiadb_free_value(uint8_t *value, size_t value_len);
+
+/*
+ Commit `count` operations atomically.
+
+ # Safety
+
+ `handle` must be a valid handle. `ops` must point to `count` operations with
+ valid key and value pointers.
+ */
+int axiadb_commit(const struct AxiaDb *handle, const struct AxiaDbOp *ops, size_t count);
+
+#endif /* AXIADB_H */
@@ -0,0 +1,375 @@
+// Copyright 2015-2020 AXIA Technologies (UK) Ltd.
+// This file is part of AXIA.
+
+// AXIA is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// AXIA is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with AXIA.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Async interface, enabled with the `async` feature.
+//!
+//! `Db::commit` blocks while the commit queue is full and `Db::get` may block on
+//! disk reads, which stalls async executors. `AsyncDb` runs these calls on a
+//! small pool of threads owned by the wrapper and returns futures that complete
+//! when the call does. The futures don't depend on any particular runtime.
+//! `examples/async_db.rs` shows concurrent use from tokio tasks.
+//!
+//! ```no_run
+//! #[tokio::main]
+//! async fn main() -> axia_db::Result<()> {
+//!     let db = axia_db::Db::with_columns("/tmp/async-example", 1)?;
+//!     let db = axia_db::AsyncDb::new(db)?;
+//!     db.commit(vec![(0, b"key".to_vec(), Some(b"value".to_vec()))]).await?;
+//!     assert_eq!(db.get(0, b"key").await?, Some(b"value".to_vec()));
+//!     Ok(())
+//! }
+//! ```
+//!
+//! # Cancellation
+//!
+//! Arguments are copied when the future is created, so the futures are `Send` and
+//! `'static`. The call is handed to the pool when the future is first polled. From then on it
+//! runs to completion even if the future is dropped. A commit is queued by a single
+//! call that either queues all changes or fails before queueing any of them, so a
+//! cancelled commit future leaves the commit either fully queued or not queued at
+//! all. A commit future dropped before it was polled is never queued. Since there
+//! is no way to tell which happened, callers that need to know should read back.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use parking_lot::{Condvar, Mutex};
+use crate::{
+	column::ColId,
+	db::{Db, Value},
+	error::Result,
+};
+
+/// Default number of pool threads.
+const DEFAULT_THREADS: usize = 4;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// Result of a call, passed from the pool thread to the future.
+struct Slot<T> {
+	state: Mutex<(Option<std::thread::Result<T>>, Option<Waker>)>,
+}
+
+struct Call<T>(Arc<Slot<T>>);
+
+impl<T> Future for Call<T> {
+	type Output = T;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+		let mut state = self.0.state.lock();
+		match state.0.take() {
+			// Panics are passed on to the caller.
+			Some(result) => Poll::Ready(result.unwrap_or_else(|p| std::panic::resume_unwind(p))),
+			None => {
+				state.1 = Some(cx.waker().clone());
+				Poll::Pending
+			}
+		}
+	}
+}
+
+#[derive(Default)]
+struct PoolQueue {
+	jobs: VecDeque<Job>,
+	shutdown: bool,
+}
+
+struct Pool {
+	queue: Arc<(Mutex<PoolQueue>, Condvar)>,
+	threads: Vec<std::thread::JoinHandle<()>>,
+}
+
+impl Pool {
+	fn new(db: &Db, threads: usize) -> Result<Pool> {
+		let queue = Arc::new((Mutex::new(PoolQueue::default()), Condvar::new()));
+		let mut pool = Pool { queue, threads: Vec::with_capacity(threads) };
+		for i in 0 .. threads {
+			let queue = pool.queue.clone();
+			// Threads started so far are stopped when `pool` is dropped on error.
+			pool.threads.push(Db::spawn_worker(db.thread_config(), &format!("async-{}", i), move || {
+				Self::worker(&queue)
+			})?);
+		}
+		Ok(pool)
+	}
+
+	fn worker(queue: &(Mutex<PoolQueue>, Condvar)) {
+		loop {
+			let job = {
+				let mut state = queue.0.lock();
+				loop {
+					if let Some(job) = state.jobs.pop_front() {
+						break job;
+					}
+					if state.shutdown {
+						return;
+					}
+					queue.1.wait(&mut state);
+				}
+			};
+			job();
+		}
+	}
+
+	fn call<T: Send + 'static>(&self, f: impl FnOnce() -> T + Send + 'static) -> Call<T> {
+		let slot = Arc::new(Slot { state: Mutex::new((None, None)) });
+		let result = slot.clone();
+		let job = Box::new(move || {
+			let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+			let waker = {
+				let mut state = result.state.lock();
+				state.0 = Some(r);
+				state.1.take()
+			};
+			if let Some(waker) = waker {
+				waker.wake();
+			}
+		});
+		self.queue.0.lock().jobs.push_back(job);
+		self.queue.1.notify_one();
+		Call(slot)
+	}
+}
+
+impl Drop for Pool {
+	fn drop(&mut self) {
+		// Queued calls are completed before the threads exit.
+		self.queue.0.lock().shutdown = true;
+		self.queue.1.notify_all();
+		for t in self.threads.drain(..) {
+			let _ = t.join();
+		}
+	}
+}
+
+/// Async wrapper for `Db`. Cloning is cheap and shares the database and the pool.
+#[derive(Clone)]
+pub struct AsyncDb {
+	db: Arc<Db>,
+	pool: Arc<Pool>,
+}
+
+impl AsyncDb {
+	/// Wrap the database, with the default number of pool threads.
+	pub fn new(db: Db) -> Result<AsyncDb> {
+		Self::with_threads(Arc::new(db), DEFAULT_THREADS)
+	}
+
+	/// Wrap a shared database, with `threads` pool threads. At most `threads` calls
+	/// run at once, others wait in the pool queue.
+	pub fn with_threads(db: Arc<Db>, threads: usize) -> Result<AsyncDb> {
+		let pool = Pool::new(&db, std::cmp::max(threads, 1))?;
+		Ok(AsyncDb { db, pool: Arc::new(pool) })
+	}
+
+	/// The wrapped database, for calls that don't block.
+	pub fn db(&self) -> &Arc<Db> {
+		&self.db
+	}
+
+	/// Async version of `Db::get`.
+	pub fn get(&self, col: ColId, key: &[u8]) -> impl Future<Output=Result<Option<Value>>> + Send + 'static {
+		let (db, pool) = (self.db.clone(), self.pool.clone());
+		let key = key.to_vec();
+		async move { pool.call(move || db.get(col, &key)).await }
+	}
+
+	/// Async version of `Db::commit`. Completes once the changes are queued, with the commit id.
+	pub fn commit<I, K>(&self, tx: I) -> impl Future<Output=Result<u64>> + Send + 'static
+	where
+		I: IntoIterator<Item=(ColId, K, Option<Value>)>,
+		K: AsRef<[u8]>,
+	{
+		let (db, pool) = (self.db.clone(), self.pool.clone());
+		let tx = Self::owned(tx);
+		async move { pool.call(move || db.commit(tx)).await }
+	}
+
+	/// Async version of `Db::commit_durable`. Completes once the changes are written to the log.
+	pub fn commit_durable<I, K, V>(&self, tx: I) -> impl Future<Output=Result<u64>> + Send + 'static
+	where
+		I: IntoIterator<Item=(ColId, K, Option<V>)>,
+		K: AsRef<[u8]>,
+		V: Into<Value>,
+	{
+		let (db, pool) = (self.db.clone(), self.pool.clone());
+		let tx = Self::owned(tx);
+		async move { pool.call(move || db.commit_durable(tx)).await }
+	}
+
+	fn owned<I, K, V>(tx: I) -> Vec<(ColId, Vec<u8>, Option<Value>)>
+	where
+		I: IntoIterator<Item=(ColId, K, Option<V>)>,
+		K: AsRef<[u8]>,
+		V: Into<Value>,
+	{
+		tx.into_iter().map(|(c, k, v)| (c, k.as_ref().to_vec(), v.map(Into::into))).collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use std::task::Wake;
+	use crate::testing::DbBuilder;
+	use crate::Options;
+	use tempfile::tempdir;
+
+	// Minimal executor, so that the tests don't depend on a runtime.
+	struct ThreadWaker(std::thread::Thread, AtomicUsize);
+
+	impl Wake for ThreadWaker {
+		fn wake(self: Arc<Self>) {
+			self.1.fetch_add(1, Ordering::SeqCst);
+			self.0.unpark();
+		}
+	}
+
+	fn block_on<F: Future>(f: F) -> F::Output {
+		let mut f = std::pin::pin!(f);
+		let waker = Arc::new(ThreadWaker(std::thread::current(), AtomicUsize::new(0)));
+		let w = Waker::from(waker);
+		let mut cx = Context::from_waker(&w);
+		loop {
+			if let Poll::Ready(r) = f.as_mut().poll(&mut cx) {
+				return r;
+			}
+			std::thread::park();
+		}
+	}
+
+	#[test]
+	fn async_get_commit() {
+		let tmp = tempdir().unwrap();
+		let db = AsyncDb::new(Db::with_columns(tmp.path(), 1).unwrap()).unwrap();
+		std::thread::scope(|s| {
+			for t in 0 .. 4u8 {
+				let db = db.clone();
+				s.spawn(move || block_on(async move {
+					for i in 0 .. 16u8 {
+						db.commit(vec![(0, [t, i], Some(vec![t, i]))]).await.unwrap();
+						assert_eq!(db.get(0, &[t, i]).await.unwrap(), Some(vec![t, i]));
+					}
+					db.commit_durable(vec![(0, [t, 0xff], Some(vec![t]))]).await.unwrap();
+				}));
+			}
+		});
+		block_on(db.commit(vec![(0, [0, 0], None)])).unwrap();
+		assert_eq!(block_on(db.get(0, &[0, 0])).unwrap(), None);
+		assert_eq!(block_on(db.get(0, &[3, 0xff])).unwrap(), Some(vec![3]));
+	}
+
+	#[test]
+	fn async_commit_queue_full() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		// Commits are not processed, so the queue stays full.
+		let db = DbBuilder::new(options).max_commit_queue_bytes(1024).build().unwrap();
+		let db = AsyncDb::with_threads(Arc::new(db), 1).unwrap();
+		block_on(db.commit(vec![(0, b"key1", Some(vec![0u8; 2048]))])).unwrap();
+
+		// The next commit waits on a pool thread, not on the caller.
+		let mut blocked = Box::pin(db.commit(vec![(0, b"key2", Some(vec![1u8; 16]))]));
+		let waker = Arc::new(ThreadWaker(std::thread::current(), AtomicUsize::new(0)));
+		let w = Waker::from(waker.clone());
+		assert!(blocked.as_mut().poll(&mut Context::from_waker(&w)).is_pending());
+		std::thread::sleep(std::time::Duration::from_millis(100));
+		assert!(blocked.as_mut().poll(&mut Context::from_waker(&w)).is_pending());
+		assert_eq!(waker.1.load(Ordering::SeqCst), 0);
+		assert_eq!(db.db().health().waiting_committers, 1);
+
+		// Draining the queue completes the commit and wakes the task. Polling again
+		// before that would replace the waker.
+		while db.db().process_commits().unwrap() {}
+		while waker.1.load(Ordering::SeqCst) == 0 {
+			std::thread::park_timeout(std::time::Duration::from_millis(10));
+		}
+		assert!(block_on(blocked).is_ok());
+		assert_eq!(block_on(db.get(0, b"key2")).unwrap(), Some(vec![1u8; 16]));
+	}
+
+	#[test]
+	fn async_commit_cancelled() {
+		let tmp = tempdir().unwrap();
+		let db = AsyncDb::new(Db::with_columns(tmp.path(), 1).unwrap()).unwrap();
+		// Never polled, never queued.
+		drop(db.commit(vec![(0, b"key1", Some(b"value1".to_vec()))]));
+		// Polled once, then dropped. Runs to completion.
+		let mut commit = Box::pin(db.commit_durable(vec![(0, b"key2", Some(b"value2"))]));
+		let waker = Arc::new(ThreadWaker(std::thread::current(), AtomicUsize::new(0)));
+		let _ = commit.as_mut().poll(&mut Context::from_waker(&Waker::from(waker)));
+		drop(commit);
+		let mut found = None;
+		for _ in 0 .. 100 {
+			found = block_on(db.get(0, b"key2")).unwrap();
+			if found.is_some() {
+				break;
+			}
+			std::thread::sleep(std::time::Duration::from_millis(10));
+		}
+		assert_eq!(found, Some(b"value2".to_vec()));
+		assert_eq!(block_on(db.get(0, b"key1")).unwrap(), None);
+	}
+
+	#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+	async fn tokio_get_commit() {
+		let tmp = tempdir().unwrap();
+		let db = AsyncDb::new(Db::with_columns(tmp.path(), 1).unwrap()).unwrap();
+		let tasks: Vec<_> = (0 .. 8u8).map(|t| {
+			let db = db.clone();
+			tokio::spawn(async move {
+				for i in 0 .. 16u8 {
+					db.commit(vec![(0, [t, i], Some(vec![t, i]))]).await.unwrap();
+					assert_eq!(db.get(0, &[t, i]).await.unwrap(), Some(vec![t, i]));
+				}
+				db.commit_durable(vec![(0, [t, 0xff], Some(vec![t]))]).await.unwrap();
+			})
+		}).collect();
+		for task in tasks {
+			task.await.unwrap();
+		}
+		assert_eq!(db.get(0, &[7, 0xff]).await.unwrap(), Some(vec![7]));
+	}
+
+	#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+	async fn tokio_commit_queue_full() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = DbBuilder::new(options).max_commit_queue_bytes(1024).build().unwrap();
+		let db = AsyncDb::with_threads(Arc::new(db), 2).unwrap();
+		db.commit(vec![(0, b"key1", Some(vec![0u8; 2048]))]).await.unwrap();
+
+		// The waiting commit doesn't hold the only runtime worker, other tasks keep running.
+		let blocked = tokio::spawn({
+			let db = db.clone();
+			async move { db.commit(vec![(0, b"key2", Some(vec![1u8; 16]))]).await }
+		});
+		while db.db().health().waiting_committers == 0 {
+			tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+		}
+		assert_eq!(db.get(0, b"key1").await.unwrap(), Some(vec![0u8; 2048]));
+		assert!(!blocked.is_finished());
+
+		let inner = db.db().clone();
+		tokio::task::spawn_blocking(move || while inner.process_commits().unwrap() {}).await.unwrap();
+		let result = tokio::time::timeout(std::time::Duration::from_secs(10), blocked).await;
+		assert!(result.expect("Commit not admitted").unwrap().is_ok());
+		assert_eq!(db.get(0, b"key2").await.unwrap(), Some(vec![1u8; 16]));
+	}
+}
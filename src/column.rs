@@ -14,56 +14,292 @@
 // You should have received a copy of the GNU General Public License
 // along with AXIA.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::collections::VecDeque;
-use std::sync::atomic::{AtomicU64, Ordering};
-use parking_lot::RwLock;
+use std::collections::{BTreeSet, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use parking_lot::{Mutex, RwLock};
 use crate::{
 	error::{Error, Result},
 	table::{TableId as ValueTableId, ValueTable, Key, Value},
 	log::{Log, LogOverlays, LogReader, LogWriter, LogAction},
 	display::hex,
 	index::{IndexTable, TableId as IndexTableId, PlanOutcome, Address},
-	options::{Options, ColumnOptions, Metadata},
-	stats::ColumnStats,
+	options::{Options, ColumnOptions, Metadata, IndexMode},
+	file::TableLayout,
+	stats::{ColumnStats, ColumnStatSummary, ReindexEvent, TableSlots},
 	db::check::CheckDisplay,
 };
 use crate::compress::Compress;
+use crate::encrypt::Encryption;
 
-const START_BITS: u8 = 16;
+pub const START_BITS: u8 = 16;
 const MAX_REBALANCE_BATCH: usize = 8192;
+// Number of index chunks a `par_iter` thread processes at a time.
+const PAR_ITER_BATCH: u64 = 256;
+// Number of entries read at a time while iterating a column, rounded up to whole index
+// chunks. The column lock is released between batches.
+const ITER_BATCH: usize = 256;
 
 pub type ColId = u8;
 pub type Salt = [u8; 32];
 
+// Values in ordered columns are prefixed with the original key and its length.
+const ORDERED_KEY_LEN_SIZE: usize = 4;
+// Sorted keys of an ordered column are saved to this file on a clean shutdown.
+const ORDERED_KEYS_FILE_PREFIX: &str = "ordered_keys_";
+const ORDERED_KEYS_TMP_SUFFIX: &str = ".tmp";
+
+/// Name of the file the sorted keys of ordered column `col` are saved to.
+pub fn ordered_keys_file_name(col: ColId) -> String {
+	format!("{}{:02}", ORDERED_KEYS_FILE_PREFIX, col)
+}
+
+/// Whether `name` is a file of sorted keys that was not completely written.
+pub fn is_ordered_keys_temp_file_name(name: &str) -> bool {
+	name.starts_with(ORDERED_KEYS_FILE_PREFIX) && name.ends_with(ORDERED_KEYS_TMP_SUFFIX)
+}
+
+// The file holds the number of keys as a u64, followed by each key as a u32 length and
+// the key bytes. All integers are little endian.
+fn read_ordered_keys(path: &std::path::Path) -> Result<BTreeSet<Vec<u8>>> {
+	fn take<'a>(data: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+		if data.len() < len {
+			return None;
+		}
+		let (head, tail) = data.split_at(len);
+		*data = tail;
+		Some(head)
+	}
+	let data = std::fs::read(path)?;
+	let corrupted = || Error::Corruption(format!("Invalid ordered keys file {}", path.display()));
+	let mut rest = data.as_slice();
+	let count = take(&mut rest, 8).ok_or_else(corrupted)?;
+	let count = u64::from_le_bytes(count.try_into().unwrap());
+	let mut keys = BTreeSet::new();
+	for _ in 0 .. count {
+		let len = take(&mut rest, 4).ok_or_else(corrupted)?;
+		let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+		keys.insert(take(&mut rest, len).ok_or_else(corrupted)?.to_vec());
+	}
+	if !rest.is_empty() {
+		return Err(corrupted());
+	}
+	Ok(keys)
+}
+
 struct Tables {
 	index: IndexTable,
 	value: Vec<ValueTable>,
 }
 
+impl Tables {
+	// Size tiers come from index entries and log records, so they are checked
+	// rather than trusted.
+	fn value_table(&self, tier: u8) -> Result<&ValueTable> {
+		self.value.get(tier as usize).ok_or_else(||
+			Error::Corruption(format!("Invalid value table size tier {}", tier))
+		)
+	}
+}
+
 struct Reindex {
 	queue: VecDeque<IndexTable>,
 	progress: AtomicU64,
+	// Progress information for each index in `queue`.
+	started: VecDeque<ReindexStart>,
+}
+
+struct ReindexStart {
+	record: u64,
+	time: std::time::SystemTime,
+	entries: u64,
+	bytes: u64,
+}
+
+impl ReindexStart {
+	fn new(record: u64) -> ReindexStart {
+		ReindexStart {
+			record,
+			time: std::time::SystemTime::now(),
+			entries: 0,
+			bytes: 0,
+		}
+	}
+}
+
+// Keys recently found to be absent. Bounded by evicting the oldest keys.
+struct NegativeCache {
+	keys: HashSet<Key>,
+	order: VecDeque<Key>,
+	capacity: usize,
+}
+
+impl NegativeCache {
+	fn new(capacity: usize) -> NegativeCache {
+		NegativeCache {
+			keys: HashSet::with_capacity(capacity),
+			order: VecDeque::with_capacity(capacity),
+			capacity,
+		}
+	}
+
+	fn insert(&mut self, key: Key) {
+		if self.capacity == 0 || !self.keys.insert(key) {
+			return;
+		}
+		// `order` may still hold keys that were removed, so it is bounded instead of `keys`.
+		if self.order.len() == self.capacity {
+			if let Some(oldest) = self.order.pop_front() {
+				self.keys.remove(&oldest);
+			}
+		}
+		self.order.push_back(key);
+	}
 }
 
 pub struct Column {
 	tables: RwLock<Tables>,
 	reindex: RwLock<Reindex>,
 	path: std::path::PathBuf,
+	mmap_window: Option<usize>,
 	preimage: bool,
 	uniform_keys: bool,
 	collect_stats: bool,
 	ref_counted: bool,
+	// Values are not indexed, see `IndexMode::AppendOnly`.
+	append_only: bool,
 	salt: Salt,
 	stats: ColumnStats,
 	compression: Compress,
+	encryption: Option<Encryption>,
 	db_version: u32,
+	max_chunk_entries: usize,
+	// Original keys of an ordered column, in order. Values are prefixed with the original key.
+	ordered_keys: Option<RwLock<BTreeSet<Vec<u8>>>>,
+	negative_cache: Option<Mutex<NegativeCache>>,
 }
 
+/// Column entry passed to the column iteration callback.
+#[derive(Clone, Debug)]
 pub struct IterState {
+	/// Index chunk the entry was found in.
 	pub chunk_index: u64,
+	/// Hashed key of the entry. This is the key as stored in the index,
+	/// not the original key passed to `commit`. For columns with `uniform`
+	/// keys this is the first 32 bytes of the original key. Append-only
+	/// columns only store the last 26 bytes, the first 6 are zero.
 	pub key: Key,
+	/// Reference count of the value. Always 1 for columns that are not `ref_counted`.
+	pub rc: u32,
+	/// Original key of an entry of an `ordered` column. `None` for other columns.
+	pub original_key: Option<Vec<u8>>,
+	/// Uncompressed value. For ordered columns, the original key stored with the
+	/// value is moved to `original_key`.
+	pub value: Value,
+	/// Size tier of the value table the value is stored in.
+	pub tier: u8,
+	/// Entry index of the value in the value table. For values spanning several
+	/// entries, this is the first one.
+	pub offset: u64,
+}
+
+/// Which changes `Db::iter_column_while` includes, and how consistent the result is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IterSnapshotPolicy {
+	/// Read the tables together with the commits written to the log but not yet enacted
+	/// into the tables. Commits still in the commit queue are not included. The commit
+	/// pipeline keeps running during the iteration, so entries committed or removed
+	/// meanwhile may or may not be visited. An entry is never visited twice.
+	CommitOverlayIncluded,
+	/// Read the table files only, as of the last enacted log record. Enacting log records
+	/// is paused until the iteration ends, so the entries visited are a consistent
+	/// snapshot that commits made during the iteration don't affect. Commits are still
+	/// written to the log meanwhile, until the log queue is full, so long iterations may
+	/// stall commits. Useful for backups.
+	DiskOnly,
+}
+
+// What the iteration reads, see `IterSnapshotPolicy`.
+#[derive(Clone, Copy)]
+struct IterView<'a> {
+	overlays: &'a RwLock<LogOverlays>,
+	disk_only: bool,
+}
+
+impl<'a> IterView<'a> {
+	fn overlays(log: &'a Log) -> Self {
+		IterView { overlays: log.overlays(), disk_only: false }
+	}
+}
+
+/// Value table entry as stored on disk, see `Db::iter_values_raw`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RawValueEntry {
+	/// Size tier of the value table.
+	pub tier: u8,
+	/// Entry index in the value table. For values spanning several entries,
+	/// this is the first one.
+	pub offset: u64,
+	/// Last 26 bytes of the hashed key, as stored with the value.
+	pub partial_key: [u8; 26],
+	/// Reference count. Always 1 for columns that are not `ref_counted`.
 	pub rc: u32,
-	pub value: Vec<u8>,
+	/// The payload is compressed.
+	pub compressed: bool,
+	/// Payload as stored, i.e. still compressed or encrypted. For ordered columns,
+	/// the payload starts with the original key.
+	pub value: Value,
+}
+
+/// Iterator over all value table entries of a column, see `Db::iter_values_raw`.
+pub struct RawValueIter<'a> {
+	column: &'a Column,
+	log: &'a Log,
+	tier: usize,
+	// Next index in the current table and the continuation parts to skip. `None`
+	// until the table is started.
+	table: Option<(u64, HashSet<u64>)>,
+}
+
+impl<'a> Iterator for RawValueIter<'a> {
+	type Item = Result<RawValueEntry>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		let tables = self.column.tables.read();
+		while let Some(table) = tables.value.get(self.tier) {
+			let (index, parts) = match &mut self.table {
+				Some(state) => state,
+				None => match table.continuation_parts(self.log.overlays()) {
+					Ok(parts) => self.table.insert((1, parts)),
+					Err(e) => {
+						self.tier += 1;
+						return Some(Err(e));
+					}
+				},
+			};
+			while *index < table.filled() {
+				let offset = *index;
+				*index += 1;
+				if parts.contains(&offset) {
+					continue;
+				}
+				match table.get_with_meta(offset, self.log.overlays()) {
+					Ok(Some((value, rc, partial_key, compressed))) => return Some(Ok(RawValueEntry {
+						tier: self.tier as u8,
+						offset,
+						partial_key,
+						rc,
+						compressed,
+						value,
+					})),
+					Ok(None) => continue,
+					Err(e) => return Some(Err(e)),
+				}
+			}
+			self.tier += 1;
+			self.table = None;
+		}
+		None
+	}
 }
 
 enum IterStateOrCorrupted {
@@ -73,46 +309,149 @@ enum IterStateOrCorrupted {
 
 impl Column {
 	pub fn get(&self, key: &Key, log: &RwLock<LogOverlays>) -> Result<Option<Value>> {
-		let tables = self.tables.read();
-		if let Some((tier, value)) = self.get_in_index(key, &tables.index, &*tables, log)? {
-			if self.collect_stats {
-				self.stats.query_hit(tier);
+		let mut value = Vec::new();
+		Ok(if self.get_into(key, log, &mut value)? { Some(value) } else { None })
+	}
+
+	/// Same as `get`, but reads the value into `value`, reusing its allocation.
+	/// Returns `false` if the key is not found. Compressed values still need a
+	/// temporary buffer to be decompressed, encrypted values are decrypted in place.
+	///
+	/// Misses are remembered in the negative cache, if enabled. The caller must hold
+	/// the commit overlay lock and check the overlay first, so that the cache can't
+	/// miss an insertion. See `invalidate_negative_cache`.
+	pub fn get_into(&self, key: &Key, log: &RwLock<LogOverlays>, value: &mut Vec<u8>) -> Result<bool> {
+		self.check_indexed()?;
+		if let Some(cache) = &self.negative_cache {
+			if cache.lock().keys.contains(key) {
+				if self.collect_stats {
+					self.stats.negative_cache_hit();
+				}
+				return Ok(false);
 			}
-			return Ok(Some(value));
 		}
-		for r in &self.reindex.read().queue {
-			if let Some((tier, value)) = self.get_in_index(key, &r, &*tables, log)? {
-				if self.collect_stats {
-					self.stats.query_hit(tier);
+		let tables = self.tables.read();
+		let mut probes = 0;
+		let mut found = self.get_in_index(key, &tables.index, &*tables, log, &mut probes, value)?;
+		if found.is_none() {
+			for r in &self.reindex.read().queue {
+				found = self.get_in_index(key, &r, &*tables, log, &mut probes, value)?;
+				if found.is_some() {
+					break;
 				}
-				return Ok(Some(value));
 			}
 		}
 		if self.collect_stats {
-			self.stats.query_miss();
+			match found {
+				Some(tier) => self.stats.query_hit(tier),
+				None => self.stats.query_miss(),
+			}
+			self.stats.query_probes(probes);
 		}
-		Ok(None)
+		if found.is_none() {
+			if let Some(cache) = &self.negative_cache {
+				cache.lock().insert(*key);
+			}
+			return Ok(false);
+		}
+		let offset = self.user_value_offset(value)?;
+		value.drain(.. offset);
+		Ok(true)
 	}
 
 	pub fn get_size(&self, key: &Key, log: &RwLock<LogOverlays>) -> Result<Option<u32>> {
 		self.get(key, log).map(|v| v.map(|v| v.len() as u32))
 	}
 
-	fn get_in_index(&self, key: &Key, index: &IndexTable, tables: &Tables, log: &RwLock<LogOverlays>) -> Result<Option<(u8, Value)>> {
-		let (mut entry, mut sub_index) = index.get(key, 0, log);
+	/// Read the value and the reference count of `key` from the tables, as of the last
+	/// enacted log record. Unlike `get`, the negative cache and the statistics are not
+	/// used. The value is returned as stored, i.e. with the original key for ordered
+	/// columns. Used by `Options::paranoid_checks`.
+	pub fn get_enacted(&self, key: &Key) -> Result<Option<(Value, u32)>> {
+		self.check_indexed()?;
+		let empty = RwLock::new(LogOverlays::default());
+		let tables = self.tables.read();
+		let reindex = self.reindex.read();
+		for index in std::iter::once(&tables.index).chain(reindex.queue.iter()) {
+			let (mut entry, mut sub_index) = index.get(key, 0, &empty)?;
+			while !entry.is_empty() {
+				let address = entry.address(index.id.index_bits());
+				let mut value = Vec::new();
+				let (rc, _, compressed) = tables.value_table(address.size_tier())?
+					.for_parts(Some(key), address.offset(), &empty, |buf| value.extend_from_slice(buf))?;
+				if rc > 0 {
+					let value = if self.is_encoded(compressed) { self.decode(value, compressed)? } else { value };
+					return Ok(Some((value, rc)));
+				}
+				let (next_entry, next_index) = index.get(key, sub_index + 1, &empty)?;
+				entry = next_entry;
+				sub_index = next_index;
+			}
+		}
+		Ok(None)
+	}
+
+	/// State of a key after writing `value`, given its `state` as returned by `get_enacted`.
+	pub fn state_after_write(&self, state: Option<(Value, u32)>, value: Option<Value>) -> Option<(Value, u32)> {
+		match (state, value) {
+			(Some((existing, rc)), Some(_)) if self.ref_counted => Some((existing, rc + 1)),
+			// Replacing is not supported.
+			(Some(existing), Some(_)) if self.preimage => Some(existing),
+			(_, Some(value)) => Some((value, 1)),
+			(Some((existing, rc)), None) if self.ref_counted && rc > 1 => Some((existing, rc - 1)),
+			(_, None) => None,
+		}
+	}
+
+	/// Bring the index chunks and the value entries for `key` into the page cache.
+	/// Values are not read. Does nothing for append-only columns.
+	pub fn prefetch(&self, key: &Key, log: &RwLock<LogOverlays>) -> Result<()> {
+		if self.append_only {
+			return Ok(());
+		}
+		let tables = self.tables.read();
+		self.prefetch_in_index(key, &tables.index, &tables, log)?;
+		for r in &self.reindex.read().queue {
+			self.prefetch_in_index(key, r, &tables, log)?;
+		}
+		Ok(())
+	}
+
+	fn prefetch_in_index(&self, key: &Key, index: &IndexTable, tables: &Tables, log: &RwLock<LogOverlays>) -> Result<()> {
+		let (mut entry, mut sub_index) = index.get(key, 0, log)?;
 		while !entry.is_empty() {
-			let size_tier = entry.address(index.id.index_bits()).size_tier() as usize;
-			match tables.value[size_tier].get(key, entry.address(index.id.index_bits()).offset(), log)? {
-				Some((value, compressed)) => {
-					let value = if compressed {
-						self.decompress(&value)
-					} else {
-						value
-					};
-					return Ok(Some((size_tier as u8, value)));
+			let address = entry.address(index.id.index_bits());
+			tables.value_table(address.size_tier())?.prefetch(address.offset());
+			let (next_entry, next_index) = index.get(key, sub_index + 1, log)?;
+			entry = next_entry;
+			sub_index = next_index;
+		}
+		Ok(())
+	}
+
+	// Reads the decoded value into `value` and returns its size tier.
+	fn get_in_index(
+		&self,
+		key: &Key,
+		index: &IndexTable,
+		tables: &Tables,
+		log: &RwLock<LogOverlays>,
+		probes: &mut u32,
+		value: &mut Vec<u8>,
+	) -> Result<Option<u8>> {
+		let (mut entry, mut sub_index) = index.get(key, 0, log)?;
+		while !entry.is_empty() {
+			*probes += 1;
+			let size_tier = entry.address(index.id.index_bits()).size_tier();
+			match tables.value_table(size_tier)?.get_into(key, entry.address(index.id.index_bits()).offset(), log, value)? {
+				Some(compressed) => {
+					if self.is_encoded(compressed) {
+						self.decode_in_place(value, compressed)?;
+					}
+					return Ok(Some(size_tier));
 				}
 				None =>  {
-					let (next_entry, next_index) = index.get(key, sub_index + 1, log);
+					let (next_entry, next_index) = index.get(key, sub_index + 1, log)?;
 					entry = next_entry;
 					sub_index = next_index;
 				}
@@ -121,22 +460,24 @@ impl Column {
 		Ok(None)
 	}
 
-	/// Compress if needed and return the target tier to use.
-	fn compress(&self, key: &Key, value: &[u8], tables: &Tables) -> (Option<Vec<u8>>, usize) {
-		Self::compress_internal(&self.compression, key, value, tables)
-	}
-
-	fn compress_internal(compression: &Compress, key: &Key, value: &[u8], tables: &Tables) -> (Option<Vec<u8>>, usize) {
-		let (len, result) = if value.len() > compression.treshold as usize {
+	/// Compress and encrypt if needed. Returns the value to store if it differs from
+	/// the original, whether it is compressed, and the target tier to use.
+	fn encode(&self, key: &Key, value: &[u8], tables: &Tables) -> (Option<Vec<u8>>, bool, usize) {
+		let compression = &self.compression;
+		let (mut result, compressed) = if value.len() > compression.treshold as usize {
 			let cvalue = compression.compress(value);
 			if cvalue.len() < value.len() {
-				(cvalue.len(), Some(cvalue))
+				(Some(cvalue), true)
 			} else {
-				(value.len(), None)
+				(None, false)
 			}
 		} else {
-			(value.len(), None)
+			(None, false)
 		};
+		if let Some(encryption) = &self.encryption {
+			result = Some(encryption.encrypt(result.as_deref().unwrap_or(value)));
+		}
+		let len = result.as_ref().map_or(value.len(), |r| r.len());
 		let target_tier = tables.value.iter().position(|t| len <= t.value_size() as usize);
 		let target_tier = match target_tier {
 			Some(tier) => tier as usize,
@@ -146,44 +487,341 @@ impl Column {
 			}
 		};
 
-		(result, target_tier)
+		(result, compressed, target_tier)
 	}
 
-	fn decompress(&self, buf: &[u8]) -> Vec<u8> {
-		self.compression.decompress(buf)
+	/// Reverse of `encode`.
+	fn decode(&self, value: Vec<u8>, compressed: bool) -> Result<Vec<u8>> {
+		let value = match &self.encryption {
+			Some(encryption) => encryption.decrypt(&value)?,
+			None => value,
+		};
+		if compressed {
+			self.compression.decompress(&value)
+		} else {
+			Ok(value)
+		}
+	}
+
+	/// Same as `decode`, but keeps the allocation of `value` unless it is compressed.
+	fn decode_in_place(&self, value: &mut Vec<u8>, compressed: bool) -> Result<()> {
+		if let Some(encryption) = &self.encryption {
+			encryption.decrypt_in_place(value)?;
+		}
+		if compressed {
+			*value = self.compression.decompress(value)?;
+		}
+		Ok(())
+	}
+
+	/// Stored size of a value is not the actual size if the value was transformed.
+	fn is_encoded(&self, compressed: bool) -> bool {
+		compressed || self.encryption.is_some()
 	}
 
 	pub fn open(col: ColId, options: &Options, metadata: &Metadata) -> Result<Column> {
-		let (index, reindexing, stats) = Self::open_index(&options.path, col)?;
+		let encryption = Encryption::from_options(options);
+		let index_bits = metadata.columns[col as usize].initial_index_bits.unwrap_or(START_BITS);
+		let mmap_window = options.mmap_window;
+		let (index, reindexing, stats) = Self::open_index(&options.path, col, index_bits, mmap_window)?;
 		let collect_stats = options.stats;
+		let layout = TableLayout { growth: options.table_growth, max_file_size: metadata.max_table_file_size };
+		// Not stored in the metadata. Columns missing from the options are read-only.
+		let negative_cache = options.columns.get(col as usize).and_then(|c| c.negative_cache);
 		let path = &options.path;
 		let arc_path = std::sync::Arc::new(path.clone());
 		let options = &metadata.columns[col as usize];
 		let db_version = metadata.version;
+		// Fixed size values are stored encrypted, so the table entries must fit the overhead too.
+		let fixed_len = match options.fixed_value_len {
+			Some(len) => {
+				let len = len as usize + encryption.as_ref().map_or(0, |e| e.overhead());
+				if len > crate::table::MAX_FIXED_VALUE_LEN as usize {
+					return Err(Error::InvalidConfiguration(
+						format!("Fixed value size is too large for column {}", col)
+					));
+				}
+				Some(len as u16)
+			},
+			None => None,
+		};
+		let num_tables = if fixed_len.is_some() { 1 } else { options.sizes.len() + 1 };
 		let tables = Tables {
 			index,
-			value: (0.. options.sizes.len() + 1)
-				.map(|i| Self::open_table(arc_path.clone(), col, i as u8, &options, db_version)).collect::<Result<_>>()?
+			value: (0..num_tables)
+				.map(|i| Self::open_table(arc_path.clone(), col, i as u8, fixed_len, &options, layout, db_version)).collect::<Result<_>>()?
 		};
 
 		Ok(Column {
 			tables: RwLock::new(tables),
 			reindex: RwLock::new(Reindex {
+				started: reindexing.iter().map(|_| ReindexStart::new(0)).collect(),
 				queue: reindexing,
 				progress: AtomicU64::new(0),
 			}),
 			path: path.into(),
+			mmap_window,
 			preimage: options.preimage,
 			uniform_keys: options.uniform,
 			ref_counted: options.ref_counted,
+			append_only: options.index == IndexMode::AppendOnly,
 			collect_stats,
 			salt: metadata.salt.clone(),
 			stats,
 			compression: Compress::new(options.compression, options.compression_treshold),
+			encryption,
 			db_version,
+			max_chunk_entries: crate::index::max_chunk_entries(options.target_load_factor),
+			ordered_keys: if options.ordered { Some(RwLock::new(BTreeSet::new())) } else { None },
+			negative_cache: negative_cache.map(|capacity| Mutex::new(NegativeCache::new(capacity))),
 		})
 	}
 
+	pub fn is_ordered(&self) -> bool {
+		self.ordered_keys.is_some()
+	}
+
+	/// Returns `true` if neither the index, nor tables that are still being reindexed
+	/// have any entries, including changes in the log overlay.
+	pub fn is_empty(&self, log: &Log) -> Result<bool> {
+		if self.append_only {
+			let mut empty = true;
+			self.iter_while(log, |_| {
+				empty = false;
+				false
+			})?;
+			return Ok(empty);
+		}
+		let tables = self.tables.read();
+		let reindex = self.reindex.read();
+		for index in std::iter::once(&tables.index).chain(reindex.queue.iter()) {
+			if !index.is_empty(log.overlays())? {
+				return Ok(false);
+			}
+		}
+		Ok(true)
+	}
+
+	/// Occupied index entries relative to the capacity of the index at the target load
+	/// factor. Entries of old index tables that are still being reindexed are counted
+	/// against the new index. A reindex starts when a single index chunk is full, which
+	/// usually happens before this reaches 1.0. Reads the whole index. Append-only
+	/// columns have no index and return 0.
+	pub fn load_factor(&self, log: &Log) -> Result<f64> {
+		if self.append_only {
+			return Ok(0.0);
+		}
+		let tables = self.tables.read();
+		let reindex = self.reindex.read();
+		let occupied: u64 = std::iter::once(&tables.index).chain(reindex.queue.iter())
+			.map(|t| t.occupied_entries(log.overlays()))
+			.sum::<Result<u64>>()?;
+		let capacity = tables.index.id.total_chunks() * self.max_chunk_entries as u64;
+		Ok(occupied as f64 / capacity as f64)
+	}
+
+	/// Bytes used by the value tables, see `ValueTable::used_bytes`. Doesn't touch the
+	/// files, so this is cheap enough to call on every commit.
+	pub fn value_table_bytes(&self) -> u64 {
+		self.tables.read().value.iter().map(|t| t.used_bytes()).sum()
+	}
+
+	/// Number of entries as counted by the statistics that were last saved to the
+	/// index metadata. Doesn't read the index. Only maintained with `Options::stats`,
+	/// and saved on `Db::collect_stats` and on shutdown, so later changes are not
+	/// included. While reindexing, the new index has no saved statistics yet, so the
+	/// larger counter of the current and old indexes is returned.
+	pub fn approximate_on_disk_entry_count(&self) -> u64 {
+		let tables = self.tables.read();
+		let reindex = self.reindex.read();
+		std::iter::once(&tables.index).chain(reindex.queue.iter())
+			.map(|index| index.stored_total_values())
+			.max()
+			.unwrap_or(0)
+	}
+
+	/// Start reindexing into a twice larger index, as if an index chunk was full. Old
+	/// entries are moved once `record_id` is enacted. Fails for append-only columns.
+	#[cfg(any(test, feature = "testing"))]
+	pub fn force_reindex(&self, record_id: u64) -> Result<()> {
+		self.check_indexed()?;
+		let tables = self.tables.upgradable_read();
+		let reindex = self.reindex.upgradable_read();
+		Self::trigger_reindex(tables, reindex, self.path.as_path(), self.mmap_window, record_id);
+		Ok(())
+	}
+
+	/// Forget that `key` is absent. Must be called with the commit overlay locked for
+	/// writing, before the key is added to the overlay.
+	pub fn invalidate_negative_cache(&self, key: &Key) {
+		if let Some(cache) = &self.negative_cache {
+			cache.lock().keys.remove(key);
+		}
+	}
+
+	/// Values of append-only columns can't be looked up by key.
+	pub fn check_indexed(&self) -> Result<()> {
+		if self.append_only {
+			return Err(Error::InvalidInput(format!("Column {} is append-only", self.tables.read().index.id.col())));
+		}
+		Ok(())
+	}
+
+	pub fn is_append_only(&self) -> bool {
+		self.append_only
+	}
+
+	/// Returns `true` while entries are being moved from old index tables.
+	pub fn is_reindexing(&self) -> bool {
+		!self.reindex.read().queue.is_empty()
+	}
+
+	/// Value as stored in the column. Ordered columns keep the original key with the value.
+	pub fn ordered_value(&self, key: &[u8], value: Value) -> Value {
+		if !self.is_ordered() {
+			return value;
+		}
+		Self::with_original_key(key, &value)
+	}
+
+	/// `value` prefixed with the original key, as stored in ordered columns.
+	pub fn with_original_key(key: &[u8], value: &[u8]) -> Value {
+		let mut result = Vec::with_capacity(ORDERED_KEY_LEN_SIZE + key.len() + value.len());
+		result.extend_from_slice(&(key.len() as u32).to_le_bytes());
+		result.extend_from_slice(key);
+		result.extend_from_slice(value);
+		result
+	}
+
+	/// Reverse of `ordered_value`.
+	pub fn user_value(&self, mut value: Value) -> Result<Value> {
+		let offset = self.user_value_offset(&value)?;
+		value.drain(.. offset);
+		Ok(value)
+	}
+
+	pub fn user_value_offset(&self, value: &[u8]) -> Result<usize> {
+		if !self.is_ordered() {
+			return Ok(0);
+		}
+		Ok(ORDERED_KEY_LEN_SIZE + Self::original_key(value)?.len())
+	}
+
+	/// Original key stored with a value of an ordered column.
+	pub fn original_key(value: &[u8]) -> Result<&[u8]> {
+		let corrupted = || Error::Corruption("Missing key in ordered column value".into());
+		let len_bytes = value.get(.. ORDERED_KEY_LEN_SIZE).ok_or_else(corrupted)?;
+		let mut len = [0u8; ORDERED_KEY_LEN_SIZE];
+		len.copy_from_slice(len_bytes);
+		let len = u32::from_le_bytes(len) as usize;
+		value.get(ORDERED_KEY_LEN_SIZE .. ORDERED_KEY_LEN_SIZE + len).ok_or_else(corrupted)
+	}
+
+	// Splits a value of an ordered column into the original key and the value passed
+	// to `commit`. Other values are returned unchanged.
+	fn split_ordered_value(&self, mut value: Value) -> Result<(Option<Vec<u8>>, Value)> {
+		if !self.is_ordered() {
+			return Ok((None, value));
+		}
+		let key = Self::original_key(&value)?.to_vec();
+		value.drain(.. ORDERED_KEY_LEN_SIZE + key.len());
+		Ok((Some(key), value))
+	}
+
+	/// Add the original keys in `start .. end` to `keys`. Unbounded if `None`.
+	pub fn ordered_keys_in(&self, start: Option<&[u8]>, end: Option<&[u8]>, keys: &mut BTreeSet<Vec<u8>>) {
+		use std::ops::Bound;
+		if let Some(ordered_keys) = &self.ordered_keys {
+			let start = start.map_or(Bound::Unbounded, Bound::Included);
+			let end = end.map_or(Bound::Unbounded, Bound::Excluded);
+			if matches!((start, end), (Bound::Included(s), Bound::Excluded(e)) if s >= e) {
+				return;
+			}
+			keys.extend(ordered_keys.read().range::<[u8], _>((start, end)).cloned());
+		}
+	}
+
+	/// Load the sorted keys of an ordered column. Keys saved by `save_ordered_keys` are
+	/// used if `use_saved` is set and the file can be read. Otherwise the keys are rebuilt
+	/// from the stored values. Unless `read_only` is set, the file is removed, so that it
+	/// is not used after an unclean shutdown.
+	pub fn load_ordered_keys(&self, log: &Log, use_saved: bool, read_only: bool) -> Result<()> {
+		let ordered_keys = match &self.ordered_keys {
+			Some(keys) => keys,
+			None => return Ok(()),
+		};
+		let id = self.tables.read().index.id;
+		let path = self.path.join(ordered_keys_file_name(id.col()));
+		let saved = if use_saved && path.exists() {
+			match read_ordered_keys(&path) {
+				Ok(keys) => Some(keys),
+				Err(e) => {
+					log::warn!(target: "axia-db", "{}: Error reading saved ordered keys, rebuilding: {:?}", id, e);
+					None
+				}
+			}
+		} else {
+			None
+		};
+		if !read_only && path.exists() {
+			std::fs::remove_file(&path)?;
+		}
+		let keys = match saved {
+			Some(keys) => keys,
+			None => {
+				let mut keys = BTreeSet::new();
+				let mut result = Ok(());
+				self.iter_while(log, |state| match state.original_key {
+					Some(key) => {
+						keys.insert(key);
+						true
+					},
+					None => {
+						result = Err(Error::Corruption("Missing key in ordered column value".into()));
+						false
+					},
+				})?;
+				result?;
+				keys
+			},
+		};
+		log::debug!(target: "axia-db", "{}: Loaded {} ordered keys", id, keys.len());
+		*ordered_keys.write() = keys;
+		Ok(())
+	}
+
+	/// Save the sorted keys of an ordered column, so that they are not rebuilt on the
+	/// next open. Must only be called once all changes are written to the tables. The
+	/// file is written next to it first and then renamed.
+	pub fn save_ordered_keys(&self) -> Result<()> {
+		use std::io::Write;
+		let ordered_keys = match &self.ordered_keys {
+			Some(keys) => keys.read(),
+			None => return Ok(()),
+		};
+		let path = self.path.join(ordered_keys_file_name(self.tables.read().index.id.col()));
+		let mut tmp_path = path.clone().into_os_string();
+		tmp_path.push(ORDERED_KEYS_TMP_SUFFIX);
+		let tmp_path = std::path::PathBuf::from(tmp_path);
+		let result = std::fs::File::create(&tmp_path)
+			.and_then(|file| {
+				let mut writer = std::io::BufWriter::new(file);
+				writer.write_all(&(ordered_keys.len() as u64).to_le_bytes())?;
+				for key in ordered_keys.iter() {
+					writer.write_all(&(key.len() as u32).to_le_bytes())?;
+					writer.write_all(key)?;
+				}
+				writer.into_inner().map_err(|e| e.into_error())?.sync_all()
+			})
+			.and_then(|_| std::fs::rename(&tmp_path, &path));
+		if result.is_err() {
+			let _ = std::fs::remove_file(&tmp_path);
+		}
+		Ok(result?)
+	}
+
+	/// Key as stored in the index. Does not allocate: the result is returned by value
+	/// and the blake2b state lives on the stack, so this is cheap to call on read paths.
 	pub fn hash(&self, key: &[u8]) -> Key {
 		let mut k = Key::default();
 		if self.uniform_keys {
@@ -203,13 +841,28 @@ impl Column {
 		Ok(())
 	}
 
-	fn open_index(path: &std::path::Path, col: ColId) -> Result<(IndexTable, VecDeque<IndexTable>, ColumnStats)> {
+	/// Create the index file and extend each value table file to `bytes`.
+	pub fn preallocate(&self, bytes: u64) -> Result<()> {
+		let tables = self.tables.read();
+		tables.index.preallocate()?;
+		for t in tables.value.iter() {
+			t.preallocate(bytes)?;
+		}
+		Ok(())
+	}
+
+	fn open_index(
+		path: &std::path::Path,
+		col: ColId,
+		initial_bits: u8,
+		mmap_window: Option<usize>,
+	) -> Result<(IndexTable, VecDeque<IndexTable>, ColumnStats)> {
 		let mut reindexing = VecDeque::new();
 		let mut top = None;
 		let mut stats = ColumnStats::empty();
 		for bits in (START_BITS .. 65).rev() {
 			let id = IndexTableId::new(col, bits);
-			if let Some(table) = IndexTable::open_existing(path, id)? {
+			if let Some(table) = IndexTable::open_existing(path, id, mmap_window)? {
 				if top.is_none() {
 					stats = table.load_stats();
 					top = Some(table);
@@ -220,7 +873,7 @@ impl Column {
 		}
 		let table = match top {
 			Some(table) => table,
-			None => IndexTable::create_new(path, IndexTableId::new(col, START_BITS)),
+			None => IndexTable::create_new(path, IndexTableId::new(col, initial_bits), mmap_window),
 		};
 		Ok((table, reindexing, stats))
 	}
@@ -229,18 +882,22 @@ impl Column {
 		path: std::sync::Arc<std::path::PathBuf>,
 		col: ColId,
 		tier: u8,
+		fixed_len: Option<u16>,
 		options: &ColumnOptions,
+		layout: TableLayout,
 		db_version: u32,
 	) -> Result<ValueTable> {
 		let id = ValueTableId::new(col, tier);
 		let entry_size = options.sizes.get(tier as usize).cloned();
-		ValueTable::open(path, id, entry_size, options, db_version)
+		ValueTable::open(path, id, entry_size, fixed_len, options, layout, db_version)
 	}
 
 	fn trigger_reindex(
 		tables: parking_lot::RwLockUpgradableReadGuard<Tables>,
 		reindex: parking_lot::RwLockUpgradableReadGuard<Reindex>,
 		path: &std::path::Path,
+		mmap_window: Option<usize>,
+		record_id: u64,
 	) {
 		let mut tables = parking_lot::RwLockUpgradableReadGuard::upgrade(tables);
 		let mut reindex = parking_lot::RwLockUpgradableReadGuard::upgrade(reindex);
@@ -254,21 +911,30 @@ impl Column {
 			tables.index.id.col(),
 			tables.index.id.index_bits() + 1
 		);
-		let new_table = IndexTable::create_new(path, new_index_id);
+		let new_table = IndexTable::create_new(path, new_index_id, mmap_window);
 		let old_table = std::mem::replace(&mut tables.index, new_table);
 		reindex.queue.push_back(old_table);
+		reindex.started.push_back(ReindexStart::new(record_id));
 	}
 
 	pub fn write_reindex_plan(&self, key: &Key, address: Address, log: &mut LogWriter) -> Result<PlanOutcome> {
 		let tables = self.tables.upgradable_read();
 		let reindex = self.reindex.upgradable_read();
-		if Self::search_index(key, &tables.index, &*tables, log)?.is_some() {
+		let mut collisions = 0;
+		let existing = Self::search_index(key, &tables.index, &*tables, log, &mut collisions)?;
+		if self.collect_stats {
+			self.stats.write_collisions(collisions);
+		}
+		if existing.is_some() {
 			return Ok(PlanOutcome::Skipped);
 		}
-		match tables.index.write_insert_plan(key, address, None, log)? {
+		match tables.index.write_insert_plan(key, address, None, self.max_chunk_entries, log)? {
 			PlanOutcome::NeedReindex => {
 				log::debug!(target: "axia-db", "{}: Index chunk full {}", tables.index.id, hex(key));
-				Self::trigger_reindex(tables, reindex, self.path.as_path());
+				if self.collect_stats {
+					self.stats.write_reindex();
+				}
+				Self::trigger_reindex(tables, reindex, self.path.as_path(), self.mmap_window, log.record_id());
 				self.write_reindex_plan(key, address, log)?;
 				return Ok(PlanOutcome::NeedReindex);
 			}
@@ -282,17 +948,19 @@ impl Column {
 		key: &Key,
 		index: &'a IndexTable,
 		tables: &'a Tables,
-		log: &LogWriter
+		log: &LogWriter,
+		collisions: &mut u64,
 	) -> Result<Option<(&'a IndexTable, usize, u8, Address)>> {
-		let (mut existing_entry, mut sub_index) = index.get(key, 0, log);
+		let (mut existing_entry, mut sub_index) = index.get(key, 0, log)?;
 		while !existing_entry.is_empty() {
 			let existing_address = existing_entry.address(index.id.index_bits());
 			let existing_tier = existing_address.size_tier();
-			if tables.value[existing_tier as usize].has_key_at(existing_address.offset(), &key, log)? {
+			if tables.value_table(existing_tier)?.has_key_at(existing_address.offset(), &key, log)? {
 				return Ok(Some((&index, sub_index, existing_tier, existing_address)));
 			}
+			*collisions += 1;
 
-			let (next_entry, next_index) = index.get(key, sub_index + 1, log);
+			let (next_entry, next_index) = index.get(key, sub_index + 1, log)?;
 			existing_entry = next_entry;
 			sub_index = next_index;
 		};
@@ -303,15 +971,16 @@ impl Column {
 		key: &Key,
 		tables: &'a Tables,
 		reindex: &'a Reindex,
-		log: &LogWriter
+		log: &LogWriter,
+		collisions: &mut u64,
 	) -> Result<Option<(&'a IndexTable, usize, u8, Address)>> {
-			if let Some(r) = Self::search_index(key, &tables.index, tables, log)? {
+			if let Some(r) = Self::search_index(key, &tables.index, tables, log, collisions)? {
 				return Ok(Some(r));
 			}
 			// Check old indexes
 			// TODO: don't search if index precedes reindex progress
 			for index in &reindex.queue {
-				if let Some(r) = Self::search_index(key, index, tables, log)? {
+				if let Some(r) = Self::search_index(key, index, tables, log, collisions)? {
 					return Ok(Some(r));
 				}
 			}
@@ -319,41 +988,55 @@ impl Column {
 	}
 
 	pub fn write_plan(&self, key: &Key, value: &Option<Value>, log: &mut LogWriter) -> Result<PlanOutcome> {
+		if self.append_only {
+			return self.write_append_plan(key, value, log);
+		}
 		//TODO: return sub-chunk position in index.get
 		let tables = self.tables.upgradable_read();
 		let reindex = self.reindex.upgradable_read();
-		let existing = Self::search_all_indexes(key, &*tables, &*reindex, log)?;
+		let mut collisions = 0;
+		let existing = Self::search_all_indexes(key, &*tables, &*reindex, log, &mut collisions)?;
+		if self.collect_stats {
+			self.stats.write_collisions(collisions);
+		}
 		if let &Some(ref val) = value {
+			if let Some(ordered_keys) = &self.ordered_keys {
+				ordered_keys.write().insert(Self::original_key(val)?.to_vec());
+			}
 			if let Some((table, sub_index, existing_tier, existing_address)) = existing {
 				let existing_tier = existing_tier as usize;
 				if self.ref_counted {
 					log::trace!(target: "axia-db", "{}: Increment ref {}", tables.index.id, hex(key));
 					tables.value[existing_tier].write_inc_ref(existing_address.offset(), log)?;
+					if self.collect_stats {
+						self.stats.write_replaced();
+					}
 					return Ok(PlanOutcome::Written);
 				}
 				if self.preimage {
 					// Replace is not supported
 					return Ok(PlanOutcome::Skipped);
 				}
-				let (cval, target_tier) = self.compress(&key, &val, &*tables);
-				let (cval, compressed) = cval.as_ref()
-					.map(|cval| (cval.as_slice(), true))
-					.unwrap_or((val.as_slice(), false));
+				let (cval, compressed, target_tier) = self.encode(&key, &val, &*tables);
+				let cval = cval.as_deref().unwrap_or(val.as_slice());
 
 				if self.collect_stats {
 					let (cur_size, compressed) = tables.value[existing_tier].size(&key, existing_address.offset(), log)?
 						.unwrap_or((0, false));
-					if compressed {
+					if self.is_encoded(compressed) {
 						// This is very costly.
-						let compressed = tables.value[existing_tier].get(&key, existing_address.offset(), log)?
-							.expect("Same query as size").0;
-						let uncompressed = self.decompress(compressed.as_slice());
+						let stored = tables.value[existing_tier].get(&key, existing_address.offset(), log)?
+							.ok_or_else(|| Error::Corruption(format!("Missing value for {}", hex(key))))?.0;
+						let uncompressed = self.decode(stored, compressed)?;
 
 						self.stats.replace_val(cur_size, uncompressed.len() as u32, val.len() as u32, cval.len() as u32);
 					} else {
 						self.stats.replace_val(cur_size, cur_size, val.len() as u32, cval.len() as u32);
 					}
 				}
+				if self.collect_stats {
+					self.stats.write_replaced();
+				}
 				if existing_tier == target_tier {
 					log::trace!(target: "axia-db", "{}: Replacing {}", tables.index.id, hex(key));
 					tables.value[target_tier].write_replace_plan(existing_address.offset(), key, &cval, log, compressed)?;
@@ -365,27 +1048,33 @@ impl Column {
 					let new_address = Address::new(new_offset, target_tier as u8);
 					// If it was found in an older index we just insert a new entry. Reindex won't overwrite it.
 					let sub_index = if table.id == tables.index.id { Some(sub_index) } else { None };
-					return tables.index.write_insert_plan(key, new_address, sub_index, log);
+					let outcome = tables.index.write_insert_plan(key, new_address, sub_index, self.max_chunk_entries, log)?;
+					if self.collect_stats && matches!(outcome, PlanOutcome::NeedReindex) {
+						self.stats.write_reindex();
+					}
+					return Ok(outcome);
 				}
 			} else {
-				let (cval, target_tier) = self.compress(&key, &val, &*tables);
-				let (cval, compressed) = cval.as_ref()
-					.map(|cval| (cval.as_slice(), true))
-					.unwrap_or((val.as_slice(), false));
+				let (cval, compressed, target_tier) = self.encode(&key, &val, &*tables);
+				let cval = cval.as_deref().unwrap_or(val.as_slice());
 
 				log::trace!(target: "axia-db", "{}: Inserting new index {}, size = {}", tables.index.id, hex(key), cval.len());
 				let offset = tables.value[target_tier].write_insert_plan(key, &cval, log, compressed)?;
 				let address = Address::new(offset, target_tier as u8);
-				match tables.index.write_insert_plan(key, address, None, log)? {
+				match tables.index.write_insert_plan(key, address, None, self.max_chunk_entries, log)? {
 					PlanOutcome::NeedReindex => {
 						log::debug!(target: "axia-db", "{}: Index chunk full {}", tables.index.id, hex(key));
-						Self::trigger_reindex(tables, reindex, self.path.as_path());
+						if self.collect_stats {
+							self.stats.write_reindex();
+						}
+						Self::trigger_reindex(tables, reindex, self.path.as_path(), self.mmap_window, log.record_id());
 						self.write_plan(key, value, log)?;
 						return Ok(PlanOutcome::NeedReindex);
 					}
 					_ => {
 						if self.collect_stats {
 							self.stats.insert_val(val.len() as u32, cval.len() as u32);
+							self.stats.write_inserted();
 						}
 						return Ok(PlanOutcome::Written);
 					}
@@ -398,11 +1087,11 @@ impl Column {
 				let cur_size = if self.collect_stats {
 					let (cur_size, compressed) = tables.value[existing_tier].size(&key, existing_address.offset(), log)?
 						.unwrap_or((0, false));
-					Some(if compressed {
+					Some(if self.is_encoded(compressed) {
 						// This is very costly.
-						let compressed = tables.value[existing_tier].get(&key, existing_address.offset(), log)?
-							.expect("Same query as size").0;
-						let uncompressed = self.decompress(compressed.as_slice());
+						let stored = tables.value[existing_tier].get(&key, existing_address.offset(), log)?
+							.ok_or_else(|| Error::Corruption(format!("Missing value for {}", hex(key))))?.0;
+						let uncompressed = self.decode(stored, compressed)?;
 
 						(cur_size, uncompressed.len() as u32)
 					} else {
@@ -411,6 +1100,14 @@ impl Column {
 				} else {
 					None
 				};
+				// The original key is only stored with the value.
+				let ordered_key = if self.is_ordered() {
+					let (stored, compressed) = tables.value[existing_tier].get(key, existing_address.offset(), log)?
+						.ok_or_else(|| Error::Corruption(format!("Missing value for {}", hex(key))))?;
+					Some(Self::original_key(&self.decode(stored, compressed)?)?.to_vec())
+				} else {
+					None
+				};
 				let remove = if self.ref_counted {
 					let removed = !tables.value[existing_tier].write_dec_ref(existing_address.offset(), log)?;
 					log::trace!(target: "axia-db", "{}: Dereference {}, deleted={}", table.id, hex(key), removed);
@@ -424,8 +1121,14 @@ impl Column {
 					if let Some((compressed_size, uncompressed_size)) = cur_size {
 						self.stats.remove_val(uncompressed_size, compressed_size);
 					}
+					if let (Some(ordered_keys), Some(key)) = (&self.ordered_keys, ordered_key) {
+						ordered_keys.write().remove(&key);
+					}
 					table.write_remove_plan(key, sub_index, log)?;
 				}
+				if self.collect_stats {
+					self.stats.write_removed();
+				}
 				return Ok(PlanOutcome::Written);
 			}
 			log::trace!(target: "axia-db", "{}: Deletion missed {}", tables.index.id, hex(key));
@@ -436,6 +1139,23 @@ impl Column {
 		Ok(PlanOutcome::Skipped)
 	}
 
+	// Append-only columns only write to the value tables.
+	fn write_append_plan(&self, key: &Key, value: &Option<Value>, log: &mut LogWriter) -> Result<PlanOutcome> {
+		let val = value.as_ref().ok_or_else(||
+			Error::InvalidInput("Values can't be deleted from append-only columns".into())
+		)?;
+		let tables = self.tables.read();
+		let (cval, compressed, target_tier) = self.encode(key, val, &tables);
+		let cval = cval.as_deref().unwrap_or(val.as_slice());
+		log::trace!(target: "axia-db", "{}: Appending {}, size = {}", tables.index.id, hex(key), cval.len());
+		tables.value[target_tier].write_insert_plan(key, cval, log, compressed)?;
+		if self.collect_stats {
+			self.stats.insert_val(val.len() as u32, cval.len() as u32);
+			self.stats.write_inserted();
+		}
+		Ok(PlanOutcome::Written)
+	}
+
 	pub fn enact_plan(&self, action: LogAction, log: &mut LogReader) -> Result<()> {
 		let tables = self.tables.read();
 		let reindex = self.reindex.read();
@@ -456,9 +1176,9 @@ impl Column {
 				}
 			},
 			LogAction::InsertValue(record) => {
-				tables.value[record.table.size_tier() as usize].enact_plan(record.index, log)?;
+				tables.value_table(record.table.size_tier())?.enact_plan(record.index, log)?;
 			}
-			_ => panic!("Unexpected log action"),
+			_ => return Err(Error::Corruption("Unexpected log action".into())),
 		}
 		Ok(())
 	}
@@ -481,14 +1201,14 @@ impl Column {
 						"Missing table {}, starting reindex",
 						record.table,
 					);
-					Self::trigger_reindex(tables, reindex, self.path.as_path());
+					Self::trigger_reindex(tables, reindex, self.path.as_path(), self.mmap_window, log.record_id());
 					return self.validate_plan(LogAction::InsertIndex(record), log);
 				}
 			},
 			LogAction::InsertValue(record) => {
-				tables.value[record.table.size_tier() as usize].validate_plan(record.index, log)?;
+				tables.value_table(record.table.size_tier())?.validate_plan(record.index, log)?;
 			}
-			_ => panic!("Unexpected log action"),
+			_ => return Err(Error::Corruption("Unexpected log action".into())),
 		}
 		Ok(())
 	}
@@ -508,130 +1228,425 @@ impl Column {
 		let tables = self.tables.read();
 		for t in tables.value.iter() {
 			t.refresh_metadata()?;
+			if self.collect_stats {
+				t.count_free_entries()?;
+			}
 		}
 		Ok(())
 	}
 
+	fn table_slots(tables: &Tables) -> TableSlots {
+		TableSlots {
+			value: tables.value.iter().map(|t| t.filled() - 1).collect(),
+			free: tables.value.iter().map(|t| t.free_entries()).collect(),
+			index: tables.index.id.total_entries(),
+		}
+	}
+
 	pub fn write_stats(&self, writer: &mut impl std::io::Write) {
 		let tables = self.tables.read();
 		tables.index.write_stats(&self.stats);
-		self.stats.write_summary(writer, tables.index.id.col());
+		self.stats.write_summary(writer, tables.index.id.col(), &Self::table_slots(&tables));
+	}
+
+	pub fn stats_summary(&self) -> ColumnStatSummary {
+		let tables = self.tables.read();
+		self.stats.summary(tables.index.id.col(), &Self::table_slots(&tables))
 	}
 
 	pub fn clear_stats(&self) {
 		let tables = self.tables.read();
-		let empty_stats = ColumnStats::empty();
-		tables.index.write_stats(&empty_stats);
+		self.stats.clear();
+		tables.index.write_stats(&self.stats);
 	}
 
-	pub fn iter_while(&self, log: &Log, mut f: impl FnMut(IterState) -> bool) -> Result<()> {
+	pub fn iter_while(&self, log: &Log, f: impl FnMut(IterState) -> bool) -> Result<()> {
+		self.iter_with_policy_while(log, IterSnapshotPolicy::CommitOverlayIncluded, f)
+	}
+
+	/// Same as `iter_while`, reading what `policy` allows. For `DiskOnly`, the caller must
+	/// keep log records from being enacted.
+	pub fn iter_with_policy_while(
+		&self,
+		log: &Log,
+		policy: IterSnapshotPolicy,
+		mut f: impl FnMut(IterState) -> bool,
+	) -> Result<()> {
 		let action = |state | match state {
 			IterStateOrCorrupted::Item(item) => Ok(f(item)),
 			IterStateOrCorrupted::Corrupted( .. ) => Err(Error::Corruption("Missing indexed value".into())),
 		};
-		self.iter_while_inner(log, action, 0, true)
+		let empty = RwLock::new(LogOverlays::default());
+		let view = match policy {
+			IterSnapshotPolicy::CommitOverlayIncluded => IterView::overlays(log),
+			IterSnapshotPolicy::DiskOnly => IterView { overlays: &empty, disk_only: true },
+		};
+		self.iter_while_inner(view, action, 0, true)
 	}
 
-	fn iter_while_inner(
+	/// Calls `f` for every entry on `threads` threads named `thread_name`. Index chunks are
+	/// handed out to the threads in batches, append-only columns are split by value table.
+	pub fn par_iter(
 		&self,
 		log: &Log,
+		threads: usize,
+		thread_name: &str,
+		f: impl Fn(IterState) + Sync,
+	) -> Result<()> {
+		let (index_id, tiers) = {
+			let tables = self.tables.read();
+			(tables.index.id, tables.value.len())
+		};
+		let total_chunks = index_id.total_chunks();
+		let view = IterView::overlays(log);
+		let stop = AtomicBool::new(false);
+		let next = AtomicU64::new(0);
+		let work = || -> Result<()> {
+			let mut action = |state| match state {
+				IterStateOrCorrupted::Item(item) => {
+					f(item);
+					Ok(!stop.load(Ordering::Relaxed))
+				},
+				IterStateOrCorrupted::Corrupted( .. ) => Err(Error::Corruption("Missing indexed value".into())),
+			};
+			loop {
+				if stop.load(Ordering::Relaxed) {
+					return Ok(());
+				}
+				if self.append_only {
+					let tier = next.fetch_add(1, Ordering::Relaxed) as usize;
+					if tier >= tiers {
+						return Ok(());
+					}
+					self.iter_value_table_while(tier, view, |table, index, rc, pk, value, compressed| {
+						self.append_only_entry(table, index, rc, pk, value, compressed).map(Some)
+					}, &mut action)?;
+				} else {
+					let start = next.fetch_add(PAR_ITER_BATCH, Ordering::Relaxed);
+					if start >= total_chunks {
+						return Ok(());
+					}
+					let end = std::cmp::min(start + PAR_ITER_BATCH, total_chunks);
+					self.iter_index_while(index_id, view, &mut action, start .. end, false)?;
+				}
+			}
+		};
+		std::thread::scope(|s| {
+			let handles = (0 .. threads.max(1)).map(|_| {
+				std::thread::Builder::new()
+					.name(thread_name.into())
+					.spawn_scoped(s, || work().inspect_err(|_| stop.store(true, Ordering::Relaxed)))
+					.map_err(Error::Io)
+			}).collect::<Vec<_>>();
+			let mut result = Ok(());
+			for handle in handles {
+				let r = handle.and_then(|h| h.join().unwrap_or_else(|e| std::panic::resume_unwind(e)));
+				if result.is_ok() {
+					result = r;
+				}
+			}
+			result
+		})
+	}
+
+	// Entries are read in batches of `ITER_BATCH`, and `f` is called without holding the
+	// column lock, so that a slow callback does not hold up commits. Entries present
+	// during the whole iteration are visited once, even if the column is reindexed
+	// meanwhile. Entries added or removed during the iteration may be missed.
+	fn iter_while_inner(
+		&self,
+		view: IterView,
 		mut f: impl FnMut(IterStateOrCorrupted) -> Result<bool>,
 		start_chunk: u64,
 		skip_preimage_indexes: bool,
 	) -> Result<()> {
-		let tables = self.tables.read();
-		let source = &tables.index;
+		let (index_id, tiers) = {
+			let tables = self.tables.read();
+			(tables.index.id, tables.value.len())
+		};
+		if self.append_only {
+			// There is no index, all values are in the value tables.
+			for tier in 0 .. tiers {
+				let more = self.iter_value_table_while(tier, view, |table, index, rc, pk, value, compressed| {
+					self.append_only_entry(table, index, rc, pk, value, compressed).map(Some)
+				}, &mut f)?;
+				if !more {
+					break;
+				}
+			}
+			return Ok(());
+		}
 
 		if skip_preimage_indexes && self.preimage {
 			// It is much faster to iterate over the value table than index.
 			// We have to assume hashing scheme however.
-			for table in &tables.value[..tables.value.len() - 1] {
-				log::debug!( target: "axia-db", "{}: Iterating table {}", source.id, table.id);
-				table.iter_while(&*log.overlays(), |index, rc, value, compressed| {
-					let value = if compressed {
-						self.decompress(&value)
-					} else {
-						value
+			for tier in 0 .. tiers - 1 {
+				log::debug!( target: "axia-db", "{}: Iterating table {}", index_id, tier);
+				let more = self.iter_value_table_while(tier, view, |table, index, rc, _pk, value, compressed| {
+					let value = match self.decode(value, compressed) {
+						Ok(value) => value,
+						Err(e) => {
+							log::warn!(target: "axia-db", "{}: Skipping corrupted value at {}: {:?}", table.id, index, e);
+							return Ok(None);
+						}
 					};
 					let key = blake2_rfc::blake2b::blake2b(32, &[], &value);
 					let key = self.hash(key.as_bytes());
-					let state = IterStateOrCorrupted::Item(IterState { chunk_index: index, key, rc, value });
-					f(state).unwrap_or(false)
-				})?;
-				log::debug!( target: "axia-db", "{}: Done Iterating table {}", source.id, table.id);
+					Ok(Some(IterStateOrCorrupted::Item(IterState {
+						chunk_index: index,
+						key,
+						rc,
+						original_key: None,
+						value,
+						tier: table.id.size_tier(),
+						offset: index,
+					})))
+				}, &mut f)?;
+				log::debug!( target: "axia-db", "{}: Done Iterating table {}", index_id, tier);
+				if !more {
+					return Ok(());
+				}
 			}
 		}
 
-		for c in start_chunk .. source.id.total_chunks() {
-			let entries = source.entries(c, &*log.overlays());
-			for entry in entries.iter() {
-				if entry.is_empty() {
-					continue;
-				}
-				let (size_tier, offset) = if self.db_version >= 4 {
-					let address = entry.address(source.id.index_bits());
-					(address.size_tier(), address.offset())
-				} else {
-					let addr_bits = source.id.index_bits() + 10;
-					let address = Address::from_u64(entry.as_u64() & ((1u64 << addr_bits) - 1));
-					let size_tier = (address.as_u64() & 0x0f) as u8;
-					let offset = address.as_u64() >> 4;
-					(size_tier, offset)
-				};
+		self.iter_index_while(index_id, view, f, start_chunk .. index_id.total_chunks(), skip_preimage_indexes)?;
+		Ok(())
+	}
 
-				if skip_preimage_indexes && self.preimage && size_tier as usize != tables.value.len() - 1 {
-					continue;
+	fn append_only_entry(
+		&self,
+		table: &ValueTable,
+		index: u64,
+		rc: u32,
+		pk: &[u8],
+		value: Value,
+		compressed: bool,
+	) -> Result<IterStateOrCorrupted> {
+		let mut key = Key::default();
+		key[6..].copy_from_slice(pk);
+		let (original_key, value) = self.split_ordered_value(self.decode(value, compressed)?)?;
+		Ok(IterStateOrCorrupted::Item(IterState {
+			chunk_index: 0,
+			key,
+			rc,
+			original_key,
+			value,
+			tier: table.id.size_tier(),
+			offset: index,
+		}))
+	}
+
+	// Iterates value table `tier`, turning stored values into items with `entry`, which
+	// may also skip them. Values added after the iteration started are not included.
+	// Returns `false` if `f` stopped the iteration.
+	fn iter_value_table_while(
+		&self,
+		tier: usize,
+		view: IterView,
+		mut entry: impl FnMut(&ValueTable, u64, u32, &[u8], Value, bool) -> Result<Option<IterStateOrCorrupted>>,
+		f: &mut impl FnMut(IterStateOrCorrupted) -> Result<bool>,
+	) -> Result<bool> {
+		let (parts, filled) = {
+			let tables = self.tables.read();
+			let table = &tables.value[tier];
+			// Entries allocated by commits that are not enacted yet are not on disk.
+			let filled = if view.disk_only { table.enacted_filled()? } else { table.filled() };
+			(table.continuation_parts(view.overlays)?, filled)
+		};
+		let mut next = 1;
+		while next < filled {
+			let mut batch = Vec::with_capacity(ITER_BATCH);
+			let mut result = Ok(());
+			{
+				let tables = self.tables.read();
+				let table = &tables.value[tier];
+				next = table.iter_range_while(&parts, next .. filled, view.overlays, |index, rc, pk, value, compressed| {
+					match entry(table, index, rc, pk, value, compressed) {
+						Ok(item) => {
+							batch.extend(item);
+							batch.len() < ITER_BATCH
+						},
+						Err(e) => {
+							result = Err(e);
+							false
+						},
+					}
+				})?;
+			}
+			for item in batch {
+				if !f(item)? {
+					return Ok(false);
 				}
-				let value = tables.value[size_tier as usize].get_with_meta(offset, &*log.overlays());
-				let (value, rc, pk, compressed) = match value {
-					Ok(Some(v)) => v,
-					Ok(None) => {
-						f(IterStateOrCorrupted::Corrupted(*entry, None))?;
-						continue;
-					},
-					Err(e) => {
-						f(IterStateOrCorrupted::Corrupted(*entry, Some(e)))?;
-						continue;
+			}
+			result?;
+		}
+		Ok(true)
+	}
+
+	// Iterates the chunks in `chunks` of index `index_id`. If the index is replaced by a
+	// reindex, the old index is iterated while it exists and the new one after that, at the
+	// same key position. Returns `false` if `f` stopped the iteration.
+	fn iter_index_while(
+		&self,
+		mut index_id: IndexTableId,
+		view: IterView,
+		mut f: impl FnMut(IterStateOrCorrupted) -> Result<bool>,
+		chunks: std::ops::Range<u64>,
+		skip_preimage_indexes: bool,
+	) -> Result<bool> {
+		let (mut next, mut end) = (chunks.start, chunks.end);
+		while next < end {
+			let mut batch = Vec::with_capacity(ITER_BATCH);
+			{
+				let tables = self.tables.read();
+				let reindex = self.reindex.read();
+				let source = match reindex.queue.iter().find(|index| index.id == index_id) {
+					Some(index) => index,
+					None => {
+						if tables.index.id != index_id {
+							// The old index is dropped, all its entries are in the current one.
+							let shift = tables.index.id.index_bits() - index_id.index_bits();
+							next <<= shift;
+							end <<= shift;
+							index_id = tables.index.id;
+						}
+						&tables.index
 					},
 				};
-				let mut key = source.recover_key_prefix(c, *entry);
-				key[6..].copy_from_slice(&pk);
-				let value = if compressed {
-						self.decompress(&value)
-				} else {
-					value
-				};
-				log::debug!(
-					target: "axia-db",
-					"{}: Iterating at {}/{}, key={:?}, pk={:?}",
-					source.id,
-					c,
-					source.id.total_chunks(),
-					hex(&key),
-					hex(&pk),
-				);
-				let state = IterStateOrCorrupted::Item(IterState { chunk_index: c, key, rc, value });
-				if !f(state)? {
-					return Ok(())
+				while next < end && batch.len() < ITER_BATCH {
+					self.read_index_chunk(&tables, source, next, view, skip_preimage_indexes, &mut batch)?;
+					next += 1;
+				}
+			}
+			for item in batch {
+				if !f(item)? {
+					return Ok(false);
 				}
 			}
 		}
+		Ok(true)
+	}
+
+	fn read_index_chunk(
+		&self,
+		tables: &Tables,
+		source: &IndexTable,
+		c: u64,
+		view: IterView,
+		skip_preimage_indexes: bool,
+		batch: &mut Vec<IterStateOrCorrupted>,
+	) -> Result<()> {
+		let entries = source.entries(c, view.overlays)?;
+		for entry in entries.iter() {
+			if entry.is_empty() {
+				continue;
+			}
+			let (size_tier, offset) = if self.db_version >= 4 {
+				let address = entry.address(source.id.index_bits());
+				(address.size_tier(), address.offset())
+			} else {
+				let addr_bits = source.id.index_bits() + 10;
+				let address = Address::from_u64(entry.as_u64() & ((1u64 << addr_bits) - 1));
+				let size_tier = (address.as_u64() & 0x0f) as u8;
+				let offset = address.as_u64() >> 4;
+				(size_tier, offset)
+			};
+
+			if skip_preimage_indexes && self.preimage && size_tier as usize != tables.value.len() - 1 {
+				continue;
+			}
+			let value = tables.value_table(size_tier).and_then(|table| table.get_with_meta(offset, view.overlays));
+			let (value, rc, pk, compressed) = match value {
+				Ok(Some(v)) => v,
+				Ok(None) => {
+					batch.push(IterStateOrCorrupted::Corrupted(*entry, None));
+					continue;
+				},
+				Err(e) => {
+					batch.push(IterStateOrCorrupted::Corrupted(*entry, Some(e)));
+					continue;
+				},
+			};
+			let mut key = source.recover_key_prefix(c, *entry);
+			key[6..].copy_from_slice(&pk);
+			let (original_key, value) = match self.decode(value, compressed).and_then(|v| self.split_ordered_value(v)) {
+				Ok(value) => value,
+				Err(e) => {
+					batch.push(IterStateOrCorrupted::Corrupted(*entry, Some(e)));
+					continue;
+				},
+			};
+			log::debug!(
+				target: "axia-db",
+				"{}: Iterating at {}/{}, key={:?}, pk={:?}",
+				source.id,
+				c,
+				source.id.total_chunks(),
+				hex(&key),
+				hex(&pk),
+			);
+			batch.push(IterStateOrCorrupted::Item(IterState {
+				chunk_index: c,
+				key,
+				rc,
+				original_key,
+				value,
+				tier: size_tier,
+				offset,
+			}));
+		}
 		Ok(())
 	}
 
-	pub(crate) fn check_from_index(&self, log: &Log, check_param: &crate::CheckOptions, col: ColId) -> Result<()> {
-		let start_chunk = check_param.from.unwrap_or(0);
-		let end_chunk = check_param.bound;
+	// Hashed key for a `CheckOptions` range bound. Keys of uniform columns are not hashed,
+	// so the bound is used as a key prefix.
+	fn range_key(&self, key: &[u8]) -> Key {
+		if self.uniform_keys {
+			let mut k = Key::default();
+			let len = std::cmp::min(key.len(), k.len());
+			k[.. len].copy_from_slice(&key[.. len]);
+			k
+		} else {
+			self.hash(key)
+		}
+	}
+
+	/// Check and optionally display all entries in the key range of `check_param`.
+	/// Returns the number of checked entries.
+	/// Iterate over the entries of all value tables without using the index.
+	pub fn iter_values_raw<'a>(&'a self, log: &'a Log) -> RawValueIter<'a> {
+		RawValueIter { column: self, log, tier: 0, table: None }
+	}
+
+	pub(crate) fn check_from_index(&self, log: &Log, check_param: &crate::CheckOptions, col: ColId) -> Result<u64> {
+		let mut from = check_param.from.as_ref().map(|k| self.range_key(k));
+		let mut bound = check_param.bound.as_ref().map(|k| self.range_key(k));
+		if self.append_only && (from.is_some() || bound.is_some()) {
+			log::warn!(target: "axia-db", "Column {} is append-only, checking all keys", col);
+			from = None;
+			bound = None;
+		}
+		let (start_chunk, end_chunk) = {
+			let tables = self.tables.read();
+			let chunk = |k: &Key| tables.index.chunk_index(u64::from_be_bytes(k[0..8].try_into().unwrap()));
+			(from.as_ref().map_or(0, chunk), bound.as_ref().map(chunk))
+		};
 
 		let step = 1000;
+		let mut checked = 0;
 		let start_time = std::time::Instant::now();
 		log::info!(target: "axia-db", "Starting full index iteration at {:?}", start_time);
 		log::info!(target: "axia-db", "for {} chunks of column {}", self.tables.read().index.id.total_chunks(), col);
-		self.iter_while_inner(log, |state| match state {
-			IterStateOrCorrupted::Item(IterState { chunk_index, key, rc, value }) => {
-				if Some(chunk_index) == end_chunk {
+		self.iter_while_inner(IterView::overlays(log), |state| match state {
+			IterStateOrCorrupted::Item(IterState { chunk_index, key, rc, value, .. }) => {
+				// Entries are ordered by chunk, but not within a chunk.
+				if end_chunk.is_some_and(|end| chunk_index > end) {
 					return Ok(false);
 				}
+				if from.is_some_and(|from| key < from) || bound.is_some_and(|bound| key >= bound) {
+					return Ok(true);
+				}
+				checked += 1;
 				if chunk_index % step == 0 {
 					log::info!(target: "axia-db", "Chunk iteration at {}", chunk_index);
 				}
@@ -660,8 +1675,8 @@ impl Column {
 			},
 		}, start_chunk, false)?;
 
-		log::info!(target: "axia-db", "Ended full index check, elapsed {:?}", start_time.elapsed());
-		Ok(())
+		log::info!(target: "axia-db", "Ended full index check of {} entries, elapsed {:?}", checked, start_time.elapsed());
+		Ok(checked)
 	}
 
 	pub fn reindex(&self, log: &Log) -> Result<(Option<IndexTableId>, Vec<(Key, Address)>)> {
@@ -680,7 +1695,7 @@ impl Column {
 				log::debug!(target: "axia-db", "{}: Continue reindex at {}/{}", tables.index.id, source_index, source.id.total_chunks());
 				while source_index < source.id.total_chunks() && plan.len() < MAX_REBALANCE_BATCH {
 					log::trace!(target: "axia-db", "{}: Reindexing {}", source.id, source_index);
-					let entries = source.entries(source_index, &*log.overlays());
+					let entries = source.entries(source_index, &*log.overlays())?;
 					for entry in entries.iter() {
 						if entry.is_empty() {
 							continue;
@@ -702,12 +1717,34 @@ impl Column {
 		Ok((drop_index, plan))
 	}
 
-	pub fn drop_index(&self, id: IndexTableId) -> Result<()> {
+	/// Account for reindexed entries written to the log.
+	pub fn reindex_written(&self, entries: u64, bytes: u64) {
+		if let Some(started) = self.reindex.write().started.front_mut() {
+			started.entries += entries;
+			started.bytes += bytes;
+		}
+	}
+
+	pub fn drop_index(&self, id: IndexTableId, record_id: u64) -> Result<()> {
 		log::debug!(target: "axia-db", "Dropping {}", id);
 		let mut reindex = self.reindex.write();
 		if reindex.queue.front_mut().map_or(false, |index| index.id == id) {
 			let table = reindex.queue.pop_front();
 			reindex.progress.store(0, Ordering::Relaxed);
+			if let Some(started) = reindex.started.pop_front() {
+				if self.collect_stats {
+					let epoch_ms = |t: std::time::SystemTime| t.duration_since(std::time::UNIX_EPOCH)
+						.map_or(0, |d| d.as_millis() as u64);
+					self.stats.reindex_completed(ReindexEvent {
+						start_record: started.record,
+						end_record: record_id,
+						started: epoch_ms(started.time),
+						duration: started.time.elapsed().map_or(0, |d| d.as_millis() as u64),
+						entries: started.entries,
+						bytes: started.bytes,
+					});
+				}
+			}
 			table.unwrap().drop_file()?;
 		} else {
 			log::warn!(target: "axia-db", "Dropping invalid index {}", id);
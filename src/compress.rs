@@ -17,6 +17,8 @@
 
 //! Compression utility and types.
 
+use crate::error::Result;
+
 /// Different compression type
 /// allowend and their u8 representation.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -48,17 +50,23 @@ enum Compressor {
 	Snappy(snappy::Snappy),
 }
 
-impl From<u8> for CompressionType {
-	fn from(comp_type: u8) -> Self {
+impl CompressionType {
+	pub(crate) fn from_u8(comp_type: u8) -> Option<Self> {
 		match comp_type {
-			a if a == CompressionType::NoCompression as u8 => CompressionType::NoCompression,
-			a if a == CompressionType::Lz4 as u8 => CompressionType::Lz4,
-			a if a == CompressionType::Snappy as u8 => CompressionType::Snappy,
-			_ => panic!("Unkwown compression."),
+			a if a == CompressionType::NoCompression as u8 => Some(CompressionType::NoCompression),
+			a if a == CompressionType::Lz4 as u8 => Some(CompressionType::Lz4),
+			a if a == CompressionType::Snappy as u8 => Some(CompressionType::Snappy),
+			_ => None,
 		}
 	}
 }
 
+impl From<u8> for CompressionType {
+	fn from(comp_type: u8) -> Self {
+		CompressionType::from_u8(comp_type).expect("Unkwown compression.")
+	}
+}
+
 impl From<CompressionType> for Compressor {
 	fn from(comp_type: CompressionType) -> Self {
 		match comp_type {
@@ -94,7 +102,7 @@ impl Compress {
 		}
 	}
 
-	pub(crate) fn decompress(&self, buf: &[u8]) -> Vec<u8> {
+	pub(crate) fn decompress(&self, buf: &[u8]) -> Result<Vec<u8>> {
 		match &self.inner {
 			Compressor::NoCompression(inner) => inner.decompress(buf),
 			Compressor::Lz4(inner) => inner.decompress(buf),
@@ -112,12 +120,14 @@ impl NoCompression {
 		buf.to_vec()
 	}
 
-	fn decompress(&self, buf: &[u8]) -> Vec<u8> {
-		buf.to_vec()
+	fn decompress(&self, buf: &[u8]) -> Result<Vec<u8>> {
+		Ok(buf.to_vec())
 	}
 }
 
 mod lz4 {
+	use crate::error::{Error, Result};
+
 	pub(super) struct Lz4;
 
 	impl Lz4 {
@@ -130,15 +140,16 @@ mod lz4 {
 				.unwrap()
 		}
 
-		pub(super) fn decompress(&self, buf: &[u8]) -> Vec<u8> {
+		pub(super) fn decompress(&self, buf: &[u8]) -> Result<Vec<u8>> {
 			lz4::block::decompress(buf, None)
-				.unwrap()
+				.map_err(|e| Error::Corruption(format!("Lz4 decompression failed: {}", e)))
 		}
 	}
 }
 
 mod snappy {
     use std::io::{Read, Write};
+	use crate::error::{Error, Result};
 
 	pub(super) struct Snappy;
 
@@ -156,11 +167,12 @@ mod snappy {
 			buf
 		}
 
-		pub(super) fn decompress(&self, value: &[u8]) -> Vec<u8> {
+		pub(super) fn decompress(&self, value: &[u8]) -> Result<Vec<u8>> {
 			let mut buf = Vec::with_capacity(value.len());
 			let mut decoder = snap::read::FrameDecoder::new(value);
-			decoder.read_to_end(&mut buf).expect("Compression corrupted.");
-			buf
+			decoder.read_to_end(&mut buf)
+				.map_err(|e| Error::Corruption(format!("Snappy decompression failed: {}", e)))?;
+			Ok(buf)
 		}
 	}
 }
@@ -168,6 +180,7 @@ mod snappy {
 #[cfg(test)]
 mod tests {
     use super::*;
+	use crate::error::Error;
 
 	#[test]
 	fn test_compression_interfaces() {
@@ -182,8 +195,19 @@ mod tests {
 			let compress = Compress::new(compression_type, 0);
 			let v = compress.compress(&original[..]);
 			assert!(v.len() <= 100);
-			let round_tripped = compress.decompress( &v[..]);
+			let round_tripped = compress.decompress( &v[..]).unwrap();
 			assert_eq!(original, round_tripped);
 		}
 	}
+
+	#[test]
+	fn test_decompress_corrupted() {
+		let original = vec![42;100];
+		for compression_type in vec![CompressionType::Snappy, CompressionType::Lz4] {
+			let compress = Compress::new(compression_type, 0);
+			let mut v = compress.compress(&original[..]);
+			v.truncate(v.len() / 2);
+			assert!(matches!(compress.decompress(&v[..]), Err(Error::Corruption(_))));
+		}
+	}
 }
@@ -30,18 +30,23 @@
 /// Each background worker is signalled with a conditional variable once
 /// there is some work to be done.
 
-use std::sync::{Arc, atomic::{AtomicBool, AtomicU64, Ordering}};
-use std::convert::TryInto;
-use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering}};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use parking_lot::{RwLock, Mutex, Condvar};
+use crossbeam_queue::{ArrayQueue, SegQueue};
 use fs2::FileExt;
 use crate::{
 	table::Key,
-	error::{Error, Result},
-	column::{ColId, Column, IterState},
-	log::{Log, LogAction},
+	error::{Error, ErrorContext, Result, ResultExt},
+	display::hex,
+	encrypt::Encryption,
+	column::{ColId, Column, IterSnapshotPolicy, IterState, RawValueIter},
+	log::{Log, LogAction, LogChange, LogFileInfo, LogReader, LogWriteStats},
 	index::PlanOutcome,
-	options::{Metadata, Options},
+	options::{ColumnMode, ColumnOptions, Metadata, Options, ThreadConfig, EXTENDED_LOG_VERSION},
+	pool::{BufferPool, PooledValue},
+	stats::{DbStats, StallReason, StallStats},
+	prepared,
 };
 
 // These are in memory, so we use usize
@@ -50,77 +55,361 @@ const MAX_COMMIT_QUEUE_BYTES: usize = 16 * 1024 * 1024;
 const MAX_LOG_QUEUE_BYTES: i64 = 128 * 1024 * 1024;
 const MIN_LOG_SIZE: u64 = 64 * 1024 * 1024;
 const KEEP_LOGS: usize = 16;
+const CLEAR_COLUMN_BATCH: usize = 10240;
+// Prefetch hints queued beyond this are dropped.
+const PREFETCH_QUEUE_DEPTH: usize = 4096;
+const PREFETCH_THREADS: usize = 2;
+// Processed commits larger than this are logged at info level.
+const LARGE_COMMIT_BYTES: usize = 1024 * 1024;
+/// Maximum size of the metadata passed to `Db::commit_with_meta`.
+pub const MAX_RECORD_META: usize = 256;
+// Number of most recent record metadata entries kept in memory.
+const RECORD_META_RETAIN: usize = 65536;
+// Stats history is rotated once it grows beyond this size.
+const MAX_STATS_HISTORY_BYTES: u64 = 16 * 1024 * 1024;
+const STATS_FILE: &str = "stats.txt";
+const STATS_HISTORY_FILE: &str = "stats.ndjson";
+const STATS_HISTORY_OLD_FILE: &str = "stats.ndjson.old";
+// Exists while the database is open with `Options::no_wal`.
+const NO_WAL_DIRTY_FILE: &str = "no_wal_dirty";
+// How often the commit worker checks the commit overlay for entries of enacted commits.
+const COMMIT_OVERLAY_AUDIT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+// How often the database directory is rescanned to correct the disk usage counter, with
+// `Options::max_total_bytes`.
+const DISK_USAGE_SCAN_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
 
 /// Value is just a vector of bytes. Value sizes up to 4Gb are allowed.
 pub type Value = Vec<u8>;
 
+pub(crate) type ChangeSet = Vec<(ColId, Key, Option<Value>)>;
+// Values are tagged with the id of the commit that set them.
+type CommitOverlay = Vec<HashMap<Key, (u64, Option<Value>), IdentityBuildHasher>>;
+type ShutdownHook = Box<dyn FnOnce(&Db) + Send>;
 
-// Commit data passed to `commit`
+/// Snapshot of the commit pipeline state.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DbHealth {
+	/// Number of threads waiting for space in the commit queue.
+	pub waiting_committers: usize,
+	/// Total size of the commits queued for writing to the log.
+	pub commit_queue_bytes: usize,
+	/// Same as `commit_queue_bytes`, by column.
+	pub column_queue_bytes: Vec<usize>,
+	/// A background worker has failed and the database no longer accepts commits.
+	pub background_error: bool,
+	/// Disk space used by the database files. Logs and value tables are counted as they
+	/// grow, index files are only measured when the database is opened, or every few
+	/// seconds with `Options::max_total_bytes`.
+	pub disk_usage: u64,
+	/// Quota set with `Options::max_total_bytes`.
+	pub max_total_bytes: Option<u64>,
+}
+
+/// Snapshot of the commit pipeline progress, see `Db::pipeline_status`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PipelineStatus {
+	/// Total size of the commits queued for writing to the log.
+	pub commit_queue_bytes: usize,
+	/// Size of the log records written but not yet enacted into the tables.
+	pub log_queue_bytes: i64,
+	/// A column is being reindexed.
+	pub pending_reindex: bool,
+	/// Id of the last log record enacted into the tables, as in `DbStats::last_enacted`.
+	pub last_enacted_record: u64,
+	/// Id of the last log record started.
+	pub current_record: u64,
+	/// Whether the commit, flush, log and cleanup worker threads, in this order, are
+	/// running. Workers that were not started, e.g. with `DbBuilder`, are reported
+	/// as not running.
+	pub workers_alive: [bool; 4],
+}
+
+impl PipelineStatus {
+	/// Number of log records not yet enacted into the tables.
+	pub fn lag(&self) -> u64 {
+		self.current_record.saturating_sub(self.last_enacted_record)
+	}
+}
+
+/// Database metadata as loaded from disk, see `Db::metadata`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DbMetadata {
+	/// Database format version.
+	pub version: u32,
+	/// Settings the columns were created with.
+	pub columns: Vec<ColumnOptions>,
+	/// Values are encrypted.
+	pub encrypted: bool,
+}
+
+// A commit in the commit queue.
 #[derive(Default)]
-struct Commit {
-	// Commit ID. This is not the same as log record id, as some records
-	// are originated within the DB. E.g. reindex.
+struct QueuedCommit {
+	// Commit id, returned by `Db::commit`. Commit ids are dense within a session and
+	// start at 1 on each open. This is not the same as the log record id: records are
+	// also written for reindexing, and record ids persist across sessions.
 	id: u64,
 	// Size of user data pending insertion (keys + values) or
 	// removal (keys)
 	bytes: usize,
+	// Same as `bytes`, by column.
+	column_bytes: Vec<usize>,
 	// Operations.
 	changeset: Vec<(ColId, Key, Option<Value>)>,
+	// Set for commits that are part of a `CoordinatedCommit`, or that are waited for
+	// with `Db::commit_durable` or `Db::wait_durable`.
+	barrier: Option<Arc<CommitBarrier>>,
+	// Written with the log record.
+	record: RecordInfo,
 }
 
-// Pending commits. This may not grow beyond `MAX_COMMIT_QUEUE_BYTES` bytes.
+// A commit planned by `DbInner::plan_commit`, with the log record that is not yet written.
+struct CommitPlan {
+	record_id: u64,
+	// Changes to the index and value tables.
+	change: LogChange,
+	commit: QueuedCommit,
+	// The planned changes trigger a reindex.
+	reindex: bool,
+	// Number of operations, in total and by column. The latter is only collected when
+	// the commit is logged.
+	ops: u64,
+	column_ops: Vec<u64>,
+	level: log::Level,
+}
+
+// Additional data written with the log record of a commit.
 #[derive(Default)]
+struct RecordInfo {
+	// User metadata, see `Db::commit_with_meta`.
+	meta: Option<Vec<u8>>,
+	// Id of the prepared changeset committed by the record.
+	prepared: Option<u64>,
+}
+
+// Shared by all parts of a `CoordinatedCommit`. Each database enacts its part
+// only once all participating databases have flushed their log records.
+struct CommitBarrier {
+	participants: usize,
+	// Number of participants with flushed records and whether the commit was aborted.
+	state: Mutex<(usize, bool)>,
+	cv: Condvar,
+}
+
+impl CommitBarrier {
+	fn new(participants: usize) -> CommitBarrier {
+		CommitBarrier {
+			participants,
+			state: Mutex::new((0, false)),
+			cv: Condvar::new(),
+		}
+	}
+
+	fn prepared(&self) {
+		let mut state = self.state.lock();
+		state.0 += 1;
+		self.cv.notify_all();
+	}
+
+	fn abort(&self) {
+		let mut state = self.state.lock();
+		state.1 = true;
+		self.cv.notify_all();
+	}
+
+	// Returns `false` if waiting was interrupted by an error or shutdown.
+	fn wait(&self, shutdown: &AtomicBool) -> bool {
+		let mut state = self.state.lock();
+		loop {
+			if state.0 >= self.participants {
+				return true;
+			}
+			if state.1 || shutdown.load(Ordering::SeqCst) {
+				return false;
+			}
+			self.cv.wait_for(&mut state, std::time::Duration::from_millis(100));
+		}
+	}
+}
+
+// Pending commits. This may not grow beyond `MAX_COMMIT_QUEUE_BYTES` bytes.
+// Producers and the log worker don't share a lock. Commits are pushed while
+// holding the commit overlay write lock, so the queue is ordered by commit id.
 struct CommitQueue {
-	// Log record.
-	record_id: u64,
+	// Id of the last queued commit.
+	commit_id: AtomicU64,
 	// Total size of all commits in the queue.
-	bytes: usize,
+	bytes: AtomicUsize,
+	// Size of all commits in the queue, by column.
+	column_bytes: Vec<AtomicUsize>,
 	// FIFO queue.
-	commits: VecDeque<Commit>,
+	commits: SegQueue<QueuedCommit>,
+}
+
+impl CommitQueue {
+	fn new(columns: usize) -> Self {
+		CommitQueue {
+			commit_id: AtomicU64::new(0),
+			bytes: AtomicUsize::new(0),
+			column_bytes: (0 .. columns).map(|_| AtomicUsize::new(0)).collect(),
+			commits: SegQueue::new(),
+		}
+	}
+}
+
+// Committers waiting for space in the commit queue are admitted in the order
+// of arrival. Each waiter takes a ticket and proceeds once it is being served.
+#[derive(Default)]
+struct CommitAdmission {
+	next_ticket: u64,
+	serving: u64,
 }
 
 #[derive(Default)]
 struct IdentityKeyHash(u64);
 type IdentityBuildHasher = std::hash::BuildHasherDefault<IdentityKeyHash>;
 
+// Keys are already hashed, so the first 8 bytes are used as is. Shorter input
+// is zero-padded rather than rejected.
 impl std::hash::Hasher for IdentityKeyHash {
 	fn write(&mut self, bytes: &[u8]) {
-		self.0 = u64::from_le_bytes((&bytes[0..8]).try_into().unwrap())
+		let mut buf = [0u8; 8];
+		let len = std::cmp::min(bytes.len(), 8);
+		buf[..len].copy_from_slice(&bytes[..len]);
+		self.0 = u64::from_le_bytes(buf)
 	}
-	fn write_u8(&mut self, _: u8)       { unreachable!() }
-	fn write_u16(&mut self, _: u16)     { unreachable!() }
-	fn write_u32(&mut self, _: u32)     { unreachable!() }
-	fn write_u64(&mut self, _: u64)     { unreachable!() }
+	// Ignore the length prefix written for slices.
 	fn write_usize(&mut self, _: usize) { }
-	fn write_i8(&mut self, _: i8)       { unreachable!() }
-	fn write_i16(&mut self, _: i16)     { unreachable!() }
-	fn write_i32(&mut self, _: i32)     { unreachable!() }
-	fn write_i64(&mut self, _: i64)     { unreachable!() }
-	fn write_isize(&mut self, _: isize) { unreachable!() }
 	fn finish(&self) -> u64 { self.0 }
 }
 
+// Space allocated for the files in the database directory, except for the files that
+// `skip` returns `true` for. Index files are sparse, so the allocated size is used
+// rather than the file length where available.
+fn disk_usage(path: &std::path::Path, skip: impl Fn(&str) -> bool) -> Result<u64> {
+	let mut total = 0;
+	for entry in std::fs::read_dir(path)? {
+		let entry = match entry {
+			Ok(entry) if entry.file_name().to_str().is_some_and(&skip) => continue,
+			entry => entry,
+		};
+		let metadata = match entry.and_then(|e| e.metadata()) {
+			Ok(metadata) => metadata,
+			// Removed while reading the directory, e.g. a log being cleaned up.
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+			Err(e) => return Err(e.into()),
+		};
+		if metadata.is_file() {
+			#[cfg(unix)]
+			{
+				use std::os::unix::fs::MetadataExt;
+				total += metadata.blocks() * 512;
+			}
+			#[cfg(not(unix))]
+			{
+				total += metadata.len();
+			}
+		}
+	}
+	Ok(total)
+}
+
 struct DbInner {
 	columns: Vec<Column>,
 	options: Options,
 	metadata: Metadata,
 	shutdown: AtomicBool,
 	log: Log,
-	commit_queue: Mutex<CommitQueue>,
+	commit_queue: CommitQueue,
+	// Used with `commit_queue_full_cv` to wait for the commit queue to drain.
+	commit_queue_full: Mutex<CommitAdmission>,
 	commit_queue_full_cv: Condvar,
+	// Number of committers waiting for admission.
+	waiting_committers: AtomicUsize,
+	max_commit_queue_bytes: usize,
+	// Queued bytes allowed for each column, if limited.
+	column_queue_limits: Vec<Option<usize>>,
 	log_worker_wait: WaitCondvar<bool>,
 	commit_worker_wait: Arc<WaitCondvar<bool>>,
-	// Overlay of most recent values int the commit queue. ColumnId -> (Key -> (RecordId, Value)).
-	commit_overlay: RwLock<Vec<HashMap<Key, (u64, Option<Value>), IdentityBuildHasher>>>,
-	log_queue_wait: WaitCondvar<i64>, // This may underflow occasionally, but is bound for 0 eventually
+	// Overlay of most recent values int the commit queue. ColumnId -> (Key -> (CommitId, Value)).
+	commit_overlay: RwLock<CommitOverlay>,
+	// Bytes written to the log and not yet enacted.
+	log_queue_bytes: ByteSemaphore,
 	flush_worker_wait: Arc<WaitCondvar<bool>>,
 	cleanup_worker_wait: WaitCondvar<bool>,
+	// Keys queued with `Db::prefetch`.
+	prefetch_queue: ArrayQueue<(ColId, Key)>,
+	prefetch_worker_wait: WaitCondvar<bool>,
 	last_enacted: AtomicU64,
+	// Commit id of each log record that is not enacted yet, by record id, in order.
+	logged_commits: Mutex<VecDeque<(u64, u64)>>,
+	// Id of the last commit with an enacted log record.
+	enacted_commit: AtomicU64,
 	next_reindex: AtomicU64,
 	bg_err: Mutex<Option<Arc<Error>>>,
+	// Coordinated commit barriers by log record id.
+	barriers: Mutex<HashMap<u64, Arc<CommitBarrier>>>,
+	// Coordinated commit barriers for commits still in the queue, by commit id.
+	queued_barriers: Mutex<HashMap<u64, Arc<CommitBarrier>>>,
+	// Flush the current log regardless of its size.
+	force_flush: AtomicBool,
+	opened: std::time::Instant,
+	// When the stats file was last written, see `Options::stats_interval`. Starts at
+	// the time of opening. `None` makes the next write due right away.
+	last_stats_write: Mutex<Option<std::time::Instant>>,
+	// Log records were replayed on open, i.e. the database was not shut down cleanly.
+	recovered: bool,
+	// Opened with `Db::open_exclusive`. There are no background workers, committing
+	// threads run the pipeline while holding `pipeline_lock`.
+	exclusive: bool,
+	// Pipeline stages run by background workers.
+	commit_stages: EnableCommitPipelineStages,
+	// Records are applied without writing them to the log, see `Options::no_wal`.
+	// The dirty marker is removed on clean shutdown.
+	no_wal: bool,
+	// Opened with `Db::open_read_only`. No files are written or removed.
+	read_only: bool,
+	// Current mode of each column. Commits check it while holding the commit overlay lock.
+	column_modes: RwLock<Vec<ColumnMode>>,
+	// Modes stored in the metadata, restored on open.
+	persisted_column_modes: Mutex<BTreeMap<ColId, ColumnMode>>,
+	pipeline_lock: Mutex<()>,
+	// Held exclusively while a log record is enacted, and shared by `DiskOnly` iterations.
+	enact_lock: RwLock<()>,
+	// Changesets of the records not enacted yet, by record id. Only kept with
+	// `Options::paranoid_checks`.
+	paranoid_changes: Mutex<HashMap<u64, ChangeSet>>,
+	// Called on drop before shutting down, most recently added first.
+	shutdown_hooks: Mutex<Vec<ShutdownHook>>,
+	reads: ReadGate,
+	// Metadata of the most recent records committed with `Db::commit_with_meta`, by record id.
+	record_meta: Mutex<BTreeMap<u64, Vec<u8>>>,
+	// Id for the next changeset prepared with `Db::prepare`.
+	next_prepared: AtomicU64,
+	// Database-wide statistics, reset with `clear_db_stats`.
+	commit_count: AtomicU64,
+	logged_bytes: AtomicU64,
+	stalls: StallStats,
+	// Disk space used by the files in the database directory, other than the value tables
+	// which are counted by `total_disk_usage`. Log writes are added and cleaned logs are
+	// subtracted, other files are only measured by `resync_disk_usage`.
+	disk_usage: AtomicU64,
+	last_disk_scan: Mutex<Option<std::time::Instant>>,
 	_lock_file: std::fs::File,
 }
 
+impl Drop for DbInner {
+	fn drop(&mut self) {
+		clear_lock_pid(&self._lock_file);
+	}
+}
+
+impl std::fmt::Debug for DbInner {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		self.debug_fields("DbInner", f)
+	}
+}
+
 pub struct WaitCondvar<S> {
 	cv: Condvar,
 	work: Mutex<S>,
@@ -150,10 +439,136 @@ impl WaitCondvar<bool> {
 		*work = false;
 	}
 
+	// Same as `wait`, but returns after `timeout` even if there was no signal.
+	fn wait_timeout(&self, timeout: std::time::Duration) {
+		let mut work = self.work.lock();
+		if !*work {
+			self.cv.wait_for(&mut work, timeout);
+		}
+		*work = false;
+	}
+
 	#[cfg(test)]
 	fn wait_notify(&self) {
+		// The worker may signal before the test starts waiting. The timeout
+		// ensures a missed notification delays the test rather than hanging it.
 		let mut work = self.work.lock();
-		self.cv.wait(&mut work)
+		self.cv.wait_for(&mut work, std::time::Duration::from_secs(1));
+	}
+}
+
+// Reads in progress, so that `Db::close` can wait for them before shutting down.
+struct ReadGate {
+	closed: AtomicBool,
+	active: AtomicUsize,
+	cv: Condvar,
+	mutex: Mutex<()>,
+}
+
+// Held for the duration of a read, see `ReadGate::enter`.
+struct ReadGuard<'a>(&'a ReadGate);
+
+impl ReadGate {
+	fn new() -> Self {
+		ReadGate {
+			closed: AtomicBool::new(false),
+			active: AtomicUsize::new(0),
+			cv: Condvar::new(),
+			mutex: Mutex::new(()),
+		}
+	}
+
+	fn enter(&self) -> Result<ReadGuard<'_>> {
+		// Counted before checking the flag, so that `close` either waits for this read
+		// or the read sees the flag.
+		self.active.fetch_add(1, Ordering::SeqCst);
+		let guard = ReadGuard(self);
+		if self.closed.load(Ordering::SeqCst) {
+			return Err(Error::Closed);
+		}
+		Ok(guard)
+	}
+
+	// Rejects new reads and waits for the ones in progress. Returns `false` if already closed.
+	fn close(&self) -> bool {
+		if self.closed.swap(true, Ordering::SeqCst) {
+			return false;
+		}
+		let mut guard = self.mutex.lock();
+		while self.active.load(Ordering::SeqCst) > 0 {
+			self.cv.wait(&mut guard);
+		}
+		true
+	}
+
+	fn is_closed(&self) -> bool {
+		self.closed.load(Ordering::SeqCst)
+	}
+}
+
+impl Drop for ReadGuard<'_> {
+	fn drop(&mut self) {
+		if self.0.active.fetch_sub(1, Ordering::SeqCst) == 1 && self.0.closed.load(Ordering::SeqCst) {
+			let _guard = self.0.mutex.lock();
+			self.0.cv.notify_all();
+		}
+	}
+}
+
+/// Byte counter that threads can wait on to drop below a threshold.
+pub struct ByteSemaphore {
+	// May briefly go negative, e.g. when a record is enacted before its size is added.
+	current: AtomicI64,
+	capacity: i64,
+	waiters: AtomicUsize,
+	cv: Condvar,
+	mutex: Mutex<()>,
+}
+
+impl ByteSemaphore {
+	fn new(capacity: i64) -> Self {
+		ByteSemaphore {
+			current: AtomicI64::new(0),
+			capacity,
+			waiters: AtomicUsize::new(0),
+			cv: Condvar::new(),
+			mutex: Mutex::new(()),
+		}
+	}
+
+	fn value(&self) -> i64 {
+		self.current.load(Ordering::SeqCst)
+	}
+
+	fn add(&self, n: i64) {
+		self.current.fetch_add(n, Ordering::SeqCst);
+	}
+
+	// Returns the new value.
+	fn sub(&self, n: i64) -> i64 {
+		let value = self.current.fetch_sub(n, Ordering::SeqCst) - n;
+		if self.waiters.load(Ordering::SeqCst) > 0 {
+			self.notify_all();
+		}
+		value
+	}
+
+	// Wait until the value is at most `threshold`, or `stop` is set. Call `notify_all`
+	// after setting `stop`.
+	fn wait_below(&self, threshold: i64, stop: &AtomicBool) {
+		let mut guard = self.mutex.lock();
+		// Registered before checking the value, so that `sub` either sees the waiter
+		// or the check sees the new value.
+		self.waiters.fetch_add(1, Ordering::SeqCst);
+		while self.current.load(Ordering::SeqCst) > threshold && !stop.load(Ordering::SeqCst) {
+			self.cv.wait(&mut guard);
+		}
+		self.waiters.fetch_sub(1, Ordering::SeqCst);
+	}
+
+	fn notify_all(&self) {
+		let _guard = self.mutex.lock();
+		self.cv.notify_all();
 	}
 }
 
@@ -164,234 +579,966 @@ impl DbInner {
 		};
 		let mut lock_path: std::path::PathBuf = options.path.clone();
 		lock_path.push("lock");
-		let lock_file = std::fs::OpenOptions::new().create(true).read(true).write(true).open(lock_path.as_path())?;
+		let lock_file = std::fs::OpenOptions::new().create(true).read(true).write(true).open(lock_path.as_path())
+			.context(|| ErrorContext::new("open lock file").path(&lock_path))?;
 		if !inner_options.skip_check_lock {
 			lock_file.try_lock_exclusive().map_err(|e| Error::Locked(e))?;
+			check_lock_pid(&lock_file).context(|| ErrorContext::new("write lock file").path(&lock_path))?;
+		}
+		if !inner_options.read_only {
+			remove_temp_files(&options.path);
 		}
 
-		let metadata = options.load_and_validate_metadata(inner_options.create)?;
+		let created = !options.path.join("metadata").exists();
+		let mut metadata = options.load_and_validate_metadata(inner_options.create)?;
+		if !inner_options.read_only {
+			Options::upgrade_metadata(&options.path, &mut metadata)?;
+		}
 		let mut columns = Vec::with_capacity(metadata.columns.len());
 		let mut commit_overlay = Vec::with_capacity(metadata.columns.len());
-		let log = Log::open(&options)?;
+		let log = Log::open(&options, metadata.version)?;
 		let last_enacted = log.replay_record_id().unwrap_or(2) - 1;
+		let dirty_path = options.path.join(NO_WAL_DIRTY_FILE);
+		if dirty_path.exists() {
+			if !options.force_open_dirty {
+				return Err(Error::UncleanNoWalShutdown);
+			}
+			log::warn!(target: "axia-db", "Opening database after an unclean shutdown without a log");
+			if !options.no_wal && !inner_options.read_only {
+				std::fs::remove_file(&dirty_path)?;
+			}
+		}
+		if options.no_wal && !inner_options.read_only {
+			if log.replay_record_id().is_some() {
+				return Err(Error::InvalidConfiguration(
+					"Database has log records to replay. Open it without `no_wal` first".into()
+				));
+			}
+			std::fs::File::create(&dirty_path)?.sync_all()?;
+		}
+		if let Some(window) = options.mmap_window {
+			if !window.is_power_of_two() || window < crate::index::MIN_MMAP_WINDOW {
+				return Err(Error::InvalidConfiguration(format!(
+					"mmap_window must be a power of two of at least {} bytes",
+					crate::index::MIN_MMAP_WINDOW,
+				)));
+			}
+		}
+		let persisted_column_modes = metadata.column_modes.clone();
+		let column_modes = (0 .. metadata.columns.len())
+			.map(|c| persisted_column_modes.get(&(c as ColId)).copied().unwrap_or_default())
+			.collect();
 		for c in 0 .. metadata.columns.len() {
 			columns.push(Column::open(c as ColId, &options, &metadata)?);
-			commit_overlay.push(
-				HashMap::with_hasher(std::hash::BuildHasherDefault::<IdentityKeyHash>::default())
-			);
+			commit_overlay.push(HashMap::with_capacity_and_hasher(
+				options.overlay_initial_capacity,
+				std::hash::BuildHasherDefault::<IdentityKeyHash>::default(),
+			));
+		}
+		if created {
+			for (c, column) in columns.iter().enumerate() {
+				if let Some(bytes) = options.columns.get(c).and_then(|c| c.preallocate) {
+					column.preallocate(bytes)?;
+				}
+			}
 		}
 		log::debug!(target: "axia-db", "Opened db {:?}, metadata={:?}", options, metadata);
+		let max_commit_queue_bytes = inner_options.max_commit_queue_bytes.unwrap_or(MAX_COMMIT_QUEUE_BYTES);
+		let column_queue_limits = (0 .. metadata.columns.len()).map(|c| {
+			options.columns.get(c)
+				.and_then(|c| c.max_queue_share)
+				.map(|share| (share as f64 * max_commit_queue_bytes as f64) as usize)
+		}).collect();
+		let commit_queue = CommitQueue::new(metadata.columns.len());
 		Ok(DbInner {
 			columns,
 			options: options.clone(),
 			metadata,
 			shutdown: std::sync::atomic::AtomicBool::new(false),
 			log,
-			commit_queue: Mutex::new(Default::default()),
+			commit_queue,
+			commit_queue_full: Mutex::new(Default::default()),
 			commit_queue_full_cv: Condvar::new(),
+			waiting_committers: AtomicUsize::new(0),
+			max_commit_queue_bytes,
+			column_queue_limits,
 			log_worker_wait: WaitCondvar::new(),
 			commit_worker_wait: Arc::new(WaitCondvar::new()),
 			commit_overlay: RwLock::new(commit_overlay),
-			log_queue_wait: WaitCondvar::new(),
+			log_queue_bytes: ByteSemaphore::new(MAX_LOG_QUEUE_BYTES),
 			flush_worker_wait: Arc::new(WaitCondvar::new()),
 			cleanup_worker_wait: WaitCondvar::new(),
+			prefetch_queue: ArrayQueue::new(PREFETCH_QUEUE_DEPTH),
+			prefetch_worker_wait: WaitCondvar::new(),
 			next_reindex: AtomicU64::new(1),
 			last_enacted: AtomicU64::new(last_enacted),
+			logged_commits: Mutex::new(VecDeque::new()),
+			enacted_commit: AtomicU64::new(0),
 			bg_err: Mutex::new(None),
+			barriers: Mutex::new(HashMap::new()),
+			queued_barriers: Mutex::new(HashMap::new()),
+			force_flush: AtomicBool::new(false),
+			opened: std::time::Instant::now(),
+			last_stats_write: Mutex::new(Some(std::time::Instant::now())),
+			recovered: false,
+			exclusive: inner_options.exclusive,
+			commit_stages: inner_options.commit_stages,
+			no_wal: options.no_wal && !inner_options.read_only,
+			read_only: inner_options.read_only,
+			column_modes: RwLock::new(column_modes),
+			persisted_column_modes: Mutex::new(persisted_column_modes),
+			pipeline_lock: Mutex::new(()),
+			enact_lock: RwLock::new(()),
+			paranoid_changes: Mutex::new(HashMap::new()),
+			shutdown_hooks: Mutex::new(Vec::new()),
+			reads: ReadGate::new(),
+			record_meta: Mutex::new(BTreeMap::new()),
+			commit_count: AtomicU64::new(0),
+			logged_bytes: AtomicU64::new(0),
+			stalls: Default::default(),
+			disk_usage: AtomicU64::new(disk_usage(&options.path, crate::table::TableId::is_any_file_name)
+				.unwrap_or_else(|e| {
+					log::warn!(target: "axia-db", "Error measuring disk usage: {:?}", e);
+					0
+				})),
+			last_disk_scan: Mutex::new(Some(std::time::Instant::now())),
+			next_prepared: AtomicU64::new(prepared::list(&options.path)?.last().map_or(1, |id| id + 1)),
 			_lock_file: lock_file,
 		})
 	}
 
+	fn column(&self, col: ColId) -> Result<&Column> {
+		self.columns.get(col as usize).ok_or(Error::InvalidColumn(col))
+	}
+
+	// Same as `column`, but fails for offline columns.
+	fn readable_column(&self, col: ColId) -> Result<&Column> {
+		let column = self.column(col)?;
+		if self.column_modes.read()[col as usize] == ColumnMode::Offline {
+			return Err(Error::ColumnOffline(col));
+		}
+		Ok(column)
+	}
+
+	// Fails if any of the changed columns don't accept commits. Must be checked again
+	// while holding the commit overlay lock, before the commit is queued.
+	fn check_column_modes(&self, commit: &[(ColId, Key, Option<Value>)]) -> Result<()> {
+		let modes = self.column_modes.read();
+		for (c, _, _) in commit {
+			match modes[*c as usize] {
+				ColumnMode::ReadWrite => (),
+				ColumnMode::ReadOnly => return Err(Error::ColumnReadOnly(*c)),
+				ColumnMode::Offline => return Err(Error::ColumnOffline(*c)),
+			}
+		}
+		Ok(())
+	}
+
+	fn set_column_mode(&self, col: ColId, mode: ColumnMode, persist: bool) -> Result<()> {
+		self.column(col)?;
+		// Commits check the modes while holding the overlay lock, so no commit to the
+		// column is queued once this returns.
+		let _overlay = self.commit_overlay.write();
+		if persist {
+			let mut persisted = self.persisted_column_modes.lock();
+			let mut modes = persisted.clone();
+			if mode == ColumnMode::ReadWrite {
+				modes.remove(&col);
+			} else {
+				modes.insert(col, mode);
+			}
+			Options::write_column_modes(&self.options.path, &self.metadata, &modes)?;
+			*persisted = modes;
+		}
+		self.column_modes.write()[col as usize] = mode;
+		log::info!(target: "axia-db", "Column {} is now {:?}", col, mode);
+		Ok(())
+	}
+
+	fn column_mode(&self, col: ColId) -> Result<ColumnMode> {
+		self.column(col)?;
+		Ok(self.column_modes.read()[col as usize])
+	}
+
+	// Debug output shared by `Db` and `DbInner`. Doesn't block on locks held elsewhere.
+	fn debug_fields(&self, name: &str, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let mut s = f.debug_struct(name);
+		s.field("path", &self.options.path.as_path())
+			.field("num_columns", &(self.metadata.columns.len() as u8))
+			.field("pipeline_stages", &self.commit_stages)
+			.field("commit_queue_bytes", &self.commit_queue.bytes.load(Ordering::Relaxed))
+			.field("last_committed_id", &self.commit_queue.commit_id.load(Ordering::Relaxed));
+		match self.bg_err.try_lock() {
+			Some(err) => s.field("bg_err", &*err),
+			None => s.field("bg_err", &"<locked>"),
+		};
+		s.finish()
+	}
+
 	fn get(&self, col: ColId, key: &[u8]) -> Result<Option<Value>> {
-		let key = self.columns[col as usize].hash(key);
-		let overlay = self.commit_overlay.read();
-		// Check commit overlay first
-		if let Some(v) = overlay.get(col as usize).and_then(|o| o.get(&key).map(|(_, v)| v.clone())) {
-			return Ok(v);
+		let column = self.readable_column(col)?;
+		column.check_indexed()?;
+		let key = column.hash(key);
+		// Check commit overlay first. The lock is released before going into the tables:
+		// commits are added to the log overlay before being removed from the commit overlay.
+		let overlay_value = self.commit_overlay.read().get(col as usize)
+			.and_then(|o| o.get(&key).map(|(_, v)| v.clone()));
+		if let Some(v) = overlay_value {
+			return v.map(|v| column.user_value(v)).transpose();
+		}
+		// Go into tables and log overlay.
+		let log = self.log.overlays();
+		column.get(&key, log).context(|| ErrorContext::new("get").column(col))
+	}
+
+	fn get_into(&self, col: ColId, key: &[u8], value: &mut Vec<u8>) -> Result<bool> {
+		let column = self.readable_column(col)?;
+		column.check_indexed()?;
+		let key = column.hash(key);
+		{
+			// Check commit overlay first. Same as `get`, the lock is not held for table reads.
+			let overlay = self.commit_overlay.read();
+			if let Some(v) = overlay.get(col as usize).and_then(|o| o.get(&key).map(|(_, v)| v.as_ref())) {
+				let v = match v {
+					Some(v) => v,
+					None => return Ok(false),
+				};
+				let offset = column.user_value_offset(v)?;
+				value.clear();
+				value.extend_from_slice(&v[offset ..]);
+				return Ok(true);
+			}
 		}
 		// Go into tables and log overlay.
 		let log = self.log.overlays();
-		self.columns[col as usize].get(&key, log)
+		column.get_into(&key, log, value).context(|| ErrorContext::new("get").column(col))
+	}
+
+	fn prefetch(&self, col: ColId, keys: &[&[u8]]) -> Result<()> {
+		let column = self.readable_column(col)?;
+		if column.is_append_only() {
+			return Ok(());
+		}
+		for key in keys {
+			if self.prefetch_queue.push((col, column.hash(key))).is_err() {
+				log::trace!(target: "axia-db", "Prefetch queue is full");
+				break;
+			}
+		}
+		self.prefetch_worker_wait.signal();
+		Ok(())
+	}
+
+	fn get_across(&self, cols: &[ColId], key: &[u8]) -> Result<Vec<Option<Value>>> {
+		let columns = cols.iter().map(|col| {
+			let column = self.readable_column(*col)?;
+			column.check_indexed()?;
+			Ok(column)
+		}).collect::<Result<Vec<_>>>()?;
+		let keys: Vec<Key> = columns.iter().map(|column| column.hash(key)).collect();
+		let overlay = self.commit_overlay.read();
+		let log = self.log.overlays();
+		cols.iter().zip(columns.iter()).zip(keys.iter()).map(|((col, column), key)| {
+			// Check commit overlay first
+			if let Some(v) = overlay.get(*col as usize).and_then(|o| o.get(key).map(|(_, v)| v.clone())) {
+				return v.map(|v| column.user_value(v)).transpose();
+			}
+			column.get(key, log)
+		}).collect()
 	}
 
 	fn get_size(&self, col: ColId, key: &[u8]) -> Result<Option<u32>> {
-		let key = self.columns[col as usize].hash(key);
+		let column = self.readable_column(col)?;
+		column.check_indexed()?;
+		let key = column.hash(key);
 		let overlay = self.commit_overlay.read();
 		// Check commit overlay first
-		if let Some(l) = overlay.get(col as usize).and_then(
-			|o| o.get(&key).map(|(_, v)| v.as_ref().map(|v| v.len() as u32))
-		) {
-			return Ok(l);
+		if let Some(v) = overlay.get(col as usize).and_then(|o| o.get(&key).map(|(_, v)| v.as_ref())) {
+			return v.map(|v| column.user_value_offset(v).map(|offset| (v.len() - offset) as u32)).transpose();
 		}
 		// Go into tables and log overlay.
 		let log = self.log.overlays();
-		self.columns[col as usize].get_size(&key, log)
+		column.get_size(&key, log)
 	}
 
 	// Commit simply adds the the data to the queue and to the overlay and
 	// exits as early as possible.
-	fn commit<I, K>(&self, tx: I) -> Result<()>
+	fn commit<I, K, V>(&self, tx: I) -> Result<u64>
 	where
-		I: IntoIterator<Item=(ColId, K, Option<Value>)>,
+		I: IntoIterator<Item=(ColId, K, Option<V>)>,
 		K: AsRef<[u8]>,
+		V: Into<Value>,
 	{
-		let commit: Vec<_> = tx.into_iter().map(
-			|(c, k, v)| (c, self.columns[c as usize].hash(k.as_ref()), v)
-		).collect();
-
+		let commit = self.hash_commit(tx)?;
 		self.commit_raw(commit)
 	}
 
-	fn commit_raw(&self, commit: Vec<(ColId, Key, Option<Value>)>) -> Result<()> {
-		{
-			let mut queue = self.commit_queue.lock();
-			if queue.bytes > MAX_COMMIT_QUEUE_BYTES {
-				log::debug!(target: "axia-db", "Waiting, qb={}", queue.bytes);
-				self.commit_queue_full_cv.wait(&mut queue);
-			}
-			{
-				let bg_err = self.bg_err.lock();
-				if let Some(err) = &*bg_err {
-					return Err(Error::Background(err.clone()));
-				}
-			}
+	// Same as `commit`, but waits for the log record to be flushed. The commit gets a
+	// barrier with a single participant, that is released once the record is readable
+	// from the log, i.e. after the log file has been written.
+	fn commit_durable<I, K, V>(&self, tx: I) -> Result<u64>
+	where
+		I: IntoIterator<Item=(ColId, K, Option<V>)>,
+		K: AsRef<[u8]>,
+		V: Into<Value>,
+	{
+		let commit = self.hash_commit(tx)?;
+		self.commit_durable_raw(commit, Default::default())
+	}
 
+	fn commit_durable_raw(&self, commit: Vec<(ColId, Key, Option<Value>)>, record: RecordInfo) -> Result<u64> {
+		self.check_writable(&commit)?;
+		self.wait_commit_queue(&commit)?;
+		let barrier = Arc::new(CommitBarrier::new(1));
+		let id = {
 			let mut overlay = self.commit_overlay.write();
+			self.check_open()?;
+			self.check_column_modes(&commit)?;
+			self.enqueue_commit(&mut overlay, commit, Some(barrier.clone()), record, true)
+		};
+		if self.exclusive {
+			self.run_pipeline()?;
+		}
+		self.wait_durable(&barrier)?;
+		Ok(id)
+	}
 
-			queue.record_id += 1;
-			let record_id = queue.record_id + 1;
-
-			let mut bytes = 0;
-			for (c, k, v) in &commit {
-				bytes += k.len();
-				bytes += v.as_ref().map_or(0, |v|v.len());
-				// Don't add removed ref-counted values to overlay.
-				if !self.metadata.columns[*c as usize].ref_counted || v.is_some() {
-					overlay[*c as usize].insert(*k, (record_id, v.clone()));
-				}
+	// Queue each changeset as a separate commit with its own barrier. All of them are
+	// queued under a single overlay lock, so they get consecutive commit ids.
+	fn commit_each(&self, commits: Vec<Vec<(ColId, Key, Option<Value>)>>) -> Result<Vec<CommitToken>> {
+		for commit in &commits {
+			self.check_writable(commit)?;
+		}
+		for commit in &commits {
+			self.wait_commit_queue(commit)?;
+		}
+		let tokens = {
+			let mut overlay = self.commit_overlay.write();
+			self.check_open()?;
+			for commit in &commits {
+				self.check_column_modes(commit)?;
 			}
+			commits.into_iter().map(|commit| {
+				let barrier = Arc::new(CommitBarrier::new(1));
+				let id = self.enqueue_commit(&mut overlay, commit, Some(barrier.clone()), Default::default(), true);
+				CommitToken { id, barrier }
+			}).collect()
+		};
+		if self.exclusive {
+			self.run_pipeline()?;
+		}
+		Ok(tokens)
+	}
 
-			let commit = Commit {
-				id: record_id,
-				changeset: commit,
-				bytes,
-			};
-
-			log::debug!(
-				target: "axia-db",
-				"Queued commit {}, {} bytes",
-				commit.id,
-				bytes,
-			);
-			queue.commits.push_back(commit);
-			queue.bytes += bytes;
-			self.log_worker_wait.signal();
+	fn wait_durable(&self, barrier: &CommitBarrier) -> Result<()> {
+		if barrier.wait(&self.shutdown) {
+			return Ok(());
+		}
+		match &*self.bg_err.lock() {
+			Some(err) => Err(Error::Background(err.clone())),
+			// The commit is still written on shutdown, unless the database stops with an error.
+			None => Err(Error::DatabaseShutdown),
 		}
-		Ok(())
 	}
 
-	fn process_commits(&self) -> Result<bool> {
-		{
-			// Wait if the queue is too big.
-			let mut queue = self.log_queue_wait.work.lock();
-			if !self.shutdown.load(Ordering::Relaxed) && *queue > MAX_LOG_QUEUE_BYTES {
-				log::debug!(target: "axia-db", "Waiting, log_bytes={}", queue);
-				self.log_queue_wait.cv.wait(&mut queue);
-			}
+	fn hash_commit<I, K, V>(&self, tx: I) -> Result<Vec<(ColId, Key, Option<Value>)>>
+	where
+		I: IntoIterator<Item=(ColId, K, Option<V>)>,
+		K: AsRef<[u8]>,
+		V: Into<Value>,
+	{
+		tx.into_iter().map(|(c, k, v)| {
+			let column = self.column(c)?;
+			Ok((c, column.hash(k.as_ref()), v.map(|v| column.ordered_value(k.as_ref(), v.into()))))
+		}).collect()
+	}
+
+	fn commit_raw(&self, commit: Vec<(ColId, Key, Option<Value>)>) -> Result<u64> {
+		self.commit_raw_with_meta(commit, None)
+	}
+
+	// Databases older than `EXTENDED_LOG_VERSION` are kept at their version, their logs
+	// must stay readable by the versions that wrote them.
+	fn check_log_version(&self, what: &str) -> Result<()> {
+		if self.metadata.version < EXTENDED_LOG_VERSION {
+			return Err(Error::InvalidConfiguration(format!(
+				"{} require database version {}, the database is version {}",
+				what,
+				EXTENDED_LOG_VERSION,
+				self.metadata.version,
+			)));
 		}
-		let commit = {
-			let mut queue = self.commit_queue.lock();
-			if let Some(commit) = queue.commits.pop_front() {
-				queue.bytes -= commit.bytes;
-				log::debug!(
-					target: "axia-db",
-					"Removed {}. Still queued commits {} bytes",
-					commit.bytes,
-					queue.bytes,
-				);
-				if queue.bytes <= MAX_COMMIT_QUEUE_BYTES && (queue.bytes + commit.bytes) > MAX_COMMIT_QUEUE_BYTES {
-					// Past the waiting threshold.
-					log::debug!(
-						target: "axia-db",
-						"Waking up commit queue worker",
-					);
-					self.commit_queue_full_cv.notify_one();
-				}
-				Some(commit)
-			} else {
-				None
-			}
-		};
+		Ok(())
+	}
 
-		if let Some(commit) = commit {
-			let mut reindex = false;
-			let mut writer = self.log.begin_record();
-			log::debug!(
-				target: "axia-db",
-				"Processing commit {}, record {}, {} bytes",
-				commit.id,
-				writer.record_id(),
-				commit.bytes,
-			);
-			let mut ops: u64 = 0;
-			for (c, key, value) in commit.changeset.iter() {
-				match self.columns[*c as usize].write_plan(key, value, &mut writer)? {
-					// Reindex has triggered another reindex.
-					PlanOutcome::NeedReindex => {
-						reindex = true;
-					},
-					_ => {},
-				}
-				ops += 1;
-			}
-			// Collect final changes to value tables
-			for c in self.columns.iter() {
-				c.complete_plan(&mut writer)?;
-			}
-			let record_id = writer.record_id();
-			let l = writer.drain();
+	fn commit_raw_with_meta(&self, commit: Vec<(ColId, Key, Option<Value>)>, meta: Option<Vec<u8>>) -> Result<u64> {
+		self.commit_raw_inner(commit, meta, true)
+	}
 
-			let bytes = {
-				let bytes = self.log.end_record(l)?;
-				let mut logged_bytes = self.log_queue_wait.work.lock();
-				*logged_bytes += bytes as i64;
-				self.flush_worker_wait.signal();
-				bytes
-			};
+	// Same as `commit`, but the changes are not added to the commit overlay.
+	fn commit_no_overlay<I, K, V>(&self, tx: I) -> Result<u64>
+	where
+		I: IntoIterator<Item=(ColId, K, Option<V>)>,
+		K: AsRef<[u8]>,
+		V: Into<Value>,
+	{
+		let commit = self.hash_commit(tx)?;
+		self.commit_raw_inner(commit, None, false)
+	}
 
-			{
-				// Cleanup the commit overlay.
-				let mut overlay = self.commit_overlay.write();
-				for (c, key, _) in commit.changeset.iter() {
-					let overlay = &mut overlay[*c as usize];
-					if let std::collections::hash_map::Entry::Occupied(e) = overlay.entry(*key) {
-						if e.get().0 == commit.id {
-							e.remove_entry();
-						}
-					}
-				}
+	fn commit_raw_inner(
+		&self,
+		commit: Vec<(ColId, Key, Option<Value>)>,
+		meta: Option<Vec<u8>>,
+		update_overlay: bool,
+	) -> Result<u64> {
+		if let Some(meta) = &meta {
+			if meta.len() > MAX_RECORD_META {
+				return Err(Error::InvalidInput(format!(
+					"Commit metadata is {} bytes, at most {} allowed",
+					meta.len(),
+					MAX_RECORD_META,
+				)));
 			}
+			self.check_log_version("Commit metadata")?;
+		}
+		self.check_writable(&commit)?;
+		self.wait_commit_queue(&commit)?;
+		let id = {
+			let mut overlay = self.commit_overlay.write();
+			self.check_open()?;
+			self.check_column_modes(&commit)?;
+			self.enqueue_commit(&mut overlay, commit, None, RecordInfo { meta, ..Default::default() }, update_overlay)
+		};
+		if self.exclusive {
+			self.run_pipeline()?;
+		}
+		Ok(id)
+	}
 
-			if reindex {
-				self.start_reindex(record_id);
+	// Write, flush and enact everything queued, including reindexing. Only used in
+	// exclusive mode, where there are no workers to do it.
+	fn run_pipeline(&self) -> Result<()> {
+		let _lock = self.pipeline_lock.lock();
+		while self.process_commits()? {}
+		loop {
+			while self.flush_logs(0)? {}
+			while self.enact_logs(false)? {}
+			if !self.process_reindex()? {
+				break;
 			}
-
-			log::debug!(
-				target: "axia-db",
-				"Processed commit {} (record {}), {} ops, {} bytes written",
-				commit.id,
-				record_id,
-				ops,
-				bytes,
-			);
-			Ok(true)
-		} else {
-			Ok(false)
 		}
+		self.cleanup_logs()?;
+		Ok(())
 	}
 
-	fn start_reindex(&self, record_id: u64) {
-		self.next_reindex.store(record_id, Ordering::SeqCst);
+	fn prepare<I, K, V>(&self, tx: I) -> Result<u64>
+	where
+		I: IntoIterator<Item=(ColId, K, Option<V>)>,
+		K: AsRef<[u8]>,
+		V: Into<Value>,
+	{
+		self.check_log_version("Prepared commits")?;
+		let commit = self.hash_commit(tx)?;
+		self.check_writable(&commit)?;
+		let id = self.next_prepared.fetch_add(1, Ordering::SeqCst);
+		prepared::write(&self.options.path, id, &commit, Encryption::from_options(&self.options).as_ref())?;
+		log::debug!(target: "axia-db", "Prepared changeset {}, {} ops", id, commit.len());
+		Ok(id)
+	}
+
+	fn commit_prepared(&self, id: u64) -> Result<()> {
+		let commit = prepared::read(&self.options.path, id, Encryption::from_options(&self.options).as_ref())?
+			.ok_or_else(|| Error::InvalidInput(format!("Unknown prepared changeset {}", id)))?;
+		self.commit_durable_raw(commit, RecordInfo { prepared: Some(id), ..Default::default() })?;
+		// The changeset file is the only synced copy of the changes, so the commit must
+		// be on disk before it is removed, even if `Options::sync_wal` is not set.
+		if self.no_wal {
+			self.sync_data()?;
+		} else if !self.options.sync_wal {
+			self.log.sync_files()?;
+		}
+		// The log record also removes the changeset when enacted, in case it is replayed
+		// after a crash.
+		prepared::remove(&self.options.path, id)?;
+		Ok(())
+	}
+
+	fn rollback_prepared(&self, id: u64) -> Result<()> {
+		if !prepared::remove(&self.options.path, id)? {
+			return Err(Error::InvalidInput(format!("Unknown prepared changeset {}", id)));
+		}
+		log::debug!(target: "axia-db", "Rolled back changeset {}", id);
+		Ok(())
+	}
+
+	fn column_is_empty(&self, col: ColId) -> Result<bool> {
+		let column = self.column(col)?;
+		// Check the commit overlay first. Commits are added to the log overlay before
+		// they are removed from it.
+		if self.commit_overlay.read()[col as usize].values().any(|(_, v)| v.is_some()) {
+			return Ok(false);
+		}
+		column.is_empty(&self.log)
+	}
+
+	fn store_record_meta(&self, record_id: u64, meta: Vec<u8>) {
+		let mut record_meta = self.record_meta.lock();
+		record_meta.insert(record_id, meta);
+		while record_meta.len() > RECORD_META_RETAIN {
+			record_meta.pop_first();
+		}
+	}
+
+	// Commits that only remove keys are always allowed. Queued and new data is counted
+	// twice, as it is written to the log before it is written to the tables.
+	fn check_quota(&self, commit: &[(ColId, Key, Option<Value>)]) -> Result<()> {
+		let max = match self.options.max_total_bytes {
+			Some(max) => max,
+			None => return Ok(()),
+		};
+		let insert_bytes: usize = commit.iter()
+			.filter_map(|(_, k, v)| v.as_ref().map(|v| k.len() + v.len()))
+			.sum();
+		if insert_bytes == 0 {
+			return Ok(());
+		}
+		let pending = (self.commit_queue.bytes.load(Ordering::SeqCst) + insert_bytes) as u64;
+		let projected = self.total_disk_usage() + 2 * pending;
+		if projected > max {
+			return Err(Error::QuotaExceeded { projected, max });
+		}
+		Ok(())
+	}
+
+	fn check_open(&self) -> Result<()> {
+		if self.shutdown.load(Ordering::SeqCst) {
+			Err(Error::DatabaseShutdown)
+		} else {
+			Ok(())
+		}
+	}
+
+	fn require_exclusive(&self) -> Result<()> {
+		if self.exclusive {
+			Ok(())
+		} else {
+			Err(Error::RequiresExclusive)
+		}
+	}
+
+	// Column ids must be in range. Columns that exist in the metadata but not in
+	// the options are read-only. The number of operations may be limited by the options.
+	// Debug builds also reject changesets that change a key more than once, as the
+	// outcome depends on the order of the operations. Reference counted columns are
+	// exempt, each operation there adds or removes a reference.
+	fn check_writable(&self, commit: &[(ColId, Key, Option<Value>)]) -> Result<()> {
+		if let Some(max) = self.options.max_changeset_ops {
+			if commit.len() > max {
+				return Err(Error::ChangesetTooLarge { ops: commit.len(), max });
+			}
+		}
+		self.check_quota(commit)?;
+		if let Some((c, _, _)) = commit.iter().find(|(c, _, _)| *c as usize >= self.columns.len()) {
+			return Err(Error::InvalidColumn(*c));
+		}
+		let writable = self.options.columns.len();
+		if let Some((c, _, _)) = commit.iter().find(|(c, _, _)| *c as usize >= writable) {
+			return Err(Error::InvalidInput(format!("Column {} is read-only", c)));
+		}
+		if let Some((c, _, _)) = commit.iter().find(|(c, _, v)| v.is_none() && self.columns[*c as usize].is_append_only()) {
+			return Err(Error::InvalidInput(format!("Can't delete from append-only column {}", c)));
+		}
+		self.check_column_modes(commit)?;
+		for (c, _, v) in commit {
+			if let (Some(len), Some(v)) = (self.metadata.columns[*c as usize].fixed_value_len, v) {
+				if v.len() != len as usize {
+					return Err(Error::InvalidInput(
+						format!("Value size {} doesn't match the fixed size {} of column {}", v.len(), len, c)
+					));
+				}
+			}
+		}
+		#[cfg(debug_assertions)]
+		{
+			let mut keys = std::collections::HashSet::with_capacity(commit.len());
+			if let Some((c, _, _)) = commit.iter().find(|(c, k, _)| {
+				!self.metadata.columns[*c as usize].ref_counted && !keys.insert((*c, *k))
+			}) {
+				return Err(Error::DuplicateKeyInCommit { column: *c });
+			}
+		}
+		Ok(())
+	}
+
+	// Wait for the queue to have some space and check for background errors.
+	// Once anyone is waiting, new committers queue up behind them, so that
+	// threads making large commits can't starve the others.
+	// Waiting stops when the database shuts down, which also happens when a background
+	// worker fails. The workers that would drain the queue are gone by then.
+	fn wait_commit_queue(&self, commit: &[(ColId, Key, Option<Value>)]) -> Result<()> {
+		self.check_background_error()?;
+		self.wait_column_share(commit);
+		let max_bytes = self.max_commit_queue_bytes;
+		let bytes = self.commit_queue.bytes.load(Ordering::SeqCst);
+		if bytes > max_bytes || self.waiting_committers.load(Ordering::SeqCst) > 0 {
+			let mut admission = self.commit_queue_full.lock();
+			let ticket = admission.next_ticket;
+			admission.next_ticket += 1;
+			self.waiting_committers.fetch_add(1, Ordering::SeqCst);
+			log::debug!(target: "axia-db", "Waiting, qb={}, ticket={}", bytes, ticket);
+			let start = std::time::Instant::now();
+			while admission.serving != ticket || (
+				self.commit_queue.bytes.load(Ordering::SeqCst) > max_bytes && !self.shutdown.load(Ordering::SeqCst)
+			) {
+				self.commit_queue_full_cv.wait(&mut admission);
+			}
+			admission.serving += 1;
+			self.waiting_committers.fetch_sub(1, Ordering::SeqCst);
+			// Let the next ticket check the queue.
+			self.commit_queue_full_cv.notify_all();
+			drop(admission);
+			self.check_stall(StallReason::CommitQueue, start.elapsed());
+		}
+		self.check_background_error()
+	}
+
+	// Counts and reports waits of at least `Options::stall_warn_threshold`.
+	fn check_stall(&self, reason: StallReason, duration: std::time::Duration) {
+		if duration >= self.options.stall_warn_threshold {
+			log::warn!(target: "axia-db", "Stalled for {:?}: {}", duration, reason);
+			self.stalls.record(reason, duration);
+		}
+	}
+
+	fn check_background_error(&self) -> Result<()> {
+		match &*self.bg_err.lock() {
+			Some(err) => Err(Error::Background(err.clone())),
+			None => Ok(()),
+		}
+	}
+
+	// Commits that mostly write to a column with a queue share limit wait until the
+	// column is back within the limit. This does not hold up commits to other columns.
+	fn wait_column_share(&self, commit: &[(ColId, Key, Option<Value>)]) {
+		let mut column_bytes = vec![0; self.columns.len()];
+		for (c, k, v) in commit {
+			column_bytes[*c as usize] += k.len() + v.as_ref().map_or(0, |v| v.len());
+		}
+		let dominant = column_bytes.iter().enumerate()
+			.filter(|(_, bytes)| **bytes > 0)
+			.max_by_key(|(_, bytes)| **bytes)
+			.map(|(c, _)| c);
+		let (col, limit) = match dominant.and_then(|c| self.column_queue_limits[c].map(|l| (c, l))) {
+			Some(limit) => limit,
+			None => return,
+		};
+		let queued = &self.commit_queue.column_bytes[col];
+		if queued.load(Ordering::SeqCst) > limit {
+			log::debug!(target: "axia-db", "Waiting, col={}, qb={}", col, queued.load(Ordering::SeqCst));
+			let start = std::time::Instant::now();
+			let mut lock = self.commit_queue_full.lock();
+			while queued.load(Ordering::SeqCst) > limit && !self.shutdown.load(Ordering::SeqCst) {
+				self.commit_queue_full_cv.wait(&mut lock);
+			}
+			drop(lock);
+			self.check_stall(StallReason::CommitQueue, start.elapsed());
+		}
+	}
+
+	// Returns the commit id. Must be called with the overlay lock held, so that
+	// commits are queued in the order of their ids. With `update_overlay` unset the
+	// changes are only visible once the commit is written to the log.
+	fn enqueue_commit(
+		&self,
+		overlay: &mut CommitOverlay,
+		commit: Vec<(ColId, Key, Option<Value>)>,
+		barrier: Option<Arc<CommitBarrier>>,
+		record: RecordInfo,
+		update_overlay: bool,
+	) -> u64 {
+		let id = self.commit_queue.commit_id.fetch_add(1, Ordering::SeqCst) + 1;
+
+		let mut bytes = 0;
+		let mut column_bytes = vec![0; self.columns.len()];
+		for (c, k, v) in &commit {
+			let op_bytes = k.len() + v.as_ref().map_or(0, |v|v.len());
+			bytes += op_bytes;
+			column_bytes[*c as usize] += op_bytes;
+			if v.is_some() {
+				self.columns[*c as usize].invalidate_negative_cache(k);
+			}
+			// Don't add removed ref-counted values to overlay.
+			if update_overlay && (!self.metadata.columns[*c as usize].ref_counted || v.is_some()) {
+				overlay[*c as usize].insert(*k, (id, v.clone()));
+			}
+		}
+
+		let commit = QueuedCommit {
+			id,
+			changeset: commit,
+			bytes,
+			column_bytes,
+			barrier,
+			record,
+		};
+
+		if let Some(barrier) = &commit.barrier {
+			self.queued_barriers.lock().insert(commit.id, barrier.clone());
+		}
+
+		log::debug!(
+			target: "axia-db",
+			"Queued commit {}, {} bytes",
+			commit.id,
+			bytes,
+		);
+		// Account for the bytes first so that the log worker never sees them negative.
+		self.commit_queue.bytes.fetch_add(bytes, Ordering::SeqCst);
+		for (queued, bytes) in self.commit_queue.column_bytes.iter().zip(commit.column_bytes.iter()) {
+			queued.fetch_add(*bytes, Ordering::SeqCst);
+		}
+		self.commit_queue.commits.push(commit);
+		self.log_worker_wait.signal();
+		id
+	}
+
+	fn process_commits(&self) -> Result<bool> {
+		// Wait if the queue is too big.
+		let queue = &self.log_queue_bytes;
+		if queue.value() > queue.capacity {
+			log::debug!(target: "axia-db", "Waiting, log_bytes={}", queue.value());
+			let start = std::time::Instant::now();
+			queue.wait_below(queue.capacity, &self.shutdown);
+			self.check_stall(StallReason::LogQueue, start.elapsed());
+		}
+		match self.dequeue_commit() {
+			Some(commit) => {
+				let plan = self.plan_commit(commit)?;
+				self.write_commit_plan(plan)?;
+				Ok(true)
+			},
+			None => Ok(false),
+		}
+	}
+
+	// Takes the next commit off the queue and wakes committers waiting for space.
+	fn dequeue_commit(&self) -> Option<QueuedCommit> {
+		let commit = self.commit_queue.commits.pop()?;
+		let bytes = self.commit_queue.bytes.fetch_sub(commit.bytes, Ordering::SeqCst) - commit.bytes;
+		log::debug!(
+			target: "axia-db",
+			"Removed {}. Still queued commits {} bytes",
+			commit.bytes,
+			bytes,
+		);
+		let max_bytes = self.max_commit_queue_bytes;
+		let mut wake = bytes <= max_bytes && (bytes + commit.bytes) > max_bytes;
+		for ((queued, limit), removed) in self.commit_queue.column_bytes.iter()
+			.zip(self.column_queue_limits.iter())
+			.zip(commit.column_bytes.iter())
+		{
+			let column_bytes = queued.fetch_sub(*removed, Ordering::SeqCst) - *removed;
+			if let Some(limit) = limit {
+				wake |= column_bytes <= *limit && (column_bytes + *removed) > *limit;
+			}
+		}
+		if wake {
+			// Past the waiting threshold.
+			log::debug!(
+				target: "axia-db",
+				"Waking up commit queue worker",
+			);
+			let _lock = self.commit_queue_full.lock();
+			self.commit_queue_full_cv.notify_all();
+		}
+		Some(commit)
+	}
+
+	// Plans the changes of `commit` into a log record, without writing it. This allocates
+	// the record id, so the plan must be written with `write_commit_plan` before
+	// another record is started.
+	fn plan_commit(&self, commit: QueuedCommit) -> Result<CommitPlan> {
+		let mut reindex = false;
+		let mut writer = self.log.begin_record();
+		if let Some(meta) = &commit.record.meta {
+			writer.record_meta(meta.clone());
+		}
+		if let Some(id) = commit.record.prepared {
+			writer.commit_prepared(id);
+		}
+		log::debug!(
+			target: "axia-db",
+			"Processing commit {}, record {}, {} bytes",
+			commit.id,
+			writer.record_id(),
+			commit.bytes,
+		);
+		let mut ops: u64 = 0;
+		// Large commits are logged at info level.
+		let level = if commit.bytes > LARGE_COMMIT_BYTES { log::Level::Info } else { log::Level::Debug };
+		// Per-column breakdown is only collected when it is going to be logged.
+		let mut column_ops = if log::log_enabled!(target: "axia-db", level) {
+			vec![0u64; self.columns.len()]
+		} else {
+			Vec::new()
+		};
+		for (c, key, value) in commit.changeset.iter() {
+			let outcome = self.column(*c)?.write_plan(key, value, &mut writer)
+				.context(|| ErrorContext::new("commit").column(*c))?;
+			match outcome {
+				// Reindex has triggered another reindex.
+				PlanOutcome::NeedReindex => {
+					reindex = true;
+				},
+				_ => {},
+			}
+			ops += 1;
+			if let Some(n) = column_ops.get_mut(*c as usize) {
+				*n += 1;
+			}
+		}
+		// Collect final changes to value tables
+		for c in self.columns.iter() {
+			c.complete_plan(&mut writer)?;
+		}
+		Ok(CommitPlan {
+			record_id: writer.record_id(),
+			change: writer.drain(),
+			commit,
+			reindex,
+			ops,
+			column_ops,
+			level,
+		})
+	}
+
+	// Writes a planned commit to the log and removes it from the commit overlay.
+	// Returns the log record id.
+	fn write_commit_plan(&self, plan: CommitPlan) -> Result<u64> {
+		let CommitPlan { record_id, change, commit, reindex, ops, column_ops, level } = plan;
+		if let Some(meta) = &commit.record.meta {
+			self.store_record_meta(record_id, meta.clone());
+		}
+		if let Some(barrier) = &commit.barrier {
+			// Make the record readable as soon as possible, other databases are waiting for it.
+			self.queued_barriers.lock().remove(&commit.id);
+			self.barriers.lock().insert(record_id, barrier.clone());
+			self.force_flush.store(true, Ordering::SeqCst);
+		}
+
+		self.logged_commits.lock().push_back((record_id, commit.id));
+		if self.options.paranoid_checks {
+			self.paranoid_changes.lock().insert(record_id, commit.changeset.clone());
+		}
+		let stats = self.end_record(change)?;
+		self.commit_count.fetch_add(1, Ordering::Relaxed);
+		self.logged_bytes.fetch_add(stats.bytes_written, Ordering::Relaxed);
+
+		{
+			// Cleanup the commit overlay.
+			let mut overlay = self.commit_overlay.write();
+			for (c, key, _) in commit.changeset.iter() {
+				let overlay = &mut overlay[*c as usize];
+				if let std::collections::hash_map::Entry::Occupied(e) = overlay.entry(*key) {
+					if e.get().0 == commit.id {
+						e.remove_entry();
+					}
+				}
+			}
+		}
+
+		if reindex {
+			self.start_reindex(record_id);
+		}
+
+		#[cfg(feature = "tracing")]
+		self.trace_commit(level, commit.id, record_id, ops, &stats);
+		log::log!(
+			target: "axia-db",
+			level,
+			"Processed commit {} (record {}), {} ops ({}), {} bytes written",
+			commit.id,
+			record_id,
+			ops,
+			column_ops.iter().enumerate()
+				.filter(|(_, n)| **n != 0)
+				.map(|(c, n)| format!("col{}: {}", c, n))
+				.collect::<Vec<_>>()
+				.join(", "),
+			stats.bytes_written,
+		);
+		Ok(record_id)
+	}
+
+	// Emits the processed commit as a tracing event with structured fields, at the same
+	// level it is logged at.
+	#[cfg(feature = "tracing")]
+	fn trace_commit(&self, level: log::Level, commit_id: u64, record_id: u64, ops: u64, stats: &LogWriteStats) {
+		// Event levels must be constant.
+		macro_rules! event {
+			($level:expr) => {
+				tracing::event!(
+					target: "axia-db",
+					$level,
+					commit_id,
+					record_id,
+					ops,
+					bytes = stats.bytes_written,
+					index_insertions = stats.num_index_insertions,
+					value_insertions = stats.num_value_insertions,
+					queue_remaining_bytes = self.commit_queue.bytes.load(Ordering::Relaxed),
+					"Processed commit",
+				)
+			};
+		}
+		if level == log::Level::Info {
+			event!(tracing::Level::INFO);
+		} else {
+			event!(tracing::Level::DEBUG);
+		}
+	}
+
+	// Remove commit overlay entries of commits with an enacted log record. These are
+	// normally removed by `process_commits` as soon as the record is written, so this
+	// only catches entries that were missed. Returns the number of removed entries.
+	fn audit_commit_overlay(&self) -> usize {
+		let enacted = self.enacted_commit.load(Ordering::SeqCst);
+		let stale: Vec<(usize, Key)> = self.commit_overlay.read().iter().enumerate()
+			.flat_map(|(c, overlay)| overlay.iter()
+				.filter(|(_, (id, _))| *id <= enacted)
+				.map(move |(k, _)| (c, *k)))
+			.collect();
+		if stale.is_empty() {
+			return 0;
+		}
+		let mut removed = 0;
+		let mut overlay = self.commit_overlay.write();
+		for (c, key) in stale {
+			// The key may have been committed again in the meantime.
+			if let std::collections::hash_map::Entry::Occupied(e) = overlay[c].entry(key) {
+				if e.get().0 <= enacted {
+					e.remove_entry();
+					removed += 1;
+				}
+			}
+		}
+		log::warn!(target: "axia-db", "Removed {} stale commit overlay entries", removed);
+		removed
+	}
+
+	fn start_reindex(&self, record_id: u64) {
+		self.next_reindex.store(record_id, Ordering::SeqCst);
+	}
+
+	#[cfg(any(test, feature = "testing"))]
+	fn force_reindex(&self, col: ColId) -> Result<()> {
+		let column = self.column(col)?;
+		column.force_reindex(self.log.last_record_id())?;
+		// Records written before the new index was created may still refer to the old
+		// one, so they must be enacted first.
+		self.start_reindex(self.log.last_record_id().max(1));
+		Ok(())
 	}
 
 	fn process_reindex(&self) -> Result<bool> {
@@ -404,6 +1551,7 @@ impl DbInner {
 			let (drop_index, batch) = column.reindex(&self.log)?;
 			if !batch.is_empty() || drop_index.is_some() {
 				let mut next_reindex = false;
+				let entries = batch.len() as u64;
 				let mut writer = self.log.begin_record();
 				log::debug!(
 					target: "axia-db",
@@ -424,15 +1572,14 @@ impl DbInner {
 				let record_id = writer.record_id();
 				let l = writer.drain();
 
-				let mut logged_bytes = self.log_queue_wait.work.lock();
-				let bytes = self.log.end_record(l)?;
+				let bytes = self.end_record(l)?.bytes_written;
 				log::debug!(
 					target: "axia-db",
 					"Created reindex record {}, {} bytes",
 					record_id,
 					bytes,
 				);
-				*logged_bytes += bytes as i64;
+				column.reindex_written(entries, bytes);
 				if next_reindex {
 					self.start_reindex(record_id);
 				}
@@ -453,9 +1600,12 @@ impl DbInner {
 					self.log.clear_replay_logs()?;
 					return Ok(false);
 				}
-				Err(e) => return Err(e),
+				Err(e) => return Err(e.with_context(ErrorContext::new("read log"))),
 			};
 			if let Some(mut reader) = reader {
+				if !validation_mode {
+					self.await_barrier(reader.record_id());
+				}
 				log::debug!(
 					target: "axia-db",
 					"Enacting log {}",
@@ -495,8 +1645,8 @@ impl DbInner {
 								break;
 							},
 							LogAction::InsertIndex(insertion) => {
-								let col = insertion.table.col() as usize;
-								if let Err(e) = self.columns[col].validate_plan(LogAction::InsertIndex(insertion), &mut reader) {
+								let col = insertion.table.col();
+								if let Err(e) = self.column(col).and_then(|c| c.validate_plan(LogAction::InsertIndex(insertion), &mut reader)) {
 									log::warn!(target: "axia-db", "Error replaying log: {:?}. Reverting", e);
 									std::mem::drop(reader);
 									self.log.clear_replay_logs()?;
@@ -504,15 +1654,15 @@ impl DbInner {
 								}
 							},
 							LogAction::InsertValue(insertion) => {
-								let col = insertion.table.col() as usize;
-								if let Err(e) = self.columns[col].validate_plan(LogAction::InsertValue(insertion), &mut reader) {
+								let col = insertion.table.col();
+								if let Err(e) = self.column(col).and_then(|c| c.validate_plan(LogAction::InsertValue(insertion), &mut reader)) {
 									log::warn!(target: "axia-db", "Error replaying log: {:?}. Reverting", e);
 									std::mem::drop(reader);
 									self.log.clear_replay_logs()?;
 									return Ok(false);
 								}
 							},
-							LogAction::DropTable(_) => {
+							LogAction::DropTable(_) | LogAction::RecordMeta(_) | LogAction::CommitPrepared(_) => {
 								continue;
 							}
 						}
@@ -520,46 +1670,14 @@ impl DbInner {
 					reader.reset()?;
 					reader.next()?;
 				}
-				loop {
-					match reader.next()? {
-						LogAction::BeginRecord => {
-							return Err(Error::Corruption("Bad log record".into()));
-						},
-						LogAction::EndRecord => {
-							break;
-						},
-						LogAction::InsertIndex(insertion) => {
-							self.columns[insertion.table.col() as usize]
-								.enact_plan(LogAction::InsertIndex(insertion), &mut reader)?;
-
-						},
-						LogAction::InsertValue(insertion) => {
-							self.columns[insertion.table.col() as usize]
-								.enact_plan(LogAction::InsertValue(insertion), &mut reader)?;
-
-						},
-						LogAction::DropTable(id) => {
-							log::debug!(
-								target: "axia-db",
-								"Dropping index {}",
-								id,
-							);
-							self.columns[id.col() as usize].drop_index(id)?;
-							// Check if there's another reindex on the next iteration
-							self.start_reindex(reader.record_id());
-						}
-					}
+				{
+					let _enact = self.enact_lock.write();
+					self.enact_record_checked(&mut reader).context(|| ErrorContext::new("enact log"))?;
 				}
-				log::debug!(
-					target: "axia-db",
-					"Enacted log record {}, {} bytes",
-					reader.record_id(),
-					reader.read_bytes(),
-				);
 				let record_id = reader.record_id();
 				let bytes = reader.read_bytes();
 				let cleared = reader.drain();
-				self.last_enacted.store(record_id, Ordering::SeqCst);
+				self.record_enacted(record_id);
 				Some((record_id, cleared, bytes))
 			} else {
 				log::debug!(target: "axia-db", "End of log");
@@ -571,22 +1689,18 @@ impl DbInner {
 			self.log.end_read(cleared, record_id);
 			{
 				if !validation_mode {
-					let mut queue = self.log_queue_wait.work.lock();
-					if *queue < bytes as i64 {
+					let queue = self.log_queue_bytes.sub(bytes as i64);
+					if queue < 0 {
 						log::warn!(
 							target: "axia-db",
 							"Detected log undeflow record {}, {} bytes, {} queued, reindex = {}",
 							record_id,
 							bytes,
-							*queue,
+							queue + bytes as i64,
 							self.next_reindex.load(Ordering::SeqCst),
 						);
 					}
-					*queue -= bytes as i64;
-					if *queue <= MAX_LOG_QUEUE_BYTES && (*queue + bytes as i64) > MAX_LOG_QUEUE_BYTES {
-						self.log_queue_wait.cv.notify_all();
-					}
-					log::debug!(target: "axia-db", "Log queue size: {} bytes", *queue);
+					log::debug!(target: "axia-db", "Log queue size: {} bytes", queue);
 				}
 			}
 			Ok(true)
@@ -595,8 +1709,153 @@ impl DbInner {
 		}
 	}
 
+	// Apply the rest of a record to the tables.
+	fn enact_record(&self, reader: &mut LogReader) -> Result<()> {
+		loop {
+			match reader.next()? {
+				LogAction::BeginRecord => {
+					return Err(Error::Corruption("Bad log record".into()));
+				},
+				LogAction::EndRecord => {
+					break;
+				},
+				LogAction::InsertIndex(insertion) => {
+					let col = insertion.table.col();
+					self.column(col)?
+						.enact_plan(LogAction::InsertIndex(insertion), reader)
+						.context(|| ErrorContext::new("enact index").column(col))?;
+
+				},
+				LogAction::InsertValue(insertion) => {
+					let col = insertion.table.col();
+					self.column(col)?
+						.enact_plan(LogAction::InsertValue(insertion), reader)
+						.context(|| ErrorContext::new("enact value").column(col))?;
+
+				},
+				LogAction::DropTable(id) => {
+					log::debug!(
+						target: "axia-db",
+						"Dropping index {}",
+						id,
+					);
+					self.column(id.col())?.drop_index(id, reader.record_id())?;
+					// Check if there's another reindex on the next iteration
+					self.start_reindex(reader.record_id());
+				}
+				LogAction::RecordMeta(meta) => {
+					self.store_record_meta(reader.record_id(), meta);
+				}
+				LogAction::CommitPrepared(id) => {
+					prepared::remove(&self.options.path, id)?;
+				}
+			}
+		}
+		log::debug!(
+			target: "axia-db",
+			"Enacted log record {}, {} bytes",
+			reader.record_id(),
+			reader.read_bytes(),
+		);
+		Ok(())
+	}
+
+	// Same as `enact_record`, but with `Options::paranoid_checks` also checks that the
+	// tables match the changes of the record afterwards.
+	fn enact_record_checked(&self, reader: &mut LogReader) -> Result<()> {
+		let record_id = reader.record_id();
+		let changes = if self.options.paranoid_checks { self.paranoid_changes.lock().remove(&record_id) } else { None };
+		let changes = match changes {
+			Some(changes) => changes,
+			// Not a commit, or replayed from a previous session.
+			None => return self.enact_record(reader),
+		};
+		// The expected state follows from the tables before the record is enacted.
+		let mut expected: HashMap<(ColId, Key), Option<(Value, u32)>> = HashMap::new();
+		for (c, key, value) in changes {
+			let column = self.column(c)?;
+			if column.is_append_only() {
+				continue;
+			}
+			let state = match expected.remove(&(c, key)) {
+				Some(state) => state,
+				None => column.get_enacted(&key).context(|| ErrorContext::new("paranoid check").column(c))?,
+			};
+			expected.insert((c, key), column.state_after_write(state, value));
+		}
+		self.enact_record(reader)?;
+		for ((c, key), expected) in expected {
+			let actual = self.column(c)?.get_enacted(&key).context(|| ErrorContext::new("paranoid check").column(c))?;
+			if actual != expected {
+				let describe = |state: &Option<(Value, u32)>| match state {
+					Some((value, rc)) => format!("{} bytes ({}), rc={}", value.len(), hex(&value[.. std::cmp::min(value.len(), 32)]), rc),
+					None => "none".to_string(),
+				};
+				return Err(Error::Corruption(format!(
+					"Record {}, column {}, key {}: expected {}, found {} after enacting",
+					record_id,
+					c,
+					hex(&key),
+					describe(&expected),
+					describe(&actual),
+				))).context(|| ErrorContext::new("paranoid check").column(c));
+			}
+		}
+		Ok(())
+	}
+
+	fn record_enacted(&self, record_id: u64) {
+		self.last_enacted.store(record_id, Ordering::SeqCst);
+		let mut logged = self.logged_commits.lock();
+		while let Some((_, commit_id)) = logged.front().filter(|(r, _)| *r <= record_id).copied() {
+			logged.pop_front();
+			self.enacted_commit.store(commit_id, Ordering::SeqCst);
+		}
+	}
+
+	// Write a record to the log. With `Options::no_wal`, the record is applied to the
+	// tables right away instead.
+	fn end_record(&self, record: LogChange) -> Result<LogWriteStats> {
+		if !self.no_wal {
+			let stats = self.log.end_record(record)?;
+			self.log_queue_bytes.add(stats.bytes_written as i64);
+			self.disk_usage.fetch_add(stats.bytes_written, Ordering::Relaxed);
+			self.flush_worker_wait.signal();
+			return Ok(stats);
+		}
+		let (mut reader, stats) = self.log.end_record_in_memory(record)?;
+		self.await_barrier(stats.record_id);
+		self.enact_record_checked(&mut reader)?;
+		let cleared = reader.drain();
+		self.record_enacted(stats.record_id);
+		self.log.end_read(cleared, stats.record_id);
+		// There are no logs to flush, so the index files are only measured here.
+		self.resync_disk_usage();
+		Ok(stats)
+	}
+
+	fn await_barrier(&self, record_id: u64) {
+		let barrier = self.barriers.lock().remove(&record_id);
+		if let Some(barrier) = barrier {
+			barrier.prepared();
+			if !barrier.wait(&self.shutdown) {
+				log::warn!(
+					target: "axia-db",
+					"Enacting coordinated commit record {} before all databases have flushed it",
+					record_id,
+				);
+			}
+		}
+	}
+
 	fn flush_logs(&self, min_log_size: u64) -> Result<bool> {
-		let (flush_next, read_next, cleanup_next) = self.log.flush_one(min_log_size)?;
+		let (flush_next, read_next, cleanup_next, sync_time) = self.log.flush_one(min_log_size)?;
+		if let Some(sync_time) = sync_time {
+			self.check_stall(StallReason::Fsync, sync_time);
+		}
+		if flush_next || read_next || cleanup_next {
+			self.resync_disk_usage();
+		}
 		if read_next {
 			self.commit_worker_wait.signal();
 		}
@@ -615,41 +1874,101 @@ impl DbInner {
 					c.flush()?;
 				}
 			}
-			self.log.clean_logs(num_cleanup - keep_logs)
+			let (cleaned, freed) = self.log.clean_logs(num_cleanup - keep_logs)?;
+			self.release_disk_usage(freed);
+			self.resync_disk_usage();
+			Ok(cleaned)
 		} else {
 			Ok(false)
 		}
 	}
 
+	// Disk space used by the database files. Value tables are counted by their filled
+	// entries, which also covers tables grown since the last scan.
+	fn total_disk_usage(&self) -> u64 {
+		let tables: u64 = self.columns.iter().map(|c| c.value_table_bytes()).sum();
+		self.disk_usage.load(Ordering::Relaxed) + tables
+	}
+
+	fn release_disk_usage(&self, bytes: u64) {
+		let _ = self.disk_usage.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |usage|
+			Some(usage.saturating_sub(bytes))
+		);
+	}
+
+	// Correct the disk usage counter for index files and any drift in the log sizes by
+	// scanning the directory. Only done with a quota, at most once per
+	// `DISK_USAGE_SCAN_INTERVAL`. Scan errors are logged, the counter is kept as is.
+	fn resync_disk_usage(&self) {
+		if self.options.max_total_bytes.is_none() {
+			return;
+		}
+		{
+			let mut last_scan = self.last_disk_scan.lock();
+			if last_scan.is_some_and(|t| t.elapsed() < DISK_USAGE_SCAN_INTERVAL) {
+				return;
+			}
+			*last_scan = Some(std::time::Instant::now());
+		}
+		match disk_usage(&self.options.path, crate::table::TableId::is_any_file_name) {
+			Ok(usage) => self.disk_usage.store(usage, Ordering::Relaxed),
+			Err(e) => log::warn!(target: "axia-db", "Error measuring disk usage: {:?}", e),
+		}
+	}
+
+	fn sync_data(&self) -> Result<()> {
+		for c in self.columns.iter() {
+			c.flush()?;
+		}
+		Ok(())
+	}
+
 	fn clean_all_logs(&self) -> Result<()> {
 		for c in self.columns.iter() {
 			c.flush()?;
 		}
 		let num_cleanup = self.log.num_dirty_logs();
-		self.log.clean_logs(num_cleanup)?;
+		let (_, freed) = self.log.clean_logs(num_cleanup)?;
+		self.release_disk_usage(freed);
 		Ok(())
 	}
 
-	fn replay_all_logs(&mut self) -> Result<()> {
+	// Returns `true` if any log records were enacted.
+	fn replay_all_logs(&mut self) -> Result<bool> {
+		let mut enacted = false;
 		while let Some(id) = self.log.replay_next()? {
 			log::debug!(target: "axia-db", "Replaying database log {}", id);
-			while self.enact_logs(true)? { }
+			while self.enact_logs(true)? {
+				enacted = true;
+			}
 		}
-		// Re-read any cached metadata
+		// Re-read any cached metadata. Saved ordered keys don't include replayed changes.
 		for c in self.columns.iter() {
 			c.refresh_metadata()?;
+			c.load_ordered_keys(&self.log, !enacted, self.read_only)?;
 		}
 		log::debug!(target: "axia-db", "Replay is complete.");
-		Ok(())
+		Ok(enacted)
 	}
 
 	fn shutdown(&self) {
-		self.shutdown.store(true, Ordering::SeqCst);
-		self.log_queue_wait.cv.notify_all();
+		{
+			// Commits check the flag while holding the overlay lock, so every commit is either
+			// queued before this and written by `kill_logs`, or rejected.
+			let _overlay = self.commit_overlay.write();
+			self.shutdown.store(true, Ordering::SeqCst);
+		}
+		{
+			// Wake committers waiting for the commit queue to drain.
+			let _lock = self.commit_queue_full.lock();
+			self.commit_queue_full_cv.notify_all();
+		}
+		self.log_queue_bytes.notify_all();
 		self.flush_worker_wait.signal();
 		self.log_worker_wait.signal();
 		self.commit_worker_wait.signal();
 		self.cleanup_worker_wait.signal();
+		self.prefetch_worker_wait.signal();
 	}
 
 	fn kill_logs(&self) -> Result<()> {
@@ -661,25 +1980,156 @@ impl DbInner {
 		while self.enact_logs(false)? {};
 		self.flush_logs(0)?;
 		while self.enact_logs(false)? {};
-		self.clean_all_logs()?;
-		self.log.kill_logs()?;
+		// Stats are written before the logs are cleaned up. Failing to write them
+		// does not stop the cleanup, all errors are returned afterwards.
+		let mut errors = Vec::new();
 		if self.options.stats {
-			let mut path = self.options.path.clone();
-			path.push("stats.txt");
-			match std::fs::File::create(path) {
-				Ok(file) => {
-					let mut writer = std::io::BufWriter::new(file);
-					self.collect_stats(&mut writer, None)
+			if let Err(e) = self.write_stats(None) {
+				log::warn!(target: "axia-db", "Error writing stats file: {:?}", e);
+				errors.push(e);
+			}
+			if self.options.stats_history {
+				if let Err(e) = self.append_stats_history() {
+					log::warn!(target: "axia-db", "Error writing stats history: {:?}", e);
+					errors.push(e);
+				}
+			}
+		}
+		if let Err(e) = self.clean_all_logs().and_then(|_| self.log.kill_logs()) {
+			errors.push(e);
+		}
+		// Ordered keys are only saved if all changes are in the tables. Otherwise they are
+		// rebuilt on open.
+		if errors.is_empty() && !self.read_only && self.bg_err.lock().is_none() {
+			for c in self.columns.iter() {
+				if let Err(e) = c.save_ordered_keys() {
+					log::warn!(target: "axia-db", "Error saving ordered keys: {:?}", e);
 				}
-				Err(e) => log::warn!(target: "axia-db", "Error creating stats file: {:?}", e),
 			}
 		}
+		// Tables were flushed by `clean_all_logs`.
+		if self.no_wal && errors.is_empty() {
+			if let Err(e) = std::fs::remove_file(self.options.path.join(NO_WAL_DIRTY_FILE)) {
+				errors.push(e.into());
+			}
+		}
+		match errors.len() {
+			0 => Ok(()),
+			1 => Err(errors.remove(0)),
+			_ => Err(Error::MultiError(errors)),
+		}
+	}
+
+	// Writes the stats of all columns to `path`, or to the stats file in the database
+	// directory. The file is written next to it first and then renamed, so that it is
+	// never left partially written.
+	fn write_stats(&self, path: Option<&std::path::Path>) -> Result<()> {
+		let path = path.map_or_else(|| self.options.path.join(STATS_FILE), |p| p.to_path_buf());
+		let mut stats = Vec::new();
+		self.collect_stats(&mut stats, None);
+		let mut tmp_path = path.clone().into_os_string();
+		tmp_path.push(".tmp");
+		let tmp_path = std::path::PathBuf::from(tmp_path);
+		let result = std::fs::File::create(&tmp_path)
+			.and_then(|mut file| {
+				std::io::Write::write_all(&mut file, &stats)?;
+				file.sync_all()
+			})
+			.and_then(|_| std::fs::rename(&tmp_path, &path));
+		if result.is_err() {
+			let _ = std::fs::remove_file(&tmp_path);
+		}
+		Ok(result?)
+	}
+
+	// Time left until the stats file is due to be written, see `Options::stats_interval`.
+	fn stats_write_delay(&self) -> Option<std::time::Duration> {
+		match self.options.stats_interval {
+			Some(interval) if self.options.stats => Some(self.last_stats_write.lock()
+				.map_or(std::time::Duration::ZERO, |last| interval.saturating_sub(last.elapsed()))),
+			_ => None,
+		}
+	}
+
+	// Writes the stats file if it is due. Returns `true` if it was written. Errors are
+	// logged, as they don't affect the database.
+	fn persist_stats(&self) -> bool {
+		if self.stats_write_delay() != Some(std::time::Duration::ZERO) {
+			return false;
+		}
+		*self.last_stats_write.lock() = Some(std::time::Instant::now());
+		if let Err(e) = self.write_stats(None) {
+			log::warn!(target: "axia-db", "Error writing stats file: {:?}", e);
+		}
+		true
+	}
+
+	fn append_stats_history(&self) -> Result<()> {
+		let mut path = self.options.path.clone();
+		path.push(STATS_HISTORY_FILE);
+		if std::fs::metadata(&path).is_ok_and(|m| m.len() > MAX_STATS_HISTORY_BYTES) {
+			let mut old = self.options.path.clone();
+			old.push(STATS_HISTORY_OLD_FILE);
+			std::fs::rename(&path, &old)?;
+		}
+		let mut file = std::fs::OpenOptions::new().create(true).append(true).open(&path)?;
+		std::io::Write::write_all(&mut file, format!("{}\n", self.stats().to_json_line()).as_bytes())?;
 		Ok(())
 	}
 
+	fn stats(&self) -> DbStats {
+		let timestamp = std::time::SystemTime::now()
+			.duration_since(std::time::UNIX_EPOCH)
+			.map_or(0, |d| d.as_millis() as u64);
+		let (commit_queue_stalls, commit_queue_stall_time) = self.stalls.get(StallReason::CommitQueue);
+		let (log_queue_stalls, log_queue_stall_time) = self.stalls.get(StallReason::LogQueue);
+		let (fsync_stalls, fsync_stall_time) = self.stalls.get(StallReason::Fsync);
+		DbStats {
+			timestamp,
+			uptime: self.opened.elapsed().as_millis() as u64,
+			last_enacted: self.last_enacted.load(Ordering::Relaxed),
+			commits: self.commit_count.load(Ordering::Relaxed),
+			logged_bytes: self.logged_bytes.load(Ordering::Relaxed),
+			commit_queue_stalls,
+			commit_queue_stall_time,
+			log_queue_stalls,
+			log_queue_stall_time,
+			fsync_stalls,
+			fsync_stall_time,
+			columns: self.columns.iter().map(|c| c.stats_summary()).collect(),
+		}
+	}
+
+	fn health(&self) -> DbHealth {
+		DbHealth {
+			waiting_committers: self.waiting_committers.load(Ordering::SeqCst),
+			commit_queue_bytes: self.commit_queue.bytes.load(Ordering::SeqCst),
+			column_queue_bytes: self.commit_queue.column_bytes.iter().map(|b| b.load(Ordering::SeqCst)).collect(),
+			background_error: self.bg_err.lock().is_some(),
+			disk_usage: self.total_disk_usage(),
+			max_total_bytes: self.options.max_total_bytes,
+		}
+	}
+
+	fn pipeline_status(&self, workers_alive: [bool; 4]) -> PipelineStatus {
+		let current_record = self.log.last_record_id();
+		PipelineStatus {
+			commit_queue_bytes: self.commit_queue.bytes.load(Ordering::SeqCst),
+			log_queue_bytes: self.log_queue_bytes.value(),
+			pending_reindex: self.columns.iter().any(|c| c.is_reindexing()),
+			// `last_enacted` starts at 1 when there are no logs to replay.
+			last_enacted_record: self.last_enacted.load(Ordering::SeqCst).min(current_record),
+			current_record,
+			workers_alive,
+		}
+	}
+
 	fn collect_stats(&self, writer: &mut impl std::io::Write, column: Option<u8>) {
 		if let Some(col) = column {
-			self.columns[col as usize].write_stats(writer);
+			match self.column(col) {
+				Ok(column) => column.write_stats(writer),
+				Err(e) => log::warn!(target: "axia-db", "Error writing stats: {}", e),
+			}
 		} else {
 			for c in self.columns.iter() {
 				c.write_stats(writer);
@@ -689,43 +2139,289 @@ impl DbInner {
 
 	fn clear_stats(&self, column: Option<u8>) {
 		if let Some(col) = column {
-			self.columns[col as usize].clear_stats();
+			match self.column(col) {
+				Ok(column) => column.clear_stats(),
+				Err(e) => log::warn!(target: "axia-db", "Error clearing stats: {}", e),
+			}
 		} else {
 			for c in self.columns.iter() {
 				c.clear_stats();
 			}
+			self.clear_db_stats();
 		}
 	}
 
+	// Reset the statistics that don't belong to a column.
+	fn clear_db_stats(&self) {
+		self.commit_count.store(0, Ordering::Relaxed);
+		self.logged_bytes.store(0, Ordering::Relaxed);
+		self.stalls.clear();
+	}
+
 	fn store_err(&self, result: Result<()>) {
 		if let Err(e) = result {
 			log::warn!(target: "axia-db", "Background worker error: {}", e);
-			let mut err =  self.bg_err.lock();
-			if err.is_none() {
-				*err = Some(Arc::new(e));
-				self.shutdown();
+			// `capture` is a no-op unless enabled with `RUST_BACKTRACE`.
+			let backtrace = std::backtrace::Backtrace::capture();
+			let e = if backtrace.status() == std::backtrace::BacktraceStatus::Captured {
+				Error::WithBacktrace { source: Box::new(e), backtrace }
+			} else {
+				e
+			};
+			let first = {
+				let mut err = self.bg_err.lock();
+				let first = err.is_none();
+				if first {
+					*err = Some(Arc::new(e));
+				}
+				first
+			};
+			// Not called with `bg_err` locked. Waiting committers check it while holding
+			// the commit queue lock, which `shutdown` takes to wake them.
+			if first {
+				self.shutdown();
+			}
+			// Don't keep other databases waiting for commits that won't be enacted.
+			for barrier in self.barriers.lock().values().chain(self.queued_barriers.lock().values()) {
+				barrier.abort();
+			}
+		}
+	}
+
+	fn iter_column_while(&self, c: ColId, policy: IterSnapshotPolicy, f: impl FnMut(IterState) -> bool) -> Result<()> {
+		let column = self.readable_column(c)?;
+		let _enact = match policy {
+			IterSnapshotPolicy::CommitOverlayIncluded => None,
+			IterSnapshotPolicy::DiskOnly => Some(self.enact_lock.read()),
+		};
+		column.iter_with_policy_while(&self.log, policy, f)
+	}
+
+	fn par_iter(&self, c: ColId, f: impl Fn(Key, Value) + Sync) -> Result<()> {
+		let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+		let name = format!("{}-iter", self.options.thread_config.name_prefix);
+		self.readable_column(c)?.par_iter(&self.log, threads, &name, |state| f(state.key, state.value))
+	}
+
+	fn content_hash(&self, col: Option<ColId>) -> Result<[u8; 32]> {
+		let cols: Vec<ColId> = match col {
+			Some(c) => vec![c],
+			None => (0 .. self.columns.len()).map(|c| c as ColId).collect(),
+		};
+		let mut hasher = blake2_rfc::blake2b::Blake2b::new(32);
+		for c in cols {
+			let column = self.readable_column(c)?;
+			// Entries in old index tables are not iterated.
+			let check_reindex = || if column.is_reindexing() {
+				Err(Error::InvalidInput(format!("Column {} is being reindexed", c)))
+			} else {
+				Ok(())
+			};
+			check_reindex()?;
+			let mut entries = Vec::new();
+			column.iter_while(&self.log, |state| {
+				let mut entry = blake2_rfc::blake2b::Blake2b::new(32);
+				entry.update(&state.rc.to_le_bytes());
+				entry.update(&state.value);
+				let mut digest = [0u8; 32];
+				digest.copy_from_slice(entry.finalize().as_bytes());
+				entries.push((state.key, digest));
+				true
+			})?;
+			check_reindex()?;
+			// Index order depends on the table layout, key order does not.
+			entries.sort_unstable();
+			hasher.update(&[c]);
+			hasher.update(&(entries.len() as u64).to_le_bytes());
+			for (key, digest) in entries {
+				hasher.update(&key);
+				hasher.update(&digest);
+			}
+		}
+		let mut hash = [0u8; 32];
+		hash.copy_from_slice(hasher.finalize().as_bytes());
+		Ok(hash)
+	}
+
+	fn iter_range(
+		&self,
+		col: ColId,
+		start: Option<&[u8]>,
+		end: Option<&[u8]>,
+		mut f: impl FnMut(&[u8], Value) -> bool,
+	) -> Result<()> {
+		let column = self.readable_column(col)?;
+		if !column.is_ordered() {
+			return Err(Error::InvalidInput(format!("Column {} is not ordered", col)));
+		}
+		let in_range = |k: &[u8]| start.is_none_or(|s| k >= s) && end.is_none_or(|e| k < e);
+		let mut keys = BTreeSet::new();
+		// Queued keys are added to the index only when the commit is processed.
+		// Check the queue first, so that keys moving to the index are not missed.
+		for (_, value) in self.commit_overlay.read()[col as usize].values() {
+			if let Some(value) = value {
+				let key = Column::original_key(value)?;
+				if in_range(key) {
+					keys.insert(key.to_vec());
+				}
+			}
+		}
+		column.ordered_keys_in(start, end, &mut keys);
+		// Removed keys are still listed until the removal is processed.
+		for key in keys {
+			if let Some(value) = self.get(col, &key)? {
+				if !f(&key, value) {
+					break;
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+// File locks may not be enforced, e.g. on some NFS mounts. As a fallback, the lock
+// file holds the id of the process that has the database open. The check and the
+// write are not atomic, so this only catches some cases of concurrent opening.
+fn check_lock_pid(lock_file: &std::fs::File) -> Result<()> {
+	use std::io::{Read, Seek, Write};
+	let mut file = lock_file;
+	// The process id is only informational, the file is overwritten if it can't be read.
+	let mut content = Vec::new();
+	if let Err(e) = file.read_to_end(&mut content) {
+		log::warn!(target: "axia-db", "Error reading lock file: {:?}", e);
+	}
+	if let Ok(pid) = String::from_utf8_lossy(&content).trim().parse::<u32>() {
+		if pid != std::process::id() && process_running(pid) {
+			return Err(Error::AlreadyOpen { pid });
+		}
+	}
+	file.set_len(0)?;
+	file.seek(std::io::SeekFrom::Start(0))?;
+	writeln!(file, "{}", std::process::id())?;
+	Ok(())
+}
+
+// Remove temporary files left over by writes that were interrupted before the file was
+// renamed into place. They are never read, so failing to remove them is not an error.
+fn remove_temp_files(path: &std::path::Path) {
+	let entries = match std::fs::read_dir(path) {
+		Ok(entries) => entries,
+		Err(e) => {
+			log::warn!(target: "axia-db", "Error listing {}: {:?}", path.display(), e);
+			return;
+		}
+	};
+	for entry in entries.flatten() {
+		let name = entry.file_name();
+		let name = match name.to_str() {
+			Some(name) => name,
+			None => continue,
+		};
+		let temp = name == "metadata.tmp"
+			|| name == format!("{}.tmp", STATS_FILE)
+			|| prepared::is_temp_file_name(name)
+			|| crate::column::is_ordered_keys_temp_file_name(name);
+		if temp {
+			log::warn!(target: "axia-db", "Removing leftover temporary file {}", name);
+			if let Err(e) = std::fs::remove_file(entry.path()) {
+				log::warn!(target: "axia-db", "Error removing {}: {:?}", name, e);
 			}
-			self.commit_queue_full_cv.notify_one();
 		}
 	}
+}
+
+// Clear the process id on close, so that it is not mistaken for a running process later.
+fn clear_lock_pid(lock_file: &std::fs::File) {
+	if let Err(e) = lock_file.set_len(0) {
+		log::warn!(target: "axia-db", "Error clearing lock file: {:?}", e);
+	}
+}
+
+#[cfg(target_os = "linux")]
+fn process_running(pid: u32) -> bool {
+	std::path::Path::new(&format!("/proc/{}", pid)).exists()
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn process_running(pid: u32) -> bool {
+	// Signal 0 only checks that the process exists.
+	let pid = match libc::pid_t::try_from(pid) {
+		Ok(pid) => pid,
+		Err(_) => return false,
+	};
+	unsafe { libc::kill(pid, 0) == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM) }
+}
+
+#[cfg(not(unix))]
+fn process_running(_pid: u32) -> bool {
+	false
+}
+
+// Niceness applies to the calling thread on Linux. Not supported elsewhere.
+#[cfg(target_os = "linux")]
+fn set_thread_nice(nice: i32) {
+	// Safety: only changes the priority of the calling thread.
+	if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+		log::warn!(target: "axia-db", "Error setting worker niceness: {}", std::io::Error::last_os_error());
+	}
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_thread_nice(_nice: i32) {
+	log::warn!(target: "axia-db", "Worker niceness is not supported on this platform");
+}
+
+// Background worker threads of a `Db`.
+#[derive(Default)]
+struct Workers {
+	commit: Option<std::thread::JoinHandle<()>>,
+	flush: Option<std::thread::JoinHandle<()>>,
+	log: Option<std::thread::JoinHandle<()>>,
+	cleanup: Option<std::thread::JoinHandle<()>>,
+	prefetch: Vec<std::thread::JoinHandle<()>>,
+}
 
-	fn iter_column_while(&self, c: ColId, f: impl FnMut(IterState) -> bool) -> Result<()> {
-		self.columns[c as usize].iter_while(&self.log, f)
+impl Workers {
+	// Waits for all workers to exit. The database must be shut down first.
+	fn join(&mut self) {
+		self.log.take().map(|t| t.join());
+		self.flush.take().map(|t| t.join());
+		self.commit.take().map(|t| t.join());
+		self.cleanup.take().map(|t| t.join());
+		for t in self.prefetch.drain(..) {
+			let _ = t.join();
+		}
 	}
 }
 
 pub struct Db {
 	inner: Arc<DbInner>,
-	commit_thread: Option<std::thread::JoinHandle<()>>,
-	flush_thread: Option<std::thread::JoinHandle<()>>,
-	log_thread: Option<std::thread::JoinHandle<()>>,
-	cleanup_thread: Option<std::thread::JoinHandle<()>>,
+	workers: Mutex<Workers>,
 	do_drop: bool,
 }
 
+impl std::fmt::Debug for Db {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		self.inner.debug_fields("Db", f)
+	}
+}
+
 impl Db {
-	pub fn with_columns(path: &std::path::Path, num_columns: u8) -> Result<Db> {
-		let options = Options::with_columns(path, num_columns);
+	pub fn with_columns(path: impl AsRef<std::path::Path>, num_columns: u8) -> Result<Db> {
+		Self::with_column_configs(path, &vec![ColumnOptions::default(); num_columns as usize])
+	}
+
+	/// Open or create the database at `path` with one column for each entry of `columns`.
+	/// Other options are the same as for `with_columns`.
+	pub fn with_column_configs(path: impl AsRef<std::path::Path>, columns: &[ColumnOptions]) -> Result<Db> {
+		if columns.len() > u8::MAX as usize {
+			return Err(Error::InvalidConfiguration(format!("Too many columns: {}", columns.len())));
+		}
+		let mut options = Options::with_columns(path, 0);
+		options.columns = columns.to_vec();
+		if !options.is_valid() {
+			return Err(Error::InvalidConfiguration("Invalid column options".into()));
+		}
 		let mut inner_options = InternalOptions::default();
 		inner_options.create = true;
 		Self::open_inner(&options, &inner_options)
@@ -747,6 +2443,16 @@ impl Db {
 			.map(|r| r.0)
 	}
 
+	/// Open the database for maintenance. No background workers are started, commits
+	/// are written and enacted by the committing thread before `commit` returns.
+	/// Maintenance operations such as `clear_column` are only allowed in this mode.
+	/// Coordinated commits are not supported.
+	pub fn open_exclusive(options: &Options) -> Result<Db> {
+		let inner_options = InternalOptions { exclusive: true, ..Default::default() };
+		Self::open_inner(options, &inner_options)
+			.map(|r| r.0)
+	}
+
 	pub fn open_read_only(options: &Options) -> Result<Db> {
 		let mut inner_options = InternalOptions::default();
 		inner_options.read_only = true;
@@ -754,7 +2460,7 @@ impl Db {
 			.map(|r| r.0)
 	}
 
-	fn open_inner(
+	pub(crate) fn open_inner(
 		options: &Options,
 		inner_options: &InternalOptions,
 	) -> Result<(Db, Option<Arc<WaitCondvar<bool>>>)> {
@@ -762,79 +2468,137 @@ impl Db {
 		let mut db = DbInner::open(options, &inner_options)?;
 		// This needs to be call before log thread: so first reindexing
 		// will run in correct state.
-		db.replay_all_logs()?;
-		let db = Arc::new(db);
-		if inner_options.read_only {
-			return Ok((Db {
-				inner: db,
-				commit_thread: None,
-				flush_thread: None,
-				log_thread: None,
-				cleanup_thread: None,
-				do_drop: inner_options.commit_stages.do_drop(),
-			}, None))
+		db.recovered = db.replay_all_logs()?;
+		let inner = Arc::new(db);
+		// Threads started so far are stopped when `db` is dropped on error.
+		let db = Db {
+			inner: inner.clone(),
+			workers: Mutex::new(Workers { prefetch: Vec::with_capacity(PREFETCH_THREADS), ..Default::default() }),
+			// Queued commits are written on drop whichever stages run in the background.
+			do_drop: true,
+		};
+		for i in 0 .. PREFETCH_THREADS {
+			let prefetch_worker_db = inner.clone();
+			db.workers.lock().prefetch.push(Self::spawn_worker(&options.thread_config, &format!("prefetch-{}", i), move ||
+				Self::prefetch_worker(prefetch_worker_db)
+			)?);
+		}
+		if inner_options.read_only || inner_options.exclusive {
+			return Ok((db, None))
 		}
 		let run_test_cv = match inner_options.commit_stages {
-			EnableCommitPipelineStages::LogOverlay => Some(db.flush_worker_wait.clone()),
-			EnableCommitPipelineStages::DbFile => Some(db.commit_worker_wait.clone()),
+			EnableCommitPipelineStages::LogOverlay => Some(inner.flush_worker_wait.clone()),
+			EnableCommitPipelineStages::DbFile => Some(inner.commit_worker_wait.clone()),
 			_ => None,
 		};
-		let commit_thread = if inner_options.commit_stages.spawn_commit_thread() {
-			let commit_worker_db = db.clone();
-			Some(std::thread::spawn(move ||
+		if inner_options.commit_stages.spawn_commit_thread() {
+			let commit_worker_db = inner.clone();
+			db.workers.lock().commit = Some(Self::spawn_worker(&options.thread_config, "commit", move ||
 				commit_worker_db.store_err(Self::commit_worker(commit_worker_db.clone()))
-			))
-		} else {
-			None
-		};
-		let flush_thread = if inner_options.commit_stages.spawn_flush_thread() {
-			let flush_worker_db = db.clone();
+			)?);
+		}
+		if inner_options.commit_stages.spawn_flush_thread() {
+			let flush_worker_db = inner.clone();
 			let min_log_size = if matches!(inner_options.commit_stages, EnableCommitPipelineStages::DbFile) {
 				0
 			} else {
 				MIN_LOG_SIZE
 			};
-			Some(std::thread::spawn(move ||
+			db.workers.lock().flush = Some(Self::spawn_worker(&options.thread_config, "flush", move ||
 				flush_worker_db.store_err(Self::flush_worker(flush_worker_db.clone(), min_log_size))
-			))
-		} else {
-			None
-		};
-		let log_thread = if inner_options.commit_stages.spawn_log_thread() {
-			let log_worker_db = db.clone();
-			Some(std::thread::spawn(move ||
+			)?);
+		}
+		if inner_options.commit_stages.spawn_log_thread() {
+			let log_worker_db = inner.clone();
+			db.workers.lock().log = Some(Self::spawn_worker(&options.thread_config, "log", move ||
 				log_worker_db.store_err(Self::log_worker(log_worker_db.clone()))
-			))
-		} else {
-			None
-		};
-		let cleanup_thread = if inner_options.commit_stages.spawn_cleanup_thread() {
-			let cleanup_worker_db = db.clone();
-			Some(std::thread::spawn(move ||
+			)?);
+		}
+		if inner_options.commit_stages.spawn_cleanup_thread() {
+			let cleanup_worker_db = inner.clone();
+			db.workers.lock().cleanup = Some(Self::spawn_worker(&options.thread_config, "cleanup", move ||
 				cleanup_worker_db.store_err(Self::cleanup_worker(cleanup_worker_db.clone()))
-			))
-		} else {
-			None
-		};
-		Ok((Db {
-			inner: db,
-			commit_thread,
-			flush_thread: flush_thread,
-			log_thread: log_thread,
-			cleanup_thread: cleanup_thread,
-			do_drop: inner_options.commit_stages.do_drop(),
-		}, run_test_cv))
+			)?);
+		}
+		Ok((db, run_test_cv))
+	}
+
+	pub(crate) fn spawn_worker(
+		config: &ThreadConfig,
+		name: &str,
+		f: impl FnOnce() + Send + 'static,
+	) -> Result<std::thread::JoinHandle<()>> {
+		#[cfg(test)]
+		tests::FAIL_SPAWN_AFTER.with(|n| match n.get() {
+			Some(0) => Err(Error::ThreadSpawn(std::io::ErrorKind::WouldBlock.into())),
+			Some(left) => {
+				n.set(Some(left - 1));
+				Ok(())
+			},
+			None => Ok(()),
+		})?;
+		let nice = config.nice;
+		let handle = std::thread::Builder::new()
+			.name(format!("{}-{}", config.name_prefix, name))
+			.spawn(move || {
+				if let Some(nice) = nice {
+					set_thread_nice(nice);
+				}
+				f()
+			})
+			.map_err(Error::ThreadSpawn)?;
+		Ok(handle)
 	}
 
 	pub fn get(&self, col: ColId, key: &[u8]) -> Result<Option<Value>> {
+		let _read = self.inner.reads.enter()?;
 		self.inner.get(col, key)
 	}
 
+	/// Hint that `keys` will be read from column `col` soon. Index and value table pages
+	/// for the keys are loaded into the page cache in the background, so that subsequent
+	/// `get` calls don't wait for the disk. Returns immediately; hints are dropped when
+	/// too many are pending.
+	pub fn prefetch(&self, col: ColId, keys: &[&[u8]]) -> Result<()> {
+		self.inner.prefetch(col, keys)
+	}
+
+	/// Same as `get`, but reads the value into `value`, reusing its allocation. Returns
+	/// `false` and leaves `value` in an unspecified state if the key is not found.
+	pub fn get_into(&self, col: ColId, key: &[u8], value: &mut Vec<u8>) -> Result<bool> {
+		let _read = self.inner.reads.enter()?;
+		self.inner.get_into(col, key, value)
+	}
+
+	/// Same as `get`, but the value is read into a buffer taken from `pool`. The buffer
+	/// goes back to the pool when the returned value is dropped.
+	pub fn get_with_pool(&self, col: ColId, key: &[u8], pool: &mut BufferPool) -> Result<Option<PooledValue>> {
+		let _read = self.inner.reads.enter()?;
+		let mut value = pool.take();
+		Ok(if self.inner.get_into(col, key, value.buffer())? { Some(value) } else { None })
+	}
+
+	/// Query the same key in several columns at once. Values are returned in the
+	/// order of `cols`. The commit overlay is only locked once for all columns.
+	pub fn get_across(&self, cols: &[ColId], key: &[u8]) -> Result<Vec<Option<Value>>> {
+		let _read = self.inner.reads.enter()?;
+		self.inner.get_across(cols, key)
+	}
+
 	pub fn get_size(&self, col: ColId, key: &[u8]) -> Result<Option<u32>> {
+		let _read = self.inner.reads.enter()?;
 		self.inner.get_size(col, key)
 	}
 
-	pub fn commit<I, K>(&self, tx: I) -> Result<()>
+	/// Commit a set of changes to the database.
+	/// `None` removes the key, while an empty value is stored and read back as such.
+	/// Returns `Error::DatabaseShutdown` once the database has started shutting down.
+	///
+	/// Returns the commit id. Commit ids start at 1 when the database is opened and
+	/// increase by one with each commit, in the order commits are applied. They are
+	/// unrelated to log record ids, which are used by `record_meta`, `log_files` and
+	/// `DbStats::last_enacted`, and also count records written for reindexing.
+	pub fn commit<I, K>(&self, tx: I) -> Result<u64>
 	where
 		I: IntoIterator<Item=(ColId, K, Option<Value>)>,
 		K: AsRef<[u8]>,
@@ -842,26 +2606,476 @@ impl Db {
 		self.inner.commit(tx)
 	}
 
-	pub(crate) fn commit_raw(&self, commit: Vec<(ColId, Key, Option<Value>)>) -> Result<()> {
+	/// Same as `commit`, but values may be passed as anything that converts into `Vec<u8>`,
+	/// e.g. `&[u8]`, so borrowed data needs no explicit copy.
+	pub fn commit_values<I, K, V>(&self, tx: I) -> Result<u64>
+	where
+		I: IntoIterator<Item=(ColId, K, Option<V>)>,
+		K: AsRef<[u8]>,
+		V: Into<Value>,
+	{
+		self.inner.commit(tx)
+	}
+
+	/// Commit changes built with `CommitBuilder`. Same as `commit`, but the changes are
+	/// taken as they are, without going through an iterator.
+	pub fn submit_commit(&self, commit: Commit) -> Result<()> {
+		self.inner.commit(commit.changes).map(|_| ())
+	}
+
+	/// Insert a single value. Shorthand for `commit` with a single insertion.
+	#[inline]
+	pub fn put(&self, col: ColId, key: impl AsRef<[u8]>, value: impl Into<Vec<u8>>) -> Result<()> {
+		self.commit(std::iter::once((col, key, Some(value.into())))).map(|_| ())
+	}
+
+	/// Remove a single value. Shorthand for `commit` with a single deletion.
+	#[inline]
+	pub fn delete(&self, col: ColId, key: impl AsRef<[u8]>) -> Result<()> {
+		self.commit(std::iter::once((col, key, None))).map(|_| ())
+	}
+
+	/// Same as `commit`, but only returns once the changes are written to the log file,
+	/// so that they are recovered after a crash. The log is synced to disk only if
+	/// `Options::sync_wal` is set. Commits queued earlier are written first.
+	/// Returns the commit id.
+	pub fn commit_durable<I, K, V>(&self, tx: I) -> Result<u64>
+	where
+		I: IntoIterator<Item=(ColId, K, Option<V>)>,
+		K: AsRef<[u8]>,
+		V: Into<Value>,
+	{
+		self.inner.commit_durable(tx)
+	}
+
+	/// Same as `commit`, but the changes are not added to the in-memory commit overlay,
+	/// which saves memory and allocations for very large commits, such as batch deletions
+	/// of maintenance jobs. Returns the commit id.
+	///
+	/// **The changes are not visible to reads until the commit has been written to the
+	/// log by the background worker.** Until then, reads of the changed keys return the
+	/// previous values, so this must not be used by code that reads its own writes.
+	/// Commits made later with `commit` are always visible over this one.
+	pub fn commit_no_overlay<I, K, V>(&self, tx: I) -> Result<u64>
+	where
+		I: IntoIterator<Item=(ColId, K, Option<V>)>,
+		K: AsRef<[u8]>,
+		V: Into<Value>,
+	{
+		self.inner.commit_no_overlay(tx)
+	}
+
+	pub(crate) fn commit_raw(&self, commit: Vec<(ColId, Key, Option<Value>)>) -> Result<u64> {
 		self.inner.commit_raw(commit)
 	}
 
-	pub fn num_columns(&self) -> u8 {
-		self.inner.columns.len() as u8
+	/// First phase of a two-phase commit. The changes are written to disk, so that they
+	/// survive a crash, but are not applied or visible until `commit_prepared` is called.
+	/// A coordinator that commits to several databases prepares the changes in all of
+	/// them first, then commits or rolls back the returned tokens.
+	pub fn prepare<I, K, V>(&self, tx: I) -> Result<PreparedToken>
+	where
+		I: IntoIterator<Item=(ColId, K, Option<V>)>,
+		K: AsRef<[u8]>,
+		V: Into<Value>,
+	{
+		self.inner.prepare(tx).map(PreparedToken)
+	}
+
+	/// Apply a prepared changeset. Returns once the changes are written to the log and
+	/// synced to disk, even if `Options::sync_wal` is not set, since the changeset
+	/// is removed afterwards. The changes are applied exactly once, also when the
+	/// process crashes before this returns: after reopening, the changeset is either
+	/// still pending or already committed. Must not be called concurrently for the
+	/// same token.
+	pub fn commit_prepared(&self, token: PreparedToken) -> Result<()> {
+		self.inner.commit_prepared(token.0)
+	}
+
+	/// Discard a prepared changeset.
+	pub fn rollback_prepared(&self, token: PreparedToken) -> Result<()> {
+		self.inner.rollback_prepared(token.0)
+	}
+
+	/// Changesets that were prepared but neither committed nor rolled back, including
+	/// those left from before the database was reopened.
+	pub fn pending_prepared(&self) -> Result<Vec<PreparedToken>> {
+		Ok(prepared::list(&self.inner.options.path)?.into_iter().map(PreparedToken).collect())
+	}
+
+	/// Commit several batches as a single atomic commit, written with one log record.
+	/// Changes within a batch keep their order. If several batches change the same key
+	/// in the same column, only the change from the last of these batches in `batches`
+	/// is applied; changes of earlier batches to that key are dropped, which also
+	/// applies to reference counted columns. Returns the commit id.
+	pub fn multi_commit(&self, batches: Vec<PendingCommit>) -> Result<u64> {
+		let mut hashed = batches.into_iter()
+			.map(|batch| self.inner.hash_commit(batch.changes))
+			.collect::<Result<Vec<_>>>()?;
+		// Later batches win.
+		let mut seen = std::collections::HashSet::new();
+		for batch in hashed.iter_mut().rev() {
+			batch.retain(|(c, k, _)| !seen.contains(&(*c, *k)));
+			seen.extend(batch.iter().map(|(c, k, _)| (*c, *k)));
+		}
+		self.inner.commit_raw(hashed.into_iter().flatten().collect())
+	}
+
+	/// Commit each batch as a separate commit, with its own commit id and log record.
+	/// Unlike `multi_commit`, batches are not atomic as a whole. All of them are queued
+	/// together, in order, so no other commit is queued in between. Returns a token per
+	/// batch, in the same order, that can be passed to `wait_durable`. If any batch is
+	/// invalid, nothing is committed.
+	pub fn commit_each(&self, batches: Vec<PendingCommit>) -> Result<Vec<CommitToken>> {
+		let hashed = batches.into_iter()
+			.map(|batch| self.inner.hash_commit(batch.changes))
+			.collect::<Result<Vec<_>>>()?;
+		self.inner.commit_each(hashed)
+	}
+
+	/// Wait until a commit queued with `commit_each` is written to the log file, the
+	/// same as `commit_durable` does. Returns immediately if it already is.
+	pub fn wait_durable(&self, token: &CommitToken) -> Result<()> {
+		self.inner.wait_durable(&token.barrier)
+	}
+
+	/// Same as `commit`, but attaches `meta` to the log record written for the commit.
+	/// `meta` is at most `MAX_RECORD_META` bytes. It is not interpreted by the database.
+	/// Returns the commit id.
+	pub fn commit_with_meta<I, K, V>(&self, tx: I, meta: Vec<u8>) -> Result<u64>
+	where
+		I: IntoIterator<Item=(ColId, K, Option<V>)>,
+		K: AsRef<[u8]>,
+		V: Into<Value>,
+	{
+		let commit = self.inner.hash_commit(tx)?;
+		self.inner.commit_raw_with_meta(commit, Some(meta))
+	}
+
+	/// Metadata attached to a log record with `commit_with_meta`. Returns `None` if the
+	/// record has no metadata, or is too old to be retained. Metadata of records
+	/// replayed on open is restored.
+	pub fn record_meta(&self, record_id: u64) -> Option<Vec<u8>> {
+		self.inner.record_meta.lock().get(&record_id).cloned()
+	}
+
+	/// Ids of the retained records that have metadata, in ascending order.
+	pub fn records_with_meta(&self) -> Vec<u64> {
+		self.inner.record_meta.lock().keys().copied().collect()
+	}
+
+	/// Register a function to be called when the database is dropped, before
+	/// the final commits are written. The function may still commit to the
+	/// database. Hooks run in reverse order of registration.
+	pub fn add_shutdown_hook(&self, f: impl FnOnce(&Db) + Send + 'static) {
+		self.inner.shutdown_hooks.lock().push(Box::new(f));
+	}
+
+	/// Close the database without a clean shutdown, as if the process was killed. Background
+	/// workers are stopped, but logged commits are not applied to the tables and shutdown
+	/// hooks don't run. Commits still in the queue may or may not be written. Meant for
+	/// testing durability: everything acknowledged by `commit_durable` must be present
+	/// after the database is reopened.
+	pub fn forget(mut self) {
+		self.do_drop = false;
+		self.inner.shutdown_hooks.lock().clear();
+		self.inner.shutdown();
+		self.workers.get_mut().join();
+	}
+
+	/// Shut the database down cleanly without dropping it. Shutdown hooks run first, then
+	/// new reads fail with `Error::Closed` and reads already in progress are waited for.
+	/// After that, background workers are stopped and queued commits are written, as on
+	/// drop. Commits fail with `Error::DatabaseShutdown` afterwards. Calling this again
+	/// does nothing.
+	pub fn close(&self) -> Result<()> {
+		self.run_shutdown_hooks();
+		self.close_inner()
+	}
+
+	fn run_shutdown_hooks(&self) {
+		// Hooks are taken out so that the lock is not held while they run.
+		let hooks = std::mem::take(&mut *self.inner.shutdown_hooks.lock());
+		for hook in hooks.into_iter().rev() {
+			hook(self);
+		}
+	}
+
+	fn close_inner(&self) -> Result<()> {
+		if !self.inner.reads.close() {
+			return Ok(());
+		}
+		self.inner.shutdown();
+		self.workers.lock().join();
+		self.inner.kill_logs()
+	}
+
+	/// Open the database, running only the given pipeline stages in the background.
+	/// Stages that don't run in the background are run on drop, so that queued commits
+	/// are still written.
+	#[cfg(any(test, feature = "testing"))]
+	pub fn open_with_stages(options: &Options, stages: EnableCommitPipelineStages) -> Result<Db> {
+		let inner_options = InternalOptions {
+			create: true,
+			commit_stages: stages,
+			..Default::default()
+		};
+		Self::open_for_testing(options, &inner_options)
+	}
+
+	#[cfg(any(test, feature = "testing"))]
+	pub(crate) fn open_for_testing(options: &Options, inner_options: &InternalOptions) -> Result<Db> {
+		// Lets the whole test suite run with paranoid checks.
+		if std::env::var_os("AXIA_DB_PARANOID").is_some() {
+			let mut options = options.clone();
+			options.paranoid_checks = true;
+			return Self::open_inner(&options, inner_options).map(|(db, _)| db);
+		}
+		Self::open_inner(options, inner_options).map(|(db, _)| db)
+	}
+
+	/// Synchronously do the next piece of work of the pipeline stages that don't run in the
+	/// background. That is, in order: write a queued commit to the log, enact a log
+	/// record, flush the log, continue reindexing, or clean up a log file. Returns `false`
+	/// if there was nothing to do. Fails if all stages run in the background.
+	#[cfg(any(test, feature = "testing"))]
+	pub fn step(&self) -> Result<bool> {
+		let (log_thread, commit_thread) = {
+			let workers = self.workers.lock();
+			(workers.log.is_some(), workers.commit.is_some())
+		};
+		if log_thread && commit_thread {
+			return Err(Error::InvalidInput("All pipeline stages run in the background".into()));
+		}
+		let _lock = self.inner.pipeline_lock.lock();
+		if !log_thread && self.inner.process_commits()? {
+			return Ok(true);
+		}
+		if !commit_thread && self.inner.enact_logs(false)? {
+			return Ok(true);
+		}
+		if !log_thread {
+			// The log reader is done at this point, so flushing does not block.
+			let (flush_next, read_next, cleanup_next, sync_time) = self.inner.log.flush_one(0)?;
+			if let Some(sync_time) = sync_time {
+				self.inner.check_stall(StallReason::Fsync, sync_time);
+			}
+			if flush_next || read_next || cleanup_next || self.inner.process_reindex()? {
+				return Ok(true);
+			}
+		}
+		if !commit_thread && (self.inner.cleanup_logs()? || self.inner.persist_stats()) {
+			return Ok(true);
+		}
+		Ok(false)
+	}
+
+	/// Synchronously run the pipeline until everything queued reaches `stage`:
+	/// `CommitOverlay` does nothing, `LogOverlay` writes queued commits to the log,
+	/// `DbFile` also flushes the log, enacts it and finishes reindexing, and `Standard`
+	/// also cleans up enacted log files. Fails if any stage runs in the background.
+	#[cfg(any(test, feature = "testing"))]
+	pub fn advance_to(&self, stage: EnableCommitPipelineStages) -> Result<()> {
+		if self.workers.lock().log.is_some() || self.workers.lock().commit.is_some() {
+			return Err(Error::InvalidInput("Pipeline stages run in the background".into()));
+		}
+		let _lock = self.inner.pipeline_lock.lock();
+		if let EnableCommitPipelineStages::CommitOverlay = stage {
+			return Ok(());
+		}
+		while self.inner.process_commits()? {}
+		if let EnableCommitPipelineStages::LogOverlay = stage {
+			return Ok(());
+		}
+		loop {
+			while self.inner.flush_logs(0)? {}
+			while self.inner.enact_logs(false)? {}
+			if !self.inner.process_reindex()? {
+				break;
+			}
+		}
+		if let EnableCommitPipelineStages::Standard = stage {
+			self.inner.cleanup_logs()?;
+		}
+		Ok(())
+	}
+
+	/// Whether no commits to `col` are waiting to be written to the log.
+	#[cfg(any(test, feature = "testing"))]
+	pub fn commit_overlay_is_empty(&self, col: ColId) -> Result<bool> {
+		self.inner.column(col)?;
+		Ok(self.inner.commit_overlay.read()[col as usize].is_empty())
+	}
+
+	/// Whether all log records have been enacted.
+	#[cfg(any(test, feature = "testing"))]
+	pub fn log_overlay_is_empty(&self) -> bool {
+		self.inner.log.overlays().read().is_empty()
+	}
+
+	/// Start reindexing `col` into a twice larger index, as if its index was full. The
+	/// reindex is done by the pipeline as usual, e.g. with `advance_to` or `step`.
+	#[cfg(any(test, feature = "testing"))]
+	pub fn force_reindex(&self, col: ColId) -> Result<()> {
+		self.inner.force_reindex(col)
+	}
+
+	/// Flush all columns and clean all logs that have been enacted, regardless of
+	/// how many logs are normally kept. Reduces disk usage, e.g. before taking a snapshot.
+	pub fn compact_log(&self) -> Result<()> {
+		self.inner.clean_all_logs()
+	}
+
+	/// Flush the index and value files of all columns to disk now, regardless of
+	/// `Options::sync_data` and log cleanup. Only commits already written to the
+	/// tables are covered, queued commits and unenacted log records are not.
+	pub fn sync_data(&self) -> Result<()> {
+		self.inner.sync_data()
+	}
+
+	/// Log files that hold records, ordered by record id. Records are enacted in order,
+	/// so this shows how far enactment and log cleanup are behind the commits.
+	pub fn log_files(&self) -> Result<Vec<LogFileInfo>> {
+		Ok(self.inner.log.log_files())
+	}
+
+	/// Returns `true` if log records left by an unclean shutdown were replayed when opening the database.
+	pub fn was_recovered(&self) -> bool {
+		self.inner.recovered
+	}
+
+	pub fn num_columns(&self) -> usize {
+		self.inner.columns.len()
+	}
+
+	/// Returns `true` if the database was opened with `open_exclusive`.
+	pub fn is_exclusive(&self) -> bool {
+		self.inner.exclusive
+	}
+
+	/// Remove all values from the column. Values of reference counted columns are
+	/// removed regardless of their reference count. Requires exclusive mode.
+	pub fn clear_column(&self, col: ColId) -> Result<()> {
+		self.inner.require_exclusive()?;
+		self.inner.column(col)?;
+		// Entries that are still in old index tables are not iterated.
+		self.inner.run_pipeline()?;
+		let mut removals = Vec::new();
+		self.inner.iter_column_while(col, IterSnapshotPolicy::CommitOverlayIncluded, |state| {
+			for _ in 0 .. state.rc {
+				removals.push((col, state.key, None));
+			}
+			true
+		})?;
+		log::info!(target: "axia-db", "Clearing column {}, {} removals", col, removals.len());
+		while !removals.is_empty() {
+			let rest = removals.split_off(std::cmp::min(removals.len(), CLEAR_COLUMN_BATCH));
+			self.inner.commit_raw(std::mem::replace(&mut removals, rest))?;
+		}
+		Ok(())
+	}
+
+	#[cfg(test)]
+	pub(crate) fn process_commits(&self) -> Result<bool> {
+		self.inner.process_commits()
+	}
+
+	#[cfg(feature = "async")]
+	pub(crate) fn thread_config(&self) -> &ThreadConfig {
+		&self.inner.options.thread_config
+	}
+
+	/// Iterate over all entries of a column in index order, until `f` returns `false`.
+	/// Keys are hashed and commits still in the queue are not included. Original keys
+	/// of ordered columns are passed in `IterState::original_key`. Entries are read
+	/// in small batches and `f` is called without holding any locks. With
+	/// `IterSnapshotPolicy::CommitOverlayIncluded`, a slow `f` does not hold up commits,
+	/// but entries added or removed during the iteration may or may not be visited. With
+	/// `IterSnapshotPolicy::DiskOnly`, the entries form a consistent snapshot, but log
+	/// records are not enacted until the iteration ends.
+	pub fn iter_column_while(&self, c: ColId, policy: IterSnapshotPolicy, f: impl FnMut(IterState) -> bool) -> Result<()> {
+		let _read = self.inner.reads.enter()?;
+		self.inner.iter_column_while(c, policy, f)
+	}
+
+	/// Call `f` for every entry of a column, using one thread per available CPU.
+	/// Index chunks, or the value tables of append-only columns, are split between
+	/// the threads, so `f` is called concurrently and in unspecified order. Keys are
+	/// hashed as in `iter_column_while`. Values of reference counted columns are passed
+	/// once, regardless of their reference count. Commits still in the commit queue
+	/// are not included. Iteration stops at the first error.
+	pub fn par_iter(&self, col: ColId, f: impl Fn(Key, Value) + Sync) -> Result<()> {
+		let _read = self.inner.reads.enter()?;
+		self.inner.par_iter(col, f)
+	}
+
+	/// Iterate over the entries physically present in the value tables of a column,
+	/// without consulting the index. Meant for inspecting damaged databases, e.g. opened
+	/// with `open_read_only`. Values are returned as stored, i.e. still compressed or
+	/// encrypted, and prefixed with the original key for ordered columns. Removed values
+	/// are not included.
+	pub fn iter_values_raw(&self, col: ColId) -> Result<RawValueIter<'_>> {
+		if self.inner.reads.is_closed() {
+			return Err(Error::Closed);
+		}
+		Ok(self.inner.readable_column(col)?.iter_values_raw(&self.inner.log))
+	}
+
+	/// Digest of the contents of column `col`, or of all columns. The digest only
+	/// depends on the keys, values and reference counts, not on the table layout
+	/// or the order of insertion. Keys are hashed with the database salt, so
+	/// databases can only be compared if they use the same salt, e.g. by setting
+	/// `Options::salt`. Commits still in the commit queue are not included. Fails
+	/// while the column is being reindexed.
+	pub fn content_hash(&self, col: Option<ColId>) -> Result<[u8; 32]> {
+		let _read = self.inner.reads.enter()?;
+		self.inner.content_hash(col)
+	}
+
+	/// Iterate over the keys in `start .. end` of an ordered column in key order,
+	/// until `f` returns `false`. Bounds are unlimited when `None`. Keys in the range are
+	/// found in the sorted key index of the column, and only their values are read.
+	/// Commits made during the call may or may not be included.
+	pub fn iter_range(
+		&self,
+		col: ColId,
+		start: Option<&[u8]>,
+		end: Option<&[u8]>,
+		f: impl FnMut(&[u8], Value) -> bool,
+	) -> Result<()> {
+		let _read = self.inner.reads.enter()?;
+		self.inner.iter_range(col, start, end, f)
 	}
 
-	pub(crate) fn iter_column_while(&self, c: ColId, f: impl FnMut(IterState) -> bool) -> Result<()> {
-		self.inner.iter_column_while(c, f)
+	/// Iterate over the keys starting with `prefix` of an ordered column in key order,
+	/// until `f` returns `false`.
+	pub fn iter_prefix(&self, col: ColId, prefix: &[u8], f: impl FnMut(&[u8], Value) -> bool) -> Result<()> {
+		let _read = self.inner.reads.enter()?;
+		// The first key after all keys with the prefix. None if the prefix is all 0xff.
+		let mut end = prefix.to_vec();
+		while end.last() == Some(&0xff) {
+			end.pop();
+		}
+		if let Some(last) = end.last_mut() {
+			*last += 1;
+		}
+		let end = if end.is_empty() { None } else { Some(end.as_slice()) };
+		self.inner.iter_range(col, Some(prefix), end, f)
 	}
 
 	fn commit_worker(db: Arc<DbInner>) -> Result<()> {
 		let mut more_work = false;
+		let mut last_audit = std::time::Instant::now();
 		while !db.shutdown.load(Ordering::SeqCst) || more_work {
 			if !more_work {
 				db.commit_worker_wait.wait();
 			}
 
 			more_work = db.enact_logs(false)?;
+			if last_audit.elapsed() >= COMMIT_OVERLAY_AUDIT_INTERVAL {
+				db.audit_commit_overlay();
+				last_audit = std::time::Instant::now();
+			}
 		}
 		log::debug!(target: "axia-db", "Commit worker shutdown");
 		Ok(())
@@ -889,8 +3103,12 @@ impl Db {
 			if !more_work {
 				db.flush_worker_wait.wait();
 			}
+			let min_log_size = if db.force_flush.swap(false, Ordering::SeqCst) { 0 } else { min_log_size };
 			more_work = db.flush_logs(min_log_size)?;
 		}
+		// Don't leave records below the size threshold unflushed. The commit worker may
+		// already be gone, so this must not wait for the log reader.
+		db.log.sync_appending()?;
 		log::debug!(target: "axia-db", "Flush worker shutdown");
 		Ok(())
 	}
@@ -899,14 +3117,35 @@ impl Db {
 		let mut more_work = true;
 		while !db.shutdown.load(Ordering::SeqCst) || more_work {
 			if !more_work {
-				db.cleanup_worker_wait.wait();
+				match db.stats_write_delay() {
+					Some(delay) => db.cleanup_worker_wait.wait_timeout(delay),
+					None => db.cleanup_worker_wait.wait(),
+				}
 			}
 			more_work = db.cleanup_logs()?;
+			db.persist_stats();
 		}
 		log::debug!(target: "axia-db", "Cleanup worker shutdown");
 		Ok(())
 	}
 
+	fn prefetch_worker(db: Arc<DbInner>) {
+		while !db.shutdown.load(Ordering::SeqCst) {
+			while let Some((col, key)) = db.prefetch_queue.pop() {
+				if db.shutdown.load(Ordering::SeqCst) {
+					break;
+				}
+				if let Err(e) = db.columns[col as usize].prefetch(&key, db.log.overlays()) {
+					log::debug!(target: "axia-db", "Prefetch error: {:?}", e);
+				}
+			}
+			db.prefetch_worker_wait.wait();
+		}
+		// The shutdown signal wakes a single worker, pass it on to the rest.
+		db.prefetch_worker_wait.signal();
+		log::debug!(target: "axia-db", "Prefetch worker shutdown");
+	}
+
 	pub fn collect_stats(&self, writer: &mut impl std::io::Write, column: Option<u8>) {
 		self.inner.collect_stats(writer, column)
 	}
@@ -915,53 +3154,367 @@ impl Db {
 		self.inner.clear_stats(column)
 	}
 
-	pub fn check_from_index(&self, check_param: check::CheckOptions) -> Result<()> {
-		if let Some(col) = check_param.column.clone() {
-			self.inner.columns[col as usize].check_from_index(&self.inner.log, &check_param, col)?;
-		} else {
-			for (ix, c) in self.inner.columns.iter().enumerate() {
-				c.check_from_index(&self.inner.log, &check_param, ix as ColId)?;
-			}
-		}
-		Ok(())
+	/// Write the statistics of all columns to `path`, or to `stats.txt` in the database
+	/// directory, as is done on shutdown. The file is replaced atomically.
+	pub fn write_stats(&self, path: Option<&std::path::Path>) -> Result<()> {
+		self.inner.write_stats(path)
 	}
-}
 
-impl Drop for Db {
-	fn drop(&mut self) {
-		if self.do_drop {
-			self.inner.shutdown();
-			self.log_thread.take().map(|t| t.join());
-			self.flush_thread.take().map(|t| t.join());
-			self.commit_thread.take().map(|t| t.join());
-			self.cleanup_thread.take().map(|t| t.join());
-			if let Err(e) = self.inner.kill_logs() {
-				log::warn!(target: "axia-db", "Shutdown error: {:?}", e);
-			}
-		}
+	/// Structured snapshot of the database statistics.
+	pub fn stats(&self) -> DbStats {
+		self.inner.stats()
 	}
-}
 
-/// Verification operation utilities.
-pub mod check {
-	pub enum CheckDisplay {
-		None,
-		Full,
-		Short(u64),
+	/// Current state of the commit pipeline.
+	pub fn health(&self) -> DbHealth {
+		self.inner.health()
 	}
 
-	pub struct CheckOptions {
-		pub column: Option<u8>,
-		pub from: Option<u64>,
-		pub bound: Option<u64>,
-		pub display: CheckDisplay,
+	/// Progress of the commit pipeline and liveness of the background workers.
+	pub fn pipeline_status(&self) -> PipelineStatus {
+		let alive = |thread: &Option<std::thread::JoinHandle<()>>| thread.as_ref().is_some_and(|t| !t.is_finished());
+		let workers = self.workers.lock();
+		self.inner.pipeline_status([
+			alive(&workers.commit),
+			alive(&workers.flush),
+			alive(&workers.log),
+			alive(&workers.cleanup),
+		])
+	}
+
+	/// Returns `true` if the column has no entries. Much cheaper than iterating the
+	/// column, as it stops at the first entry found. Queued removals are not taken
+	/// into account, so a column that is being cleared may not be reported as empty
+	/// until the removals are written to the log.
+	pub fn column_is_empty(&self, col: ColId) -> Result<bool> {
+		self.inner.column_is_empty(col)
+	}
+
+	/// Index occupancy of a column, relative to the capacity of the index at
+	/// `ColumnOptions::target_load_factor`. Values close to 1.0 mean that the index
+	/// will soon be reindexed to a larger size. Entries still being moved by a
+	/// running reindex are included. Commits still in the commit queue are not.
+	/// Reads the whole index, so this is expensive for large columns.
+	pub fn load_factor(&self, col: ColId) -> Result<f64> {
+		self.inner.column(col)?.load_factor(&self.inner.log)
+	}
+
+	/// Number of entries in a column, as counted by the statistics saved in the index
+	/// metadata. Doesn't read the index, but requires `Options::stats` and does not
+	/// include changes made after the statistics were last saved with `collect_stats`
+	/// or on shutdown.
+	pub fn approximate_entry_count(&self, col: ColId) -> Result<u64> {
+		Ok(self.inner.column(col)?.approximate_on_disk_entry_count())
+	}
+
+	/// Key that `key` is stored under in column `col`. The position in the index is given
+	/// by its leading bits. For columns with `ColumnOptions::uniform` set, this is the
+	/// first 32 bytes of `key`, which must be at least that long. Otherwise it is the
+	/// 32-byte BLAKE2b hash of `key`, keyed with the database salt. The salt is chosen
+	/// at random when the database is created, unless set with `Options::salt`. The
+	/// mapping is part of the database format and never changes for a database.
+	pub fn hash_key(&self, col: ColId, key: &[u8]) -> Result<Key> {
+		let column = self.inner.column(col)?;
+		if self.inner.metadata.columns[col as usize].uniform && key.len() < 32 {
+			return Err(Error::InvalidInput(format!(
+				"Keys of uniform column {} must be at least 32 bytes, got {}",
+				col,
+				key.len(),
+			)));
+		}
+		Ok(column.hash(key))
+	}
+
+	/// Settings of a column, as stored in the database metadata. Settings that are
+	/// not stored, such as `max_queue_share`, are not set.
+	pub fn column_options(&self, col: ColId) -> Result<&ColumnOptions> {
+		self.inner.metadata.columns.get(col as usize).ok_or(Error::InvalidColumn(col))
+	}
+
+	/// Change what is allowed on a column at runtime, e.g. to take it offline during a
+	/// migration. Commits to a `ReadOnly` column fail with `Error::ColumnReadOnly`. Reads
+	/// and commits of an `Offline` column fail with `Error::ColumnOffline`. Once this
+	/// returns, no further commit to the column is accepted. Commits queued earlier are
+	/// still written. With `persist` set, the mode is stored in the metadata and restored
+	/// when the database is reopened, otherwise the stored mode is restored.
+	pub fn set_column_mode(&self, col: ColId, mode: ColumnMode, persist: bool) -> Result<()> {
+		self.inner.set_column_mode(col, mode, persist)
+	}
+
+	/// Current mode of a column.
+	pub fn column_mode(&self, col: ColId) -> Result<ColumnMode> {
+		self.inner.column_mode(col)
+	}
+
+	/// Metadata the database was opened with. The salt is not included.
+	pub fn metadata(&self) -> DbMetadata {
+		let metadata = &self.inner.metadata;
+		DbMetadata {
+			version: metadata.version,
+			columns: metadata.columns.clone(),
+			encrypted: metadata.encryption_check.is_some(),
+		}
+	}
+
+	pub fn check_from_index(&self, check_param: check::CheckOptions) -> Result<()> {
+		if let Some(col) = check_param.column.clone() {
+			self.inner.column(col)?.check_from_index(&self.inner.log, &check_param, col)?;
+		} else {
+			for (ix, c) in self.inner.columns.iter().enumerate() {
+				c.check_from_index(&self.inner.log, &check_param, ix as ColId)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+impl Drop for Db {
+	fn drop(&mut self) {
+		let pending = self.inner.commit_queue.bytes.load(Ordering::SeqCst);
+		if pending > 0 {
+			log::warn!(target: "axia-db", "Db dropped with {} bytes pending in commit queue", pending);
+		}
+		self.run_shutdown_hooks();
+		if self.do_drop {
+			if let Err(e) = self.close_inner() {
+				log::warn!(target: "axia-db", "Shutdown error: {:?}", e);
+			}
+		}
+	}
+}
+
+/// Identifies a changeset prepared with `Db::prepare`. Prepared ids are persistent and
+/// unrelated to commit ids.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PreparedToken(u64);
+
+impl PreparedToken {
+	/// Token for a changeset id, e.g. one persisted by a coordinator.
+	pub fn from_id(id: u64) -> Self {
+		PreparedToken(id)
+	}
+
+	pub fn id(&self) -> u64 {
+		self.0
+	}
+}
+
+/// Identifies a commit queued with `Db::commit_each`, to wait for it with
+/// `Db::wait_durable`.
+#[derive(Clone)]
+pub struct CommitToken {
+	id: u64,
+	barrier: Arc<CommitBarrier>,
+}
+
+impl CommitToken {
+	/// Commit id, as returned by `Db::commit`.
+	pub fn id(&self) -> u64 {
+		self.id
+	}
+}
+
+impl std::fmt::Debug for CommitToken {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct("CommitToken").field("id", &self.id).finish()
+	}
+}
+
+/// Changes built with `CommitBuilder`, to be committed with `Db::submit_commit`.
+#[derive(Default, Debug, Clone)]
+pub struct Commit {
+	changes: Vec<(ColId, Vec<u8>, Option<Value>)>,
+	bytes: usize,
+}
+
+impl Commit {
+	pub fn len(&self) -> usize {
+		self.changes.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.changes.is_empty()
+	}
+
+	/// Total size of the keys and values, as counted against the commit queue limits.
+	pub fn bytes(&self) -> usize {
+		self.bytes
+	}
+}
+
+/// Builds a `Commit`. Keys and values are moved into the commit, so owned data is
+/// not copied.
+#[derive(Default)]
+pub struct CommitBuilder {
+	commit: Commit,
+}
+
+impl CommitBuilder {
+	pub fn new() -> CommitBuilder {
+		Default::default()
+	}
+
+	pub fn with_capacity(changes: usize) -> CommitBuilder {
+		CommitBuilder { commit: Commit { changes: Vec::with_capacity(changes), bytes: 0 } }
+	}
+
+	/// Set `key` to `value`.
+	pub fn insert(self, col: ColId, key: impl Into<Vec<u8>>, value: impl Into<Value>) -> CommitBuilder {
+		self.change(col, key, Some(value.into()))
+	}
+
+	/// Remove `key`.
+	pub fn remove(self, col: ColId, key: impl Into<Vec<u8>>) -> CommitBuilder {
+		self.change(col, key, None)
+	}
+
+	/// Add a change. `None` removes the key.
+	pub fn change(mut self, col: ColId, key: impl Into<Vec<u8>>, value: Option<Value>) -> CommitBuilder {
+		let key = key.into();
+		self.commit.bytes += key.len() + value.as_ref().map_or(0, |v| v.len());
+		self.commit.changes.push((col, key, value));
+		self
+	}
+
+	pub fn finish(self) -> Commit {
+		self.commit
+	}
+}
+
+/// Changes prepared independently, to be committed together with `Db::multi_commit`.
+#[derive(Default, Debug, Clone)]
+pub struct PendingCommit {
+	changes: Vec<(ColId, Vec<u8>, Option<Value>)>,
+}
+
+impl PendingCommit {
+	pub fn new() -> Self {
+		Default::default()
+	}
+
+	/// Add a change. `None` removes the key.
+	pub fn add(&mut self, col: ColId, key: impl AsRef<[u8]>, value: Option<Value>) {
+		self.changes.push((col, key.as_ref().to_vec(), value));
+	}
+
+	pub fn len(&self) -> usize {
+		self.changes.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.changes.is_empty()
+	}
+}
+
+impl<K: AsRef<[u8]>> FromIterator<(ColId, K, Option<Value>)> for PendingCommit {
+	fn from_iter<I: IntoIterator<Item=(ColId, K, Option<Value>)>>(iter: I) -> Self {
+		PendingCommit {
+			changes: iter.into_iter().map(|(c, k, v)| (c, k.as_ref().to_vec(), v)).collect(),
+		}
+	}
+}
+
+/// A set of changes committed to several databases together.
+///
+/// Each database writes its part to its own log and enacts it only after every
+/// participating database has flushed its part to disk. Changes are queued in all
+/// databases at once, so coordinated commits are ordered the same way in every
+/// participant.
+///
+/// This is best-effort. Reads are not isolated: changes may become visible in one
+/// database before another. If the process crashes after some databases have
+/// flushed their log but before the others did, the changes are only recovered
+/// in the databases that have flushed. Waiting is also abandoned when a participant
+/// stops with an error or is shut down.
+#[derive(Default)]
+pub struct CoordinatedCommit<'a> {
+	parts: Vec<(&'a Db, ChangeSet)>,
+	// First error from `add`, returned by `commit`.
+	error: Option<Error>,
+}
+
+impl<'a> CoordinatedCommit<'a> {
+	pub fn new() -> Self {
+		Default::default()
+	}
+
+	/// Add changes for the given database. Invalid changes, e.g. with a column id
+	/// out of range, make `commit` fail without queueing anything.
+	pub fn add<I, K, V>(&mut self, db: &'a Db, tx: I)
+	where
+		I: IntoIterator<Item=(ColId, K, Option<V>)>,
+		K: AsRef<[u8]>,
+		V: Into<Value>,
+	{
+		let changes = match db.inner.hash_commit(tx) {
+			Ok(changes) => changes,
+			Err(e) => {
+				self.error.get_or_insert(e);
+				return;
+			}
+		};
+		if let Some((_, part)) = self.parts.iter_mut().find(|(d, _)| Arc::ptr_eq(&d.inner, &db.inner)) {
+			part.extend(changes);
+		} else {
+			self.parts.push((db, changes));
+		}
+	}
+
+	/// Queue the changes in all databases.
+	pub fn commit(mut self) -> Result<()> {
+		if let Some(e) = self.error {
+			return Err(e);
+		}
+		if self.parts.is_empty() {
+			return Ok(());
+		}
+		// Lock overlays in a fixed order to prevent deadlocks.
+		self.parts.sort_by_key(|(db, _)| Arc::as_ptr(&db.inner) as usize);
+		for (db, changes) in self.parts.iter() {
+			if db.inner.exclusive {
+				return Err(Error::InvalidInput("Coordinated commits are not supported in exclusive mode".into()));
+			}
+			db.inner.check_writable(changes)?;
+		}
+		let barrier = Arc::new(CommitBarrier::new(self.parts.len()));
+		for (db, changes) in self.parts.iter() {
+			db.inner.wait_commit_queue(changes)?;
+		}
+		let mut overlays: Vec<_> = self.parts.iter().map(|(db, _)| db.inner.commit_overlay.write()).collect();
+		for (db, changes) in self.parts.iter() {
+			db.inner.check_open()?;
+			db.inner.check_column_modes(changes)?;
+		}
+		for ((db, changes), overlay) in self.parts.into_iter().zip(overlays.iter_mut()) {
+			db.inner.enqueue_commit(overlay, changes, Some(barrier.clone()), Default::default(), true);
+		}
+		Ok(())
+	}
+}
+
+/// Verification operation utilities.
+pub mod check {
+	pub enum CheckDisplay {
+		None,
+		Full,
+		Short(u64),
+	}
+
+	pub struct CheckOptions {
+		pub column: Option<u8>,
+		/// Only check keys starting from this one. For columns with uniform keys this is
+		/// a key prefix, otherwise the key is hashed and the range applies to hashed keys.
+		pub from: Option<Vec<u8>>,
+		/// Only check keys below this one. Interpreted the same way as `from`.
+		pub bound: Option<Vec<u8>>,
+		pub display: CheckDisplay,
 	}
 
 	impl CheckOptions {
 		pub fn new(
 			column: Option<u8>,
-			from: Option<u64>,
-			bound: Option<u64>,
+			from: Option<Vec<u8>>,
+			bound: Option<Vec<u8>>,
 			display_content: bool,
 			truncate_value_display: Option<u64>,
 		) -> Self {
@@ -984,25 +3537,29 @@ pub mod check {
 }
 
 #[derive(Default)]
-struct InternalOptions {
-	create: bool,
-	read_only: bool,
-	commit_stages: EnableCommitPipelineStages,
-	skip_check_lock: bool,
+pub(crate) struct InternalOptions {
+	pub(crate) create: bool,
+	pub(crate) read_only: bool,
+	pub(crate) commit_stages: EnableCommitPipelineStages,
+	pub(crate) skip_check_lock: bool,
+	pub(crate) max_commit_queue_bytes: Option<usize>,
+	pub(crate) exclusive: bool,
 }
 
+/// Commit pipeline stages that run in background threads. Stages that don't run in the
+/// background are advanced with `Db::step`.
 #[derive(Debug, Clone, Copy)]
-enum EnableCommitPipelineStages {
-	// No threads started, data stays in commit overlay.
+pub enum EnableCommitPipelineStages {
+	/// No threads started, data stays in commit overlay.
 	#[allow(dead_code)]
 	CommitOverlay,
-	// Log worker run, data processed up to the log overlay.
+	/// Log worker run, data processed up to the log overlay.
 	#[allow(dead_code)]
 	LogOverlay,
-	// Runing all.
+	/// Runing all, logs are flushed regardless of size.
 	#[allow(dead_code)]
 	DbFile,
-	// Default run mode.
+	/// Default run mode.
 	Standard,
 }
 
@@ -1042,43 +3599,40 @@ impl EnableCommitPipelineStages {
 	#[cfg(test)]
 	fn check_empty_overlay(&self, db: &Db, col: ColId) -> bool {
 		match self {
-			EnableCommitPipelineStages::LogOverlay => {
-			 if let Some(overlay) = db.inner.commit_overlay.read().get(col as usize) {
-				if !overlay.is_empty() {
-					let mut replayed = 5;
-					while !overlay.is_empty() {
-						if replayed > 0 {
-							replayed -= 1;
-							// the signal is triggered just before cleaning the overlay, so
-							// we wait a bit.
-							std::thread::sleep(std::time::Duration::from_millis(10));
-						} else {
-							return false;
-						}
+			EnableCommitPipelineStages::LogOverlay | EnableCommitPipelineStages::DbFile => {
+				let is_empty = || db.inner.commit_overlay.read().get(col as usize).map_or(true, |o| o.is_empty());
+				let mut replayed = 100;
+				while !is_empty() {
+					if replayed > 0 {
+						replayed -= 1;
+						// The flush and commit workers are signalled before the log worker
+						// cleans the overlay, so we wait a bit. The overlay lock must not be
+						// held while waiting.
+						std::thread::sleep(std::time::Duration::from_millis(10));
+					} else {
+						return false;
 					}
 				}
-			 }
 			},
-			EnableCommitPipelineStages::DbFile => {
-				 if let Some(overlay) = db.inner.commit_overlay.read().get(col as usize) {
-					if !overlay.is_empty() { return false; }
-				 }
-			 }
 			_ => (),
 		}
 		true
 	}
-
-	fn do_drop(&self) -> bool {
-		matches!(self, EnableCommitPipelineStages::Standard)
-	}
 }
 
 #[cfg(test)]
 mod tests {
-	use super::{Db, Options, EnableCommitPipelineStages, InternalOptions};
+	use super::{Db, check, BufferPool, ByteSemaphore, CommitBuilder, CoordinatedCommit, PendingCommit, PipelineStatus, RecordInfo, prepared, Encryption, Error, Options, EnableCommitPipelineStages, InternalOptions, IterSnapshotPolicy, AtomicBool, Ordering, MAX_RECORD_META};
+	use crate::testing::DbBuilder;
+	use crate::CompressionType;
 	use tempfile::tempdir;
 
+	thread_local! {
+		// Number of worker threads that can be started on this thread before `spawn_worker`
+		// fails, if limited.
+		pub(super) static FAIL_SPAWN_AFTER: std::cell::Cell<Option<usize>> = std::cell::Cell::new(None);
+	}
+
 	#[test]
 	fn test_db_open_should_fail() {
 		let tmp = tempdir().unwrap();
@@ -1106,14 +3660,17 @@ mod tests {
 
 	#[test]
 	fn test_indexed_keyvalues() {
-		test_indexed_keyvalues_inner(EnableCommitPipelineStages::CommitOverlay);
-		test_indexed_keyvalues_inner(EnableCommitPipelineStages::LogOverlay);
-		test_indexed_keyvalues_inner(EnableCommitPipelineStages::DbFile);
-		test_indexed_keyvalues_inner(EnableCommitPipelineStages::Standard);
+		for no_wal in [false, true] {
+			test_indexed_keyvalues_inner(EnableCommitPipelineStages::CommitOverlay, no_wal);
+			test_indexed_keyvalues_inner(EnableCommitPipelineStages::LogOverlay, no_wal);
+			test_indexed_keyvalues_inner(EnableCommitPipelineStages::DbFile, no_wal);
+			test_indexed_keyvalues_inner(EnableCommitPipelineStages::Standard, no_wal);
+		}
 	}
-	fn test_indexed_keyvalues_inner(db_test: EnableCommitPipelineStages) {
+	fn test_indexed_keyvalues_inner(db_test: EnableCommitPipelineStages, no_wal: bool) {
 		let tmp = tempdir().unwrap();
-		let options = Options::with_columns(tmp.path(), 5);
+		let mut options = Options::with_columns(tmp.path(), 5);
+		options.no_wal = no_wal;
 		let col_nb = 0;
 
 		let key1 = b"key1".to_vec();
@@ -1180,7 +3737,8 @@ mod tests {
 			(col_nb, key3.clone(), Some(b"value3".to_vec())),
 		]).unwrap();
 		wait_on.as_ref().map(|w| w.wait_notify());
-		std::mem::drop(db);
+		// Stop the workers without writing out queued commits, as in a crash.
+		db.forget();
 
 		let mut inner_options = InternalOptions::default();
 		inner_options.create = false;
@@ -1200,4 +3758,2630 @@ mod tests {
 		assert_eq!(db.inner.get(col_nb, key2.as_slice()).unwrap(), Some(b"value2b".to_vec()));
 		assert_eq!(db.inner.get(col_nb, key3.as_slice()).unwrap(), None);
 	}
+
+	#[test]
+	fn test_coordinated_commit() {
+		let tmp1 = tempdir().unwrap();
+		let tmp2 = tempdir().unwrap();
+		let options1 = Options::with_columns(tmp1.path(), 1);
+		let options2 = Options::with_columns(tmp2.path(), 2);
+		{
+			let db1 = Db::open_or_create(&options1).unwrap();
+			let db2 = Db::open_or_create(&options2).unwrap();
+
+			let mut commit = CoordinatedCommit::new();
+			commit.add(&db1, vec![(0, b"key1", Some(b"value1".to_vec()))]);
+			commit.add(&db2, vec![(1, b"key2", Some(b"value2".to_vec()))]);
+			commit.add(&db1, vec![(0, b"key3", Some(b"value3".to_vec()))]);
+			commit.commit().unwrap();
+			assert_eq!(db1.get(0, b"key1").unwrap(), Some(b"value1".to_vec()));
+			assert_eq!(db1.get(0, b"key3").unwrap(), Some(b"value3".to_vec()));
+			assert_eq!(db2.get(1, b"key2").unwrap(), Some(b"value2".to_vec()));
+
+			// Coordinated records are flushed and enacted without waiting for the log to fill up.
+			let start = std::time::Instant::now();
+			while !tmp1.path().join("index_00_16").exists() || !tmp2.path().join("index_01_16").exists() {
+				assert!(start.elapsed() < std::time::Duration::from_secs(10), "Coordinated commit was not enacted");
+				std::thread::sleep(std::time::Duration::from_millis(1));
+			}
+		}
+		let db1 = Db::open(&options1).unwrap();
+		let db2 = Db::open(&options2).unwrap();
+		assert_eq!(db1.get(0, b"key1").unwrap(), Some(b"value1".to_vec()));
+		assert_eq!(db2.get(1, b"key2").unwrap(), Some(b"value2".to_vec()));
+	}
+
+	#[test]
+	fn test_commit_borrowed_values() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		let value = b"value".to_vec();
+		db.commit_values(vec![(0, b"key1", Some(&value[..])), (0, b"key2", Some(b"value2"))]).unwrap();
+		assert_eq!(db.get(0, b"key1").unwrap(), Some(value));
+		assert_eq!(db.get(0, b"key2").unwrap(), Some(b"value2".to_vec()));
+	}
+
+	#[test]
+	fn test_put_delete() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		db.put(0, b"key1", b"value1").unwrap();
+		db.put(0, vec![2u8], vec![2u8]).unwrap();
+		assert_eq!(db.get(0, b"key1").unwrap(), Some(b"value1".to_vec()));
+		assert_eq!(db.get(0, &[2]).unwrap(), Some(vec![2]));
+		db.delete(0, b"key1").unwrap();
+		assert_eq!(db.get(0, b"key1").unwrap(), None);
+		assert_eq!(db.get(0, &[2]).unwrap(), Some(vec![2]));
+	}
+
+	#[test]
+	fn test_close() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		// Iteration does not include commits still in the commit queue.
+		db.commit_durable(vec![(0, b"key", Some(b"value"))]).unwrap();
+		let started = AtomicBool::new(false);
+		let finished = AtomicBool::new(false);
+		std::thread::scope(|s| {
+			let reader = s.spawn(|| {
+				db.iter_column_while(0, IterSnapshotPolicy::CommitOverlayIncluded, |_| {
+					started.store(true, Ordering::SeqCst);
+					std::thread::sleep(std::time::Duration::from_millis(100));
+					// Set by the callback itself, while the iteration still holds the database open.
+					finished.store(true, Ordering::SeqCst);
+					true
+				}).unwrap();
+				loop {
+					match db.get(0, b"key") {
+						Ok(value) => assert_eq!(value, Some(b"value".to_vec())),
+						Err(e) => return e,
+					}
+				}
+			});
+			while !started.load(Ordering::SeqCst) {
+				std::thread::yield_now();
+			}
+			db.close().unwrap();
+			// The iteration in progress completes before the database shuts down.
+			assert!(finished.load(Ordering::SeqCst));
+			assert!(matches!(reader.join().unwrap(), Error::Closed));
+		});
+		assert!(matches!(db.get(0, b"key"), Err(Error::Closed)));
+		assert!(matches!(db.put(0, b"key", b"other"), Err(Error::DatabaseShutdown)));
+		db.close().unwrap();
+		drop(db);
+
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"key").unwrap(), Some(b"value".to_vec()));
+	}
+
+	#[test]
+	fn test_compact_log() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.sync_data = false;
+		let mut inner_options = InternalOptions::default();
+		inner_options.create = true;
+		inner_options.commit_stages = EnableCommitPipelineStages::DbFile;
+		let (db, _) = Db::open_inner(&options, &inner_options).unwrap();
+		let wait = |f: &dyn Fn() -> bool| {
+			let start = std::time::Instant::now();
+			while !f() {
+				assert!(start.elapsed() < std::time::Duration::from_secs(10));
+				std::thread::sleep(std::time::Duration::from_millis(1));
+			}
+		};
+		// Record ids start at 1.
+		for i in 0 .. 4u8 {
+			db.commit(vec![(0, vec![i], Some(vec![i]))]).unwrap();
+			wait(&|| db.inner.last_enacted.load(Ordering::SeqCst) > i as u64);
+		}
+		// Enacted logs are kept for reuse.
+		wait(&|| db.inner.log.num_dirty_logs() >= 2);
+		db.compact_log().unwrap();
+		assert_eq!(db.inner.log.num_dirty_logs(), 0);
+		assert_eq!(db.get(0, &[3]).unwrap(), Some(vec![3]));
+		db.forget();
+	}
+
+	#[test]
+	fn test_commit_queue_fairness() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let inner_options = InternalOptions {
+			create: true,
+			max_commit_queue_bytes: Some(1024 * 1024),
+			..Default::default()
+		};
+		let (db, _) = Db::open_inner(&options, &inner_options).unwrap();
+		let big = vec![1u8; 8 * 1024 * 1024];
+		let small = vec![2u8; 1024];
+		std::thread::scope(|s| {
+			s.spawn(|| for i in 0 .. 8u8 {
+				db.commit(vec![(0, vec![0, i], Some(big.clone()))]).unwrap();
+			});
+			s.spawn(|| for i in 0 .. 256u32 {
+				db.commit(vec![(0, i.to_le_bytes().to_vec(), Some(small.clone()))]).unwrap();
+			});
+		});
+		assert_eq!(db.health().waiting_committers, 0);
+		assert_eq!(db.get(0, &[0, 7]).unwrap(), Some(big));
+		assert_eq!(db.get(0, &255u32.to_le_bytes()).unwrap(), Some(small));
+		drop(db);
+
+		// Waiters are admitted in the order they arrived, and each commit taken off the
+		// queue admits at least one of them. A committer arriving while others are
+		// waiting queues up behind them, even if the queue has room by then.
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = DbBuilder::new(options).max_commit_queue_bytes(1024).build().unwrap();
+		let value = vec![3u8; 2048];
+		db.commit(vec![(0, vec![0xff], Some(value.clone()))]).unwrap();
+		let waiters = 8u8;
+		let total = waiters as usize + 1;
+		let admitted = parking_lot::Mutex::new(Vec::new());
+		let mut in_order = true;
+		std::thread::scope(|s| {
+			let spawn = |i: u8, value: Vec<u8>| {
+				let (db, admitted) = (&db, &admitted);
+				s.spawn(move || {
+					db.commit(vec![(0, vec![i], Some(value))]).unwrap();
+					admitted.lock().push(i);
+				});
+			};
+			for i in 0 .. waiters {
+				spawn(i, value.clone());
+				while db.health().waiting_committers != i as usize + 1 {
+					std::thread::yield_now();
+				}
+			}
+			let mut count = 0;
+			let mut late = false;
+			while count < total {
+				while db.inner.process_commits().unwrap() {}
+				if !late {
+					// The queue is empty now, but earlier committers are still waiting.
+					spawn(waiters, vec![4u8; 1]);
+					late = true;
+				}
+				// Wait until some committer is admitted and all admitted ones are done.
+				let start = std::time::Instant::now();
+				count = loop {
+					let done = admitted.lock().len();
+					if done > count && done + db.health().waiting_committers == total {
+						break done;
+					}
+					assert!(start.elapsed() < std::time::Duration::from_secs(10), "No committer admitted");
+					std::thread::yield_now();
+				};
+				let mut done = admitted.lock().clone();
+				done.sort_unstable();
+				in_order &= done == (0 .. count as u8).collect::<Vec<_>>();
+			}
+		});
+		assert!(in_order, "Committers admitted out of order: {:?}", admitted.lock());
+	}
+
+	#[test]
+	fn test_commit_ids() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		let ids: Vec<Vec<u64>> = std::thread::scope(|s| {
+			let threads: Vec<_> = (0 .. 4u8).map(|t| {
+				let db = &db;
+				s.spawn(move || (0 .. 100u8).map(|i| {
+					db.commit(vec![(0, vec![t, i], Some(vec![i]))]).unwrap()
+				}).collect())
+			}).collect();
+			threads.into_iter().map(|t| t.join().unwrap()).collect()
+		});
+		for thread_ids in ids.iter() {
+			assert!(thread_ids.windows(2).all(|w| w[0] < w[1]));
+		}
+		let mut all: Vec<u64> = ids.into_iter().flatten().collect();
+		all.sort();
+		assert_eq!(all, (1 ..= 400).collect::<Vec<_>>());
+		assert_eq!(db.commit_durable(vec![(0, b"key", Some(b"value"))]).unwrap(), 401);
+		assert_eq!(db.multi_commit(vec![PendingCommit::new()]).unwrap(), 402);
+		drop(db);
+
+		// Commit ids are not persisted.
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.commit(vec![(0, b"key", Some(b"value".to_vec()))]).unwrap(), 1);
+	}
+
+	#[test]
+	fn test_column_queue_share() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].max_queue_share = Some(0.25);
+		let db = DbBuilder::new(options).max_commit_queue_bytes(1024 * 1024).build().unwrap();
+		// Nothing drains the queue until `process_commits` is called.
+		db.commit(vec![(1, b"blob0", Some(vec![1u8; 512 * 1024]))]).unwrap();
+		let (elapsed, blocked_finished, health) = std::thread::scope(|s| {
+			let blocked = s.spawn(|| db.commit(vec![(1, b"blob1", Some(vec![1u8; 1024]))]).unwrap());
+			let start = std::time::Instant::now();
+			for i in 0 .. 16u8 {
+				db.commit(vec![(0, vec![i], Some(vec![i]))]).unwrap();
+			}
+			let result = (start.elapsed(), blocked.is_finished(), db.health());
+			// Unblock the other thread before checking anything.
+			while db.inner.process_commits().unwrap() { }
+			result
+		});
+		assert!(elapsed < std::time::Duration::from_secs(1));
+		assert!(!blocked_finished);
+		// Keys are hashed to 32 bytes.
+		assert_eq!(health.column_queue_bytes[1], 512 * 1024 + 32);
+		assert_eq!(health.column_queue_bytes[0], 16 * 33);
+		assert_eq!(db.get(1, b"blob1").unwrap(), Some(vec![1u8; 1024]));
+	}
+
+	#[test]
+	fn test_background_error_wakes_committers() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = DbBuilder::new(options).max_commit_queue_bytes(1024).build().unwrap();
+		// Nothing drains the queue, so the next commit waits.
+		db.commit(vec![(0, b"blob", Some(vec![1u8; 2048]))]).unwrap();
+		std::thread::scope(|s| {
+			let blocked = s.spawn(|| db.commit(vec![(0, b"key", Some(b"value".to_vec()))]));
+			while db.health().waiting_committers == 0 {
+				std::thread::sleep(std::time::Duration::from_millis(1));
+			}
+			db.inner.store_err(Err(Error::Corruption("Worker failed".into())));
+			assert!(matches!(blocked.join().unwrap(), Err(Error::Background(_))));
+		});
+		// Rejected without waiting for the full queue.
+		assert!(matches!(db.commit(vec![(0, b"key", Some(b"value".to_vec()))]), Err(Error::Background(_))));
+		assert_eq!(db.health().waiting_committers, 0);
+		db.forget();
+	}
+
+	#[test]
+	fn test_shutdown_wakes_committers() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = DbBuilder::new(options).max_commit_queue_bytes(1024).build().unwrap();
+		db.commit(vec![(0, b"blob", Some(vec![1u8; 2048]))]).unwrap();
+		std::thread::scope(|s| {
+			let blocked = s.spawn(|| db.commit(vec![(0, b"key", Some(b"value".to_vec()))]));
+			while db.health().waiting_committers == 0 {
+				std::thread::sleep(std::time::Duration::from_millis(1));
+			}
+			db.inner.shutdown();
+			assert!(matches!(blocked.join().unwrap(), Err(Error::DatabaseShutdown)));
+		});
+		db.forget();
+	}
+
+	#[test]
+	fn test_flush_on_shutdown() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let mut inner_options = InternalOptions::default();
+		inner_options.create = true;
+		inner_options.commit_stages = EnableCommitPipelineStages::LogOverlay;
+		let (db, _) = Db::open_inner(&options, &inner_options).unwrap();
+		db.commit(vec![(0, b"key", Some(b"value".to_vec()))]).unwrap();
+		// Wait for the log worker to write the record. The log is too small to be flushed.
+		let start = std::time::Instant::now();
+		while !db.inner.commit_overlay.read()[0].is_empty() {
+			assert!(start.elapsed() < std::time::Duration::from_secs(10));
+			std::thread::sleep(std::time::Duration::from_millis(1));
+		}
+		// Logs are not killed when only the workers are stopped. The flush worker flushes the
+		// record on shutdown.
+		db.forget();
+
+		let db = Db::open(&options).unwrap();
+		assert!(db.was_recovered());
+		assert_eq!(db.get(0, b"key").unwrap(), Some(b"value".to_vec()));
+	}
+
+	#[cfg(feature = "encryption")]
+	#[test]
+	fn test_encryption_key() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.encryption_key = Some([1; 32]);
+		let value = b"plaintext value".to_vec();
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			db.commit(vec![(0, b"key", Some(value.clone()))]).unwrap();
+			db.prepare(vec![(0, b"prepared", Some(value.clone()))]).unwrap();
+		}
+		// Neither the key nor the value is stored in plain, prepared changesets included.
+		for entry in std::fs::read_dir(tmp.path()).unwrap() {
+			let data = std::fs::read(entry.unwrap().path()).unwrap();
+			assert!(!data.windows(value.len()).any(|w| w == value.as_slice()));
+			assert!(!data.windows(32).any(|w| w == [1; 32]));
+			assert!(!String::from_utf8_lossy(&data).contains(&hex::encode([1; 32])));
+		}
+
+		let mut no_key = options.clone();
+		no_key.encryption_key = None;
+		assert!(matches!(Db::open(&no_key), Err(Error::EncryptionKeyRequired)));
+		let mut wrong_key = options.clone();
+		wrong_key.encryption_key = Some([2; 32]);
+		assert!(matches!(Db::open(&wrong_key), Err(Error::InvalidEncryptionKey)));
+
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"key").unwrap(), Some(value.clone()));
+		let token = db.pending_prepared().unwrap()[0];
+		db.commit_prepared(token).unwrap();
+		assert_eq!(db.get(0, b"prepared").unwrap(), Some(value));
+	}
+
+	#[test]
+	fn test_ordered_column() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[0].ordered = true;
+		let collect = |db: &Db, start: Option<&[u8]>, end: Option<&[u8]>| {
+			let mut keys = Vec::new();
+			db.iter_range(0, start, end, |k, v| {
+				assert_eq!(v, [b"v".as_ref(), k].concat());
+				keys.push(k.to_vec());
+				true
+			}).unwrap();
+			keys
+		};
+		let prefixed = |db: &Db, prefix: &[u8]| {
+			let mut keys = Vec::new();
+			db.iter_prefix(0, prefix, |k, _| { keys.push(k.to_vec()); true }).unwrap();
+			keys
+		};
+		let keys: [&[u8]; 6] = [b"a1", b"a2", b"b1", b"b2", b"c", &[0xff, 0xff, 1]];
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			db.commit(keys.iter().map(|k| (0, k.to_vec(), Some([b"v".as_ref(), k].concat())))).unwrap();
+			db.commit(vec![(0, b"b1".to_vec(), None)]).unwrap();
+			assert_eq!(db.get(0, b"a1").unwrap(), Some(b"va1".to_vec()));
+			assert_eq!(db.get_size(0, b"a1").unwrap(), Some(3));
+			assert_eq!(collect(&db, Some(b"a2"), Some(b"c")), vec![b"a2".to_vec(), b"b2".to_vec()]);
+			assert!(matches!(db.iter_range(1, None, None, |_, _| true), Err(Error::InvalidInput(_))));
+		}
+		// Sorted keys are saved on shutdown and the file is removed once they are loaded.
+		let saved = tmp.path().join("ordered_keys_00");
+		assert!(saved.exists());
+		assert!(!tmp.path().join("ordered_keys_01").exists());
+		let db = Db::open(&options).unwrap();
+		assert!(!saved.exists());
+		assert_eq!(db.get(0, b"a1").unwrap(), Some(b"va1".to_vec()));
+		assert_eq!(collect(&db, Some(b"a2"), Some(b"c")), vec![b"a2".to_vec(), b"b2".to_vec()]);
+		assert_eq!(collect(&db, None, None).len(), 5);
+		assert_eq!(prefixed(&db, b"a"), vec![b"a1".to_vec(), b"a2".to_vec()]);
+		assert_eq!(prefixed(&db, &[0xff]), vec![vec![0xff, 0xff, 1]]);
+		assert!(prefixed(&db, b"d").is_empty());
+		let mut first = Vec::new();
+		db.iter_range(0, None, None, |k, _| { first.push(k.to_vec()); false }).unwrap();
+		assert_eq!(first, vec![b"a1".to_vec()]);
+		let mut entries = Vec::new();
+		db.iter_column_while(0, IterSnapshotPolicy::CommitOverlayIncluded, |state| {
+			entries.push((state.original_key.unwrap(), state.value));
+			true
+		}).unwrap();
+		entries.sort();
+		assert_eq!(entries[0], (b"a1".to_vec(), b"va1".to_vec()));
+		assert_eq!(entries.len(), 5);
+		let values = parking_lot::Mutex::new(Vec::new());
+		db.par_iter(0, |_, value| values.lock().push(value)).unwrap();
+		assert!(values.into_inner().contains(&b"va2".to_vec()));
+		drop(db);
+
+		// Commits still in the queue replace the entries in the column.
+		let db = DbBuilder::new(options).stage(EnableCommitPipelineStages::CommitOverlay).build().unwrap();
+		db.commit(vec![(0, b"a1".to_vec(), None), (0, b"a3".to_vec(), Some(b"va3".to_vec()))]).unwrap();
+		db.commit(vec![(0, b"a2".to_vec(), Some(b"va2".to_vec()))]).unwrap();
+		assert_eq!(prefixed(&db, b"a"), vec![b"a2".to_vec(), b"a3".to_vec()]);
+		db.process_commits().unwrap();
+		assert_eq!(prefixed(&db, b"a"), vec![b"a2".to_vec(), b"a3".to_vec()]);
+	}
+
+	#[test]
+	fn test_ordered_keys_saved() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.columns[0].ordered = true;
+		let saved = tmp.path().join("ordered_keys_00");
+		let range = |db: &Db, start: u32, end: u32| {
+			let mut keys = Vec::new();
+			db.iter_range(0, Some(&start.to_be_bytes()), Some(&end.to_be_bytes()), |k, _| {
+				keys.push(u32::from_be_bytes(k.try_into().unwrap()));
+				true
+			}).unwrap();
+			keys
+		};
+		let queries = |db: &Db| {
+			let stats = &db.stats().columns[0];
+			stats.query_histogram.iter().sum::<u64>() + stats.queries_miss
+		};
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			db.commit((0 .. 1000u32).map(|i| (0, i.to_be_bytes().to_vec(), Some(i.to_le_bytes().to_vec())))).unwrap();
+			db.commit(vec![(0, 502u32.to_be_bytes().to_vec(), None)]).unwrap();
+		}
+		let db = Db::open(&options).unwrap();
+		// Only the values of the keys in the range are read.
+		let before = queries(&db);
+		assert_eq!(range(&db, 500, 505), vec![500, 501, 503, 504]);
+		assert_eq!(queries(&db) - before, 4);
+		// Changes made after the keys were saved are found after an unclean shutdown.
+		db.commit_durable(vec![(0, 2000u32.to_be_bytes().to_vec(), Some(vec![0])), (0, 501u32.to_be_bytes().to_vec(), None)]).unwrap();
+		db.forget();
+		assert!(!saved.exists());
+		let db = Db::open(&options).unwrap();
+		assert_eq!(range(&db, 500, 505), vec![500, 503, 504]);
+		assert_eq!(range(&db, 1000, 3000), vec![2000]);
+		drop(db);
+
+		// Keys are rebuilt if the saved file can't be read.
+		let len = std::fs::metadata(&saved).unwrap().len();
+		std::fs::OpenOptions::new().write(true).open(&saved).unwrap().set_len(len - 1).unwrap();
+		let db = Db::open(&options).unwrap();
+		assert!(!saved.exists());
+		assert_eq!(range(&db, 998, 2001), vec![998, 999, 2000]);
+	}
+
+	#[test]
+	fn test_thread_config() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.thread_config.name_prefix = "test-db".into();
+		options.thread_config.nice = Some(5);
+		let db = Db::open_or_create(&options).unwrap();
+		let name = |t: &Option<std::thread::JoinHandle<()>>| t.as_ref().unwrap().thread().name().map(String::from);
+		let workers = db.workers.lock();
+		assert_eq!(name(&workers.commit).as_deref(), Some("test-db-commit"));
+		assert_eq!(name(&workers.flush).as_deref(), Some("test-db-flush"));
+		assert_eq!(name(&workers.log).as_deref(), Some("test-db-log"));
+		assert_eq!(name(&workers.cleanup).as_deref(), Some("test-db-cleanup"));
+		assert_eq!(workers.prefetch[0].thread().name(), Some("test-db-prefetch-0"));
+		drop(workers);
+		db.commit(vec![(0, b"key", Some(b"value".to_vec()))]).unwrap();
+		assert_eq!(db.get(0, b"key").unwrap(), Some(b"value".to_vec()));
+	}
+
+	#[test]
+	fn test_options_path() {
+		let tmp = tempdir().unwrap();
+		let path = tmp.path().to_str().unwrap().to_string();
+		let options = Options::with_path_str(&path, 1).unwrap();
+		assert_eq!(options.path, tmp.path());
+		assert_eq!(Options::with_columns(path.clone(), 1).path, options.path);
+		assert!(Options::with_path_str("", 1).is_err());
+		assert!(Options::with_path_str("relative/db", 1).is_err());
+		let db = Db::with_columns(&path, 1).unwrap();
+		db.commit(vec![(0, b"key", Some(b"value".to_vec()))]).unwrap();
+	}
+
+	#[test]
+	fn test_preallocate() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.columns[0].preallocate = Some(1024 * 1024);
+		let len = |name: &str| std::fs::metadata(tmp.path().join(name)).unwrap().len();
+		let db = Db::open_or_create(&options).unwrap();
+		assert!(tmp.path().join("index_00_16").exists());
+		let sizes = (0 .. 16).map(|tier| len(&format!("table_00_{:02x}", tier))).collect::<Vec<_>>();
+		assert!(sizes.iter().all(|s| *s >= 1024 * 1024));
+		for i in 0 .. 1000u32 {
+			db.commit(vec![(0, i.to_le_bytes(), Some(vec![i as u8; 10]))]).unwrap();
+		}
+		drop(db);
+		assert_eq!(len("table_00_00"), sizes[0]);
+
+		// Ignored for existing databases, and not truncated on reopen.
+		options.columns[0].preallocate = Some(4 * 1024 * 1024);
+		let db = Db::open(&options).unwrap();
+		db.commit(vec![(0, b"key", Some(b"value".to_vec()))]).unwrap();
+		drop(db);
+		assert_eq!(len("table_00_00"), sizes[0]);
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, &999u32.to_le_bytes()).unwrap(), Some(vec![231u8; 10]));
+	}
+
+	#[test]
+	fn test_multi_commit() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 2);
+		let db = DbBuilder::new(options).build().unwrap();
+		let mut first = PendingCommit::new();
+		first.add(0, b"a", Some(b"first".to_vec()));
+		first.add(0, b"b", Some(b"first".to_vec()));
+		first.add(1, b"b", Some(b"first".to_vec()));
+		let second: PendingCommit = vec![
+			(0, b"b", Some(b"second".to_vec())),
+			(0, b"c", None),
+		].into_iter().collect();
+		db.multi_commit(vec![first, second]).unwrap();
+		assert_eq!(db.inner.commit_queue.commits.len(), 1);
+		assert_eq!(db.get(0, b"a").unwrap(), Some(b"first".to_vec()));
+		assert_eq!(db.get(0, b"b").unwrap(), Some(b"second".to_vec()));
+		assert_eq!(db.get(1, b"b").unwrap(), Some(b"first".to_vec()));
+
+		let mut invalid = PendingCommit::new();
+		invalid.add(2, b"a", None);
+		assert!(matches!(db.multi_commit(vec![PendingCommit::new(), invalid]), Err(Error::InvalidColumn(2))));
+		assert_eq!(db.inner.commit_queue.commits.len(), 1);
+	}
+
+	#[test]
+	fn test_submit_commit() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].ordered = true;
+		let db = DbBuilder::new(options)
+			.fixture(0, b"removed", Some(b"value".to_vec()))
+			.stage(EnableCommitPipelineStages::CommitOverlay)
+			.build()
+			.unwrap();
+		let commit = CommitBuilder::with_capacity(3)
+			.insert(0, b"a", b"value".to_vec())
+			.insert(1, b"b".to_vec(), &b"ordered"[..])
+			.remove(0, &b"removed"[..])
+			.finish();
+		assert_eq!(commit.len(), 3);
+		assert_eq!(commit.bytes(), 1 + 5 + 1 + 7 + 7);
+		db.submit_commit(commit).unwrap();
+		assert_eq!(db.inner.commit_queue.commit_id.load(Ordering::SeqCst), 2);
+		db.advance_to(EnableCommitPipelineStages::Standard).unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), Some(b"value".to_vec()));
+		assert_eq!(db.get(0, b"removed").unwrap(), None);
+		assert_eq!(db.get(1, b"b").unwrap(), Some(b"ordered".to_vec()));
+
+		let invalid = CommitBuilder::new().remove(2, b"a").finish();
+		assert!(matches!(db.submit_commit(invalid), Err(Error::InvalidColumn(2))));
+		assert!(db.submit_commit(CommitBuilder::new().finish()).is_ok());
+	}
+
+	#[test]
+	fn test_empty_values() {
+		for stage in [
+			EnableCommitPipelineStages::CommitOverlay,
+			EnableCommitPipelineStages::LogOverlay,
+			EnableCommitPipelineStages::DbFile,
+			EnableCommitPipelineStages::Standard,
+		] {
+			let tmp = tempdir().unwrap();
+			let mut options = Options::with_columns(tmp.path(), 4);
+			options.columns[1].ref_counted = true;
+			options.columns[2].ordered = true;
+			options.columns[3].uniform = true;
+			let key = [7u8; 32];
+			let db = DbBuilder::new(options.clone())
+				.fixtures((0 .. 4).map(|c| (c, key, Some(Vec::new()))))
+				.fixture(0, b"removed", Some(Vec::new()))
+				.stage(stage)
+				.build()
+				.unwrap();
+			for c in 0 .. 4 {
+				assert_eq!(db.get(c, &key).unwrap(), Some(Vec::new()), "{:?}", stage);
+				assert_eq!(db.get_size(c, &key).unwrap(), Some(0), "{:?}", stage);
+			}
+			assert_eq!(db.get(0, b"removed").unwrap(), Some(Vec::new()), "{:?}", stage);
+			db.delete(0, b"removed").unwrap();
+			db.advance_to(stage).unwrap();
+			assert_eq!(db.get(0, b"removed").unwrap(), None, "{:?}", stage);
+			db.commit(vec![(0, key, Some(b"value".to_vec()))]).unwrap();
+			db.advance_to(stage).unwrap();
+			db.commit(vec![(0, key, Some(Vec::new()))]).unwrap();
+			db.advance_to(stage).unwrap();
+			assert_eq!(db.get(0, &key).unwrap(), Some(Vec::new()), "{:?}", stage);
+			drop(db);
+
+			let db = Db::open(&options).unwrap();
+			for c in 0 .. 4 {
+				assert_eq!(db.get(c, &key).unwrap(), Some(Vec::new()), "{:?}", stage);
+			}
+			assert_eq!(db.get(0, b"removed").unwrap(), None, "{:?}", stage);
+			let mut visited = Vec::new();
+			db.iter_prefix(2, b"", |k, v| { visited.push((k.to_vec(), v)); true }).unwrap();
+			assert_eq!(visited, vec![(key.to_vec(), Vec::new())]);
+		}
+	}
+
+	#[test]
+	fn test_commit_each() {
+		let tmp = tempdir().unwrap();
+		let db = DbBuilder::with_columns(tmp.path(), 2).stage(EnableCommitPipelineStages::CommitOverlay).build().unwrap();
+		let batches: Vec<PendingCommit> = (0 .. 3u8).map(|i| {
+			let mut batch = PendingCommit::new();
+			batch.add(0, [i], Some(vec![i]));
+			batch.add(1, b"key", Some(vec![i]));
+			batch
+		}).collect();
+		let tokens = db.commit_each(batches).unwrap();
+		assert_eq!(tokens.iter().map(|t| t.id()).collect::<Vec<_>>(), vec![1, 2, 3]);
+		assert_eq!(db.inner.commit_queue.commits.len(), 3);
+		assert_eq!(db.get(1, b"key").unwrap(), Some(vec![2]));
+
+		let mut invalid = PendingCommit::new();
+		invalid.add(2, b"a", None);
+		assert!(matches!(db.commit_each(vec![PendingCommit::new(), invalid]), Err(Error::InvalidColumn(2))));
+		assert_eq!(db.inner.commit_queue.commits.len(), 3);
+
+		// Each commit is written with its own record.
+		db.advance_to(EnableCommitPipelineStages::LogOverlay).unwrap();
+		let files = db.log_files().unwrap();
+		assert_eq!(files[0].last_record - files[0].first_record, 2);
+		db.advance_to(EnableCommitPipelineStages::DbFile).unwrap();
+		for token in &tokens {
+			db.wait_durable(token).unwrap();
+		}
+		drop(db);
+
+		// Tokens can be waited for separately while the workers write the log.
+		let db = Db::open(&Options::with_columns(tmp.path(), 2)).unwrap();
+		let batches = (0 .. 4u8).map(|i| vec![(0, vec![i], Some(vec![i, i]))].into_iter().collect()).collect();
+		let tokens = db.commit_each(batches).unwrap();
+		db.wait_durable(&tokens[1]).unwrap();
+		assert_eq!(db.get(0, &[1]).unwrap(), Some(vec![1, 1]));
+		for (i, token) in tokens.iter().enumerate().rev() {
+			db.wait_durable(token).unwrap();
+			assert_eq!(db.get(0, &[i as u8]).unwrap(), Some(vec![i as u8, i as u8]));
+		}
+	}
+
+	#[test]
+	fn test_prepared_commit() {
+		let tmp_a = tempdir().unwrap();
+		let tmp_b = tempdir().unwrap();
+		let options_a = Options::with_columns(tmp_a.path(), 1);
+		let options_b = Options::with_columns(tmp_b.path(), 1);
+		let a = Db::open_or_create(&options_a).unwrap();
+		let b = Db::open_or_create(&options_b).unwrap();
+		// Crash after preparing in `a`, before preparing in `b`.
+		let token = a.prepare(vec![(0, b"key", Some(b"a1"))]).unwrap();
+		assert_eq!(a.get(0, b"key").unwrap(), None);
+		drop(a);
+		drop(b);
+
+		let a = Db::open(&options_a).unwrap();
+		let b = Db::open(&options_b).unwrap();
+		assert_eq!(a.pending_prepared().unwrap(), vec![token]);
+		assert!(b.pending_prepared().unwrap().is_empty());
+		a.rollback_prepared(token).unwrap();
+		assert!(a.pending_prepared().unwrap().is_empty());
+		assert!(matches!(a.commit_prepared(token), Err(Error::InvalidInput(_))));
+		assert_eq!(a.get(0, b"key").unwrap(), None);
+
+		let token_a = a.prepare(vec![(0, b"key", Some(b"a2"))]).unwrap();
+		assert_ne!(token_a, token);
+		let token_b = b.prepare(vec![(0, b"key", Some(b"b2"))]).unwrap();
+		a.commit_prepared(token_a).unwrap();
+		b.commit_prepared(token_b).unwrap();
+		assert_eq!(a.get(0, b"key").unwrap(), Some(b"a2".to_vec()));
+		assert_eq!(b.get(0, b"key").unwrap(), Some(b"b2".to_vec()));
+		assert!(a.pending_prepared().unwrap().is_empty());
+		drop(a);
+		drop(b);
+
+		// Crash after the commit record is written, before the changeset is removed.
+		let b = DbBuilder::new(options_b.clone()).build().unwrap();
+		let token = b.prepare(vec![(0, b"key", Some(b"b3"))]).unwrap();
+		let encryption = Encryption::from_options(&options_b);
+		let changes = prepared::read(tmp_b.path(), token.id(), encryption.as_ref()).unwrap().unwrap();
+		b.inner.enqueue_commit(
+			&mut b.inner.commit_overlay.write(),
+			changes,
+			None,
+			RecordInfo { prepared: Some(token.id()), ..Default::default() },
+			true,
+		);
+		b.inner.process_commits().unwrap();
+		b.inner.flush_logs(0).unwrap();
+		drop(b);
+
+		let b = Db::open(&options_b).unwrap();
+		assert!(b.pending_prepared().unwrap().is_empty());
+		assert_eq!(b.get(0, b"key").unwrap(), Some(b"b3".to_vec()));
+	}
+
+	#[test]
+	fn test_step() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_with_stages(&options, EnableCommitPipelineStages::CommitOverlay).unwrap();
+		db.commit(vec![(0, b"key", Some(b"value".to_vec()))]).unwrap();
+		assert_eq!(db.inner.commit_queue.commits.len(), 1);
+		assert!(db.step().unwrap());
+		assert!(db.inner.commit_queue.commits.is_empty());
+		assert!(db.inner.log.replay_record_id().is_none());
+		let mut steps = 1;
+		while db.step().unwrap() {
+			steps += 1;
+		}
+		assert!(steps > 2);
+		assert!(db.inner.commit_overlay.read()[0].is_empty());
+		db.commit(vec![(0, b"key2", Some(b"value".to_vec()))]).unwrap();
+		while db.step().unwrap() {}
+		assert_eq!(db.stats().last_enacted, 2);
+		drop(db);
+
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"key").unwrap(), Some(b"value".to_vec()));
+		assert!(matches!(db.step(), Err(Error::InvalidInput(_))));
+	}
+
+	#[test]
+	fn test_drop_writes_queued_commits() {
+		for stages in [
+			EnableCommitPipelineStages::CommitOverlay,
+			EnableCommitPipelineStages::LogOverlay,
+			EnableCommitPipelineStages::DbFile,
+			EnableCommitPipelineStages::Standard,
+		] {
+			let tmp = tempdir().unwrap();
+			let options = Options::with_columns(tmp.path(), 1);
+			let db = Db::open_with_stages(&options, stages).unwrap();
+			db.commit(vec![(0, b"key1", Some(b"value1".to_vec()))]).unwrap();
+			db.commit(vec![(0, b"key2", Some(b"value2".to_vec()))]).unwrap();
+			drop(db);
+
+			let db = Db::open(&options).unwrap();
+			assert_eq!(db.get(0, b"key1").unwrap(), Some(b"value1".to_vec()), "{:?}", stages);
+			assert_eq!(db.get(0, b"key2").unwrap(), Some(b"value2".to_vec()), "{:?}", stages);
+		}
+	}
+
+	#[test]
+	fn test_iter_values_raw() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].ref_counted = true;
+		options.columns[1].preimage = true;
+		let db = Db::open_or_create(&options).unwrap();
+		let values: Vec<(Vec<u8>, Vec<u8>)> = vec![
+			(b"small".to_vec(), b"value".to_vec()),
+			(b"medium".to_vec(), vec![1; 1000]),
+			(b"large".to_vec(), vec![2; 100_000]),
+		];
+		db.commit_durable(values.iter().map(|(k, v)| (0, k.clone(), Some(v.clone())))
+			.chain([(0, b"removed".to_vec(), Some(b"gone".to_vec())), (1, b"rc".to_vec(), Some(b"value".to_vec()))])).unwrap();
+		db.commit_durable(vec![(0, &b"removed"[..], None), (1, &b"rc"[..], Some(b"value"))]).unwrap();
+
+		// Payloads are returned as stored, i.e. encrypted with the `encryption` feature.
+		let encryption = Encryption::from_options(&options);
+		let decode = |value: Vec<u8>| match &encryption {
+			Some(encryption) => encryption.decrypt(&value).unwrap(),
+			None => value,
+		};
+		let mut raw: Vec<_> = db.iter_values_raw(0).unwrap().map(|e| {
+			let e = e.unwrap();
+			assert_eq!(e.rc, 1);
+			assert!(!e.compressed);
+			(e.partial_key.to_vec(), decode(e.value))
+		}).collect();
+		raw.sort();
+		let mut expected: Vec<_> = values.iter().map(|(k, v)| (db.inner.columns[0].hash(k)[6..].to_vec(), v.clone())).collect();
+		expected.sort();
+		assert_eq!(raw, expected);
+
+		let raw: Vec<_> = db.iter_values_raw(1).unwrap().collect::<Result<_, _>>().unwrap();
+		assert_eq!(raw.len(), 1);
+		assert_eq!(raw[0].rc, 2);
+		assert_eq!(decode(raw[0].value.clone()), b"value");
+		assert!(matches!(db.iter_values_raw(2), Err(Error::InvalidColumn(2))));
+	}
+
+	#[test]
+	fn test_par_iter() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 3);
+		options.columns[1].ref_counted = true;
+		options.columns[2].index = crate::IndexMode::AppendOnly;
+		let db = Db::open_or_create(&options).unwrap();
+		let values: Vec<Vec<u8>> = (0 .. 1000u32).map(|i| vec![(i % 251) as u8; (i as usize % 7) * 100 + 1]).collect();
+		for c in 0 .. 3 {
+			db.commit(values.iter().enumerate().map(|(i, v)| (c, (i as u32).to_le_bytes(), Some(v.clone())))).unwrap();
+		}
+		// Commits queued earlier are written to the log first.
+		db.commit_durable(vec![(1, 0u32.to_le_bytes(), Some(values[0].clone())), (0, 1u32.to_le_bytes(), None)]).unwrap();
+		for c in 0 .. 3 {
+			let mut expected = Vec::new();
+			db.iter_column_while(c, IterSnapshotPolicy::CommitOverlayIncluded, |state| {
+				expected.push((state.key, state.value));
+				true
+			}).unwrap();
+			expected.sort();
+			let found = parking_lot::Mutex::new(Vec::new());
+			db.par_iter(c, |key, value| found.lock().push((key, value))).unwrap();
+			let mut found = found.into_inner();
+			found.sort();
+			assert_eq!(found.len(), if c == 0 { 999 } else { 1000 });
+			assert_eq!(found, expected);
+		}
+		assert!(matches!(db.par_iter(3, |_, _| ()), Err(Error::InvalidColumn(3))));
+	}
+
+	#[test]
+	fn test_iter_slow_callback() {
+		use std::sync::atomic::{AtomicBool, AtomicUsize};
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		const ENTRIES: u32 = 2000;
+		db.commit_durable((0 .. ENTRIES).map(|i| (0, i.to_le_bytes(), Some(i.to_le_bytes().to_vec())))).unwrap();
+		while db.pipeline_status().lag() > 0 {
+			std::thread::sleep(std::time::Duration::from_millis(1));
+		}
+
+		let visited = AtomicUsize::new(0);
+		let committed = AtomicBool::new(false);
+		let visited_after_commit = AtomicUsize::new(0);
+		let mut keys = std::collections::HashSet::new();
+		std::thread::scope(|s| {
+			s.spawn(|| {
+				while visited.load(Ordering::SeqCst) == 0 {
+					std::thread::sleep(std::time::Duration::from_millis(1));
+				}
+				// Replacing the index needs exclusive access to the column.
+				db.force_reindex(0).unwrap();
+				db.commit_durable(vec![(0, ENTRIES.to_le_bytes(), Some(b"new"))]).unwrap();
+				committed.store(true, Ordering::SeqCst);
+			});
+			db.iter_column_while(0, IterSnapshotPolicy::CommitOverlayIncluded, |state| {
+				std::thread::sleep(std::time::Duration::from_millis(1));
+				visited.fetch_add(1, Ordering::SeqCst);
+				if committed.load(Ordering::SeqCst) {
+					visited_after_commit.fetch_add(1, Ordering::SeqCst);
+				}
+				keys.insert(state.key);
+				true
+			}).unwrap();
+		});
+		// The commit did not wait for the iteration to finish.
+		assert!(visited_after_commit.load(Ordering::SeqCst) > 0);
+		// Entries present during the whole iteration are visited once, despite the reindex.
+		let expected: std::collections::HashSet<_> = (0 .. ENTRIES).map(|i| db.inner.columns[0].hash(&i.to_le_bytes())).collect();
+		assert!(keys.is_superset(&expected));
+		assert!(visited.load(Ordering::SeqCst) <= ENTRIES as usize + 1);
+		assert_eq!(db.get(0, &ENTRIES.to_le_bytes()).unwrap(), Some(b"new".to_vec()));
+	}
+
+	#[test]
+	fn test_iter_snapshot_policy() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].index = crate::IndexMode::AppendOnly;
+		let db = DbBuilder::new(options)
+			.fixture(0, b"enacted", Some(b"1".to_vec()))
+			.fixture(1, b"enacted", Some(b"1".to_vec()))
+			.build()
+			.unwrap();
+		db.commit(vec![(0, &b"logged"[..], Some(b"2".to_vec())), (0, b"enacted", None), (1, b"logged", Some(b"2".to_vec()))]).unwrap();
+		db.advance_to(EnableCommitPipelineStages::LogOverlay).unwrap();
+		db.commit(vec![(0, b"queued", Some(b"3".to_vec()))]).unwrap();
+		let values = |col, policy| {
+			let mut values = Vec::new();
+			db.iter_column_while(col, policy, |state| {
+				values.push(state.value);
+				true
+			}).unwrap();
+			values.sort();
+			values
+		};
+		assert_eq!(values(0, IterSnapshotPolicy::CommitOverlayIncluded), vec![b"2".to_vec()]);
+		assert_eq!(values(0, IterSnapshotPolicy::DiskOnly), vec![b"1".to_vec()]);
+		assert_eq!(values(1, IterSnapshotPolicy::CommitOverlayIncluded), vec![b"1".to_vec(), b"2".to_vec()]);
+		assert_eq!(values(1, IterSnapshotPolicy::DiskOnly), vec![b"1".to_vec()]);
+
+		// Log records are not enacted while a `DiskOnly` iteration runs.
+		std::thread::scope(|s| {
+			db.iter_column_while(0, IterSnapshotPolicy::DiskOnly, |_| {
+				let enact = s.spawn(|| db.advance_to(EnableCommitPipelineStages::DbFile).unwrap());
+				std::thread::sleep(std::time::Duration::from_millis(100));
+				assert!(!enact.is_finished());
+				true
+			}).unwrap();
+		});
+		assert_eq!(values(0, IterSnapshotPolicy::DiskOnly), vec![b"2".to_vec(), b"3".to_vec()]);
+	}
+
+	#[test]
+	fn test_max_changeset_ops() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.max_changeset_ops = Some(2);
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(vec![(0, b"a", Some(b"1".to_vec())), (0, b"b", Some(b"2".to_vec()))]).unwrap();
+		let large = vec![(0, b"c", Some(b"3".to_vec())), (0, b"d", Some(b"4".to_vec())), (0, b"e", None)];
+		assert!(matches!(db.commit(large.clone()), Err(Error::ChangesetTooLarge { ops: 3, max: 2 })));
+		assert!(matches!(db.commit_durable(large.clone()), Err(Error::ChangesetTooLarge { .. })));
+		assert!(matches!(db.prepare(large), Err(Error::ChangesetTooLarge { .. })));
+		assert_eq!(db.get(0, b"c").unwrap(), None);
+		assert_eq!(db.get(0, b"b").unwrap(), Some(b"2".to_vec()));
+	}
+
+	#[cfg(debug_assertions)]
+	#[test]
+	fn test_max_total_bytes() {
+		const MAX: u64 = 4 * 1024 * 1024;
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.max_total_bytes = Some(MAX);
+		let db = DbBuilder::new(options).build().unwrap();
+		let value = vec![0x42; 64 * 1024];
+		let mut committed = 0u32;
+		let err = loop {
+			match db.commit(vec![(0, committed.to_le_bytes(), Some(value.clone()))]) {
+				Ok(_) => committed += 1,
+				Err(e) => break e,
+			}
+			// Rescan the index files instead of waiting.
+			*db.inner.last_disk_scan.lock() = None;
+			db.advance_to(EnableCommitPipelineStages::Standard).unwrap();
+		};
+		assert!(matches!(err, Error::QuotaExceeded { max: MAX, .. }));
+		assert!(committed > 8);
+		let health = db.health();
+		assert_eq!(health.max_total_bytes, Some(MAX));
+		assert!(health.disk_usage > 0 && health.disk_usage <= MAX);
+		assert!(super::disk_usage(tmp.path(), |_| false).unwrap() <= MAX);
+
+		// Removing keys is still allowed.
+		db.commit(vec![(0, 0u32.to_le_bytes(), None)]).unwrap();
+		db.advance_to(EnableCommitPipelineStages::Standard).unwrap();
+		assert_eq!(db.get(0, &0u32.to_le_bytes()).unwrap(), None);
+		assert_eq!(db.get(0, &1u32.to_le_bytes()).unwrap(), Some(value));
+	}
+
+	#[cfg(debug_assertions)]
+	#[test]
+	fn test_disk_usage_without_quota() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = DbBuilder::new(options).build().unwrap();
+		let opened = db.health().disk_usage;
+		let value = vec![0x42; 64 * 1024];
+		db.commit(vec![(0, 0u32.to_le_bytes(), Some(value.clone()))]).unwrap();
+		db.advance_to(EnableCommitPipelineStages::Standard).unwrap();
+		// Counted when written to the log and to the value table.
+		let enacted = db.health().disk_usage;
+		assert!(enacted >= opened + 2 * value.len() as u64);
+		// The log is queued for cleanup when the next one is flushed. Once cleaned, it is
+		// subtracted, and only the small second log is left.
+		db.commit(vec![(0, 0u32.to_le_bytes(), None)]).unwrap();
+		db.advance_to(EnableCommitPipelineStages::Standard).unwrap();
+		db.inner.clean_all_logs().unwrap();
+		assert!(db.health().disk_usage <= enacted - value.len() as u64);
+		assert!(db.inner.disk_usage.load(Ordering::Relaxed) < opened + 1024);
+	}
+
+	#[test]
+	fn test_pipeline_status() {
+		let tmp = tempdir().unwrap();
+		let db = DbBuilder::with_columns(tmp.path(), 1)
+			.stage(EnableCommitPipelineStages::CommitOverlay)
+			.build()
+			.unwrap();
+		assert_eq!(db.pipeline_status(), PipelineStatus::default());
+		db.commit(vec![(0, b"key1", Some(b"value1".to_vec()))]).unwrap();
+		db.advance_to(EnableCommitPipelineStages::Standard).unwrap();
+		let status = db.pipeline_status();
+		assert_eq!((status.current_record, status.last_enacted_record, status.lag()), (1, 1, 0));
+
+		db.commit(vec![(0, b"key2", Some(b"value2".to_vec()))]).unwrap();
+		let status = db.pipeline_status();
+		// Keys are hashed.
+		assert_eq!(status.commit_queue_bytes, 32 + 6);
+		assert_eq!(status.lag(), 0);
+
+		db.advance_to(EnableCommitPipelineStages::LogOverlay).unwrap();
+		let status = db.pipeline_status();
+		assert_eq!(status.commit_queue_bytes, 0);
+		assert!(status.log_queue_bytes > 0);
+		assert_eq!((status.current_record, status.last_enacted_record, status.lag()), (2, 1, 1));
+
+		db.force_reindex(0).unwrap();
+		assert!(db.pipeline_status().pending_reindex);
+		db.advance_to(EnableCommitPipelineStages::Standard).unwrap();
+		let status = db.pipeline_status();
+		assert!(!status.pending_reindex);
+		assert_eq!(status.log_queue_bytes, 0);
+		assert!(status.current_record > 2);
+		assert_eq!(status.lag(), 0);
+		drop(db);
+
+		let db = Db::open(&Options::with_columns(tmp.path(), 1)).unwrap();
+		assert_eq!(db.pipeline_status().workers_alive, [true; 4]);
+	}
+
+	#[test]
+	fn test_duplicate_key_in_commit() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 3);
+		options.columns[2].ref_counted = true;
+		let db = Db::open_or_create(&options).unwrap();
+		let dup = vec![(1, b"a", Some(b"1".to_vec())), (0, b"a", Some(b"2".to_vec())), (1, b"a", None)];
+		assert!(matches!(db.commit(dup.clone()), Err(Error::DuplicateKeyInCommit { column: 1 })));
+		assert!(matches!(db.commit_durable(dup), Err(Error::DuplicateKeyInCommit { column: 1 })));
+		assert_eq!(db.get(0, b"a").unwrap(), None);
+		// The same key in different columns is fine.
+		db.commit(vec![(0, b"a", Some(b"1".to_vec())), (1, b"a", Some(b"2".to_vec()))]).unwrap();
+		assert_eq!(db.get(1, b"a").unwrap(), Some(b"2".to_vec()));
+		// Each operation on a reference counted column changes the reference count.
+		db.commit(vec![(2, b"a", Some(b"1".to_vec())), (2, b"a", Some(b"1".to_vec())), (2, b"a", None)]).unwrap();
+		assert_eq!(db.get(2, b"a").unwrap(), Some(b"1".to_vec()));
+	}
+
+	#[test]
+	fn test_check_key_range() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[0].uniform = true;
+		let db = Db::open_or_create(&options).unwrap();
+		let keys: Vec<[u8; 32]> = (0u8 .. 100).map(|i| [i; 32]).collect();
+		db.commit_durable(keys.iter().flat_map(|k| [(0, k.to_vec(), Some(b"value".to_vec())), (1, k.to_vec(), Some(b"value".to_vec()))])).unwrap();
+		let check = |col: u8, from: Option<&[u8]>, bound: Option<&[u8]>| {
+			let options = check::CheckOptions::new(Some(col), from.map(|k| k.to_vec()), bound.map(|k| k.to_vec()), false, None);
+			db.inner.columns[col as usize].check_from_index(&db.inner.log, &options, col).unwrap()
+		};
+		assert_eq!(check(0, None, None), 100);
+		assert_eq!(check(0, Some(&[10]), Some(&[20])), 10);
+		assert_eq!(check(0, Some(&[90, 0]), None), 10);
+		assert_eq!(check(0, None, Some(&[5, 5, 6])), 6);
+		assert_eq!(check(0, Some(&[50]), Some(&[50])), 0);
+
+		// Hashed keys are checked in hash order.
+		assert_eq!(check(1, None, None), 100);
+		let mut hashed: Vec<_> = keys.iter().map(|k| db.inner.columns[1].hash(k)).collect();
+		hashed.sort();
+		let from_key = keys.iter().find(|k| db.inner.columns[1].hash(*k) == hashed[30]).unwrap();
+		let bound_key = keys.iter().find(|k| db.inner.columns[1].hash(*k) == hashed[70]).unwrap();
+		assert_eq!(check(1, Some(from_key), Some(bound_key)), 40);
+		db.check_from_index(check::CheckOptions::new(None, Some(vec![10]), None, true, Some(4))).unwrap();
+	}
+
+	#[test]
+	fn test_sync_data() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 2);
+		let db = Db::open_or_create(&options).unwrap();
+		db.sync_data().unwrap();
+		db.commit_durable(vec![(0, b"key", Some(b"value")), (1, b"key", Some(b"value"))]).unwrap();
+		db.sync_data().unwrap();
+		drop(db);
+		let db = Db::open_read_only(&options).unwrap();
+		db.sync_data().unwrap();
+		assert_eq!(db.get(1, b"key").unwrap(), Some(b"value".to_vec()));
+	}
+
+	#[test]
+	fn test_prefetch() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let keys: Vec<Vec<u8>> = (0u32 .. 1000).map(|i| i.to_le_bytes().to_vec()).collect();
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			db.commit_durable(keys.iter().map(|k| (0, k.clone(), Some(vec![k[0]; 100 + k[1] as usize * 40])))).unwrap();
+			db.commit(vec![(0, b"queued", Some(b"value".to_vec()))]).unwrap();
+			let hints: Vec<&[u8]> = keys.iter().map(|k| k.as_slice()).chain([&b"queued"[..], &b"missing"[..]]).collect();
+			db.prefetch(0, &hints).unwrap();
+			for k in &keys {
+				assert_eq!(db.get(0, k).unwrap(), Some(vec![k[0]; 100 + k[1] as usize * 40]));
+			}
+			assert_eq!(db.get(0, b"queued").unwrap(), Some(b"value".to_vec()));
+			assert_eq!(db.get(0, b"missing").unwrap(), None);
+			assert!(matches!(db.prefetch(1, &[b"key"]), Err(Error::InvalidColumn(1))));
+			// More hints than the queue holds, pending ones are dropped on close.
+			for _ in 0 .. 10 {
+				db.prefetch(0, &hints).unwrap();
+			}
+		}
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, &keys[10]).unwrap(), Some(vec![keys[10][0]; 100 + keys[10][1] as usize * 40]));
+		assert_eq!(db.get(0, b"queued").unwrap(), Some(b"value".to_vec()));
+	}
+
+	#[test]
+	fn test_get_with_pool() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].ordered = true;
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(vec![(0, b"key1", Some(vec![1u8; 64])), (1, b"key1", Some(vec![1u8; 64]))]).unwrap();
+		db.commit_durable(vec![(0, b"key2", Some(vec![2u8; 32])), (1, b"key2", Some(vec![2u8; 32]))]).unwrap();
+
+		let mut buf = Vec::new();
+		for col in 0 .. 2 {
+			assert!(db.get_into(col, b"key1", &mut buf).unwrap());
+			assert_eq!(buf, vec![1u8; 64]);
+			assert!(db.get_into(col, b"key2", &mut buf).unwrap());
+			assert_eq!(buf, vec![2u8; 32]);
+			assert!(!db.get_into(col, b"missing", &mut buf).unwrap());
+		}
+
+		let mut pool = BufferPool::new(1);
+		let value = db.get_with_pool(0, b"key1", &mut pool).unwrap().unwrap();
+		assert_eq!(&*value, &[1u8; 64][..]);
+		let ptr = value.as_ptr();
+		drop(value);
+		assert_eq!(pool.available(), 1);
+		// The buffer is reused.
+		let value = db.get_with_pool(0, b"key2", &mut pool).unwrap().unwrap();
+		assert_eq!(&*value, &[2u8; 32][..]);
+		assert_eq!(value.as_ptr(), ptr);
+		assert_eq!(pool.available(), 0);
+		let other = db.get_with_pool(1, b"key1", &mut pool).unwrap().unwrap();
+		assert_eq!(other.into_vec(), vec![1u8; 64]);
+		drop(value);
+		assert!(db.get_with_pool(0, b"missing", &mut pool).unwrap().is_none());
+		assert_eq!(pool.available(), 1);
+	}
+
+	#[test]
+	fn test_byte_semaphore() {
+		let semaphore = ByteSemaphore::new(100);
+		let stop = std::sync::atomic::AtomicBool::new(false);
+		semaphore.add(150);
+		std::thread::scope(|s| {
+			let waiter = s.spawn(|| semaphore.wait_below(100, &stop));
+			std::thread::sleep(std::time::Duration::from_millis(10));
+			assert_eq!(semaphore.sub(20), 130);
+			std::thread::sleep(std::time::Duration::from_millis(10));
+			assert!(!waiter.is_finished());
+			assert_eq!(semaphore.sub(30), 100);
+			waiter.join().unwrap();
+
+			semaphore.add(1);
+			let waiter = s.spawn(|| semaphore.wait_below(100, &stop));
+			stop.store(true, Ordering::SeqCst);
+			semaphore.notify_all();
+			waiter.join().unwrap();
+		});
+		assert_eq!(semaphore.value(), 101);
+	}
+
+	#[test]
+	fn test_negative_cache() {
+		for stages in [
+			EnableCommitPipelineStages::CommitOverlay,
+			EnableCommitPipelineStages::LogOverlay,
+			EnableCommitPipelineStages::DbFile,
+			EnableCommitPipelineStages::Standard,
+		] {
+			let tmp = tempdir().unwrap();
+			let mut options = Options::with_columns(tmp.path(), 1);
+			options.columns[0].negative_cache = Some(16);
+			let db = Db::open_with_stages(&options, stages).unwrap();
+			let hits = || db.stats().columns[0].negative_cache_hits;
+			assert_eq!(db.get(0, b"key").unwrap(), None);
+			assert_eq!(hits(), 0);
+			assert_eq!(db.get(0, b"key").unwrap(), None);
+			assert_eq!(hits(), 1, "{:?}", stages);
+
+			db.commit(vec![(0, b"key", Some(b"value".to_vec()))]).unwrap();
+			assert_eq!(db.get(0, b"key").unwrap(), Some(b"value".to_vec()), "{:?}", stages);
+			if matches!(stages, EnableCommitPipelineStages::CommitOverlay) {
+				while db.step().unwrap() {}
+			}
+			for _ in 0 .. 10 {
+				assert_eq!(db.get(0, b"key").unwrap(), Some(b"value".to_vec()), "{:?}", stages);
+				std::thread::sleep(std::time::Duration::from_millis(1));
+			}
+
+			db.commit(vec![(0, b"key", None)]).unwrap();
+			if matches!(stages, EnableCommitPipelineStages::CommitOverlay) {
+				while db.step().unwrap() {}
+			}
+			let start = std::time::Instant::now();
+			while db.get(0, b"key").unwrap().is_some() {
+				assert!(start.elapsed() < std::time::Duration::from_secs(10));
+				std::thread::sleep(std::time::Duration::from_millis(1));
+			}
+			db.commit(vec![(0, b"key", Some(b"value2".to_vec()))]).unwrap();
+			assert_eq!(db.get(0, b"key").unwrap(), Some(b"value2".to_vec()), "{:?}", stages);
+			assert_eq!(db.get_size(0, b"key").unwrap(), Some(6));
+		}
+	}
+
+	#[test]
+	fn test_with_column_configs() {
+		let tmp = tempdir().unwrap();
+		let refc = crate::ColumnOptions { ref_counted: true, ..Default::default() };
+		let db = Db::with_column_configs(tmp.path(), &[Default::default(), refc.clone()]).unwrap();
+		assert_eq!(db.num_columns(), 2);
+		assert_eq!(db.column_options(1).unwrap(), &refc);
+		db.commit(vec![(1, b"key", Some(b"value".to_vec()))]).unwrap();
+		drop(db);
+
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].ref_counted = true;
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(1, b"key").unwrap(), Some(b"value".to_vec()));
+		drop(db);
+
+		let tmp = tempdir().unwrap();
+		let invalid = crate::ColumnOptions { target_load_factor: 2.0, ..Default::default() };
+		assert!(matches!(Db::with_column_configs(tmp.path(), &[invalid]), Err(Error::InvalidConfiguration(_))));
+	}
+
+	#[test]
+	fn test_column_options() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].ref_counted = true;
+		options.columns[1].compression = crate::CompressionType::Lz4;
+		options.columns[1].max_queue_share = Some(0.5);
+		Db::open_or_create(&options).unwrap();
+		options.columns[1].max_queue_share = None;
+
+		let db = Db::open(&options).unwrap();
+		assert!(!db.column_options(0).unwrap().ref_counted);
+		assert_eq!(db.column_options(1).unwrap(), &options.columns[1]);
+		assert!(matches!(db.column_options(2), Err(Error::InvalidColumn(2))));
+		let metadata = db.metadata();
+		assert_eq!(metadata.version, crate::options::CURRENT_VERSION);
+		assert_eq!(metadata.columns, options.columns);
+		assert_eq!(metadata.encrypted, cfg!(feature = "encryption"));
+	}
+
+	#[test]
+	fn test_unsupported_version() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		Db::open_or_create(&options).unwrap();
+		let path = tmp.path().join("metadata");
+		let current = crate::options::CURRENT_VERSION;
+		let metadata = std::fs::read_to_string(&path).unwrap();
+		// A newer version may store settings this one can't parse.
+		let metadata = metadata.replace(&format!("version={}", current), &format!("version={}", current + 1));
+		std::fs::write(&path, format!("{}col1=unknown_setting: 1\n", metadata)).unwrap();
+		let err = Db::open(&options).unwrap_err();
+		assert!(matches!(err, Error::UnsupportedVersion { found, supported } if found == current + 1 && supported == current));
+		assert_eq!(err.code(), crate::ErrorCode::InvalidConfiguration);
+	}
+
+	#[test]
+	fn test_version_upgrade() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		Db::open_or_create(&options).unwrap().commit(vec![(0, b"key", Some(b"value".to_vec()))]).unwrap();
+		let path = tmp.path().join("metadata");
+		let current = crate::options::CURRENT_VERSION;
+		let set_version = |version: u32| {
+			let metadata = std::fs::read_to_string(&path).unwrap();
+			let metadata = metadata.replace(&format!("version={}", current), &format!("version={}", version));
+			std::fs::write(&path, metadata).unwrap();
+		};
+
+		// Version 5 databases only differ in the log format and are upgraded on open.
+		set_version(5);
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.metadata().version, current);
+		assert_eq!(db.get(0, b"key").unwrap(), Some(b"value".to_vec()));
+		drop(db);
+		assert!(std::fs::read_to_string(&path).unwrap().starts_with(&format!("version={}\n", current)));
+
+		// Older ones keep their version and don't write the newer log entries.
+		set_version(4);
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.metadata().version, 4);
+		assert!(matches!(
+			db.commit_with_meta(vec![(0, b"key", Some(b"other".to_vec()))], b"meta".to_vec()),
+			Err(Error::InvalidConfiguration(_))
+		));
+		assert!(matches!(db.prepare(vec![(0, b"key", Some(b"other".to_vec()))]), Err(Error::InvalidConfiguration(_))));
+		db.commit(vec![(0, b"key", Some(b"other".to_vec()))]).unwrap();
+		drop(db);
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"key").unwrap(), Some(b"other".to_vec()));
+	}
+
+	#[test]
+	fn test_column_is_empty() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 2);
+		let db = DbBuilder::new(options).build().unwrap();
+		assert!(db.column_is_empty(0).unwrap());
+		assert!(matches!(db.column_is_empty(2), Err(Error::InvalidColumn(2))));
+		db.commit(vec![(0, b"key", Some(b"value".to_vec()))]).unwrap();
+		assert!(!db.column_is_empty(0).unwrap());
+		// In the log overlay.
+		db.inner.process_commits().unwrap();
+		assert!(!db.column_is_empty(0).unwrap());
+		assert!(db.column_is_empty(1).unwrap());
+		db.commit(vec![(0, b"key", None)]).unwrap();
+		db.inner.process_commits().unwrap();
+		assert!(db.column_is_empty(0).unwrap());
+		drop(db);
+
+		let db = Db::with_columns(tmp.path(), 2).unwrap();
+		db.commit_durable(vec![(1, b"key", Some(b"value"))]).unwrap();
+		drop(db);
+		let db = Db::with_columns(tmp.path(), 2).unwrap();
+		assert!(db.column_is_empty(0).unwrap());
+		assert!(!db.column_is_empty(1).unwrap());
+	}
+
+	#[test]
+	fn test_append_only_column() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].index = crate::IndexMode::AppendOnly;
+		let db = Db::open_or_create(&options).unwrap();
+		assert!(db.column_is_empty(1).unwrap());
+		let blob = vec![3u8; 100 * 1024];
+		db.commit(vec![(1, b"key", Some(vec![1u8])), (0, b"key", Some(vec![0u8]))]).unwrap();
+		db.commit(vec![(1, &b"key"[..], Some(vec![2u8])), (1, &b"blob"[..], Some(blob.clone()))]).unwrap();
+		assert!(matches!(db.get(1, b"key"), Err(Error::InvalidInput(_))));
+		assert!(matches!(db.get_size(1, b"key"), Err(Error::InvalidInput(_))));
+		assert!(matches!(db.commit(vec![(1, b"key", None)]), Err(Error::InvalidInput(_))));
+		assert_eq!(db.get(0, b"key").unwrap(), Some(vec![0u8]));
+		drop(db);
+
+		let db = Db::open(&options).unwrap();
+		let mut values = Vec::new();
+		db.iter_column_while(1, IterSnapshotPolicy::CommitOverlayIncluded, |state| {
+			values.push(state.value);
+			true
+		}).unwrap();
+		values.sort();
+		assert_eq!(values, vec![vec![1u8], vec![2u8], blob]);
+		assert!(!db.column_is_empty(1).unwrap());
+		assert!(!tmp.path().join("index_01_16").exists());
+		drop(db);
+
+		options.columns[1].index = crate::IndexMode::Hash;
+		assert!(Db::open(&options).is_err());
+	}
+
+	#[test]
+	fn test_fixed_value_len_column() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].fixed_value_len = Some(16);
+		let db = Db::open_or_create(&options).unwrap();
+		let values: Vec<_> = (0u8 .. 100).map(|i| [i; 16].to_vec()).collect();
+		db.commit(values.iter().map(|v| (1, v.clone(), Some(v.clone())))).unwrap();
+		assert!(matches!(db.commit(vec![(1, b"key", Some(vec![0u8; 15]))]), Err(Error::InvalidInput(_))));
+		assert!(matches!(db.commit(vec![(1, b"key", Some(vec![0u8; 17]))]), Err(Error::InvalidInput(_))));
+		db.commit(vec![(1, values[0].clone(), None), (1, values[1].clone(), Some(values[2].clone()))]).unwrap();
+		drop(db);
+
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(1, &values[0]).unwrap(), None);
+		assert_eq!(db.get(1, &values[1]).unwrap(), Some(values[2].clone()));
+		for v in &values[2..] {
+			assert_eq!(db.get(1, v).unwrap(), Some(v.clone()));
+			assert_eq!(db.get_size(1, v).unwrap(), Some(16));
+		}
+		assert!(tmp.path().join("table_01_00").exists());
+		assert!(!tmp.path().join("table_01_01").exists());
+		drop(db);
+
+		options.columns[1].fixed_value_len = None;
+		assert!(Db::open(&options).is_err());
+	}
+
+	#[test]
+	fn test_thread_spawn_error() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		Db::open_or_create(&options).unwrap().commit(vec![(0, b"key", Some(b"value".to_vec()))]).unwrap();
+		// Fail after some of the prefetch threads and after some of the pipeline workers.
+		for started in [1, super::PREFETCH_THREADS + 2] {
+			FAIL_SPAWN_AFTER.with(|n| n.set(Some(started)));
+			assert!(matches!(Db::open(&options), Err(Error::ThreadSpawn(_))));
+			FAIL_SPAWN_AFTER.with(|n| n.set(None));
+		}
+		// Workers started before the failure are stopped and the database is unlocked.
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"key").unwrap(), Some(b"value".to_vec()));
+	}
+
+	#[test]
+	fn test_forget() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		let hook_called = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+		let called = hook_called.clone();
+		db.add_shutdown_hook(move |_| called.store(true, Ordering::SeqCst));
+		let keys: Vec<_> = (0u32 .. 100).map(|i| i.to_le_bytes()).collect();
+		for k in &keys {
+			db.commit_durable(vec![(0, k, Some(k.to_vec()))]).unwrap();
+		}
+		db.forget();
+		assert!(!hook_called.load(Ordering::SeqCst));
+
+		let db = Db::open(&options).unwrap();
+		for k in &keys {
+			assert_eq!(db.get(0, k).unwrap(), Some(k.to_vec()));
+		}
+	}
+
+	#[test]
+	fn test_column_mode() {
+		use crate::ColumnMode;
+		use std::sync::atomic::AtomicBool;
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 2);
+		let db = std::sync::Arc::new(Db::open_or_create(&options).unwrap());
+		db.commit(vec![(0, b"key", Some(b"value".to_vec())), (1, b"key", Some(b"value".to_vec()))]).unwrap();
+		let read_only = std::sync::Arc::new(AtomicBool::new(false));
+		let offline = std::sync::Arc::new(AtomicBool::new(false));
+		let threads: Vec<_> = (0 .. 4u32).map(|t| {
+			let db = db.clone();
+			let read_only = read_only.clone();
+			let offline = offline.clone();
+			std::thread::spawn(move || {
+				for i in 0u32 .. {
+					let switched = read_only.load(Ordering::SeqCst);
+					let key = (t * 1_000_000 + i).to_le_bytes();
+					match db.commit(vec![(0, key, Some(b"value".to_vec()))]) {
+						Ok(_) => assert!(!switched, "Commit accepted after the switch"),
+						Err(Error::ColumnReadOnly(0)) | Err(Error::ColumnOffline(0)) => (),
+						Err(e) => panic!("Unexpected error {:?}", e),
+					}
+					let switched = offline.load(Ordering::SeqCst);
+					match db.get(0, b"key") {
+						Ok(v) => {
+							assert!(!switched, "Read allowed after the switch");
+							assert_eq!(v, Some(b"value".to_vec()));
+						},
+						Err(Error::ColumnOffline(0)) => {
+							if switched {
+								break;
+							}
+						},
+						Err(e) => panic!("Unexpected error {:?}", e),
+					}
+				}
+			})
+		}).collect();
+		std::thread::sleep(std::time::Duration::from_millis(50));
+		db.set_column_mode(0, ColumnMode::ReadOnly, false).unwrap();
+		read_only.store(true, Ordering::SeqCst);
+		std::thread::sleep(std::time::Duration::from_millis(50));
+		db.set_column_mode(0, ColumnMode::Offline, false).unwrap();
+		offline.store(true, Ordering::SeqCst);
+		for t in threads {
+			t.join().unwrap();
+		}
+		assert_eq!(db.column_mode(0).unwrap(), ColumnMode::Offline);
+		assert!(matches!(db.iter_column_while(0, IterSnapshotPolicy::CommitOverlayIncluded, |_| true), Err(Error::ColumnOffline(0))));
+		assert!(matches!(db.get_across(&[1, 0], b"key"), Err(Error::ColumnOffline(0))));
+		// Other columns are not affected.
+		db.commit(vec![(1, b"key", Some(b"value2".to_vec()))]).unwrap();
+		assert_eq!(db.get(1, b"key").unwrap(), Some(b"value2".to_vec()));
+		drop(db);
+
+		// Modes that are not persisted are reset on reopen.
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.column_mode(0).unwrap(), ColumnMode::ReadWrite);
+		db.set_column_mode(0, ColumnMode::ReadOnly, true).unwrap();
+		db.set_column_mode(1, ColumnMode::Offline, false).unwrap();
+		assert!(matches!(db.commit(vec![(0, b"key", Some(b"value".to_vec()))]), Err(Error::ColumnReadOnly(0))));
+		assert_eq!(db.get(0, b"key").unwrap(), Some(b"value".to_vec()));
+		drop(db);
+
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.column_mode(0).unwrap(), ColumnMode::ReadOnly);
+		assert_eq!(db.column_mode(1).unwrap(), ColumnMode::ReadWrite);
+		db.set_column_mode(0, ColumnMode::ReadWrite, true).unwrap();
+		drop(db);
+
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.column_mode(0).unwrap(), ColumnMode::ReadWrite);
+		db.commit(vec![(0, b"key", Some(b"value2".to_vec()))]).unwrap();
+		assert_eq!(db.get(0, b"key").unwrap(), Some(b"value2".to_vec()));
+	}
+
+	#[test]
+	fn test_debug() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 2);
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(vec![(0, b"key", Some(b"value".to_vec()))]).unwrap();
+		let debug = format!("{:?}", db);
+		assert!(debug.starts_with("Db { path: "));
+		assert!(debug.contains("num_columns: 2, pipeline_stages: Standard"));
+		assert!(debug.contains("last_committed_id: 1, bg_err: None"));
+		let _err = db.inner.bg_err.lock();
+		assert!(format!("{:?}", db.inner).contains("bg_err: \"<locked>\""));
+	}
+
+	#[test]
+	fn test_commit_no_overlay() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = DbBuilder::new(options.clone()).build().unwrap();
+		let keys: Vec<_> = (0u32 .. 1000).map(|i| i.to_le_bytes()).collect();
+		db.commit(keys.iter().map(|k| (0, k, Some(b"value".to_vec())))).unwrap();
+		while db.inner.process_commits().unwrap() {}
+		assert!(db.inner.commit_overlay.read()[0].is_empty());
+
+		db.commit_no_overlay(keys.iter().map(|k| (0, k, None::<Vec<u8>>))).unwrap();
+		db.commit_no_overlay(vec![(0, b"new", Some(b"value"))]).unwrap();
+		assert!(db.inner.commit_overlay.read()[0].is_empty());
+		// Not visible until written to the log.
+		assert_eq!(db.get(0, &keys[0]).unwrap(), Some(b"value".to_vec()));
+		assert_eq!(db.get(0, b"new").unwrap(), None);
+		while db.inner.process_commits().unwrap() {}
+		assert!(keys.iter().all(|k| db.get(0, k).unwrap().is_none()));
+		assert_eq!(db.get(0, b"new").unwrap(), Some(b"value".to_vec()));
+		drop(db);
+
+		let db = Db::open(&options).unwrap();
+		assert!(keys.iter().all(|k| db.get(0, k).unwrap().is_none()));
+		assert_eq!(db.get(0, b"new").unwrap(), Some(b"value".to_vec()));
+	}
+
+	#[test]
+	fn test_commit_during_shutdown() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		let committers: Vec<_> = (0 .. 4u32).map(|t| {
+			let inner = db.inner.clone();
+			std::thread::spawn(move || {
+				let mut accepted = Vec::new();
+				for i in 0u32 .. {
+					let key = [t.to_le_bytes(), i.to_le_bytes()].concat();
+					match inner.commit(vec![(0, key.clone(), Some(key.clone()))]) {
+						Ok(_) => accepted.push(key),
+						Err(Error::DatabaseShutdown) => break,
+						Err(e) => panic!("Unexpected error: {:?}", e),
+					}
+				}
+				accepted
+			})
+		}).collect();
+		std::thread::sleep(std::time::Duration::from_millis(20));
+		drop(db);
+		let accepted: Vec<_> = committers.into_iter().flat_map(|t| t.join().unwrap()).collect();
+
+		let db = Db::open(&options).unwrap();
+		for key in accepted {
+			assert_eq!(db.get(0, &key).unwrap(), Some(key));
+		}
+	}
+
+	#[test]
+	fn test_invalid_column() {
+		let tmp = tempdir().unwrap();
+		let db = Db::with_columns(tmp.path(), 2).unwrap();
+		let err = db.commit(vec![(0, b"key", Some(b"value".to_vec())), (2, b"key", Some(b"value".to_vec()))]);
+		assert!(matches!(err, Err(Error::InvalidColumn(2))));
+		assert!(matches!(db.commit_raw(vec![(5, Default::default(), None)]), Err(Error::InvalidColumn(5))));
+		let mut commit = CoordinatedCommit::new();
+		commit.add(&db, vec![(0, b"key", Some(b"value")), (3, b"key", None)]);
+		assert!(matches!(commit.commit(), Err(Error::InvalidColumn(3))));
+		// Nothing was queued.
+		assert_eq!(db.get(0, b"key").unwrap(), None);
+	}
+
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn test_lock_pid() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let lock_path = tmp.path().join("lock");
+		let db = Db::open_or_create(&options).unwrap();
+		assert_eq!(std::fs::read_to_string(&lock_path).unwrap().trim(), std::process::id().to_string());
+		drop(db);
+		assert_eq!(std::fs::read_to_string(&lock_path).unwrap(), "");
+
+		// Process 1 is always running.
+		std::fs::write(&lock_path, "1\n").unwrap();
+		assert!(matches!(Db::open(&options), Err(Error::AlreadyOpen { pid: 1 })));
+		// Stale process id.
+		std::fs::write(&lock_path, format!("{}\n", u32::MAX)).unwrap();
+		let db = Db::open(&options).unwrap();
+		assert_eq!(std::fs::read_to_string(&lock_path).unwrap().trim(), std::process::id().to_string());
+		drop(db);
+	}
+
+	#[test]
+	fn test_damaged_auxiliary_files() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.stats_history = true;
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(vec![(0, b"key", Some(b"value".to_vec()))]).unwrap();
+		drop(db);
+		assert!(tmp.path().join("stats.txt").exists());
+
+		let path = |name: &str| tmp.path().join(name);
+		let reopen = || {
+			let db = Db::open(&options).unwrap();
+			assert_eq!(db.get(0, b"key").unwrap(), Some(b"value".to_vec()));
+		};
+		// Missing.
+		for name in ["stats.txt", "stats.ndjson", "lock"] {
+			std::fs::remove_file(path(name)).unwrap();
+			reopen();
+		}
+		// Truncated or garbled.
+		for name in ["stats.txt", "stats.ndjson", "lock"] {
+			std::fs::write(path(name), b"\xff\xfe\x00garbage").unwrap();
+			reopen();
+			std::fs::OpenOptions::new().write(true).open(path(name)).unwrap().set_len(0).unwrap();
+			reopen();
+		}
+		// Leftovers of interrupted writes are removed.
+		let leftovers = ["metadata.tmp", "stats.txt.tmp", "prepared_0000000000000001.tmp"];
+		for name in leftovers {
+			std::fs::write(path(name), b"partial").unwrap();
+		}
+		reopen();
+		for name in leftovers {
+			assert!(!path(name).exists(), "{}", name);
+		}
+		assert_eq!(Db::open(&options).unwrap().pending_prepared().unwrap().len(), 0);
+
+		// Required files still fail the open, naming the file.
+		std::fs::write(path("metadata"), b"version=5\n").unwrap();
+		let err = Db::open(&options).unwrap_err();
+		assert!(err.to_string().contains(&path("metadata").display().to_string()), "{}", err);
+		std::fs::write(path("metadata"), b"version\n").unwrap();
+		let err = Db::open(&options).unwrap_err();
+		assert_eq!(err.context().and_then(|c| c.path.clone()), Some(path("metadata")));
+	}
+
+	#[test]
+	fn test_content_hash() {
+		let tmp = tempdir().unwrap();
+		let open = |name: &str, compression| {
+			let mut options = Options::with_columns(&tmp.path().join(name), 2);
+			options.salt = Some([7; 32]);
+			options.columns[1].compression = compression;
+			Db::open_or_create(&options).unwrap()
+		};
+		let a = open("a", crate::CompressionType::NoCompression);
+		let b = open("b", crate::CompressionType::Lz4);
+		let empty = a.content_hash(None).unwrap();
+		for i in 0 .. 100u32 {
+			a.commit_durable(vec![(1, i.to_le_bytes(), Some(vec![i as u8; 5000]))]).unwrap();
+		}
+		for i in (0 .. 100u32).rev() {
+			b.commit_durable(vec![(1, i.to_le_bytes(), Some(vec![i as u8; 5000]))]).unwrap();
+		}
+		assert_ne!(a.content_hash(None).unwrap(), empty);
+		assert_eq!(a.content_hash(None).unwrap(), b.content_hash(None).unwrap());
+		assert_eq!(a.content_hash(Some(0)).unwrap(), b.content_hash(Some(0)).unwrap());
+		assert_ne!(a.content_hash(Some(0)).unwrap(), a.content_hash(Some(1)).unwrap());
+		assert!(matches!(a.content_hash(Some(2)), Err(Error::InvalidColumn(2))));
+
+		b.commit_durable(vec![(1, 5u32.to_le_bytes(), None::<Vec<u8>>)]).unwrap();
+		assert_ne!(a.content_hash(None).unwrap(), b.content_hash(None).unwrap());
+		a.commit_durable(vec![(1, 5u32.to_le_bytes(), None::<Vec<u8>>)]).unwrap();
+		let hash = a.content_hash(None).unwrap();
+		assert_eq!(hash, b.content_hash(None).unwrap());
+		drop(a);
+		assert_eq!(open("a", crate::CompressionType::NoCompression).content_hash(None).unwrap(), hash);
+	}
+
+	#[test]
+	fn test_open_exclusive() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].ref_counted = true;
+		let db = Db::open_or_create(&options).unwrap();
+		assert!(!db.is_exclusive());
+		assert!(matches!(db.clear_column(0), Err(Error::RequiresExclusive)));
+		drop(db);
+
+		let db = Db::open_exclusive(&options).unwrap();
+		assert!(db.is_exclusive());
+		assert!(db.workers.lock().commit.is_none() && db.workers.lock().log.is_none());
+		for i in 0 .. 100u32 {
+			db.commit(vec![(0, i.to_le_bytes().to_vec(), Some(vec![i as u8; 100])), (1, i.to_le_bytes().to_vec(), Some(vec![1]))]).unwrap();
+		}
+		db.commit(vec![(1, 0u32.to_le_bytes(), Some(vec![1]))]).unwrap();
+		db.commit_durable(vec![(0, b"durable", Some(b"value"))]).unwrap();
+		// Written and enacted without background workers.
+		assert!(db.inner.commit_queue.commits.is_empty());
+		assert!(db.inner.commit_overlay.read().iter().all(|o| o.is_empty()));
+		assert_eq!(db.get(0, &7u32.to_le_bytes()).unwrap(), Some(vec![7u8; 100]));
+		let mut commit = CoordinatedCommit::new();
+		commit.add(&db, vec![(0, b"key", Some(b"value"))]);
+		assert!(matches!(commit.commit(), Err(Error::InvalidInput(_))));
+
+		db.clear_column(1).unwrap();
+		assert_eq!(db.get(1, &0u32.to_le_bytes()).unwrap(), None);
+		assert_eq!(db.get(1, &7u32.to_le_bytes()).unwrap(), None);
+		db.commit(vec![(0, b"last", Some(b"value".to_vec()))]).unwrap();
+		drop(db);
+
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, &7u32.to_le_bytes()).unwrap(), Some(vec![7u8; 100]));
+		assert_eq!(db.get(0, b"durable").unwrap(), Some(b"value".to_vec()));
+		assert_eq!(db.get(0, b"last").unwrap(), Some(b"value".to_vec()));
+		assert_eq!(db.get(1, &3u32.to_le_bytes()).unwrap(), None);
+	}
+
+	#[test]
+	fn test_iter_tier_offset() {
+		let tmp = tempdir().unwrap();
+		let db = Db::with_columns(tmp.path(), 1).unwrap();
+		db.commit_durable(vec![
+			(0, &b"small1"[..], Some(vec![1u8; 10])),
+			(0, &b"small2"[..], Some(vec![2u8; 10])),
+			(0, &b"large"[..], Some(vec![3u8; 10000])),
+		]).unwrap();
+		let mut entries = Vec::new();
+		db.iter_column_while(0, IterSnapshotPolicy::CommitOverlayIncluded, |state| {
+			entries.push((state.value[0], state.tier, state.offset));
+			true
+		}).unwrap();
+		entries.sort();
+		assert_eq!(entries.len(), 3);
+		assert_eq!(entries[0].1, entries[1].1);
+		assert_ne!(entries[0].2, entries[1].2);
+		assert!(entries[2].1 > entries[0].1);
+	}
+
+	#[test]
+	fn test_commit_with_meta() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = DbBuilder::new(options.clone()).build().unwrap();
+		assert!(matches!(
+			db.commit_with_meta(vec![(0, b"key", Some(b"value"))], vec![0; MAX_RECORD_META + 1]),
+			Err(Error::InvalidInput(_))
+		));
+		db.commit(vec![(0, b"plain", Some(b"value".to_vec()))]).unwrap();
+		db.commit_with_meta(vec![(0, b"key", Some(b"value"))], b"block 1".to_vec()).unwrap();
+		// Write the log, but don't enact it.
+		while db.inner.process_commits().unwrap() {}
+		db.inner.flush_logs(0).unwrap();
+		let records = db.records_with_meta();
+		assert_eq!(records.len(), 1);
+		let record_id = records[0];
+		assert_eq!(db.record_meta(record_id), Some(b"block 1".to_vec()));
+		assert_eq!(db.record_meta(record_id - 1), None);
+		db.forget();
+
+		// Restored when the log is replayed.
+		let db = Db::open(&options).unwrap();
+		assert!(db.was_recovered());
+		assert_eq!(db.record_meta(record_id), Some(b"block 1".to_vec()));
+		assert_eq!(db.record_meta(record_id - 1), None);
+		assert_eq!(db.get(0, b"key").unwrap(), Some(b"value".to_vec()));
+	}
+
+	#[test]
+	fn test_log_compression() {
+		// Values with a lot of repetition, but still distinct.
+		let values: Vec<Vec<u8>> = (0 .. 64u32).map(|i| {
+			(0 .. 1000u32).flat_map(|j| ((i + j % 8) as u16).to_le_bytes()).collect()
+		}).collect();
+		let log_size = |compression: Option<CompressionType>| {
+			let tmp = tempdir().unwrap();
+			let mut options = Options::with_columns(tmp.path(), 1);
+			options.log_compression = compression;
+			// Encrypted values don't compress.
+			#[cfg(feature = "encryption")]
+			{
+				options.encryption_key = None;
+			}
+			let db = DbBuilder::new(options.clone())
+				.fixtures(values.iter().enumerate().map(|(i, v)| (0, [i as u8], Some(v.clone()))))
+				.stage(EnableCommitPipelineStages::LogOverlay)
+				.build()
+				.unwrap();
+			let size: u64 = db.log_files().unwrap().iter().map(|f| f.path.metadata().unwrap().len()).sum();
+			db.forget();
+
+			// Replayed from the log on open.
+			let db = Db::open(&options).unwrap();
+			assert!(db.was_recovered());
+			for (i, v) in values.iter().enumerate() {
+				assert_eq!(db.get(0, &[i as u8]).unwrap().as_ref(), Some(v));
+			}
+			size
+		};
+		let uncompressed = log_size(None);
+		assert!(log_size(Some(CompressionType::Lz4)) * 2 < uncompressed);
+		assert!(log_size(Some(CompressionType::Snappy)) * 2 < uncompressed);
+
+		// Logs written with different settings are replayed together.
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		let db = DbBuilder::new(options.clone()).build().unwrap();
+		db.commit(vec![(0, b"plain", Some(values[0].clone()))]).unwrap();
+		db.advance_to(EnableCommitPipelineStages::LogOverlay).unwrap();
+		db.forget();
+		options.log_compression = Some(CompressionType::Lz4);
+		let db = Db::open_with_stages(&options, EnableCommitPipelineStages::CommitOverlay).unwrap();
+		db.commit(vec![(0, b"compressed", Some(values[1].clone()))]).unwrap();
+		db.advance_to(EnableCommitPipelineStages::LogOverlay).unwrap();
+		db.forget();
+		options.log_compression = None;
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"plain").unwrap(), Some(values[0].clone()));
+		assert_eq!(db.get(0, b"compressed").unwrap(), Some(values[1].clone()));
+	}
+
+	#[test]
+	fn test_audit_commit_overlay() {
+		let tmp = tempdir().unwrap();
+		let db = DbBuilder::with_columns(tmp.path(), 1)
+			.fixture(0, b"enacted", Some(b"value".to_vec()))
+			.stage(EnableCommitPipelineStages::DbFile)
+			.build()
+			.unwrap();
+		assert_eq!(db.inner.enacted_commit.load(Ordering::SeqCst), 1);
+		assert_eq!(db.inner.audit_commit_overlay(), 0);
+
+		// An entry of the enacted commit that was missed by the cleanup.
+		let key = db.inner.columns[0].hash(b"missed");
+		db.inner.commit_overlay.write()[0].insert(key, (1, Some(b"value".to_vec())));
+		db.commit(vec![(0, b"queued", Some(b"value".to_vec()))]).unwrap();
+		assert_eq!(db.inner.audit_commit_overlay(), 1);
+		assert_eq!(db.inner.commit_overlay.read()[0].len(), 1);
+		assert_eq!(db.get(0, b"queued").unwrap(), Some(b"value".to_vec()));
+
+		db.advance_to(EnableCommitPipelineStages::DbFile).unwrap();
+		assert_eq!(db.inner.enacted_commit.load(Ordering::SeqCst), 2);
+		assert!(db.inner.logged_commits.lock().is_empty());
+	}
+
+	#[test]
+	fn test_no_wal() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].uniform = true;
+		options.no_wal = true;
+		// Keys that share the top 16 bits fill a single index chunk and trigger a reindex.
+		let keys: Vec<Vec<u8>> = (0 .. 256u32).map(|i| {
+			let mut key = vec![0x12, 0x34, i as u8];
+			key.extend_from_slice(&[0; 29]);
+			key
+		}).collect();
+		let db = DbBuilder::new(options.clone())
+			.fixtures(keys.iter().map(|k| (1, k.clone(), Some(k.clone()))))
+			.build()
+			.unwrap();
+		assert!(tmp.path().join("no_wal_dirty").exists());
+		db.commit(vec![(0, b"key", Some(b"value".to_vec()))]).unwrap();
+		db.advance_to(EnableCommitPipelineStages::Standard).unwrap();
+		assert!(db.commit_overlay_is_empty(0).unwrap());
+		assert!(db.log_overlay_is_empty());
+		assert!(db.log_files().unwrap().is_empty());
+		assert!(!db.inner.columns[1].is_reindexing());
+		assert!(std::fs::read_dir(tmp.path()).unwrap().all(|e| !e.unwrap().file_name().to_string_lossy().starts_with("log")));
+		drop(db);
+		assert!(!tmp.path().join("no_wal_dirty").exists());
+
+		options.no_wal = false;
+		let db = DbBuilder::new(options.clone()).build().unwrap();
+		assert_eq!(db.get(0, b"key").unwrap(), Some(b"value".to_vec()));
+		assert_eq!(db.get(1, &keys[100]).unwrap(), Some(keys[100].clone()));
+		// Logged records must be replayed before running without the log.
+		db.commit(vec![(0, b"key2", Some(b"value2".to_vec()))]).unwrap();
+		db.inner.process_commits().unwrap();
+		db.inner.flush_logs(0).unwrap();
+		db.forget();
+		options.no_wal = true;
+		assert!(matches!(Db::open(&options), Err(Error::InvalidConfiguration(_))));
+
+		options.no_wal = false;
+		drop(Db::open(&options).unwrap());
+		options.no_wal = true;
+		let db = Db::open(&options).unwrap();
+		db.commit(vec![(0, b"key3", Some(b"value3".to_vec()))]).unwrap();
+		db.forget();
+		assert!(matches!(Db::open(&options), Err(Error::UncleanNoWalShutdown)));
+		options.no_wal = false;
+		assert!(matches!(Db::open(&options), Err(Error::UncleanNoWalShutdown)));
+		options.force_open_dirty = true;
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"key2").unwrap(), Some(b"value2".to_vec()));
+		drop(db);
+		// The marker is removed once opened with the log.
+		options.force_open_dirty = false;
+		Db::open(&options).unwrap();
+	}
+
+	#[test]
+	fn test_was_recovered() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = DbBuilder::new(options.clone()).build().unwrap();
+		assert!(!db.was_recovered());
+		db.commit(vec![(0, b"key", Some(b"value".to_vec()))]).unwrap();
+		// Write the log, but don't enact it.
+		db.inner.process_commits().unwrap();
+		db.inner.flush_logs(0).unwrap();
+		db.forget();
+
+		let db = Db::open(&options).unwrap();
+		assert!(db.was_recovered());
+		assert_eq!(db.get(0, b"key").unwrap(), Some(b"value".to_vec()));
+		drop(db);
+
+		let db = Db::open(&options).unwrap();
+		assert!(!db.was_recovered());
+	}
+
+	#[test]
+	fn test_log_files() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = DbBuilder::new(options.clone()).build().unwrap();
+		assert_eq!(db.log_files().unwrap(), vec![]);
+		db.commit(vec![(0, b"key1", Some(b"value".to_vec()))]).unwrap();
+		db.commit(vec![(0, b"key2", Some(b"value".to_vec()))]).unwrap();
+		db.inner.process_commits().unwrap();
+		db.inner.process_commits().unwrap();
+		let files = db.log_files().unwrap();
+		assert_eq!(files.len(), 1);
+		assert_eq!(files[0].last_record, files[0].first_record + 1);
+		assert!(!files[0].flushed && !files[0].enacted);
+		assert!(files[0].path.exists());
+
+		db.inner.flush_logs(0).unwrap();
+		db.commit(vec![(0, b"key3", Some(b"value".to_vec()))]).unwrap();
+		db.inner.process_commits().unwrap();
+		let files = db.log_files().unwrap();
+		assert_eq!(files.len(), 2);
+		assert!(files[0].flushed && !files[0].enacted);
+		assert!(!files[1].flushed);
+		assert_eq!(files[1].first_record, files[0].last_record + 1);
+
+		// The first file is handed to the reader and then to cleanup by the next flushes.
+		db.inner.flush_logs(0).unwrap();
+		while db.inner.enact_logs(false).unwrap() {}
+		db.inner.flush_logs(0).unwrap();
+		let files = db.log_files().unwrap();
+		assert!(files[0].enacted);
+		assert!(files[1].flushed && !files[1].enacted);
+		let last_record = files[1].last_record;
+		db.forget();
+
+		// Replayed logs are enacted on open.
+		let db = Db::open(&options).unwrap();
+		let files = db.log_files().unwrap();
+		assert_eq!(files.last().unwrap().last_record, last_record);
+		assert!(files.iter().all(|f| f.flushed && f.enacted));
+		db.compact_log().unwrap();
+		assert_eq!(db.log_files().unwrap(), vec![]);
+	}
+
+	#[test]
+	fn test_kill_logs_stats_errors() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.stats_history = true;
+		let db = DbBuilder::new(options.clone()).build().unwrap();
+		db.commit(vec![(0, b"key", Some(b"value".to_vec()))]).unwrap();
+		// Stats files can't be created over directories.
+		std::fs::create_dir(tmp.path().join("stats.txt")).unwrap();
+		match db.inner.kill_logs() {
+			Err(Error::Io(_)) => (),
+			r => panic!("Unexpected result {:?}", r),
+		}
+		db.forget();
+
+		let db = DbBuilder::new(options.clone()).build().unwrap();
+		// History was still appended.
+		std::fs::remove_file(tmp.path().join("stats.ndjson")).unwrap();
+		std::fs::create_dir(tmp.path().join("stats.ndjson")).unwrap();
+		db.commit(vec![(0, b"key2", Some(b"value2".to_vec()))]).unwrap();
+		match db.inner.kill_logs() {
+			Err(Error::MultiError(errors)) => assert_eq!(errors.len(), 2),
+			r => panic!("Unexpected result {:?}", r),
+		}
+		db.forget();
+
+		// The commits were still written.
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"key").unwrap(), Some(b"value".to_vec()));
+		assert_eq!(db.get(0, b"key2").unwrap(), Some(b"value2".to_vec()));
+	}
+
+	#[test]
+	fn test_stats_interval() {
+		let tmp = tempdir().unwrap();
+		let stats_path = tmp.path().join("stats.txt");
+		let interval = std::time::Duration::from_secs(3600);
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.stats_interval = Some(interval);
+		let db = DbBuilder::new(options)
+			.fixture(0, b"key", Some(b"value".to_vec()))
+			.build()
+			.unwrap();
+		assert!(!db.step().unwrap());
+		assert!(!stats_path.exists());
+
+		// Make the write due instead of waiting.
+		*db.inner.last_stats_write.lock() = None;
+		assert!(db.step().unwrap());
+		let stats = std::fs::read_to_string(&stats_path).unwrap();
+		assert!(stats.starts_with("Column 0\n"));
+		assert!(stats.contains("Total values: 1\n"));
+		assert!(!tmp.path().join("stats.txt.tmp").exists());
+		assert!(!db.step().unwrap());
+
+		let other_path = tmp.path().join("other.txt");
+		db.write_stats(Some(&other_path)).unwrap();
+		assert_eq!(std::fs::read_to_string(&other_path).unwrap(), stats);
+	}
+
+	#[test]
+	fn test_on_log_cleanup() {
+		let tmp = tempdir().unwrap();
+		let archive = tempdir().unwrap();
+		let archived = std::sync::Arc::new(parking_lot::Mutex::new(Vec::new()));
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.on_log_cleanup = Some({
+			let archive = archive.path().to_path_buf();
+			let archived = archived.clone();
+			std::sync::Arc::new(move |path: &std::path::Path| {
+				let mut archived = archived.lock();
+				let copy = archive.join(format!("{}", archived.len()));
+				std::fs::copy(path, &copy).unwrap();
+				archived.push(copy);
+			})
+		});
+		let db = DbBuilder::new(options.clone()).build().unwrap();
+		for i in 0 .. 3u32 {
+			db.commit(vec![(0, i.to_le_bytes(), Some(b"value".to_vec()))]).unwrap();
+			db.advance_to(EnableCommitPipelineStages::Standard).unwrap();
+		}
+		assert!(!archived.lock().is_empty());
+		drop(db);
+
+		// The log left on shutdown is archived too. Archived logs are complete, in order.
+		let first_records: Vec<_> = archived.lock().iter()
+			.map(|path| crate::log::Log::open_log_file(path).unwrap().1.unwrap())
+			.collect();
+		assert_eq!(first_records.len(), 3);
+		assert!(first_records.windows(2).all(|w| w[0] < w[1]));
+		assert!(std::fs::read_dir(tmp.path()).unwrap().all(|e| !e.unwrap().file_name().to_string_lossy().starts_with("log")));
+	}
+
+	#[test]
+	fn test_shutdown_hooks() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let order = std::sync::Arc::new(parking_lot::Mutex::new(Vec::new()));
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			let first = order.clone();
+			db.add_shutdown_hook(move |db| {
+				first.lock().push(1);
+				db.commit(vec![(0, b"final", Some(b"value".to_vec()))]).unwrap();
+			});
+			let second = order.clone();
+			db.add_shutdown_hook(move |_| second.lock().push(2));
+		}
+		assert_eq!(*order.lock(), vec![2, 1]);
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"final").unwrap(), Some(b"value".to_vec()));
+	}
+
+	#[test]
+	fn test_column_count_change() {
+		let tmp = tempdir().unwrap();
+		{
+			let db = Db::open_or_create(&Options::with_columns(tmp.path(), 2)).unwrap();
+			db.commit(vec![(1, b"key1", Some(b"value1".to_vec()))]).unwrap();
+		}
+		let mut options = Options::with_columns(tmp.path(), 3);
+		assert!(matches!(
+			Db::open_or_create(&options).map(|_| ()),
+			Err(Error::ColumnCountMismatch { existing: 2, requested: 3 }),
+		));
+
+		options.allow_column_count_change = true;
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			assert_eq!(db.num_columns(), 3);
+			assert_eq!(db.get(1, b"key1").unwrap(), Some(b"value1".to_vec()));
+			db.commit(vec![(2, b"key2", Some(b"value2".to_vec()))]).unwrap();
+		}
+		{
+			// The extra column is now part of the database.
+			let db = Db::open(&Options::with_columns(tmp.path(), 3)).unwrap();
+			assert_eq!(db.get(2, b"key2").unwrap(), Some(b"value2".to_vec()));
+		}
+
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.allow_column_count_change = true;
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.num_columns(), 3);
+		assert_eq!(db.get(2, b"key2").unwrap(), Some(b"value2".to_vec()));
+		assert!(matches!(db.commit(vec![(2, b"key2", None)]), Err(Error::InvalidInput(_))));
+		db.commit(vec![(0, b"key0", Some(b"value0".to_vec()))]).unwrap();
+	}
+
+	#[test]
+	fn test_write_counters() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].uniform = true;
+		let db = DbBuilder::new(options).build().unwrap();
+		let commit = |changes: Vec<(u8, Vec<u8>, Option<Vec<u8>>)>| {
+			db.commit(changes).unwrap();
+			while db.inner.process_commits().unwrap() {}
+		};
+		commit(vec![
+			(0, b"key1".to_vec(), Some(b"value1".to_vec())),
+			(0, b"key2".to_vec(), Some(b"value2".to_vec())),
+			(0, b"key3".to_vec(), Some(b"value3".to_vec())),
+		]);
+		commit(vec![(0, b"key1".to_vec(), Some(b"value1b".to_vec())), (0, b"key2".to_vec(), None)]);
+		commit(vec![(0, b"key3".to_vec(), Some(b"value3b".to_vec())), (0, b"key4".to_vec(), None)]);
+		// Uniform keys with the same prefix share an index entry position.
+		commit(vec![(1, [1u8; 32].to_vec(), Some(b"a".to_vec()))]);
+		let mut key = [1u8; 32];
+		key[31] = 2;
+		commit(vec![(1, key.to_vec(), Some(b"b".to_vec()))]);
+
+		let stats = db.stats();
+		let col0 = &stats.columns[0];
+		assert_eq!(col0.writes_inserted, 3);
+		assert_eq!(col0.writes_replaced, 2);
+		assert_eq!(col0.writes_removed, 1);
+		assert_eq!(col0.write_collisions, 0);
+		assert_eq!(col0.writes_reindex, 0);
+		let col1 = &stats.columns[1];
+		assert_eq!(col1.writes_inserted, 2);
+		assert_eq!(col1.write_collisions, 1);
+		assert_eq!(stats.commits, 5);
+		assert!(stats.logged_bytes > 0);
+
+		// Database counters are only reset along with all columns.
+		db.clear_stats(Some(1));
+		assert_eq!(db.stats().commits, 5);
+		db.clear_stats(None);
+		let stats = db.stats();
+		let col0 = &stats.columns[0];
+		assert_eq!((col0.writes_inserted, col0.writes_replaced, col0.writes_removed), (0, 0, 0));
+		assert_eq!((stats.commits, stats.logged_bytes), (0, 0));
+	}
+
+	#[test]
+	fn test_commit_plan() {
+		let tmp = tempdir().unwrap();
+		let db = DbBuilder::with_columns(tmp.path(), 2)
+			.stage(EnableCommitPipelineStages::CommitOverlay)
+			.build()
+			.unwrap();
+		db.commit(vec![(0, &b"key1"[..], Some(b"value1".to_vec())), (1, b"key2", None), (0, b"key3", Some(b"value3".to_vec()))]).unwrap();
+		let commit = db.inner.dequeue_commit().unwrap();
+		assert_eq!(db.inner.commit_queue.bytes.load(Ordering::SeqCst), 0);
+		let plan = db.inner.plan_commit(commit).unwrap();
+		assert_eq!(plan.ops, 3);
+		assert!(!plan.reindex);
+
+		// Nothing is written or removed from the commit overlay until the plan is written.
+		assert_eq!(db.stats().commits, 0);
+		assert!(!db.commit_overlay_is_empty(0).unwrap());
+		let column = &db.inner.columns[0];
+		assert_eq!(column.get(&column.hash(b"key1"), db.inner.log.overlays()).unwrap(), None);
+
+		let record_id = plan.record_id;
+		assert_eq!(db.inner.write_commit_plan(plan).unwrap(), record_id);
+		assert_eq!(db.stats().commits, 1);
+		assert!(db.commit_overlay_is_empty(0).unwrap());
+		assert_eq!(column.get(&column.hash(b"key1"), db.inner.log.overlays()).unwrap(), Some(b"value1".to_vec()));
+		assert!(db.inner.dequeue_commit().is_none());
+	}
+
+	#[test]
+	fn test_stall_stats() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.stall_warn_threshold = std::time::Duration::ZERO;
+		let db = DbBuilder::new(options).max_commit_queue_bytes(16).build().unwrap();
+		let wait = |f: &dyn Fn() -> bool| {
+			while !f() {
+				std::thread::sleep(std::time::Duration::from_millis(1));
+			}
+		};
+		let stalls = |db: &Db| {
+			let stats = db.stats();
+			(stats.commit_queue_stalls, stats.log_queue_stalls, stats.fsync_stalls)
+		};
+		assert_eq!(stalls(&db), (0, 0, 0));
+
+		// The second commit waits until the first one is written to the log.
+		db.commit(vec![(0, b"key1", Some(vec![0u8; 32]))]).unwrap();
+		std::thread::scope(|s| {
+			let committer = s.spawn(|| db.commit(vec![(0, b"key2", Some(b"value2".to_vec()))]));
+			wait(&|| db.inner.waiting_committers.load(Ordering::SeqCst) > 0);
+			db.inner.process_commits().unwrap();
+			committer.join().unwrap().unwrap();
+		});
+		assert_eq!(stalls(&db), (1, 0, 0));
+
+		// The log worker waits until enough of the log is enacted.
+		let queue = &db.inner.log_queue_bytes;
+		queue.add(queue.capacity + 1);
+		std::thread::scope(|s| {
+			s.spawn(|| {
+				wait(&|| queue.waiters.load(Ordering::SeqCst) > 0);
+				queue.sub(queue.capacity + 1);
+			});
+			db.inner.process_commits().unwrap();
+		});
+		assert_eq!(stalls(&db), (1, 1, 0));
+
+		// Every log fsync takes at least the threshold.
+		while db.inner.flush_logs(0).unwrap() {}
+		assert_eq!(stalls(&db), (1, 1, 1));
+		assert_eq!(db.get(0, b"key2").unwrap(), Some(b"value2".to_vec()));
+
+		db.clear_stats(None);
+		assert_eq!(stalls(&db), (0, 0, 0));
+	}
+
+	#[test]
+	fn test_get_across() {
+		let tmp = tempdir().unwrap();
+		let db = DbBuilder::with_columns(tmp.path(), 3)
+			.fixtures(vec![(0, b"key", Some(b"value0".to_vec())), (1, b"abc", Some(b"value1".to_vec()))])
+			.stage(EnableCommitPipelineStages::LogOverlay)
+			.build()
+			.unwrap();
+		// Second commit stays in the commit overlay.
+		db.commit(vec![(2, b"key", Some(b"value2".to_vec()))]).unwrap();
+
+		assert_eq!(
+			db.get_across(&[2, 1, 0], b"key").unwrap(),
+			vec![Some(b"value2".to_vec()), None, Some(b"value0".to_vec())],
+		);
+		assert!(db.get_across(&[], b"key").unwrap().is_empty());
+	}
+
+	#[test]
+	fn test_stats_histograms() {
+		let tmp = tempdir().unwrap();
+		let sizes = [1usize, 3, 100, 100, 5000];
+		let db = DbBuilder::with_columns(tmp.path(), 1)
+			.fixtures(sizes.iter().enumerate().map(|(i, size)| (0, vec![i as u8], Some(vec![i as u8; *size]))))
+			.stage(EnableCommitPipelineStages::LogOverlay)
+			.build()
+			.unwrap();
+
+		// Query the column directly, bypassing the commit overlay.
+		let column = &db.inner.columns[0];
+		assert_eq!(column.get(&column.hash(&[2]), db.inner.log.overlays()).unwrap(), Some(vec![2; 100]));
+		assert_eq!(column.get(&column.hash(&[10]), db.inner.log.overlays()).unwrap(), None);
+		let stats = db.stats();
+		let column = &stats.columns[0];
+		let mut expected = vec![0; column.value_size_histogram.len()];
+		expected[1] = 1; // 1
+		expected[2] = 1; // 2..3
+		expected[7] = 2; // 64..127
+		expected[13] = 1; // 4096..8191
+		assert_eq!(column.value_size_histogram, expected);
+		assert_eq!(column.probe_histogram[0], 1);
+		assert_eq!(column.probe_histogram[1], 1);
+		assert_eq!(column.probe_histogram.iter().sum::<u64>(), 2);
+
+		db.clear_stats(None);
+		let column = &db.stats().columns[0];
+		assert!(column.value_size_histogram.iter().all(|c| *c == 0));
+		assert!(column.probe_histogram.iter().all(|c| *c == 0));
+	}
+
+	#[test]
+	fn test_overlay_initial_capacity() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.overlay_initial_capacity = 10_000;
+		let db = Db::open_or_create(&options).unwrap();
+		assert!(db.inner.commit_overlay.read().iter().all(|o| o.capacity() >= 10_000));
+		db.commit((0 .. 1000u32).map(|i| (1, i.to_le_bytes(), Some(b"value".to_vec())))).unwrap();
+		assert_eq!(db.get(1, &7u32.to_le_bytes()).unwrap(), Some(b"value".to_vec()));
+	}
+
+	#[test]
+	fn test_load_factor() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[0].target_load_factor = 0.5;
+		options.columns[1].uniform = true;
+		let db = DbBuilder::new(options).build().unwrap();
+		assert_eq!(db.load_factor(0).unwrap(), 0.0);
+		assert!(matches!(db.load_factor(2), Err(Error::InvalidColumn(2))));
+
+		// 2^16 chunks of 64 entries in a default index, half of which may be used.
+		db.commit((0 .. 1024u32).map(|i| (0, i.to_le_bytes(), Some(b"value".to_vec())))).unwrap();
+		db.advance_to(EnableCommitPipelineStages::LogOverlay).unwrap();
+		assert_eq!(db.load_factor(0).unwrap(), 1024.0 / (65536.0 * 32.0));
+
+		// Keys that share the top 16 bits fill a single chunk and trigger a reindex
+		// into a larger index, with all entries still counted.
+		let keys: Vec<Vec<u8>> = (0 .. 256u32).map(|i| {
+			let mut key = vec![0x12, 0x34, i as u8];
+			key.extend_from_slice(&[0; 29]);
+			key
+		}).collect();
+		db.commit(keys.iter().map(|k| (1, k.clone(), Some(k.clone())))).unwrap();
+		db.advance_to(EnableCommitPipelineStages::LogOverlay).unwrap();
+		assert!(db.inner.columns[1].is_reindexing());
+		let during = db.load_factor(1).unwrap();
+		assert!(during > 0.0);
+		db.advance_to(EnableCommitPipelineStages::DbFile).unwrap();
+		assert!(!db.inner.columns[1].is_reindexing());
+		assert_eq!(db.load_factor(1).unwrap(), during);
+	}
+
+	#[test]
+	fn test_mmap_window() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		for window in [1000, 32 * 1024] {
+			options.mmap_window = Some(window);
+			assert!(matches!(Db::open_or_create(&options), Err(Error::InvalidConfiguration(_))));
+		}
+		options.mmap_window = Some(crate::index::MIN_MMAP_WINDOW);
+		let db = DbBuilder::new(options.clone())
+			.fixtures((0 .. 4096u32).map(|i| (0, i.to_le_bytes(), Some(i.to_be_bytes().to_vec()))))
+			.build()
+			.unwrap();
+		assert_eq!(db.load_factor(0).unwrap(), 4096.0 / (65536.0 * 64.0));
+		// Reindex reads every chunk of the old index.
+		db.force_reindex(0).unwrap();
+		db.advance_to(EnableCommitPipelineStages::Standard).unwrap();
+		assert!(tmp.path().join("index_00_17").exists());
+		drop(db);
+
+		let db = Db::open(&options).unwrap();
+		for i in 0 .. 4096u32 {
+			assert_eq!(db.get(0, &i.to_le_bytes()).unwrap(), Some(i.to_be_bytes().to_vec()));
+		}
+		db.commit(vec![(0, b"key", Some(b"value".to_vec()))]).unwrap();
+		drop(db);
+
+		// Files written with windows are the same as fully mapped ones.
+		options.mmap_window = None;
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"key").unwrap(), Some(b"value".to_vec()));
+		assert_eq!(db.get(0, &4095u32.to_le_bytes()).unwrap(), Some(4095u32.to_be_bytes().to_vec()));
+	}
+
+	#[test]
+	fn test_max_table_file_size() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.max_table_file_size = Some(1024);
+		assert!(matches!(Db::open_or_create(&options), Err(Error::InvalidConfiguration(_))));
+
+		options.max_table_file_size = Some(64 * 1024);
+		let value = |i: u32| i.to_le_bytes().repeat(if i % 100 == 0 { 5000 } else { 50 });
+		let db = DbBuilder::new(options.clone())
+			.fixtures((0 .. 4096u32).map(|i| (0, i.to_le_bytes(), Some(value(i)))))
+			.build()
+			.unwrap();
+		for i in 0 .. 4096u32 {
+			assert_eq!(db.get(0, &i.to_le_bytes()).unwrap(), Some(value(i)));
+		}
+		drop(db);
+		let files: Vec<_> = std::fs::read_dir(tmp.path()).unwrap()
+			.map(|e| e.unwrap().file_name().into_string().unwrap())
+			.filter(|name| name.starts_with("table_00_"))
+			.collect();
+		assert!(files.iter().any(|name| name.ends_with(".1")));
+		for name in &files {
+			let len = std::fs::metadata(tmp.path().join(name)).unwrap().len();
+			assert!(len <= 64 * 1024, "{} is {} bytes", name, len);
+		}
+
+		let db = Db::open(&options).unwrap();
+		for i in 0 .. 4096u32 {
+			assert_eq!(db.get(0, &i.to_le_bytes()).unwrap(), Some(value(i)));
+		}
+		db.commit(vec![(0, 0u32.to_le_bytes(), None), (0, 4096u32.to_le_bytes(), Some(value(4096)))]).unwrap();
+		drop(db);
+
+		// The limit is stored in the metadata.
+		options.max_table_file_size = None;
+		assert!(matches!(Db::open(&options), Err(Error::InvalidConfiguration(_))));
+		options.max_table_file_size = Some(64 * 1024);
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, &0u32.to_le_bytes()).unwrap(), None);
+		assert_eq!(db.get(0, &4096u32.to_le_bytes()).unwrap(), Some(value(4096)));
+		assert_eq!(db.get(0, &4095u32.to_le_bytes()).unwrap(), Some(value(4095)));
+	}
+
+	#[test]
+	fn test_paranoid_checks() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 3);
+		options.paranoid_checks = true;
+		options.columns[1].ref_counted = true;
+		options.columns[1].preimage = true;
+		options.columns[2].ordered = true;
+		let db = DbBuilder::new(options)
+			.fixtures((0 .. 3).flat_map(|c| (0 .. 100u32).map(move |i| (c, i.to_le_bytes(), Some(vec![c; 1 + i as usize * 50])))))
+			.build()
+			.unwrap();
+		db.commit(vec![
+			(0, 1u32.to_le_bytes(), Some(b"replaced".to_vec())),
+			(0, 2u32.to_le_bytes(), None),
+			(1, 1u32.to_le_bytes(), Some(vec![1; 51])),
+			(1, 1u32.to_le_bytes(), Some(vec![1; 51])),
+			(1, 2u32.to_le_bytes(), None),
+			(1, 3u32.to_le_bytes(), Some(vec![1; 151])),
+			(1, 3u32.to_le_bytes(), None),
+			(2, 1u32.to_le_bytes(), None),
+		]).unwrap();
+		db.force_reindex(0).unwrap();
+		db.advance_to(EnableCommitPipelineStages::Standard).unwrap();
+		assert_eq!(db.get(0, &1u32.to_le_bytes()).unwrap(), Some(b"replaced".to_vec()));
+		assert_eq!(db.get(1, &2u32.to_le_bytes()).unwrap(), None);
+		assert_eq!(db.get(1, &3u32.to_le_bytes()).unwrap(), Some(vec![1; 151]));
+
+		// A change that the tables don't match is reported.
+		db.commit(vec![(0, b"key", Some(b"value".to_vec()))]).unwrap();
+		db.advance_to(EnableCommitPipelineStages::LogOverlay).unwrap();
+		for changes in db.inner.paranoid_changes.lock().values_mut() {
+			changes[0].2 = Some(b"other".to_vec());
+		}
+		let err = db.advance_to(EnableCommitPipelineStages::Standard).unwrap_err();
+		assert!(matches!(err.root(), Error::Corruption(_)), "{}", err);
+		assert_eq!(err.context().and_then(|c| c.column), Some(0));
+	}
+
+	#[test]
+	fn test_approximate_entry_count() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.stats = true;
+		let db = DbBuilder::new(options.clone())
+			.fixtures((0 .. 100u32).map(|i| (0, i.to_le_bytes(), Some(vec![1; 8]))))
+			.build()
+			.unwrap();
+		// Not saved yet.
+		assert_eq!(db.approximate_entry_count(0).unwrap(), 0);
+		db.collect_stats(&mut Vec::new(), None);
+		assert_eq!(db.approximate_entry_count(0).unwrap(), 100);
+		assert_eq!(db.approximate_entry_count(1).unwrap(), 0);
+		assert!(matches!(db.approximate_entry_count(2), Err(Error::InvalidColumn(2))));
+
+		db.commit((0 .. 10u32).map(|i| (0, i.to_le_bytes(), None))).unwrap();
+		db.advance_to(EnableCommitPipelineStages::Standard).unwrap();
+		assert_eq!(db.approximate_entry_count(0).unwrap(), 100);
+		// Saved on shutdown.
+		drop(db);
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.approximate_entry_count(0).unwrap(), 90);
+	}
+
+	#[test]
+	fn test_free_slot_stats() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.stats = true;
+		let db = DbBuilder::new(options.clone())
+			.fixtures((0 .. 100u32).map(|i| (0, i.to_le_bytes(), Some(vec![1; 8]))))
+			.build()
+			.unwrap();
+		let column = db.stats().columns.remove(0);
+		assert_eq!(column.total_values, 100);
+		assert_eq!(column.value_slots.iter().sum::<u64>(), 100);
+		assert_eq!(column.free_slots.iter().sum::<u64>(), 0);
+		assert_eq!(column.index_slots, 1 << 22);
+		assert!(!column.needs_compaction());
+
+		db.commit((0 .. 50u32).map(|i| (0, i.to_le_bytes(), None))).unwrap();
+		db.advance_to(EnableCommitPipelineStages::Standard).unwrap();
+		let column = db.stats().columns.remove(0);
+		assert_eq!(column.total_values, 50);
+		assert_eq!(column.value_slots.iter().sum::<u64>(), 100);
+		assert_eq!(column.free_slots.iter().sum::<u64>(), 50);
+		assert_eq!(column.free_slot_ratio(), 0.5);
+		assert!(column.needs_compaction());
+		let mut text = Vec::new();
+		db.collect_stats(&mut text, None);
+		assert!(String::from_utf8(text).unwrap().contains("Index slots: 50 occupied of 4194304"));
+		drop(db);
+
+		// Counted from the free lists on open, and reused by inserts.
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.stats().columns[0].free_slots.iter().sum::<u64>(), 50);
+		db.commit_durable((0 .. 20u32).map(|i| (0, i.to_le_bytes(), Some(vec![2; 8])))).unwrap();
+		let column = db.stats().columns.remove(0);
+		assert_eq!(column.total_values, 70);
+		assert_eq!(column.value_slots.iter().sum::<u64>(), 100);
+		assert_eq!(column.free_slots.iter().sum::<u64>(), 30);
+		assert!(!column.needs_compaction());
+	}
+
+	#[test]
+	fn test_error_context() {
+		let tmp = tempdir().unwrap();
+		let db = DbBuilder::with_columns(tmp.path(), 1)
+			.fixture(0, b"key", Some(b"value".to_vec()))
+			.build()
+			.unwrap();
+		let table = std::fs::read_dir(tmp.path()).unwrap()
+			.map(|e| e.unwrap().path())
+			.find(|p| p.file_name().unwrap().to_string_lossy().starts_with("table_00_"))
+			.unwrap();
+		std::fs::OpenOptions::new().write(true).open(&table).unwrap().set_len(0).unwrap();
+
+		let e = db.get(0, b"key").unwrap_err();
+		assert_eq!(e.code(), crate::ErrorCode::Io);
+		assert!(matches!(e.root(), Error::Io(_)));
+		let context = e.context().unwrap();
+		assert_eq!(context.operation, Some("read"));
+		assert_eq!(context.column, Some(0));
+		assert_eq!(context.path.as_deref(), Some(table.as_path()));
+		assert!(context.offset.is_some());
+		assert!(e.to_string().contains(&table.display().to_string()));
+		let source = std::error::Error::source(&e).unwrap();
+		assert!(matches!(source.downcast_ref::<Error>(), Some(Error::Io(_))));
+		// Errors that already say what failed are not wrapped.
+		assert!(matches!(db.get(1, b"key"), Err(Error::InvalidColumn(1))));
+	}
+
+	#[test]
+	fn test_hash_key() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].uniform = true;
+		options.salt = Some([7; 32]);
+		let db = DbBuilder::new(options).build().unwrap();
+		let expected = blake2_rfc::blake2b::blake2b(32, &[7; 32], b"key");
+		assert_eq!(&db.hash_key(0, b"key").unwrap()[..], expected.as_bytes());
+		let key: Vec<u8> = (0 .. 40u8).collect();
+		assert_eq!(&db.hash_key(1, &key).unwrap()[..], &key[.. 32]);
+		assert!(matches!(db.hash_key(1, b"short"), Err(Error::InvalidInput(_))));
+		assert!(matches!(db.hash_key(2, b"key"), Err(Error::InvalidColumn(2))));
+	}
+
+	#[test]
+	fn test_initial_index_bits() {
+		// Keys share the top 16 bits, so they all land in the same chunk of a
+		// default-sized index and spread over 16 chunks of a 20-bit index.
+		let keys: Vec<Vec<u8>> = (0 .. 512u32).map(|i| {
+			let mut key = vec![0x12, 0x34, ((i % 16) << 4) as u8];
+			key.extend_from_slice(&[0; 25]);
+			key.extend_from_slice(&i.to_be_bytes());
+			key
+		}).collect();
+		let reindexed = |initial_index_bits| {
+			let tmp = tempdir().unwrap();
+			let mut options = Options::with_columns(tmp.path(), 1);
+			options.columns[0].uniform = true;
+			options.columns[0].initial_index_bits = initial_index_bits;
+			let db = DbBuilder::new(options)
+				.fixtures(keys.iter().map(|k| (0, k.clone(), Some(k.clone()))))
+				.stage(EnableCommitPipelineStages::LogOverlay)
+				.build()
+				.unwrap();
+			let (_, plan) = db.inner.columns[0].reindex(&db.inner.log).unwrap();
+			!plan.is_empty()
+		};
+		assert!(reindexed(None));
+		assert!(!reindexed(Some(20)));
+
+		let mut options = crate::ColumnOptions::default();
+		options.initial_index_bits = Some(8);
+		assert!(!options.is_valid());
+		options.initial_index_bits = Some(20);
+		options.target_load_factor = 0.1;
+		assert!(!options.is_valid());
+		options.target_load_factor = 0.75;
+		assert!(options.is_valid());
+
+		// The default load factor is not written, so the metadata of existing databases
+		// stays the same.
+		let metadata = |tmp: &tempfile::TempDir| std::fs::read_to_string(tmp.path().join("metadata")).unwrap();
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].target_load_factor = 0.75;
+		drop(Db::open_or_create(&options).unwrap());
+		let written = metadata(&tmp);
+		assert_eq!(written.matches("load_factor").count(), 1);
+		assert!(written.contains("load_factor: 0.75"));
+		drop(Db::open(&options).unwrap());
+		assert_eq!(metadata(&tmp), written);
+	}
 }
@@ -0,0 +1,159 @@
+// Copyright 2015-2020 AXIA Technologies (UK) Ltd.
+// This file is part of AXIA.
+
+// AXIA is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// AXIA is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with AXIA.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Value encryption.
+//!
+//! Values are encrypted with XChaCha20-Poly1305 after compression. Each value
+//! gets a random 24 byte nonce, stored in front of the ciphertext. Nonces are
+//! not derived from the value location, because value table slots are reused
+//! and a repeated nonce would expose the plaintext.
+
+#[cfg(feature = "encryption")]
+use crate::{column::Salt, error::Error};
+use crate::error::Result;
+use crate::options::Options;
+
+/// Size of the encryption key.
+#[cfg(feature = "encryption")]
+pub const KEY_LEN: usize = 32;
+
+#[cfg(feature = "encryption")]
+const NONCE_LEN: usize = 24;
+#[cfg(feature = "encryption")]
+const TAG_LEN: usize = 16;
+
+/// Keyed hash of the salt, stored in the metadata to check the key on open
+/// without storing the key itself.
+#[cfg(feature = "encryption")]
+pub(crate) fn key_check(key: &[u8; KEY_LEN], salt: &Salt) -> [u8; 32] {
+	let mut check = [0u8; 32];
+	check.copy_from_slice(blake2_rfc::blake2b::blake2b(32, key, salt).as_bytes());
+	check
+}
+
+/// Encryption implementation.
+#[cfg(feature = "encryption")]
+pub(crate) struct Encryption {
+	cipher: chacha20poly1305::XChaCha20Poly1305,
+}
+
+/// Encryption is not available without the `encryption` feature.
+#[cfg(not(feature = "encryption"))]
+pub(crate) enum Encryption {}
+
+#[cfg(feature = "encryption")]
+impl Encryption {
+	pub(crate) fn from_options(options: &Options) -> Option<Self> {
+		options.encryption_key.as_ref().map(Self::new)
+	}
+
+	pub(crate) fn new(key: &[u8; KEY_LEN]) -> Self {
+		use chacha20poly1305::KeyInit;
+		Encryption {
+			cipher: chacha20poly1305::XChaCha20Poly1305::new(key.into()),
+		}
+	}
+
+	/// Number of bytes encryption adds to each value.
+	pub(crate) fn overhead(&self) -> usize {
+		NONCE_LEN + TAG_LEN
+	}
+
+	pub(crate) fn encrypt(&self, buf: &[u8]) -> Vec<u8> {
+		use chacha20poly1305::aead::Aead;
+		use rand::RngCore;
+		let mut nonce = [0u8; NONCE_LEN];
+		rand::thread_rng().fill_bytes(&mut nonce);
+		let ciphertext = self.cipher.encrypt(&nonce.into(), buf).expect("Buffer size is not limited");
+		let mut result = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+		result.extend_from_slice(&nonce);
+		result.extend_from_slice(&ciphertext);
+		result
+	}
+
+	pub(crate) fn decrypt(&self, buf: &[u8]) -> Result<Vec<u8>> {
+		use chacha20poly1305::aead::Aead;
+		if buf.len() < NONCE_LEN + TAG_LEN {
+			return Err(Error::Corruption("Encrypted value is too short".into()));
+		}
+		let (nonce, ciphertext) = buf.split_at(NONCE_LEN);
+		self.cipher.decrypt(nonce.into(), ciphertext)
+			.map_err(|_| Error::Corruption("Value decryption failed".into()))
+	}
+
+	/// Same as `decrypt`, but replaces the contents of `buf` with the plaintext,
+	/// keeping its allocation.
+	pub(crate) fn decrypt_in_place(&self, buf: &mut Vec<u8>) -> Result<()> {
+		use chacha20poly1305::AeadInPlace;
+		if buf.len() < NONCE_LEN + TAG_LEN {
+			return Err(Error::Corruption("Encrypted value is too short".into()));
+		}
+		let mut nonce = [0u8; NONCE_LEN];
+		nonce.copy_from_slice(&buf[.. NONCE_LEN]);
+		buf.drain(.. NONCE_LEN);
+		self.cipher.decrypt_in_place(&nonce.into(), &[], buf)
+			.map_err(|_| Error::Corruption("Value decryption failed".into()))
+	}
+}
+
+#[cfg(not(feature = "encryption"))]
+impl Encryption {
+	pub(crate) fn from_options(_options: &Options) -> Option<Self> {
+		None
+	}
+
+	pub(crate) fn overhead(&self) -> usize {
+		match *self {}
+	}
+
+	pub(crate) fn encrypt(&self, _buf: &[u8]) -> Vec<u8> {
+		match *self {}
+	}
+
+	pub(crate) fn decrypt(&self, _buf: &[u8]) -> Result<Vec<u8>> {
+		match *self {}
+	}
+
+	pub(crate) fn decrypt_in_place(&self, _buf: &mut Vec<u8>) -> Result<()> {
+		match *self {}
+	}
+}
+
+#[cfg(all(test, feature = "encryption"))]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_encrypt_decrypt() {
+		let encryption = Encryption::new(&[1; KEY_LEN]);
+		let value = b"value".to_vec();
+		let encrypted = encryption.encrypt(&value);
+		assert_eq!(encrypted.len(), value.len() + NONCE_LEN + TAG_LEN);
+		assert_eq!(encryption.decrypt(&encrypted).unwrap(), value);
+		let mut buf = encrypted.clone();
+		let ptr = buf.as_ptr();
+		encryption.decrypt_in_place(&mut buf).unwrap();
+		assert_eq!(buf, value);
+		assert_eq!(buf.as_ptr(), ptr);
+		// Same value gets a different nonce.
+		assert_ne!(encryption.encrypt(&value), encrypted);
+
+		let mut tampered = encrypted.clone();
+		*tampered.last_mut().unwrap() ^= 1;
+		assert!(encryption.decrypt(&tampered).is_err());
+		assert!(Encryption::new(&[2; KEY_LEN]).decrypt(&encrypted).is_err());
+	}
+}
@@ -15,11 +15,95 @@
 // along with AXIA.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::fmt;
+use std::path::PathBuf;
 use std::sync::Arc;
+use crate::column::ColId;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Broad error category, see `Error::code`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorCode {
+	/// IO error.
+	Io,
+	/// Database files are corrupted.
+	Corruption,
+	/// Options don't match the database or are invalid.
+	InvalidConfiguration,
+	/// Invalid argument, e.g. an out of range column.
+	InvalidArgument,
+	/// A background worker has failed. The database must be closed.
+	Background,
+	/// The database is opened by another process.
+	Locked,
+	/// Any other error.
+	Other,
+}
+
+/// Where an error happened. Fields are set where known.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ErrorContext {
+	/// What was being done, e.g. `"read"`.
+	pub operation: Option<&'static str>,
+	pub column: Option<ColId>,
+	pub path: Option<PathBuf>,
+	/// Offset in the file.
+	pub offset: Option<u64>,
+}
+
+impl ErrorContext {
+	pub fn new(operation: &'static str) -> ErrorContext {
+		ErrorContext { operation: Some(operation), ..Default::default() }
+	}
+
+	pub fn column(mut self, column: ColId) -> ErrorContext {
+		self.column = Some(column);
+		self
+	}
+
+	pub fn path(mut self, path: impl Into<PathBuf>) -> ErrorContext {
+		self.path = Some(path.into());
+		self
+	}
+
+	pub fn offset(mut self, offset: u64) -> ErrorContext {
+		self.offset = Some(offset);
+		self
+	}
+
+	// Fields already set were attached closer to the error and are kept.
+	fn merge(&mut self, outer: ErrorContext) {
+		self.operation = self.operation.or(outer.operation);
+		self.column = self.column.or(outer.column);
+		self.path = self.path.take().or(outer.path);
+		self.offset = self.offset.or(outer.offset);
+	}
+}
+
+impl fmt::Display for ErrorContext {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let mut separator = "";
+		let mut next = || std::mem::replace(&mut separator, ", ");
+		if let Some(operation) = self.operation {
+			write!(f, "{}{}", next(), operation)?;
+		}
+		if let Some(column) = self.column {
+			write!(f, "{}column {}", next(), column)?;
+		}
+		if let Some(path) = &self.path {
+			write!(f, "{}file {}", next(), path.display())?;
+		}
+		if let Some(offset) = self.offset {
+			write!(f, "{}offset {}", next(), offset)?;
+		}
+		Ok(())
+	}
+}
+
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
 	Io(std::io::Error),
 	Corruption(String),
@@ -28,6 +112,107 @@ pub enum Error {
 	Background(Arc<Error>),
 	Locked(std::io::Error),
 	Migration(String),
+	/// Number of columns in the options does not match the existing database.
+	ColumnCountMismatch { existing: usize, requested: usize },
+	/// The database is encrypted, but no encryption key was given.
+	EncryptionKeyRequired,
+	/// The encryption key does not match the one the database was created with.
+	InvalidEncryptionKey,
+	/// The lock file names another running process that has the database open.
+	AlreadyOpen { pid: u32 },
+	/// The operation is only allowed on databases opened with `Db::open_exclusive`.
+	RequiresExclusive,
+	/// Column id is out of range.
+	InvalidColumn(crate::column::ColId),
+	/// The database is shutting down and no longer accepts commits.
+	DatabaseShutdown,
+	/// The database was closed with `Db::close`.
+	Closed,
+	/// The commit has more operations than `Options::max_changeset_ops` allows.
+	ChangesetTooLarge { ops: usize, max: usize },
+	/// Inserting the commit could make the database use more disk space than
+	/// `Options::max_total_bytes` allows.
+	QuotaExceeded { projected: u64, max: u64 },
+	/// The commit has several operations on the same key. Only checked in debug builds.
+	DuplicateKeyInCommit { column: crate::column::ColId },
+	/// The column was made read-only with `Db::set_column_mode`.
+	ColumnReadOnly(crate::column::ColId),
+	/// The column was taken offline with `Db::set_column_mode`.
+	ColumnOffline(crate::column::ColId),
+	/// A background worker thread could not be started.
+	ThreadSpawn(std::io::Error),
+	/// The database was not shut down cleanly while running with `Options::no_wal`.
+	UncleanNoWalShutdown,
+	/// The database was written by a newer version of this crate. `supported` is the
+	/// newest database version this build can open.
+	UnsupportedVersion { found: u32, supported: u32 },
+	/// Several operations failed, e.g. when shutting down.
+	MultiError(Vec<Error>),
+	/// Background worker error with the backtrace of the worker that reported it.
+	/// Only produced when backtraces are enabled with `RUST_BACKTRACE`.
+	WithBacktrace { source: Box<Error>, backtrace: std::backtrace::Backtrace },
+	/// IO error or corruption with the operation, column, file and offset it happened at.
+	WithContext { source: Box<Error>, context: ErrorContext },
+}
+
+impl Error {
+	/// Attach context to IO and corruption errors. Other errors already say what failed
+	/// and are returned unchanged, so that they can still be matched directly.
+	pub(crate) fn with_context(self, context: ErrorContext) -> Error {
+		match self {
+			Error::WithContext { source, context: mut inner } => {
+				inner.merge(context);
+				Error::WithContext { source, context: inner }
+			},
+			Error::Io(_) | Error::Corruption(_) => Error::WithContext { source: Box::new(self), context },
+			e => e,
+		}
+	}
+
+	/// Context of an IO or corruption error, if known.
+	pub fn context(&self) -> Option<&ErrorContext> {
+		match self {
+			Error::WithContext { context, .. } => Some(context),
+			Error::WithBacktrace { source, .. } => source.context(),
+			_ => None,
+		}
+	}
+
+	/// The error without context or backtrace.
+	pub fn root(&self) -> &Error {
+		match self {
+			Error::WithContext { source, .. } | Error::WithBacktrace { source, .. } => source.root(),
+			e => e,
+		}
+	}
+
+	/// Error category, e.g. for FFI and logging. For `MultiError`, the category of the first error.
+	pub fn code(&self) -> ErrorCode {
+		match self {
+			Error::Io(_) | Error::ThreadSpawn(_) => ErrorCode::Io,
+			Error::Corruption(_) | Error::UncleanNoWalShutdown => ErrorCode::Corruption,
+			Error::InvalidConfiguration(_)
+			| Error::ColumnCountMismatch { .. }
+			| Error::EncryptionKeyRequired
+			| Error::InvalidEncryptionKey
+			| Error::UnsupportedVersion { .. } => ErrorCode::InvalidConfiguration,
+			Error::InvalidInput(_)
+			| Error::InvalidColumn(_)
+			| Error::ChangesetTooLarge { .. }
+			| Error::DuplicateKeyInCommit { .. } => ErrorCode::InvalidArgument,
+			Error::Background(_) => ErrorCode::Background,
+			Error::Locked(_) | Error::AlreadyOpen { .. } => ErrorCode::Locked,
+			Error::WithBacktrace { source, .. } | Error::WithContext { source, .. } => source.code(),
+			Error::MultiError(errors) => errors.first().map_or(ErrorCode::Other, Error::code),
+			Error::Migration(_)
+			| Error::RequiresExclusive
+			| Error::DatabaseShutdown
+			| Error::Closed
+			| Error::ColumnReadOnly(_)
+			| Error::ColumnOffline(_)
+			| Error::QuotaExceeded { .. } => ErrorCode::Other,
+		}
+	}
 }
 
 impl fmt::Display for Error {
@@ -40,10 +225,67 @@ impl fmt::Display for Error {
 			Error::Background(e) => write!(f, "Background worker error: {}", e),
 			Error::Locked(e) => write!(f, "Database file is in use. ({})", e),
 			Error::Migration(e) => write!(f, "Migration error: {}", e),
+			Error::ColumnCountMismatch { existing, requested } => write!(f,
+				"Column count mismatch: database has {} columns, options specify {}",
+				existing, requested,
+			),
+			Error::EncryptionKeyRequired => write!(f, "Database is encrypted, encryption key required"),
+			Error::InvalidEncryptionKey => write!(f, "Invalid encryption key"),
+			Error::AlreadyOpen { pid } => write!(f, "Database is already open in process {}", pid),
+			Error::RequiresExclusive => write!(f, "Operation requires a database opened in exclusive mode"),
+			Error::InvalidColumn(col) => write!(f, "Invalid column: {}", col),
+			Error::DatabaseShutdown => write!(f, "Database is shutting down"),
+			Error::Closed => write!(f, "Database is closed"),
+			Error::ChangesetTooLarge { ops, max } => write!(f, "Changeset has {} operations, at most {} allowed", ops, max),
+			Error::QuotaExceeded { projected, max } =>
+				write!(f, "Commit would use up to {} bytes of disk space, at most {} allowed", projected, max),
+			Error::DuplicateKeyInCommit { column } => write!(f, "Duplicate key in commit, column {}", column),
+			Error::ColumnReadOnly(col) => write!(f, "Column {} is read-only", col),
+			Error::ColumnOffline(col) => write!(f, "Column {} is offline", col),
+			Error::ThreadSpawn(e) => write!(f, "Failed to start a worker thread: {}", e),
+			Error::UncleanNoWalShutdown => write!(f,
+				"Database was not shut down cleanly while running without a write-ahead log and may be corrupted. \
+				Set `force_open_dirty` to open it anyway",
+			),
+			Error::UnsupportedVersion { found, supported } => write!(f,
+				"Database version {} was written by a newer version, at most {} is supported",
+				found, supported,
+			),
+			Error::MultiError(errors) => {
+				write!(f, "Multiple errors:")?;
+				for e in errors {
+					write!(f, "\n{}", e)?;
+				}
+				Ok(())
+			},
+			Error::WithBacktrace { source, backtrace } => write!(f, "{}\nBacktrace:\n{}", source, backtrace),
+			Error::WithContext { source, context } => write!(f, "{} ({})", source, context),
 		}
     }
 }
 
+impl std::error::Error for Error {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			Error::Io(e) | Error::Locked(e) | Error::ThreadSpawn(e) => Some(e),
+			Error::Background(e) => Some(e.as_ref()),
+			Error::WithBacktrace { source, .. } | Error::WithContext { source, .. } => Some(source.as_ref()),
+			_ => None,
+		}
+	}
+}
+
+/// Attach `ErrorContext` to the error of a result, see `Error::with_context`.
+pub(crate) trait ResultExt<T> {
+	fn context(self, context: impl FnOnce() -> ErrorContext) -> Result<T>;
+}
+
+impl<T, E: Into<Error>> ResultExt<T> for std::result::Result<T, E> {
+	fn context(self, context: impl FnOnce() -> ErrorContext) -> Result<T> {
+		self.map_err(|e| e.into().with_context(context()))
+	}
+}
+
 impl From<std::io::Error> for Error {
 	fn from(e: std::io::Error) -> Self {
 		Error::Io(e)
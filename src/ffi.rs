@@ -0,0 +1,401 @@
+// Copyright 2015-2020 AXIA Technologies (UK) Ltd.
+// This file is part of AXIA.
+
+// AXIA is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// AXIA is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with AXIA.  If not, see <http://www.gnu.org/licenses/>.
+
+//! C API, enabled with the `ffi` feature.
+//!
+//! The header is generated with cbindgen into `include/axiadb.h` when building
+//! with the feature. To build a C library use e.g.
+//! `cargo rustc --lib --release --features ffi --crate-type cdylib`.
+//!
+//! Memory ownership:
+//! - Buffers passed to the library (paths, keys, values) are only read during
+//!   the call and remain owned by the caller.
+//! - Values passed to a `AxiaDbValueCallback` are only valid during the callback.
+//! - Values returned by `axiadb_get_copy` are owned by the caller and must be
+//!   released with `axiadb_free_value`.
+//! - Handles returned by `axiadb_open` must be released with `axiadb_close`.
+
+use std::ffi::{c_void, CStr};
+use std::os::raw::{c_char, c_int};
+use crate::{Db, Error, ErrorCode, Options, ColumnOptions, CompressionType};
+
+/// Operation completed successfully.
+pub const AXIADB_OK: c_int = 0;
+/// The key was not found.
+pub const AXIADB_NOT_FOUND: c_int = 1;
+/// Invalid argument, e.g. a null pointer or an out of range column.
+pub const AXIADB_INVALID_ARGUMENT: c_int = 2;
+/// IO error.
+pub const AXIADB_IO: c_int = 3;
+/// Database files are corrupted.
+pub const AXIADB_CORRUPTION: c_int = 4;
+/// Options don't match the database or are invalid.
+pub const AXIADB_INVALID_CONFIGURATION: c_int = 5;
+/// A background worker has failed. The database must be closed.
+pub const AXIADB_BACKGROUND: c_int = 6;
+/// The database is opened by another process.
+pub const AXIADB_LOCKED: c_int = 7;
+/// Any other error.
+pub const AXIADB_OTHER: c_int = 8;
+
+/// Opaque database handle.
+pub struct AxiaDb {
+	db: Db,
+}
+
+/// Database options. Null pointers passed for options select the defaults.
+#[repr(C)]
+pub struct AxiaDbOptions {
+	/// Create the database if it does not exist.
+	pub create: bool,
+	pub sync_wal: bool,
+	pub sync_data: bool,
+	pub stats: bool,
+	/// Column options, an array with an entry for each column. May be null.
+	pub columns: *const AxiaDbColumnOptions,
+}
+
+/// Column options. See `ColumnOptions` for details.
+#[repr(C)]
+pub struct AxiaDbColumnOptions {
+	pub preimage: bool,
+	pub uniform: bool,
+	pub ref_counted: bool,
+	/// 0 for none, 1 for lz4, 2 for snappy.
+	pub compression: u8,
+}
+
+/// A single change for `axiadb_commit`.
+#[repr(C)]
+pub struct AxiaDbOp {
+	pub col: u8,
+	pub key: *const u8,
+	pub key_len: usize,
+	/// Value to set, or null to remove the key.
+	pub value: *const u8,
+	pub value_len: usize,
+}
+
+/// Called with the value found by `axiadb_get`. The value buffer is only valid
+/// during the call.
+pub type AxiaDbValueCallback = extern "C" fn(context: *mut c_void, value: *const u8, value_len: usize);
+
+fn error_code(e: &Error) -> c_int {
+	match e.code() {
+		ErrorCode::Io => AXIADB_IO,
+		ErrorCode::Corruption => AXIADB_CORRUPTION,
+		ErrorCode::InvalidConfiguration => AXIADB_INVALID_CONFIGURATION,
+		ErrorCode::InvalidArgument => AXIADB_INVALID_ARGUMENT,
+		ErrorCode::Background => AXIADB_BACKGROUND,
+		ErrorCode::Locked => AXIADB_LOCKED,
+		ErrorCode::Other => AXIADB_OTHER,
+	}
+}
+
+fn result_code(result: crate::Result<()>) -> c_int {
+	match result {
+		Ok(()) => AXIADB_OK,
+		Err(e) => {
+			log::debug!(target: "axia-db", "FFI call failed: {}", e);
+			error_code(&e)
+		}
+	}
+}
+
+unsafe fn slice<'a>(ptr: *const u8, len: usize) -> Option<&'a [u8]> {
+	if len == 0 {
+		Some(&[])
+	} else if ptr.is_null() {
+		None
+	} else {
+		Some(std::slice::from_raw_parts(ptr, len))
+	}
+}
+
+unsafe fn options(path: &CStr, num_columns: u8, opts: *const AxiaDbOptions) -> Option<(Options, bool)> {
+	let path = std::path::Path::new(path.to_str().ok()?);
+	let mut options = Options::with_columns(path, num_columns);
+	let mut create = false;
+	if let Some(opts) = opts.as_ref() {
+		create = opts.create;
+		options.sync_wal = opts.sync_wal;
+		options.sync_data = opts.sync_data;
+		options.stats = opts.stats;
+		if !opts.columns.is_null() {
+			let columns = std::slice::from_raw_parts(opts.columns, num_columns as usize);
+			for (options, c) in options.columns.iter_mut().zip(columns) {
+				*options = ColumnOptions {
+					preimage: c.preimage,
+					uniform: c.uniform,
+					ref_counted: c.ref_counted,
+					compression: CompressionType::from_u8(c.compression)?,
+					..Default::default()
+				};
+			}
+		}
+	}
+	if !options.is_valid() {
+		return None;
+	}
+	Some((options, create))
+}
+
+/// Open a database with `num_columns` columns at `path`. Returns null on failure.
+/// The reason is written to `error`, unless it is null.
+///
+/// # Safety
+///
+/// `path` must be a null-terminated string. `opts` must be null or point to valid
+/// options, with `columns` being null or pointing to `num_columns` entries.
+/// `error` must be null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn axiadb_open(
+	path: *const c_char,
+	num_columns: u8,
+	opts: *const AxiaDbOptions,
+	error: *mut c_int,
+) -> *mut AxiaDb {
+	let set_error = |code| if let Some(error) = error.as_mut() {
+		*error = code;
+	};
+	if path.is_null() {
+		set_error(AXIADB_INVALID_ARGUMENT);
+		return std::ptr::null_mut();
+	}
+	let (options, create) = match options(CStr::from_ptr(path), num_columns, opts) {
+		Some(options) => options,
+		None => {
+			set_error(AXIADB_INVALID_ARGUMENT);
+			return std::ptr::null_mut();
+		}
+	};
+	let db = if create { Db::open_or_create(&options) } else { Db::open(&options) };
+	match db {
+		Ok(db) => {
+			set_error(AXIADB_OK);
+			Box::into_raw(Box::new(AxiaDb { db }))
+		}
+		Err(e) => {
+			log::debug!(target: "axia-db", "FFI open failed: {}", e);
+			set_error(error_code(&e));
+			std::ptr::null_mut()
+		}
+	}
+}
+
+/// Close the database and release the handle.
+///
+/// # Safety
+///
+/// `handle` must be null or returned by `axiadb_open` and not closed yet.
+#[no_mangle]
+pub unsafe extern "C" fn axiadb_close(handle: *mut AxiaDb) {
+	if !handle.is_null() {
+		drop(Box::from_raw(handle));
+	}
+}
+
+/// Look up a value and pass it to `callback`. Returns `AXIADB_NOT_FOUND` without
+/// calling `callback` if there is no value for the key.
+///
+/// # Safety
+///
+/// `handle` must be a valid handle. `key` must point to `key_len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn axiadb_get(
+	handle: *const AxiaDb,
+	col: u8,
+	key: *const u8,
+	key_len: usize,
+	callback: AxiaDbValueCallback,
+	context: *mut c_void,
+) -> c_int {
+	let (db, key) = match (handle.as_ref(), slice(key, key_len)) {
+		(Some(handle), Some(key)) if (col as usize) < handle.db.num_columns() => (&handle.db, key),
+		_ => return AXIADB_INVALID_ARGUMENT,
+	};
+	match db.get(col, key) {
+		Ok(Some(value)) => {
+			callback(context, value.as_ptr(), value.len());
+			AXIADB_OK
+		}
+		Ok(None) => AXIADB_NOT_FOUND,
+		Err(e) => result_code(Err(e)),
+	}
+}
+
+/// Look up a value and return a copy in `value` and `value_len`. The copy must
+/// be released with `axiadb_free_value`. Returns `AXIADB_NOT_FOUND` and sets
+/// `value` to null if there is no value for the key.
+///
+/// # Safety
+///
+/// `handle` must be a valid handle. `key` must point to `key_len` bytes.
+/// `value` and `value_len` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn axiadb_get_copy(
+	handle: *const AxiaDb,
+	col: u8,
+	key: *const u8,
+	key_len: usize,
+	value: *mut *mut u8,
+	value_len: *mut usize,
+) -> c_int {
+	let (db, key) = match (handle.as_ref(), slice(key, key_len)) {
+		(Some(handle), Some(key)) if (col as usize) < handle.db.num_columns() => (&handle.db, key),
+		_ => return AXIADB_INVALID_ARGUMENT,
+	};
+	let (out_value, out_len) = match (value.as_mut(), value_len.as_mut()) {
+		(Some(value), Some(len)) => (value, len),
+		_ => return AXIADB_INVALID_ARGUMENT,
+	};
+	*out_value = std::ptr::null_mut();
+	*out_len = 0;
+	match db.get(col, key) {
+		Ok(Some(v)) => {
+			let v = v.into_boxed_slice();
+			*out_len = v.len();
+			*out_value = Box::into_raw(v) as *mut u8;
+			AXIADB_OK
+		}
+		Ok(None) => AXIADB_NOT_FOUND,
+		Err(e) => result_code(Err(e)),
+	}
+}
+
+/// Release a value returned by `axiadb_get_copy`.
+///
+/// # Safety
+///
+/// `value` and `value_len` must be null or returned by `axiadb_get_copy` and not released yet.
+#[no_mangle]
+pub unsafe extern "C" fn axiadb_free_value(value: *mut u8, value_len: usize) {
+	if !value.is_null() {
+		drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(value, value_len)));
+	}
+}
+
+/// Commit `count` operations atomically.
+///
+/// # Safety
+///
+/// `handle` must be a valid handle. `ops` must point to `count` operations with
+/// valid key and value pointers.
+#[no_mangle]
+pub unsafe extern "C" fn axiadb_commit(handle: *const AxiaDb, ops: *const AxiaDbOp, count: usize) -> c_int {
+	let db = match handle.as_ref() {
+		Some(handle) => &handle.db,
+		None => return AXIADB_INVALID_ARGUMENT,
+	};
+	let ops = match slice_of(ops, count) {
+		Some(ops) => ops,
+		None => return AXIADB_INVALID_ARGUMENT,
+	};
+	let mut commit = Vec::with_capacity(ops.len());
+	for op in ops {
+		if op.col as usize >= db.num_columns() {
+			return AXIADB_INVALID_ARGUMENT;
+		}
+		let key = match slice(op.key, op.key_len) {
+			Some(key) => key,
+			None => return AXIADB_INVALID_ARGUMENT,
+		};
+		let value = if op.value.is_null() {
+			None
+		} else {
+			Some(std::slice::from_raw_parts(op.value, op.value_len).to_vec())
+		};
+		commit.push((op.col, key, value));
+	}
+	result_code(db.commit(commit).map(|_| ()))
+}
+
+unsafe fn slice_of<'a, T>(ptr: *const T, len: usize) -> Option<&'a [T]> {
+	if len == 0 {
+		Some(&[])
+	} else if ptr.is_null() {
+		None
+	} else {
+		Some(std::slice::from_raw_parts(ptr, len))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tempfile::tempdir;
+
+	extern "C" fn collect(context: *mut c_void, value: *const u8, value_len: usize) {
+		let out = unsafe { &mut *(context as *mut Vec<u8>) };
+		out.extend_from_slice(unsafe { std::slice::from_raw_parts(value, value_len) });
+	}
+
+	#[test]
+	fn ffi_smoke() {
+		let tmp = tempdir().unwrap();
+		let path = std::ffi::CString::new(tmp.path().to_str().unwrap()).unwrap();
+		unsafe {
+			let mut error = -1;
+			let handle = axiadb_open(path.as_ptr(), 1, std::ptr::null(), &mut error);
+			assert!(handle.is_null());
+			assert_eq!(error, AXIADB_INVALID_CONFIGURATION);
+
+			let opts = AxiaDbOptions {
+				create: true,
+				sync_wal: true,
+				sync_data: true,
+				stats: false,
+				columns: std::ptr::null(),
+			};
+			let handle = axiadb_open(path.as_ptr(), 2, &opts, &mut error);
+			assert!(!handle.is_null());
+			assert_eq!(error, AXIADB_OK);
+
+			let ops = [
+				AxiaDbOp { col: 0, key: b"key1".as_ptr(), key_len: 4, value: b"value1".as_ptr(), value_len: 6 },
+				AxiaDbOp { col: 1, key: b"key2".as_ptr(), key_len: 4, value: b"value2".as_ptr(), value_len: 6 },
+			];
+			assert_eq!(axiadb_commit(handle, ops.as_ptr(), ops.len()), AXIADB_OK);
+
+			let mut value = Vec::new();
+			let context = &mut value as *mut Vec<u8> as *mut c_void;
+			assert_eq!(axiadb_get(handle, 0, b"key1".as_ptr(), 4, collect, context), AXIADB_OK);
+			assert_eq!(value, b"value1");
+			assert_eq!(axiadb_get(handle, 0, b"key2".as_ptr(), 4, collect, context), AXIADB_NOT_FOUND);
+			assert_eq!(axiadb_get(handle, 2, b"key1".as_ptr(), 4, collect, context), AXIADB_INVALID_ARGUMENT);
+
+			let mut copy = std::ptr::null_mut();
+			let mut copy_len = 0;
+			assert_eq!(axiadb_get_copy(handle, 1, b"key2".as_ptr(), 4, &mut copy, &mut copy_len), AXIADB_OK);
+			assert_eq!(std::slice::from_raw_parts(copy, copy_len), b"value2");
+			axiadb_free_value(copy, copy_len);
+
+			let remove = [AxiaDbOp { col: 1, key: b"key2".as_ptr(), key_len: 4, value: std::ptr::null(), value_len: 0 }];
+			assert_eq!(axiadb_commit(handle, remove.as_ptr(), remove.len()), AXIADB_OK);
+			assert_eq!(axiadb_get_copy(handle, 1, b"key2".as_ptr(), 4, &mut copy, &mut copy_len), AXIADB_NOT_FOUND);
+			assert!(copy.is_null());
+			axiadb_close(handle);
+
+			let handle = axiadb_open(path.as_ptr(), 2, std::ptr::null(), &mut error);
+			assert!(!handle.is_null());
+			let mut value = Vec::new();
+			let context = &mut value as *mut Vec<u8> as *mut c_void;
+			assert_eq!(axiadb_get(handle, 0, b"key1".as_ptr(), 4, collect, context), AXIADB_OK);
+			assert_eq!(value, b"value1");
+			axiadb_close(handle);
+		}
+	}
+}
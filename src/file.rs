@@ -18,8 +18,9 @@
 
 use std::sync::atomic::{AtomicU64, AtomicBool, Ordering};
 use parking_lot::{RwLockUpgradableReadGuard, RwLock};
-use crate::error::Result;
+use crate::error::{Error, ErrorContext, Result, ResultExt};
 use crate::table::TableId;
+use crate::options::TableGrowth;
 
 #[cfg(target_os = "linux")]
 fn disable_read_ahead(file: &std::fs::File) -> Result<()> {
@@ -71,95 +72,237 @@ fn fsync(file: &std::fs::File) -> Result<()> {
 }
 
 
-const GROW_SIZE_BYTES: u64 = 256 * 1024;
+/// How value table files are laid out on disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TableLayout {
+	/// How much files are extended when they run out of space.
+	pub growth: TableGrowth,
+	/// Split tables into files of at most this many bytes, see `Options::max_table_file_size`.
+	pub max_file_size: Option<u64>,
+}
 
 pub struct TableFile {
-	pub file: RwLock<Option<std::fs::File>>,
+	// The first file is at `path`, continuation files have the file number appended.
+	// All but the last hold exactly `split_entries` entries.
+	files: RwLock<Vec<std::fs::File>>,
 	pub path: std::path::PathBuf,
 	pub capacity: AtomicU64,
 	pub dirty: AtomicBool,
 	pub id: TableId,
+	entry_size: u16,
+	split_entries: u64,
+	growth: TableGrowth,
 }
 
 impl TableFile {
-	pub fn open(filepath: std::path::PathBuf, entry_size: u16, id: TableId) -> Result<Self> {
-		let mut capacity = 0u64;
-		let file = if std::fs::metadata(&filepath).is_ok() {
-			let file = std::fs::OpenOptions::new().create(true).read(true).write(true).open(filepath.as_path())?;
-			disable_read_ahead(&file)?;
-			if file.metadata()?.len() == 0 {
-				// Preallocate.
-				capacity += GROW_SIZE_BYTES / entry_size as u64;
-				file.set_len(capacity * entry_size as u64)?;
+	pub fn open(filepath: std::path::PathBuf, entry_size: u16, id: TableId, layout: TableLayout) -> Result<Self> {
+		let split_entries = layout.max_file_size.map_or(u64::MAX, |max| std::cmp::max(max / entry_size as u64, 1));
+		let mut files = Vec::new();
+		let mut capacity = 0;
+		loop {
+			let path = Self::file_path(&filepath, files.len());
+			if std::fs::metadata(&path).is_err() {
+				break;
 			}
-			Some(file)
-		} else {
-			None
-		};
+			let (file, file_capacity) = Self::open_file(&path, entry_size, layout.growth)
+				.context(|| ErrorContext::new("open").column(id.col()).path(&path))?;
+			files.push(file);
+			// Files before the last one are full.
+			capacity = (files.len() as u64 - 1) * split_entries + file_capacity;
+			if file_capacity < split_entries {
+				break;
+			}
+		}
 		Ok(TableFile {
 			path: filepath,
-			file: RwLock::new(file),
+			files: RwLock::new(files),
 			capacity: AtomicU64::new(capacity),
 			dirty: AtomicBool::new(false),
 			id,
+			entry_size,
+			split_entries,
+			growth: layout.growth,
 		})
 	}
 
-	fn create_file(&self) -> Result<std::fs::File> {
-		log::debug!(target: "axia-db", "Created value table {}", self.id);
-		let file = std::fs::OpenOptions::new().create(true).read(true).write(true).open(self.path.as_path())?;
+	// Path of file number `n` of the table at `path`.
+	fn file_path(path: &std::path::Path, n: usize) -> std::path::PathBuf {
+		if n == 0 {
+			path.to_path_buf()
+		} else {
+			let mut name = path.as_os_str().to_owned();
+			name.push(format!(".{}", n));
+			name.into()
+		}
+	}
+
+	// Returns the file and its capacity in entries.
+	fn open_file(path: &std::path::Path, entry_size: u16, growth: TableGrowth) -> Result<(std::fs::File, u64)> {
+		let file = std::fs::OpenOptions::new().create(true).read(true).write(true).open(path)?;
+		disable_read_ahead(&file)?;
+		let len = file.metadata()?.len();
+		let capacity = if len == 0 {
+			// Preallocate.
+			let capacity = growth.grow_entries(0, entry_size);
+			file.set_len(capacity * entry_size as u64)?;
+			capacity
+		} else {
+			len / entry_size as u64
+		};
+		Ok((file, capacity))
+	}
+
+	fn context(&self, operation: &'static str) -> ErrorContext {
+		ErrorContext::new(operation).column(self.id.col()).path(&self.path)
+	}
+
+	fn create_file(&self, n: usize) -> Result<std::fs::File> {
+		let path = Self::file_path(&self.path, n);
+		log::debug!(target: "axia-db", "Created value table {} file {}", self.id, n);
+		let file = std::fs::OpenOptions::new().create(true).read(true).write(true).open(&path)
+			.context(|| ErrorContext::new("create").column(self.id.col()).path(&path))?;
 		disable_read_ahead(&file)?;
 		Ok(file)
 	}
 
+	/// Returns `true` if the table has been written to and has a file.
+	pub fn exists(&self) -> bool {
+		!self.files.read().is_empty()
+	}
+
+	// Calls `f` with the file, the offset in that file and the range of the buffer for
+	// each file that the `len` bytes at `offset` span. Bytes past the last file can
+	// only be addressed by corrupted data.
+	fn for_files(
+		&self,
+		files: &[std::fs::File],
+		offset: u64,
+		len: usize,
+		mut f: impl FnMut(&std::fs::File, u64, std::ops::Range<usize>) -> Result<()>,
+	) -> Result<()> {
+		let split_bytes = self.split_entries.saturating_mul(self.entry_size as u64);
+		let mut done = 0;
+		while done < len {
+			let pos = offset + done as u64;
+			let (n, file_offset) = ((pos / split_bytes) as usize, pos % split_bytes);
+			let part = std::cmp::min((split_bytes - file_offset) as usize, len - done);
+			let file = files.get(n).ok_or_else(|| Error::Corruption(
+				format!("{}: Offset {} is past the end of the table", self.id, pos)
+			))?;
+			f(file, file_offset, done .. done + part)?;
+			done += part;
+		}
+		Ok(())
+	}
+
 	#[cfg(unix)]
 	pub fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
 		use std::os::unix::fs::FileExt;
-		Ok(self.file.read().as_ref().unwrap().read_exact_at(buf, offset)?)
+		let files = self.files.read();
+		self.for_files(&files, offset, buf.len(), |file, file_offset, range| {
+			file.read_exact_at(&mut buf[range], file_offset)
+				.context(|| self.context("read").offset(offset))
+		})
 	}
 
 	#[cfg(unix)]
 	pub fn write_at(&self, buf: &[u8], offset: u64) -> Result<()> {
 		use std::os::unix::fs::FileExt;
 		self.dirty.store(true, Ordering::Relaxed);
-		self.file.read().as_ref().unwrap().write_all_at(buf, offset)?;
-		Ok(())
+		let files = self.files.read();
+		self.for_files(&files, offset, buf.len(), |file, file_offset, range| {
+			file.write_all_at(&buf[range], file_offset)
+				.context(|| self.context("write").offset(offset))
+		})
 	}
 
 	#[cfg(windows)]
 	pub fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
 		use std::os::windows::fs::FileExt;
-		self.file.read().as_ref().unwrap().seek_read(buf, offset)?;
-		Ok(())
+		let files = self.files.read();
+		self.for_files(&files, offset, buf.len(), |file, file_offset, range| {
+			file.seek_read(&mut buf[range], file_offset)
+				.context(|| self.context("read").offset(offset))?;
+			Ok(())
+		})
 	}
 
 	#[cfg(windows)]
 	pub fn write_at(&self, buf: &[u8], offset: u64) -> Result<()> {
 		use std::os::windows::fs::FileExt;
 		self.dirty.store(true, Ordering::Relaxed);
-		self.file.read().as_ref().unwrap().seek_write(buf, offset)?;
-		Ok(())
+		let files = self.files.read();
+		self.for_files(&files, offset, buf.len(), |file, file_offset, range| {
+			file.seek_write(&buf[range], file_offset)
+				.context(|| self.context("write").offset(offset))?;
+			Ok(())
+		})
+	}
+
+	/// Hint that the given range will be read soon. Errors are ignored.
+	#[cfg(target_os = "linux")]
+	pub fn prefetch(&self, offset: u64, len: u64) {
+		use std::os::unix::io::AsRawFd;
+		let files = self.files.read();
+		let _ = self.for_files(&files, offset, len as usize, |file, file_offset, range| {
+			unsafe { libc::posix_fadvise(file.as_raw_fd(), file_offset as _, range.len() as _, libc::POSIX_FADV_WILLNEED) };
+			Ok(())
+		});
+	}
+
+	#[cfg(not(target_os = "linux"))]
+	pub fn prefetch(&self, offset: u64, len: u64) {
+		// No fadvise, read the range instead.
+		if self.exists() {
+			let mut buf = vec![0; len as usize];
+			let _ = self.read_at(&mut buf, offset);
+		}
 	}
 
 	pub fn grow(&self, entry_size: u16) -> Result<()> {
 		let mut capacity = self.capacity.load(Ordering::Relaxed);
-		capacity += GROW_SIZE_BYTES / entry_size as u64;
+		capacity += self.growth.grow_entries(capacity, entry_size);
+		self.set_capacity(capacity, entry_size)
+	}
+
+	/// Extend the file to at least `bytes`, creating it if it does not exist.
+	pub fn preallocate(&self, bytes: u64, entry_size: u16) -> Result<()> {
+		let capacity = bytes.div_ceil(entry_size as u64);
+		if capacity > self.capacity.load(Ordering::Relaxed) {
+			self.set_capacity(capacity, entry_size)?;
+		}
+		Ok(())
+	}
 
+	fn set_capacity(&self, capacity: u64, entry_size: u16) -> Result<()> {
 		self.capacity.store(capacity, Ordering::Relaxed);
-		let mut file = self.file.upgradable_read();
-		if file.is_none() {
-			let mut wfile = RwLockUpgradableReadGuard::upgrade(file);
-			*wfile = Some(self.create_file()?);
-			file = parking_lot::RwLockWriteGuard::downgrade_to_upgradable(wfile);
+		let split_bytes = self.split_entries.saturating_mul(entry_size as u64);
+		let num_files = std::cmp::max(capacity.div_ceil(self.split_entries), 1) as usize;
+		let mut files = self.files.upgradable_read();
+		if files.len() < num_files {
+			let mut wfiles = RwLockUpgradableReadGuard::upgrade(files);
+			while wfiles.len() < num_files {
+				let file = self.create_file(wfiles.len())?;
+				wfiles.push(file);
+			}
+			files = parking_lot::RwLockWriteGuard::downgrade_to_upgradable(wfiles);
+		}
+		let last = num_files - 1;
+		for (n, file) in files.iter().enumerate().take(num_files) {
+			let len = if n < last {
+				split_bytes
+			} else {
+				(capacity - last as u64 * self.split_entries) * entry_size as u64
+			};
+			file.set_len(len).context(|| self.context("grow"))?;
 		}
-		file.as_ref().unwrap().set_len(capacity * entry_size as u64)?;
 		Ok(())
 	}
 
 	pub(crate) fn flush(&self) -> Result<()> {
 		if let Ok(true) = self.dirty.compare_exchange(true, false, Ordering::Relaxed, Ordering::Relaxed) {
-			if let Some(file) = self.file.read().as_ref() {
-				fsync(&file)?;
+			for file in self.files.read().iter() {
+				fsync(file).context(|| self.context("flush"))?;
 			}
 		}
 		Ok(())
@@ -15,9 +15,11 @@
 // along with AXIA.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::convert::TryInto;
-use parking_lot::{RwLockUpgradableReadGuard, RwLock};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use parking_lot::{Mutex, RwLockUpgradableReadGuard, RwLock};
 use crate::{
-	error::{Error, Result},
+	error::{Error, ErrorContext, Result, ResultExt},
 	column::ColId,
 	log::{LogReader, LogWriter, LogQuery},
 	display::hex,
@@ -35,6 +37,11 @@ const ENTRY_LEN: u8 = 64;
 pub const ENTRY_BYTES: usize = ENTRY_LEN as usize / 8;
 
 const EMPTY_CHUNK: Chunk = [0u8; CHUNK_LEN];
+/// Smallest `Options::mmap_window`. Windows must be aligned to the allocation granularity,
+/// which is 64 KiB on some platforms.
+pub const MIN_MMAP_WINDOW: usize = 64 * 1024;
+// Number of windows that stay mapped for each index file.
+const MAX_MAPPED_WINDOWS: usize = 4;
 
 pub type Key = [u8; KEY_LEN];
 pub type Chunk = [u8; CHUNK_LEN];
@@ -131,8 +138,140 @@ pub enum PlanOutcome {
 
 pub struct IndexTable {
 	pub id: TableId,
-	map: RwLock<Option<memmap2::MmapMut>>,
+	map: RwLock<Option<IndexMap>>,
 	path: std::path::PathBuf,
+	mmap_window: Option<usize>,
+}
+
+enum IndexMap {
+	Full(memmap2::MmapMut),
+	Windowed(MapWindows),
+}
+
+// Maps fixed size windows of the index file on demand. The metadata stays mapped.
+struct MapWindows {
+	file: std::fs::File,
+	path: std::path::PathBuf,
+	file_size: u64,
+	window_size: usize,
+	meta: memmap2::MmapMut,
+	// Most recently used first. Evicted windows are unmapped once no longer in use.
+	windows: Mutex<VecDeque<(u64, Arc<memmap2::MmapMut>)>>,
+}
+
+impl IndexMap {
+	fn open(file: std::fs::File, path: &std::path::Path, id: TableId, mmap_window: Option<usize>) -> Result<IndexMap> {
+		let file_size = file_size(id.index_bits());
+		Ok(match mmap_window {
+			Some(window_size) => {
+				let meta = unsafe { memmap2::MmapOptions::new().len(META_SIZE).map_mut(&file)? };
+				IndexMap::Windowed(MapWindows {
+					file,
+					path: path.into(),
+					file_size,
+					window_size,
+					meta,
+					windows: Mutex::new(VecDeque::with_capacity(MAX_MAPPED_WINDOWS)),
+				})
+			},
+			None => IndexMap::Full(unsafe { memmap2::MmapMut::map_mut(&file)? }),
+		})
+	}
+
+	fn meta(&self) -> &[u8] {
+		match self {
+			IndexMap::Full(map) => &map[.. META_SIZE],
+			IndexMap::Windowed(windows) => &windows.meta[..],
+		}
+	}
+
+	fn meta_mut(&mut self) -> &mut [u8] {
+		match self {
+			IndexMap::Full(map) => &mut map[.. META_SIZE],
+			IndexMap::Windowed(windows) => &mut windows.meta[..],
+		}
+	}
+
+	fn with_chunk<R>(&self, index: u64, f: impl FnOnce(&[u8]) -> R) -> Result<R> {
+		let offset = META_SIZE as u64 + index * CHUNK_LEN as u64;
+		match self {
+			IndexMap::Full(map) => {
+				let offset = offset as usize;
+				Ok(f(&map[offset .. offset + CHUNK_LEN]))
+			},
+			IndexMap::Windowed(windows) => {
+				// Chunks never cross a window boundary, since windows are a power of two
+				// and larger than the metadata.
+				let (window, offset) = windows.window(offset)?;
+				Ok(f(&window[offset .. offset + CHUNK_LEN]))
+			},
+		}
+	}
+
+	fn flush(&self) -> Result<()> {
+		match self {
+			IndexMap::Full(map) => map.flush()?,
+			IndexMap::Windowed(windows) => {
+				windows.meta.flush()?;
+				let mapped: Vec<_> = windows.windows.lock().iter().map(|(_, w)| w.clone()).collect();
+				for window in mapped {
+					window.flush()?;
+				}
+				// Also covers the pages of windows that were unmapped since the last flush.
+				windows.file.sync_data()?;
+			},
+		}
+		Ok(())
+	}
+
+	#[cfg(unix)]
+	fn madvise_random(&self) {
+		if let IndexMap::Full(map) = self {
+			madvise_random(map);
+		}
+	}
+
+	#[cfg(not(unix))]
+	fn madvise_random(&self) {}
+}
+
+impl MapWindows {
+	// Returns the window that contains `offset` and the position of `offset` in it.
+	fn window(&self, offset: u64) -> Result<(Arc<memmap2::MmapMut>, usize)> {
+		let window_size = self.window_size as u64;
+		let start = offset - offset % window_size;
+		let position = (offset - start) as usize;
+		let mut windows = self.windows.lock();
+		if let Some(i) = windows.iter().position(|(s, _)| *s == start) {
+			let window = windows.remove(i).unwrap();
+			let map = window.1.clone();
+			windows.push_front(window);
+			return Ok((map, position));
+		}
+		let len = std::cmp::min(window_size, self.file_size - start) as usize;
+		let map = unsafe { memmap2::MmapOptions::new().offset(start).len(len).map_mut(&self.file) }
+			.context(|| ErrorContext::new("map window").path(&self.path).offset(start))?;
+		#[cfg(unix)]
+		madvise_random(&map);
+		let map = Arc::new(map);
+		if windows.len() == MAX_MAPPED_WINDOWS {
+			windows.pop_back();
+		}
+		windows.push_front((start, map.clone()));
+		Ok((map, position))
+	}
+}
+
+#[cfg(unix)]
+fn madvise_random(map: &memmap2::MmapMut) {
+	unsafe {
+		libc::madvise(map.as_ptr() as _, map.len(), libc::MADV_RANDOM);
+	}
+}
+
+/// Number of occupied entries that make a chunk full for the given load factor.
+pub fn max_chunk_entries(load_factor: f32) -> usize {
+	std::cmp::max((CHUNK_ENTRIES as f32 * load_factor) as usize, 1)
 }
 
 fn total_entries(index_bits: u8) -> u64 {
@@ -195,60 +334,68 @@ impl std::fmt::Display for TableId {
 }
 
 impl IndexTable {
-	pub fn open_existing(path: &std::path::Path, id: TableId) -> Result<Option<IndexTable>> {
+	pub fn open_existing(path: &std::path::Path, id: TableId, mmap_window: Option<usize>) -> Result<Option<IndexTable>> {
 		let mut path: std::path::PathBuf = path.into();
 		path.push(id.file_name());
 
+		let context = || ErrorContext::new("open").column(id.col()).path(&path);
 		let file = match std::fs::OpenOptions::new().read(true).write(true).open(path.as_path()) {
 			Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
 				return Ok(None);
 			}
-			Err(e) => return Err(e.into()),
+			Err(e) => return Err(Error::from(e).with_context(context())),
 			Ok(file) => file,
 		};
 
-		file.set_len(file_size(id.index_bits()))?;
-		let map = unsafe { memmap2::MmapMut::map_mut(&file)? };
+		file.set_len(file_size(id.index_bits())).context(context)?;
+		let map = IndexMap::open(file, &path, id, mmap_window).context(context)?;
 		log::debug!(target: "axia-db", "Opened existing index {}", id);
 		Ok(Some(IndexTable {
 			id,
 			path,
 			map: RwLock::new(Some(map)),
+			mmap_window,
 		}))
 	}
 
-	pub fn create_new(path: &std::path::Path, id: TableId) -> IndexTable {
+	pub fn create_new(path: &std::path::Path, id: TableId, mmap_window: Option<usize>) -> IndexTable {
 		let mut path: std::path::PathBuf = path.into();
 		path.push(id.file_name());
 		IndexTable {
 			id,
 			path,
 			map: RwLock::new(None),
+			mmap_window,
 		}
 	}
 
 	pub fn load_stats(&self) -> ColumnStats {
 		debug_assert!(META_SIZE >= HEADER_SIZE + stats::TOTAL_SIZE);
 		if let Some(map) = &*self.map.read() {
-			ColumnStats::from_slice(&map[HEADER_SIZE .. HEADER_SIZE + stats::TOTAL_SIZE])
+			ColumnStats::from_slice(&map.meta()[HEADER_SIZE .. HEADER_SIZE + stats::TOTAL_SIZE])
 		} else {
 			ColumnStats::empty()
 		}
 	}
 
+	/// Number of values in the stats stored in the metadata, 0 if the file was not
+	/// created yet. Cheaper than `load_stats`.
+	pub fn stored_total_values(&self) -> u64 {
+		if let Some(map) = &*self.map.read() {
+			ColumnStats::total_values_from_slice(&map.meta()[HEADER_SIZE .. HEADER_SIZE + stats::TOTAL_SIZE])
+		} else {
+			0
+		}
+	}
+
 	pub fn write_stats(&self, stats: &ColumnStats) {
 		debug_assert!(META_SIZE >= HEADER_SIZE + stats::TOTAL_SIZE);
 		if let Some(map) = &mut *self.map.write() {
-			let mut slice = &mut map[HEADER_SIZE .. HEADER_SIZE + stats::TOTAL_SIZE];
+			let mut slice = &mut map.meta_mut()[HEADER_SIZE .. HEADER_SIZE + stats::TOTAL_SIZE];
 			stats.to_slice(&mut slice);
 		}
 	}
 
-	fn chunk_at(index: u64, map: &memmap2::MmapMut) -> &[u8] {
-		let offset = META_SIZE + index as usize * CHUNK_LEN;
-		&map[offset .. offset + CHUNK_LEN]
-	}
-
 	fn find_entry(&self, key: u64, sub_index: usize, chunk: &[u8]) -> (Entry, usize) {
 		let partial_key = Entry::extract_key(key, self.id.index_bits());
 		for i in sub_index .. CHUNK_ENTRIES {
@@ -272,7 +419,7 @@ impl IndexTable {
 		key
 	}
 
-	pub fn get(&self, key: &Key, sub_index: usize, log: &impl LogQuery) -> (Entry, usize) {
+	pub fn get(&self, key: &Key, sub_index: usize, log: &impl LogQuery) -> Result<(Entry, usize)> {
 		log::trace!(target: "axia-db", "{}: Querying {}", self.id, hex(&key));
 		let key = u64::from_be_bytes((key[0..8]).try_into().unwrap());
 		let chunk_index = self.chunk_index(key);
@@ -281,30 +428,46 @@ impl IndexTable {
 				log::trace!(target: "axia-db", "{}: Querying overlay at {}", self.id, chunk_index);
 				self.find_entry(key, sub_index, chunk)
 			}) {
-			return entry;
+			return Ok(entry);
 		}
 
 		if let Some(map) = &*self.map.read() {
 			log::trace!(target: "axia-db", "{}: Querying chunk at {}", self.id, chunk_index);
-			let chunk = Self::chunk_at(chunk_index, map);
-			return self.find_entry(key, sub_index, chunk);
+			return map.with_chunk(chunk_index, |chunk| self.find_entry(key, sub_index, chunk));
+		}
+		Ok((Entry::empty(), 0))
+	}
 
+	/// Returns `true` if there are no occupied entries. Stops at the first one found.
+	pub fn is_empty(&self, log: &impl LogQuery) -> Result<bool> {
+		for c in 0 .. self.id.total_chunks() {
+			if !self.entries(c, log)?.iter().all(|e| e.is_empty()) {
+				return Ok(false);
+			}
 		}
-		return (Entry::empty(), 0)
+		Ok(true)
+	}
+
+	/// Number of occupied entries. Reads the whole table.
+	pub fn occupied_entries(&self, log: &impl LogQuery) -> Result<u64> {
+		let mut occupied = 0;
+		for c in 0 .. self.id.total_chunks() {
+			occupied += self.entries(c, log)?.iter().filter(|e| !e.is_empty()).count() as u64;
+		}
+		Ok(occupied)
 	}
 
-	pub fn entries(&self, chunk_index: u64, log: &impl LogQuery) -> [Entry; CHUNK_ENTRIES] {
+	pub fn entries(&self, chunk_index: u64, log: &impl LogQuery) -> Result<[Entry; CHUNK_ENTRIES]> {
 		let mut chunk = [0; CHUNK_LEN];
 		if let Some(entry) = log.with_index(self.id, chunk_index, |chunk|
 			Self::transmute_chunk(*chunk)) {
-			return entry;
+			return Ok(entry);
 		}
 		if let Some(map) = &*self.map.read() {
-			let source = Self::chunk_at(chunk_index, map);
-			chunk.copy_from_slice(source);
-			return Self::transmute_chunk(chunk);
+			map.with_chunk(chunk_index, |source| chunk.copy_from_slice(source))?;
+			return Ok(Self::transmute_chunk(chunk));
 		}
-		return Self::transmute_chunk(EMPTY_CHUNK);
+		Ok(Self::transmute_chunk(EMPTY_CHUNK))
 	}
 
 	#[inline(always)]
@@ -329,7 +492,7 @@ impl IndexTable {
 	}
 
 	#[inline(always)]
-	fn chunk_index(&self, key: u64) -> u64 {
+	pub fn chunk_index(&self, key: u64) -> u64 {
 		key >> (ENTRY_LEN - self.id.index_bits())
 	}
 
@@ -339,6 +502,7 @@ impl IndexTable {
 		address: Address,
 		source: &[u8],
 		sub_index: Option<usize>,
+		max_entries: usize,
 		log: &mut LogWriter,
 	) -> Result<PlanOutcome> {
 		let chunk_index = self.chunk_index(key);
@@ -359,6 +523,11 @@ impl IndexTable {
 			log.insert_index(self.id, chunk_index, i as u8, &chunk);
 			return Ok(PlanOutcome::Written);
 		}
+		let occupied = (0 .. CHUNK_ENTRIES).filter(|i| !Self::read_entry(&chunk, *i).is_empty()).count();
+		if occupied >= max_entries {
+			log::trace!(target: "axia-db", "{}: Full at {}", self.id, chunk_index);
+			return Ok(PlanOutcome::NeedReindex);
+		}
 		for i in 0 .. CHUNK_ENTRIES {
 			let entry = Self::read_entry(&chunk, i);
 			if entry.is_empty() {
@@ -372,22 +541,29 @@ impl IndexTable {
 		return Ok(PlanOutcome::NeedReindex);
 	}
 
-	pub fn write_insert_plan(&self, key: &Key, address: Address, sub_index: Option<usize>, log: &mut LogWriter) -> Result<PlanOutcome> {
+	pub fn write_insert_plan(
+		&self,
+		key: &Key,
+		address: Address,
+		sub_index: Option<usize>,
+		max_entries: usize,
+		log: &mut LogWriter,
+	) -> Result<PlanOutcome> {
 		log::trace!(target: "axia-db", "{}: Inserting {} -> {}", self.id, hex(&key), address);
 		let key = u64::from_be_bytes((key[0..8]).try_into().unwrap());
 		let chunk_index = self.chunk_index(key);
 
 		if let Some(chunk) = log.with_index(self.id, chunk_index, |chunk| chunk.clone()) {
-			return self.plan_insert_chunk(key, address, &chunk, sub_index, log)
+			return self.plan_insert_chunk(key, address, &chunk, sub_index, max_entries, log)
 		}
 
 		if let Some(map) = &*self.map.read() {
-			let chunk = Self::chunk_at(chunk_index, map);
-			return self.plan_insert_chunk(key, address, chunk, sub_index, log);
+			return map.with_chunk(chunk_index, |chunk|
+				self.plan_insert_chunk(key, address, chunk, sub_index, max_entries, log))?;
 		}
 
 		let chunk = &EMPTY_CHUNK;
-		self.plan_insert_chunk(key, address, chunk, sub_index, log)
+		self.plan_insert_chunk(key, address, chunk, sub_index, max_entries, log)
 	}
 
 	fn plan_remove_chunk(&self, key: u64, source: &[u8], sub_index: usize, log: &mut LogWriter) -> Result<PlanOutcome> {
@@ -418,8 +594,7 @@ impl IndexTable {
 		}
 
 		if let Some(map) = &*self.map.read() {
-			let chunk = Self::chunk_at(chunk_index, map);
-			return self.plan_remove_chunk(key, chunk, sub_index, log);
+			return map.with_chunk(chunk_index, |chunk| self.plan_remove_chunk(key, chunk, sub_index, log))?;
 		}
 
 		Ok(PlanOutcome::Skipped)
@@ -429,33 +604,27 @@ impl IndexTable {
 		let mut map = self.map.upgradable_read();
 		if map.is_none() {
 			let mut wmap = RwLockUpgradableReadGuard::upgrade(map);
-			let file = std::fs::OpenOptions::new().write(true).read(true).create_new(true).open(self.path.as_path())?;
-			log::debug!(target: "axia-db", "Created new index {}", self.id);
-			//TODO: check for potential overflows on 32-bit platforms
-			file.set_len(file_size(self.id.index_bits()))?;
-			let mut mmap = unsafe { memmap2::MmapMut::map_mut(&file)? };
-			self.madvise_random(&mut mmap);
-			*wmap = Some(mmap);
+			*wmap = Some(self.create_map()?);
 			map = parking_lot::RwLockWriteGuard::downgrade_to_upgradable(wmap);
 		}
 
 		let map = map.as_ref().unwrap();
-		let offset = META_SIZE + index as usize * CHUNK_LEN;
-		// Nasty mutable pointer cast. We do ensure that all chunks that are being written are accessed
-		// through the overlay in other threads.
-		let ptr: *mut u8 = map.as_ptr() as *mut u8;
-		let chunk: &mut[u8] = unsafe {
-			let ptr = ptr.offset(offset as isize);
-			std::slice::from_raw_parts_mut(ptr, CHUNK_LEN)
-		};
-		let mut mask_buf = [0u8; 8];
-		log.read(&mut mask_buf)?;
-		let mut mask = u64::from_le_bytes(mask_buf);
-		while mask != 0 {
-			let i = mask.trailing_zeros();
-			mask = mask & !(1 << i);
-			log.read(&mut chunk[i as usize *ENTRY_BYTES .. (i as usize + 1)*ENTRY_BYTES])?;
-		}
+		map.with_chunk(index, |chunk| -> Result<()> {
+			// Nasty mutable pointer cast. We do ensure that all chunks that are being written are accessed
+			// through the overlay in other threads.
+			let chunk: &mut[u8] = unsafe {
+				std::slice::from_raw_parts_mut(chunk.as_ptr() as *mut u8, CHUNK_LEN)
+			};
+			let mut mask_buf = [0u8; 8];
+			log.read(&mut mask_buf)?;
+			let mut mask = u64::from_le_bytes(mask_buf);
+			while mask != 0 {
+				let i = mask.trailing_zeros();
+				mask = mask & !(1 << i);
+				log.read(&mut chunk[i as usize *ENTRY_BYTES .. (i as usize + 1)*ENTRY_BYTES])?;
+			}
+			Ok(())
+		})??;
 		log::trace!(target: "axia-db", "{}: Enacted chunk {}", self.id, index);
 		Ok(())
 	}
@@ -485,20 +654,36 @@ impl IndexTable {
 
 	pub fn flush(&self) -> Result<()> {
 		if let Some(map) = &*self.map.read() {
-			map.flush()?;
+			map.flush().context(|| self.context("flush"))?;
 		}
 		Ok(())
 	}
 
-	#[cfg(unix)]
-	fn madvise_random(&self, map: &mut memmap2::MmapMut) {
-		unsafe {
-			libc::madvise(map.as_mut_ptr() as _, file_size(self.id.index_bits()) as usize, libc::MADV_RANDOM);
+	fn context(&self, operation: &'static str) -> ErrorContext {
+		ErrorContext::new(operation).column(self.id.col()).path(&self.path)
+	}
+
+	/// Create the index file now, rather than on the first write.
+	pub fn preallocate(&self) -> Result<()> {
+		let map = self.map.upgradable_read();
+		if map.is_none() {
+			let mut wmap = RwLockUpgradableReadGuard::upgrade(map);
+			*wmap = Some(self.create_map()?);
 		}
+		Ok(())
 	}
 
-	#[cfg(not(unix))]
-	fn madvise_random(&self, _map: &mut memmap2::MmapMut) {}
+	fn create_map(&self) -> Result<IndexMap> {
+		let context = || self.context("create");
+		let file = std::fs::OpenOptions::new().write(true).read(true).create_new(true).open(self.path.as_path())
+			.context(context)?;
+		log::debug!(target: "axia-db", "Created new index {}", self.id);
+		//TODO: check for potential overflows on 32-bit platforms
+		file.set_len(file_size(self.id.index_bits())).context(context)?;
+		let map = IndexMap::open(file, &self.path, self.id, self.mmap_window).context(context)?;
+		map.madvise_random();
+		Ok(map)
+	}
 }
 
 #[cfg(test)]
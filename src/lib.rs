@@ -25,11 +25,34 @@ mod display;
 mod options;
 mod stats;
 mod compress;
+mod encrypt;
 mod migration;
+mod prepared;
+mod pool;
+#[cfg(any(test, feature = "test_helpers"))]
+mod testing;
+#[cfg(feature = "async")]
+mod async_db;
+#[cfg(feature = "ffi")]
+pub mod ffi;
 
-pub use db::{Db, Value, check::CheckOptions};
+pub use db::{Db, DbHealth, DbMetadata, PipelineStatus, Commit, CommitBuilder, CommitToken, CoordinatedCommit, PendingCommit, PreparedToken, Value, MAX_RECORD_META, check::CheckOptions};
+#[cfg(feature = "testing")]
+pub use db::EnableCommitPipelineStages;
+#[cfg(feature = "test_helpers")]
+pub use testing::DbBuilder;
+pub use column::{IterSnapshotPolicy, IterState, RawValueEntry, RawValueIter};
 pub use table::Key;
-pub use error::{Error, Result};
-pub use options::{ColumnOptions, Options};
-pub use migration::migrate;
+pub use log::LogFileInfo;
+pub use error::{Error, ErrorCode, ErrorContext, Result};
+pub use options::{ColumnMode, ColumnOptions, IndexMode, LogCleanupHook, Options, TableGrowth, ThreadConfig};
+pub use migration::{migrate, import_from, verify_import, ImportOptions, SourceIterator};
+#[cfg(feature = "sled")]
+pub use migration::SledSource;
+#[cfg(feature = "lmdb")]
+pub use migration::LmdbSource;
 pub use compress::CompressionType;
+pub use pool::{BufferPool, PooledValue};
+#[cfg(feature = "async")]
+pub use async_db::AsyncDb;
+pub use stats::{DbStats, ColumnStatSummary, ReindexEvent};
@@ -14,20 +14,41 @@
 // You should have received a copy of the GNU General Public License
 // along with AXIA.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::collections::{VecDeque, HashMap};
+use std::collections::{VecDeque, HashMap, HashSet};
 use std::io::{Read, Write, Seek};
 use std::convert::TryInto;
 use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU32, Ordering};
 use parking_lot::{Condvar, Mutex, RwLock, RwLockWriteGuard, MappedRwLockWriteGuard};
 use crate::{
-	error::{Error, Result},
+	error::{Error, ErrorContext, Result, ResultExt},
 	table::TableId as ValueTableId,
 	index::{TableId as IndexTableId, Chunk as IndexChunk, ENTRY_BYTES},
-	options::Options,
+	options::{LogCleanupHook, Options, EXTENDED_LOG_VERSION},
+	compress::{Compress, CompressionType},
 };
 
 const MAX_LOG_POOL_SIZE: usize = 16;
 
+// Call `f` until it succeeds, at most `retries` more times after the first failure.
+fn retry<T>(
+	retries: u32,
+	backoff: std::time::Duration,
+	mut f: impl FnMut() -> std::io::Result<T>,
+) -> std::io::Result<T> {
+	let mut attempt = 0;
+	loop {
+		match f() {
+			Ok(r) => return Ok(r),
+			Err(e) if attempt < retries => {
+				attempt += 1;
+				log::warn!(target: "axia-db", "Log fsync failed: {}. Retrying ({}/{})", e, attempt, retries);
+				std::thread::sleep(backoff * attempt);
+			}
+			Err(e) => return Err(e),
+		}
+	}
+}
+
 pub struct InsertIndexAction {
 	pub table: IndexTableId,
 	pub index: u64,
@@ -43,6 +64,10 @@ pub enum LogAction {
 	InsertIndex(InsertIndexAction),
 	InsertValue(InsertValueAction),
 	DropTable(IndexTableId),
+	/// User metadata attached to the record.
+	RecordMeta(Vec<u8>),
+	/// The record commits a prepared changeset with the given id.
+	CommitPrepared(u64),
 	EndRecord,
 }
 
@@ -57,6 +82,13 @@ pub struct LogOverlays {
 	value: HashMap<ValueTableId, ValueLogOverlay>,
 }
 
+impl LogOverlays {
+	#[cfg(any(test, feature = "testing"))]
+	pub fn is_empty(&self) -> bool {
+		self.index.values().all(|o| o.map.is_empty()) && self.value.values().all(|o| o.map.is_empty())
+	}
+}
+
 impl LogQuery for RwLock<LogOverlays> {
 	fn with_index<R, F: FnOnce(&IndexChunk) -> R> (&self, table: IndexTableId, index: u64, f: F) -> Option<R> {
 		self.read().index.get(&table).and_then(|o| o.map.get(&index).map(|(_id, _mask, data)| f(data)))
@@ -81,13 +113,41 @@ pub struct Cleared {
 	values: Vec<(ValueTableId, u64)>,
 }
 
+enum ReaderSource<'a> {
+	File(MappedRwLockWriteGuard<'a, std::io::BufReader<std::fs::File>>),
+	// A record that is applied without writing it to a log file, see `Options::no_wal`.
+	Memory(std::io::Cursor<Vec<u8>>),
+}
+
+impl<'a> Read for ReaderSource<'a> {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		match self {
+			ReaderSource::File(file) => file.read(buf),
+			ReaderSource::Memory(data) => data.read(buf),
+		}
+	}
+}
+
+impl<'a> Seek for ReaderSource<'a> {
+	fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+		match self {
+			ReaderSource::File(file) => file.seek(pos),
+			ReaderSource::Memory(data) => data.seek(pos),
+		}
+	}
+}
+
 pub struct LogReader<'a> {
-	file: MappedRwLockWriteGuard<'a, std::io::BufReader<std::fs::File>>,
+	file: ReaderSource<'a>,
 	record_id: u64,
 	read_bytes: u64,
 	crc32: crc32fast::Hasher,
 	validate: bool,
+	// Database version, entries added later are rejected.
+	version: u32,
 	cleared: Cleared,
+	// Decompressed body of the current record, read before the rest of the file.
+	body: Option<std::io::Cursor<Vec<u8>>>,
 }
 
 impl<'a> LogReader<'a> {
@@ -96,8 +156,9 @@ impl<'a> LogReader<'a> {
 	}
 
 	fn new(
-		file: MappedRwLockWriteGuard<'a, std::io::BufReader<std::fs::File>>,
+		file: ReaderSource<'a>,
 		validate: bool,
+		version: u32,
 	) -> LogReader<'a> {
 		LogReader {
 			cleared: Default::default(),
@@ -106,11 +167,14 @@ impl<'a> LogReader<'a> {
 			read_bytes: 0,
 			crc32: crc32fast::Hasher::new(),
 			validate,
+			version,
+			body: None,
 		}
 	}
 
 	pub fn reset(&mut self) -> Result<()> {
 		self.cleared = Default::default();
+		self.body = None;
 		self.file.seek(std::io::SeekFrom::Current(-(self.read_bytes as i64)))?;
 		self.read_bytes = 0;
 		self.record_id = 0;
@@ -119,17 +183,18 @@ impl<'a> LogReader<'a> {
 	}
 
 	pub fn next(&mut self) -> Result<LogAction> {
+		let version = self.version;
 		let mut read_buf = |size, buf: &mut [u8; 8]| -> Result<()> {
-			self.file.read_exact(&mut buf[0..size])?;
-			self.read_bytes += size as u64;
-			if self.validate {
-				self.crc32.update(&buf[0..size]);
-			}
-			Ok(())
+			self.read(&mut buf[0..size])
 		};
 
 		let mut buf = [0u8; 8];
 		read_buf(1, &mut buf)?;
+		if matches!(buf[0], 6 ..= 8) && version < EXTENDED_LOG_VERSION {
+			return Err(Error::Corruption(format!(
+				"Log entry type {} in a version {} database", buf[0], version
+			)));
+		}
 		match buf[0] {
 			1 =>  { // BeginRecord
 				read_buf(8, &mut buf)?;
@@ -154,6 +219,9 @@ impl<'a> LogReader<'a> {
 				Ok(LogAction::InsertValue(InsertValueAction { table, index }))
 			},
 			4 => {  // EndRecord
+				if self.body.take().is_some_and(|b| b.position() < b.get_ref().len() as u64) {
+					return Err(Error::Corruption("End of record in compressed log record body".into()))
+				}
 				self.file.read_exact(&mut buf[0..4])?;
 				self.read_bytes += 4;
 				if self.validate {
@@ -177,6 +245,32 @@ impl<'a> LogReader<'a> {
 				let table = IndexTableId::from_u16(u16::from_le_bytes(buf[0..2].try_into().unwrap()));
 				Ok(LogAction::DropTable(table))
 			}
+			6 => { // RecordMeta
+				read_buf(2, &mut buf)?;
+				let len = u16::from_le_bytes(buf[0..2].try_into().unwrap());
+				let mut meta = vec![0; len as usize];
+				self.read(&mut meta)?;
+				Ok(LogAction::RecordMeta(meta))
+			}
+			7 => { // CommitPrepared
+				read_buf(8, &mut buf)?;
+				let id = u64::from_le_bytes(buf[0..8].try_into().unwrap());
+				Ok(LogAction::CommitPrepared(id))
+			}
+			8 => { // CompressedBody
+				if self.body.is_some() {
+					return Err(Error::Corruption("Nested compressed log record body".into()))
+				}
+				self.read(&mut buf[0..5])?;
+				let compression = CompressionType::from_u8(buf[0])
+					.ok_or_else(|| Error::Corruption("Bad log record compression".into()))?;
+				let len = u32::from_le_bytes(buf[1..5].try_into().unwrap());
+				let mut data = vec![0; len as usize];
+				self.read(&mut data)?;
+				let body = Compress::new(compression, 0).decompress(&data)?;
+				self.body = Some(std::io::Cursor::new(body));
+				self.next()
+			}
 			_ => {
 				Err(Error::Corruption("Bad log entry type".into()))
 			}
@@ -184,6 +278,13 @@ impl<'a> LogReader<'a> {
 	}
 
 	pub fn read(&mut self, buf: &mut [u8]) -> Result<()> {
+		if let Some(body) = &mut self.body {
+			if body.position() < body.get_ref().len() as u64 {
+				return body.read_exact(buf)
+					.map_err(|_| Error::Corruption("Truncated compressed log record body".into()));
+			}
+			self.body = None;
+		}
 		self.file.read_exact(buf)?;
 		self.read_bytes += buf.len() as u64;
 		if self.validate {
@@ -201,11 +302,25 @@ impl<'a> LogReader<'a> {
 	}
 }
 
+/// Summary of a record written with `Log::end_record`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LogWriteStats {
+	/// Size of the record in the log file, including the checksum.
+	pub bytes_written: u64,
+	/// Number of index chunks written.
+	pub num_index_insertions: u64,
+	/// Number of value table entries written.
+	pub num_value_insertions: u64,
+	pub record_id: u64,
+}
+
 pub struct LogChange {
 	local_index: HashMap<IndexTableId, IndexLogOverlay>,
 	local_values: HashMap<ValueTableId, ValueLogOverlay>,
 	record_id: u64,
 	dropped_tables: Vec<IndexTableId>,
+	meta: Option<Vec<u8>>,
+	prepared: Option<u64>,
 }
 
 impl LogChange {
@@ -217,12 +332,18 @@ impl LogChange {
 			local_values: Default::default(),
 			dropped_tables: Default::default(),
 			record_id,
+			meta: None,
+			prepared: None,
 		}
 	}
 
-	pub fn to_file(self, file: &mut std::io::BufWriter<std::fs::File>)
-		-> Result<(HashMap<IndexTableId, IndexLogOverlay>, HashMap<ValueTableId, ValueLogOverlay>, u64)>
+	/// Write the record. With `compression`, everything between the record id and the
+	/// checksum is written as a single compressed entry, unless that does not make it
+	/// smaller. The checksum covers the bytes in the file.
+	pub fn to_file(self, file: &mut impl Write, compression: Option<&Compress>)
+		-> Result<(HashMap<IndexTableId, IndexLogOverlay>, HashMap<ValueTableId, ValueLogOverlay>, LogWriteStats)>
 	{
+		let mut stats = LogWriteStats { record_id: self.record_id, ..Default::default() };
 		let mut crc32 = crc32fast::Hasher::new();
 		let mut bytes: u64 = 0;
 
@@ -235,6 +356,46 @@ impl LogChange {
 
 		write(&1u8.to_le_bytes())?; // Begin record
 		write(&self.record_id.to_le_bytes())?;
+		match compression {
+			Some(compression) => {
+				let mut body = Vec::new();
+				self.write_body(&mut stats, &mut |buf| {
+					body.extend_from_slice(buf);
+					Ok(())
+				})?;
+				let compressed = compression.compress(&body);
+				if compressed.len() + 6 < body.len() && compressed.len() <= u32::MAX as usize {
+					write(&8u8.to_le_bytes())?;
+					write(&[CompressionType::from(compression) as u8])?;
+					write(&(compressed.len() as u32).to_le_bytes())?;
+					write(&compressed)?;
+				} else {
+					write(&body)?;
+				}
+			}
+			None => self.write_body(&mut stats, &mut write)?,
+		}
+
+		write(&4u8.to_le_bytes())?; // End record
+		let checksum: u32 = crc32.finalize();
+		file.write(&checksum.to_le_bytes())?;
+		bytes += 4;
+		file.flush()?;
+		stats.bytes_written = bytes;
+		Ok((self.local_index, self.local_values, stats))
+	}
+
+	fn write_body(&self, stats: &mut LogWriteStats, write: &mut dyn FnMut(&[u8]) -> Result<()>) -> Result<()> {
+		// Logs written before metadata was added don't have this entry.
+		if let Some(meta) = &self.meta {
+			write(&6u8.to_le_bytes())?;
+			write(&(meta.len() as u16).to_le_bytes())?;
+			write(meta)?;
+		}
+		if let Some(id) = self.prepared {
+			write(&7u8.to_le_bytes())?;
+			write(&id.to_le_bytes())?;
+		}
 
 		for (id, overlay) in self.local_index.iter() {
 			for (index, (_, modified_entries_mask, chunk)) in overlay.map.iter() {
@@ -242,6 +403,7 @@ impl LogChange {
 				write(&id.as_u16().to_le_bytes())?;
 				write(&index.to_le_bytes())?;
 				write(&modified_entries_mask.to_le_bytes())?;
+				stats.num_index_insertions += 1;
 				let mut mask = *modified_entries_mask;
 				while mask != 0 {
 					let i = mask.trailing_zeros();
@@ -256,6 +418,7 @@ impl LogChange {
 				write(&id.as_u16().to_le_bytes())?;
 				write(&index.to_le_bytes())?;
 				write(value)?;
+				stats.num_value_insertions += 1;
 			}
 		}
 		for id in self.dropped_tables.iter() {
@@ -263,13 +426,7 @@ impl LogChange {
 			write(&5u8.to_le_bytes().as_ref())?;
 			write(&id.as_u16().to_le_bytes())?;
 		}
-
-		write(&4u8.to_le_bytes())?; // End record
-		let checksum: u32 = crc32.finalize();
-		file.write(&checksum.to_le_bytes())?;
-		bytes += 4;
-		file.flush()?;
-		Ok((self.local_index, self.local_values, bytes))
+		Ok(())
 	}
 }
 
@@ -312,6 +469,16 @@ impl<'a> LogWriter<'a> {
 		self.log.dropped_tables.push(id);
 	}
 
+	/// Attach user metadata to the record. Must fit in `u16` bytes.
+	pub fn record_meta(&mut self, meta: Vec<u8>) {
+		self.log.meta = Some(meta);
+	}
+
+	/// Mark the record as committing the prepared changeset `id`.
+	pub fn commit_prepared(&mut self, id: u64) {
+		self.log.prepared = Some(id);
+	}
+
 	pub fn drain(self) -> LogChange {
 		self.log
 	}
@@ -390,6 +557,21 @@ enum ReadingState {
 	Idle,
 }
 
+/// Log file state, see `Db::log_files`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogFileInfo {
+	pub path: std::path::PathBuf,
+	/// Id of the first record in the file.
+	pub first_record: u64,
+	/// Id of the last record written to the file, or read from it for files
+	/// replayed on open.
+	pub last_record: u64,
+	/// The file is no longer appended to and has been flushed to disk.
+	pub flushed: bool,
+	/// All records in the file were enacted. The file is waiting to be cleaned up.
+	pub enacted: bool,
+}
+
 pub struct Log {
 	overlays: RwLock<LogOverlays>,
 	appending: RwLock<Option<Appending>>,
@@ -404,11 +586,19 @@ pub struct Log {
 	replay_queue: RwLock<VecDeque<(u32, u64, std::fs::File)>>,
 	path: std::path::PathBuf,
 	next_log_id: AtomicU32,
+	// First and last record id of the log files that are not in the pool, by log id.
+	record_ranges: Mutex<HashMap<u32, (u64, u64)>>,
 	sync: bool,
+	max_flush_retries: u32,
+	flush_retry_backoff: std::time::Duration,
+	compression: Option<Compress>,
+	on_cleanup: Option<LogCleanupHook>,
+	version: u32,
 }
 
 impl Log {
-	pub fn open(options: &Options) -> Result<Log> {
+	/// Open the logs of a database with format `version`.
+	pub fn open(options: &Options, version: u32) -> Result<Log> {
 		let path = options.path.clone();
 		let mut logs = VecDeque::new();
 		let mut max_log_id = 0;
@@ -435,6 +625,7 @@ impl Log {
 			}
 		}
 		logs.make_contiguous().sort_by_key(|(_id, record_id,  _)| *record_id);
+		let record_ranges = logs.iter().map(|(id, record_id, _)| (*id, (*record_id, *record_id))).collect();
 		let next_log_id = if logs.is_empty() { 0 } else { max_log_id + 1 };
 
 		Ok(Log {
@@ -446,8 +637,22 @@ impl Log {
 			flushing: Mutex::new(None),
 			next_record_id: AtomicU64::new(1),
 			next_log_id: AtomicU32::new(next_log_id),
+			record_ranges: Mutex::new(record_ranges),
 			dirty: AtomicBool::new(true),
 			sync: options.sync_wal,
+			max_flush_retries: options.max_flush_retries,
+			flush_retry_backoff: options.flush_retry_backoff,
+			compression: options.log_compression
+				.filter(|c| *c != CompressionType::NoCompression)
+				.filter(|_| {
+					if version < EXTENDED_LOG_VERSION {
+						log::warn!(target: "axia-db", "Log compression is not supported for database version {}", version);
+					}
+					version >= EXTENDED_LOG_VERSION
+				})
+				.map(|c| Compress::new(c, 0)),
+			on_cleanup: options.on_log_cleanup.clone(),
+			version,
 			replay_queue: RwLock::new(logs),
 			cleanup_queue: RwLock::new(VecDeque::new()),
 			log_pool: RwLock::new(Default::default()),
@@ -466,24 +671,29 @@ impl Log {
 	}
 
 	pub fn open_log_file(path: &std::path::Path) -> Result<(std::fs::File, Option<u64>)> {
-		let mut file = std::fs::OpenOptions::new().read(true).write(true).open(path)?;
-		if file.metadata()?.len() == 0 {
-			return Ok((file, None));
+		let open = || -> Result<_> {
+			let mut file = std::fs::OpenOptions::new().read(true).write(true).open(path)?;
+			if file.metadata()?.len() == 0 {
+				return Ok((file, None));
+			}
+			// read first record id
+			let mut buf = [0; 9];
+			file.read_exact(&mut buf)?;
+			file.seek(std::io::SeekFrom::Start(0))?;
+			Ok((file, Some(u64::from_le_bytes(buf[1..].try_into().unwrap()))))
+		};
+		let (file, id) = open().context(|| ErrorContext::new("open log").path(path))?;
+		if let Some(id) = id {
+			log::debug!(target: "axia-db", "Opened existing log {}, first record_id = {}", path.display(), id);
 		}
-		// read first record id
-		let mut buf = [0; 9];
-		file.read_exact(&mut buf)?;
-		file.seek(std::io::SeekFrom::Start(0))?;
-		let id = u64::from_le_bytes(buf[1..].try_into().unwrap());
-		log::debug!(target: "axia-db", "Opened existing log {}, first record_id = {}", path.display(), id);
-		Ok((file, Some(id)))
+		Ok((file, id))
 	}
 
 	fn drop_log(&self, id: u32) -> Result<()> {
 		log::debug!(target: "axia-db", "Drop log {}", id);
+		self.record_ranges.lock().remove(&id);
 		let path = Self::log_path(&self.path, id);
-		std::fs::remove_file(&path)?;
-		Ok(())
+		std::fs::remove_file(&path).context(|| ErrorContext::new("remove log").path(&path))
 	}
 
 	pub fn clear_replay_logs(&self) -> Result<()> {
@@ -519,7 +729,7 @@ impl Log {
 		writer
 	}
 
-	pub fn end_record(&self, log: LogChange) -> Result<u64> {
+	pub fn end_record(&self, log: LogChange) -> Result<LogWriteStats> {
 		assert!(log.record_id + 1 == self.next_record_id.load(Ordering::Relaxed));
 		let record_id = log.record_id;
 		let mut appending = self.appending.write();
@@ -532,7 +742,8 @@ impl Log {
 				// find a free id
 				let id = self.next_log_id.fetch_add(1, Ordering::SeqCst);
 				let path = Self::log_path(&self.path, id);
-				let file = std::fs::OpenOptions::new().create(true).read(true).write(true).open(path)?;
+				let file = std::fs::OpenOptions::new().create(true).read(true).write(true).open(&path)
+					.context(|| ErrorContext::new("create log").path(&path))?;
 				log::debug!(target: "axia-db", "Flush: Activated new writer {}", id);
 				(id, file)
 			};
@@ -543,7 +754,37 @@ impl Log {
 			});
 		}
 		let appending = appending.as_mut().unwrap();
-		let (index, values, bytes) = log.to_file(&mut appending.file)?;
+		let (index, values, stats) = log.to_file(&mut appending.file, self.compression.as_ref())?;
+		self.record_ranges.lock().entry(appending.id).or_insert((record_id, record_id)).1 = record_id;
+		self.add_overlays(record_id, index, values);
+		appending.size += stats.bytes_written;
+		self.dirty.store(true, Ordering::Relaxed);
+		Ok(stats)
+	}
+
+	/// Same as `end_record`, but the record is kept in memory instead of being written to
+	/// a log file. Returns a reader for the record, positioned after the record header,
+	/// that must be enacted right away. The changes are visible in the overlays until
+	/// `end_read` is called, as with records read from a file.
+	pub fn end_record_in_memory(&self, log: LogChange) -> Result<(LogReader<'static>, LogWriteStats)> {
+		assert!(log.record_id + 1 == self.next_record_id.load(Ordering::Relaxed));
+		let record_id = log.record_id;
+		let mut data = Vec::new();
+		let (index, values, stats) = log.to_file(&mut data, None)?;
+		self.add_overlays(record_id, index, values);
+		let mut reader = LogReader::new(ReaderSource::Memory(std::io::Cursor::new(data)), false, self.version);
+		match reader.next()? {
+			LogAction::BeginRecord => Ok((reader, stats)),
+			_ => Err(Error::Corruption("Bad log record structure".into())),
+		}
+	}
+
+	fn add_overlays(
+		&self,
+		record_id: u64,
+		index: HashMap<IndexTableId, IndexLogOverlay>,
+		values: HashMap<ValueTableId, ValueLogOverlay>,
+	) {
 		let mut overlays = self.overlays.write();
 		let mut total_index = 0;
 		for (id, overlay) in index.into_iter() {
@@ -562,9 +803,6 @@ impl Log {
 			total_index,
 			total_value,
 		);
-		appending.size += bytes;
-		self.dirty.store(true, Ordering::Relaxed);
-		Ok(bytes)
 	}
 
 	pub fn end_read(&self, cleared: Cleared, record_id: u64) {
@@ -600,7 +838,9 @@ impl Log {
 		overlays.index.retain(|_, overlay| !overlay.map.is_empty());
 	}
 
-	pub fn flush_one(&self, min_size: u64) -> Result<(bool, bool, bool)> {
+	// Returns whether a log was flushed, whether it can be read and whether one can be
+	// cleaned up, along with the time spent syncing it to disk.
+	pub fn flush_one(&self, min_size: u64) -> Result<(bool, bool, bool, Option<std::time::Duration>)> {
 		// Wait for the reader to finish reading
 		let mut flushing = self.flushing.lock();
 		let mut read_next = false;
@@ -650,15 +890,50 @@ impl Log {
 		}
 
 		// Flush to disk
+		let mut sync_time = None;
 		if self.sync {
 			if let Some(flushing) = flushing.as_ref() {
 				log::debug!(target: "axia-db", "Flush: Flushing log to disk");
-				flushing.file.sync_data()?;
+				let start = std::time::Instant::now();
+				retry(self.max_flush_retries, self.flush_retry_backoff, || flushing.file.sync_data())
+					.context(|| ErrorContext::new("flush log").path(Self::log_path(&self.path, flushing.id)))?;
+				sync_time = Some(start.elapsed());
 				log::debug!(target: "axia-db", "Flush: Flushing log completed");
 			}
 		}
 
-		Ok((flushing.is_some(), read_next, cleanup))
+		Ok((flushing.is_some(), read_next, cleanup, sync_time))
+	}
+
+	/// Write out the log file that is currently being appended to without handing it
+	/// over to the reader. Unlike `flush_one` this never waits for the log reader.
+	pub fn sync_appending(&self) -> Result<()> {
+		if let Some(appending) = self.appending.write().as_mut() {
+			appending.file.flush()?;
+			if self.sync {
+				let file = appending.file.get_ref();
+				retry(self.max_flush_retries, self.flush_retry_backoff, || file.sync_data())?;
+			}
+		}
+		Ok(())
+	}
+
+	/// Sync every log file that still holds records to disk, even if `Options::sync_wal`
+	/// is not set. Files that are cleaned up concurrently are skipped, their records
+	/// are already enacted.
+	pub fn sync_files(&self) -> Result<()> {
+		let ids: Vec<u32> = self.record_ranges.lock().keys().copied().collect();
+		for id in ids {
+			let path = Self::log_path(&self.path, id);
+			let file = match std::fs::OpenOptions::new().read(true).write(true).open(&path) {
+				Ok(file) => file,
+				Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+				Err(e) => return Err(e).context(|| ErrorContext::new("sync log").path(&path)),
+			};
+			retry(self.max_flush_retries, self.flush_retry_backoff, || file.sync_data())
+				.context(|| ErrorContext::new("sync log").path(&path))?;
+		}
+		Ok(())
 	}
 
 	pub fn replay_next(&mut self) -> Result<Option<u32>> {
@@ -684,14 +959,28 @@ impl Log {
 		}
 	}
 
-	pub fn clean_logs(&self, count: usize) -> Result<bool> {
+	/// Truncate up to `count` enacted logs and return them to the pool. Returns `true` if
+	/// there are more logs to clean, and the number of bytes freed.
+	pub fn clean_logs(&self, count: usize) -> Result<(bool, u64)> {
 		let mut cleaned: Vec<_> = {
-			self.cleanup_queue.write().drain(0..count).collect()
+			let mut queue = self.cleanup_queue.write();
+			// Another thread may have cleaned some of the logs already.
+			let count = std::cmp::min(count, queue.len());
+			// Removed under the queue lock, so that `log_files` never sees a cleaned log.
+			let mut record_ranges = self.record_ranges.lock();
+			queue.drain(0..count).inspect(|(id, _)| { record_ranges.remove(id); }).collect()
 		};
+		let mut freed = 0;
 		for (id, ref mut file) in cleaned.iter_mut() {
+			if let Some(hook) = &self.on_cleanup {
+				hook(&Self::log_path(&self.path, *id));
+			}
 			log::debug!(target: "axia-db", "Cleaned: {}", id);
-			file.seek(std::io::SeekFrom::Start(0))?;
-			file.set_len(0)?;
+			let len = file.metadata()
+				.and_then(|m| file.seek(std::io::SeekFrom::Start(0)).map(|_| m.len()))
+				.and_then(|len| file.set_len(0).map(|_| len))
+				.context(|| ErrorContext::new("clean log").path(Self::log_path(&self.path, *id)))?;
+			freed += len;
 		}
 		// Move cleaned logs back to the pool
 		let mut pool = self.log_pool.write();
@@ -705,7 +994,7 @@ impl Log {
 				self.drop_log(id)?;
 			}
 		}
-		Ok(!self.cleanup_queue.read().is_empty())
+		Ok((!self.cleanup_queue.read().is_empty(), freed))
 	}
 
 	pub fn num_dirty_logs(&self) -> usize {
@@ -720,14 +1009,21 @@ impl Log {
 		}
 
 		let reading = self.reading.write();
-		if reading.is_none() {
-			log::trace!(target: "axia-db", "No active reader");
-			return Ok(None);
-		}
+		let id = match reading.as_ref() {
+			Some(reading) => reading.id,
+			None => {
+				log::trace!(target: "axia-db", "No active reader");
+				return Ok(None);
+			}
+		};
 		let reading = RwLockWriteGuard::map(reading, |r| &mut r.as_mut().unwrap().file);
-		let mut reader = LogReader::new(reading, validate);
+		let mut reader = LogReader::new(ReaderSource::File(reading), validate, self.version);
 		match reader.next() {
 			Ok(LogAction::BeginRecord) => {
+				// Replayed logs only have the first record id known on open.
+				if let Some(range) = self.record_ranges.lock().get_mut(&id) {
+					range.1 = std::cmp::max(range.1, reader.record_id());
+				}
 				return Ok(Some(reader));
 			}
 			Ok(_) => return Err(Error::Corruption("Bad log record structure".into())),
@@ -741,10 +1037,31 @@ impl Log {
 		};
 	}
 
+	/// Id of the last record started, or 0 if there was none.
+	pub fn last_record_id(&self) -> u64 {
+		self.next_record_id.load(Ordering::Relaxed) - 1
+	}
+
 	pub fn overlays(&self) -> &RwLock<LogOverlays> {
 		&self.overlays
 	}
 
+	/// Log files that hold records, ordered by record id.
+	pub fn log_files(&self) -> Vec<LogFileInfo> {
+		let appending = self.appending.read().as_ref().map(|a| a.id);
+		let cleanup_queue = self.cleanup_queue.read();
+		let enacted: HashSet<u32> = cleanup_queue.iter().map(|(id, _)| *id).collect();
+		let mut files: Vec<_> = self.record_ranges.lock().iter().map(|(id, (first, last))| LogFileInfo {
+			path: Self::log_path(&self.path, *id),
+			first_record: *first,
+			last_record: *last,
+			flushed: appending != Some(*id),
+			enacted: enacted.contains(id),
+		}).collect();
+		files.sort_by_key(|f| f.first_record);
+		files
+	}
+
 	pub fn kill_logs(&self) -> Result<()> {
 		let mut log_pool = self.log_pool.write();
 		for (id, file) in log_pool.drain(..) {
@@ -752,9 +1069,41 @@ impl Log {
 			self.drop_log(id)?;
 		}
 		if let Some(reading) = self.reading.write().take() {
+			// Everything is enacted on shutdown, so this is the last log to clean up.
+			if let Some(hook) = &self.on_cleanup {
+				if reading.file.get_ref().metadata().is_ok_and(|m| m.len() > 0) {
+					hook(&Self::log_path(&self.path, reading.id));
+				}
+			}
 			std::mem::drop(reading.file);
 			self.drop_log(reading.id)?;
 		}
 		Ok(())
 	}
 }
+
+#[cfg(test)]
+mod test {
+	use super::retry;
+	use std::time::Duration;
+
+	#[test]
+	fn test_retry() {
+		let failure = || std::io::Error::new(std::io::ErrorKind::Other, "transient");
+		let mut calls = 0;
+		let r = retry(2, Duration::from_millis(1), || {
+			calls += 1;
+			if calls < 3 { Err(failure()) } else { Ok(calls) }
+		});
+		assert_eq!(r.unwrap(), 3);
+
+		calls = 0;
+		let r: std::io::Result<()> = retry(2, Duration::from_millis(1), || {
+			calls += 1;
+			Err(failure())
+		});
+		assert!(r.is_err());
+		assert_eq!(calls, 3);
+		assert!(retry(0, Duration::from_millis(1), || Err::<(), _>(failure())).is_err());
+	}
+}
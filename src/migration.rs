@@ -17,7 +17,7 @@
 /// Database migration.
 
 use std::path::Path;
-use crate::{options::Options, db::Db, Error, Result, column::{ColId, IterState}};
+use crate::{options::Options, db::Db, Error, Result, column::{ColId, Column, IterSnapshotPolicy, IterState}};
 
 const COMMIT_SIZE: usize = 10240;
 const OVERWRITE_TMP_PATH: &str = "to_revert_overwrite";
@@ -54,7 +54,7 @@ pub fn migrate(from: &Path, mut to: Options, overwrite: bool, force_migrate: &Ve
 	let mut commit = Vec::with_capacity(COMMIT_SIZE);
 	let mut last_time = std::time::Instant::now();
 	for c in 0 .. source_options.columns.len() as ColId {
-		if source_options.columns[c as usize] != to.columns[c as usize] {
+		if source.column_options(c)? != dest.column_options(c)? {
 			to_migrate.insert(c);
 		}
 	}
@@ -68,7 +68,14 @@ pub fn migrate(from: &Path, mut to: Options, overwrite: bool, force_migrate: &Ve
 			continue;
 		}
 		log::info!("Migrating col {}", c);
-		source.iter_column_while(c, |IterState { chunk_index: index, key, rc, mut value }| {
+		let ordered = dest.column_options(c)?.ordered;
+		if ordered && !source_options.columns[c as usize].ordered {
+			return Err(Error::Migration(format!("Column {} can't be made ordered, original keys are not stored", c)));
+		}
+		source.iter_column_while(c, IterSnapshotPolicy::CommitOverlayIncluded, |IterState { chunk_index: index, key, rc, original_key, mut value, .. }| {
+			if let (true, Some(original_key)) = (ordered, &original_key) {
+				value = Column::with_original_key(original_key, &value);
+			}
 			//TODO: more efficient ref migration
 			for _ in 0 .. rc {
 				let value = std::mem::take(&mut value);
@@ -128,6 +135,256 @@ pub fn migrate(from: &Path, mut to: Options, overwrite: bool, force_migrate: &Ve
 	Ok(())
 }
 
+/// Source of data for `import_from`, e.g. another key-value store. Columns and
+/// the entries in each column must be returned in the same order every time, so
+/// that an interrupted import can be resumed.
+pub trait SourceIterator {
+	/// Move to the next column and return its name. Returns `None` when there are no more columns.
+	fn next_column(&mut self) -> Result<Option<Vec<u8>>>;
+	/// Next key and value in the current column. Returns `None` at the end of the column.
+	fn next_entry(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>>;
+}
+
+/// Options for `import_from`.
+#[derive(Debug, Default, Clone)]
+pub struct ImportOptions {
+	/// File to record progress in. If the file exists, the import resumes from the
+	/// recorded position. The file is removed once the import is complete.
+	pub checkpoint: Option<std::path::PathBuf>,
+}
+
+// Position of the import: source column index and number of entries imported from it.
+fn read_checkpoint(path: &Path) -> Result<(u64, u64)> {
+	if !path.exists() {
+		return Ok((0, 0));
+	}
+	let content = std::fs::read_to_string(path)?;
+	let mut parts = content.split_whitespace().map(|p| p.parse::<u64>());
+	match (parts.next(), parts.next()) {
+		(Some(Ok(column)), Some(Ok(entries))) => Ok((column, entries)),
+		_ => Err(Error::Migration(format!("Invalid import checkpoint {}", path.display()))),
+	}
+}
+
+fn write_checkpoint(path: &Path, column: u64, entries: u64) -> Result<()> {
+	let mut tmp = path.as_os_str().to_owned();
+	tmp.push(".tmp");
+	std::fs::write(&tmp, format!("{} {}\n", column, entries))?;
+	std::fs::rename(&tmp, path)?;
+	Ok(())
+}
+
+/// Import all entries from `source` into `dest`. `mapping` returns the destination
+/// column for a source column name, or `None` to skip the column. Changes are
+/// committed in batches, each written to the log before the checkpoint is updated.
+/// Returns the number of imported entries.
+pub fn import_from(
+	mut source: impl SourceIterator,
+	dest: &Db,
+	mapping: impl Fn(&[u8]) -> Option<ColId>,
+	options: &ImportOptions,
+) -> Result<u64> {
+	let (start_column, start_entries) = match &options.checkpoint {
+		Some(path) => read_checkpoint(path)?,
+		None => (0, 0),
+	};
+	if start_column != 0 || start_entries != 0 {
+		log::info!("Resuming import at column #{}, entry #{}", start_column, start_entries);
+	}
+	let mut imported = 0;
+	let mut column_index = 0;
+	let mut last_time = std::time::Instant::now();
+	while let Some(name) = source.next_column()? {
+		let col = match mapping(&name) {
+			Some(col) if column_index >= start_column => col,
+			_ => {
+				column_index += 1;
+				continue;
+			}
+		};
+		log::info!("Importing column {} into col {}", String::from_utf8_lossy(&name), col);
+		let mut entries = 0;
+		let mut commit = Vec::with_capacity(COMMIT_SIZE);
+		while let Some((key, value)) = source.next_entry()? {
+			entries += 1;
+			if column_index == start_column && entries <= start_entries {
+				continue;
+			}
+			commit.push((col, key, Some(value)));
+			if commit.len() == COMMIT_SIZE {
+				imported += commit.len() as u64;
+				dest.commit_durable(std::mem::take(&mut commit))?;
+				if let Some(path) = &options.checkpoint {
+					write_checkpoint(path, column_index, entries)?;
+				}
+				if last_time.elapsed() > std::time::Duration::from_secs(3) {
+					last_time = std::time::Instant::now();
+					log::info!("Importing col {}, #{}", col, entries);
+				}
+			}
+		}
+		imported += commit.len() as u64;
+		dest.commit_durable(commit)?;
+		column_index += 1;
+		if let Some(path) = &options.checkpoint {
+			write_checkpoint(path, column_index, 0)?;
+		}
+	}
+	if let Some(path) = &options.checkpoint {
+		std::fs::remove_file(path)?;
+	}
+	Ok(imported)
+}
+
+/// Check that all entries of `source` are present in `dest`, e.g. after `import_from`
+/// with the same mapping. Returns an error for the first missing or different value.
+pub fn verify_import(
+	mut source: impl SourceIterator,
+	dest: &Db,
+	mapping: impl Fn(&[u8]) -> Option<ColId>,
+) -> Result<()> {
+	while let Some(name) = source.next_column()? {
+		let col = match mapping(&name) {
+			Some(col) => col,
+			None => continue,
+		};
+		while let Some((key, value)) = source.next_entry()? {
+			if dest.get(col, &key)?.as_ref() != Some(&value) {
+				return Err(Error::Migration(format!(
+					"Imported value mismatch in column {}, key {}",
+					String::from_utf8_lossy(&name),
+					hex::encode(&key),
+				)));
+			}
+		}
+	}
+	Ok(())
+}
+
+/// `SourceIterator` over the trees of a sled database, enabled with the `sled` feature.
+/// Columns are named after the trees, including the default tree `__sled__default`.
+#[cfg(feature = "sled")]
+pub struct SledSource {
+	trees: std::vec::IntoIter<sled::Tree>,
+	current: Option<sled::Iter>,
+}
+
+#[cfg(feature = "sled")]
+impl SledSource {
+	pub fn new(db: &sled::Db) -> Result<SledSource> {
+		let trees = db.tree_names().into_iter()
+			.map(|name| db.open_tree(name))
+			.collect::<std::result::Result<Vec<_>, _>>()
+			.map_err(|e| Error::Migration(format!("Error opening sled tree: {}", e)))?;
+		Ok(SledSource { trees: trees.into_iter(), current: None })
+	}
+}
+
+#[cfg(feature = "sled")]
+impl SourceIterator for SledSource {
+	fn next_column(&mut self) -> Result<Option<Vec<u8>>> {
+		let tree = self.trees.next();
+		self.current = tree.as_ref().map(|tree| tree.iter());
+		Ok(tree.map(|tree| tree.name().to_vec()))
+	}
+
+	fn next_entry(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+		match self.current.as_mut().and_then(|iter| iter.next()) {
+			Some(Ok((key, value))) => Ok(Some((key.to_vec(), value.to_vec()))),
+			Some(Err(e)) => Err(Error::Migration(format!("Error reading sled tree: {}", e))),
+			None => Ok(None),
+		}
+	}
+}
+
+/// `SourceIterator` over databases of an LMDB environment, enabled with the `lmdb` feature.
+/// Entries are read in batches, each in its own read transaction, so that writers are
+/// not blocked for the whole import.
+#[cfg(feature = "lmdb")]
+pub struct LmdbSource<'a> {
+	env: &'a lmdb::Environment,
+	names: std::vec::IntoIter<Option<String>>,
+	db: Option<lmdb::Database>,
+	batch: std::collections::VecDeque<(Vec<u8>, Vec<u8>)>,
+	last_key: Option<Vec<u8>>,
+	done: bool,
+}
+
+#[cfg(feature = "lmdb")]
+const LMDB_BATCH: usize = 1024;
+
+#[cfg(feature = "lmdb")]
+impl<'a> LmdbSource<'a> {
+	/// Read the databases `names` of `env`, in that order. `None` is the unnamed database.
+	/// Columns are named after the databases, the unnamed one with an empty name.
+	pub fn new(env: &'a lmdb::Environment, names: Vec<Option<String>>) -> LmdbSource<'a> {
+		LmdbSource {
+			env,
+			names: names.into_iter(),
+			db: None,
+			batch: Default::default(),
+			last_key: None,
+			done: true,
+		}
+	}
+
+	fn read_batch(&mut self) -> Result<()> {
+		use lmdb::{Cursor, Transaction};
+		let db = match self.db {
+			Some(db) => db,
+			None => return Ok(()),
+		};
+		let txn = self.env.begin_ro_txn().map_err(lmdb_error)?;
+		{
+			let mut cursor = txn.open_ro_cursor(db).map_err(lmdb_error)?;
+			let iter = match &self.last_key {
+				Some(key) => cursor.iter_from(key),
+				None => cursor.iter_start(),
+			};
+			// Continues at the last key read, which was already returned.
+			let last_key = self.last_key.as_deref();
+			self.batch.extend(iter
+				.skip_while(|(key, _)| Some(*key) == last_key)
+				.take(LMDB_BATCH)
+				.map(|(key, value)| (key.to_vec(), value.to_vec())));
+		}
+		self.done = self.batch.len() < LMDB_BATCH;
+		self.last_key = self.batch.back().map(|(key, _)| key.clone());
+		Ok(())
+	}
+}
+
+#[cfg(feature = "lmdb")]
+fn lmdb_error(e: lmdb::Error) -> Error {
+	Error::Migration(format!("LMDB error: {}", e))
+}
+
+#[cfg(feature = "lmdb")]
+impl<'a> SourceIterator for LmdbSource<'a> {
+	fn next_column(&mut self) -> Result<Option<Vec<u8>>> {
+		self.batch.clear();
+		self.last_key = None;
+		let name = match self.names.next() {
+			Some(name) => name,
+			None => {
+				self.db = None;
+				self.done = true;
+				return Ok(None);
+			}
+		};
+		self.db = Some(self.env.open_db(name.as_deref()).map_err(lmdb_error)?);
+		self.done = false;
+		Ok(Some(name.unwrap_or_default().into_bytes()))
+	}
+
+	fn next_entry(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+		if self.batch.is_empty() && !self.done {
+			self.read_batch()?;
+		}
+		Ok(self.batch.pop_front())
+	}
+}
+
 fn move_column(c: ColId, from: &Path, to: &Path) -> Result<()> {
 	deplace_column(c, from, to, false)
 }
@@ -137,11 +394,18 @@ fn copy_column(c: ColId, from: &Path, to: &Path) -> Result<()> {
 }
 
 fn deplace_column(c: ColId, from: &Path, to: &Path, copy: bool) -> Result<()> {
+	// Saved ordered keys of the replaced column don't match the new tables.
+	let ordered_keys = crate::column::ordered_keys_file_name(c);
+	match std::fs::remove_file(to.join(&ordered_keys)) {
+		Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+		_ => (),
+	}
 	for entry in std::fs::read_dir(from)? {
 		let entry = entry?;
 		if let Some(file) = entry.path().file_name().and_then(|f| f.to_str()) {
 			if crate::index::TableId::is_file_name(c, file)
-				|| crate::table::TableId::is_file_name(c, file) {
+				|| crate::table::TableId::is_file_name(c, file)
+				|| file == ordered_keys {
 
 				let mut from = from.to_path_buf();
 				from.push(file);
@@ -160,7 +424,7 @@ fn deplace_column(c: ColId, from: &Path, to: &Path, copy: bool) -> Result<()> {
 
 #[cfg(test)]
 mod test {
-	use crate::{Db, Options, migration::migrate};
+	use crate::{Db, Options, Result, migration::{migrate, import_from, verify_import, ImportOptions, SourceIterator}};
 
 	struct TempDir(std::path::PathBuf);
 
@@ -210,4 +474,154 @@ mod test {
 		let dest = Db::with_columns(&dest_dir, 1).unwrap();
 		assert_eq!(dest.get(0, b"1").unwrap(), Some("value".as_bytes().to_vec()));
 	}
+
+	#[test]
+	fn migrate_ordered() {
+		let dir = TempDir::new("migrate_ordered");
+		let source_dir = dir.path("source");
+		let mut source_opts = Options::with_columns(&source_dir, 2);
+		source_opts.columns[0].ordered = true;
+		{
+			let source = Db::open_or_create(&source_opts).unwrap();
+			source.commit([(0, b"b".to_vec(), Some(b"vb".to_vec())), (0, b"a".to_vec(), Some(b"va".to_vec()))]).unwrap();
+		}
+
+		let mut dest_opts = Options::with_columns(&dir.path("dest"), 2);
+		dest_opts.columns[0].ordered = true;
+		dest_opts.columns[0].compression = crate::CompressionType::Lz4;
+		migrate(&source_dir, dest_opts.clone(), false, &vec![]).unwrap();
+		let dest = Db::open(&dest_opts).unwrap();
+		let mut entries = Vec::new();
+		dest.iter_range(0, None, None, |k, v| { entries.push((k.to_vec(), v)); true }).unwrap();
+		assert_eq!(entries, vec![(b"a".to_vec(), b"va".to_vec()), (b"b".to_vec(), b"vb".to_vec())]);
+
+		// Original keys of other columns are not known.
+		let mut dest_opts = Options::with_columns(&dir.path("dest_unordered"), 2);
+		dest_opts.columns[1].ordered = true;
+		assert!(matches!(migrate(&source_dir, dest_opts, false, &vec![]), Err(crate::Error::Migration(_))));
+	}
+
+	// Columns of `(name, entries)`. Fails after `fail_after` entries, if set.
+	struct VecSource {
+		columns: Vec<(Vec<u8>, Vec<(Vec<u8>, Vec<u8>)>)>,
+		column: Option<usize>,
+		entry: usize,
+		fail_after: Option<usize>,
+	}
+
+	impl VecSource {
+		fn new(fail_after: Option<usize>) -> Self {
+			let column = |name: &str, n: u32| (
+				name.as_bytes().to_vec(),
+				(0 .. n).map(|i| (i.to_le_bytes().to_vec(), format!("{}{}", name, i).into_bytes())).collect(),
+			);
+			VecSource {
+				columns: vec![column("a", 20000), column("skip", 10), column("b", 100)],
+				column: None,
+				entry: 0,
+				fail_after,
+			}
+		}
+	}
+
+	impl SourceIterator for VecSource {
+		fn next_column(&mut self) -> Result<Option<Vec<u8>>> {
+			let next = self.column.map_or(0, |c| c + 1);
+			self.column = Some(next);
+			self.entry = 0;
+			Ok(self.columns.get(next).map(|(name, _)| name.clone()))
+		}
+
+		fn next_entry(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+			if let Some(n) = self.fail_after.as_mut() {
+				if *n == 0 {
+					return Err(crate::Error::Migration("Source failed".into()));
+				}
+				*n -= 1;
+			}
+			let column = &self.columns[self.column.unwrap()].1;
+			self.entry += 1;
+			Ok(column.get(self.entry - 1).cloned())
+		}
+	}
+
+	#[test]
+	fn import_resume() {
+		let dir = TempDir::new("import_resume");
+		let checkpoint = dir.path("checkpoint");
+		let dest = Db::with_columns(dir.path("dest"), 2).unwrap();
+		let mapping = |name: &[u8]| match name {
+			b"a" => Some(0),
+			b"b" => Some(1),
+			_ => None,
+		};
+		let options = ImportOptions { checkpoint: Some(checkpoint.clone()) };
+
+		// Interrupted after the first batch.
+		assert!(import_from(VecSource::new(Some(15000)), &dest, mapping, &options).is_err());
+		assert!(checkpoint.exists());
+		assert!(verify_import(VecSource::new(None), &dest, mapping).is_err());
+
+		let imported = import_from(VecSource::new(None), &dest, mapping, &options).unwrap();
+		assert_eq!(imported, 20000 + 100 - super::COMMIT_SIZE as u64);
+		assert!(!checkpoint.exists());
+		verify_import(VecSource::new(None), &dest, mapping).unwrap();
+		assert_eq!(dest.get(1, &5u32.to_le_bytes()).unwrap(), Some(b"b5".to_vec()));
+	}
+
+	#[cfg(feature = "sled")]
+	#[test]
+	fn import_sled() {
+		let dir = TempDir::new("import_sled");
+		let source = sled::open(dir.path("source")).unwrap();
+		let tree = source.open_tree(b"b").unwrap();
+		for i in 0 .. 100u32 {
+			source.insert(i.to_le_bytes(), b"default".to_vec()).unwrap();
+			tree.insert(i.to_le_bytes(), format!("b{}", i).into_bytes()).unwrap();
+		}
+		let dest = Db::with_columns(dir.path("dest"), 2).unwrap();
+		let mapping = |name: &[u8]| match name {
+			b"__sled__default" => Some(0),
+			b"b" => Some(1),
+			_ => None,
+		};
+		let options = ImportOptions::default();
+		let imported = import_from(super::SledSource::new(&source).unwrap(), &dest, mapping, &options).unwrap();
+		assert_eq!(imported, 200);
+		verify_import(super::SledSource::new(&source).unwrap(), &dest, mapping).unwrap();
+		assert_eq!(dest.get(1, &5u32.to_le_bytes()).unwrap(), Some(b"b5".to_vec()));
+	}
+
+	#[cfg(feature = "lmdb")]
+	#[test]
+	fn import_lmdb() {
+		use lmdb::Transaction;
+		let dir = TempDir::new("import_lmdb");
+		let source_dir = dir.path("source");
+		std::fs::create_dir_all(&source_dir).unwrap();
+		let env = lmdb::Environment::new().set_max_dbs(2).open(&source_dir).unwrap();
+		let a = env.create_db(Some("a"), lmdb::DatabaseFlags::empty()).unwrap();
+		let b = env.create_db(Some("b"), lmdb::DatabaseFlags::empty()).unwrap();
+		let mut txn = env.begin_rw_txn().unwrap();
+		// More than a batch, so that reading continues in another transaction.
+		for i in 0 .. 3000u32 {
+			txn.put(a, &i.to_be_bytes(), &format!("a{}", i), lmdb::WriteFlags::empty()).unwrap();
+		}
+		txn.put(b, b"key", b"value", lmdb::WriteFlags::empty()).unwrap();
+		txn.commit().unwrap();
+
+		let dest = Db::with_columns(dir.path("dest"), 2).unwrap();
+		let names = || vec![Some("a".to_string()), Some("b".to_string())];
+		let mapping = |name: &[u8]| match name {
+			b"a" => Some(0),
+			b"b" => Some(1),
+			_ => None,
+		};
+		let options = ImportOptions::default();
+		let imported = import_from(super::LmdbSource::new(&env, names()), &dest, mapping, &options).unwrap();
+		assert_eq!(imported, 3001);
+		verify_import(super::LmdbSource::new(&env, names()), &dest, mapping).unwrap();
+		assert_eq!(dest.get(0, &2999u32.to_be_bytes()).unwrap(), Some(b"a2999".to_vec()));
+		assert_eq!(dest.get(1, b"key").unwrap(), Some(b"value".to_vec()));
+	}
 }
@@ -15,19 +15,29 @@
 // along with AXIA.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::io::Write;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::path::{PathBuf, Path};
-use crate::error::{Error, Result};
+use crate::error::{Error, ErrorContext, Result, ResultExt};
 use crate::column::Salt;
 use crate::compress::CompressionType;
 use rand::Rng;
 
-pub const CURRENT_VERSION: u32 = 5;
+/// Called with the path of a log file before it is cleaned up, see `Options::on_log_cleanup`.
+pub type LogCleanupHook = std::sync::Arc<dyn Fn(&Path) + Send + Sync>;
+
+pub const CURRENT_VERSION: u32 = 6;
+// Log records may contain commit metadata, prepared commit ids and compressed bodies.
+pub(crate) const EXTENDED_LOG_VERSION: u32 = 6;
 // TODO on last supported 5, remove MULTIHEAD_V4 and MULTIPART_V4
 const LAST_SUPPORTED_VERSION: u32 = 4;
+const DEFAULT_TABLE_GROW_BYTES: u64 = 256 * 1024;
+// Largest accepted `initial_index_bits`. Creates an 8GiB index file.
+const MAX_INITIAL_INDEX_BITS: u8 = 24;
+const MIN_LOAD_FACTOR: f32 = 0.5;
+const DEFAULT_LOAD_FACTOR: f32 = 1.0;
 
 /// Database configuration.
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub struct Options {
 	/// Database path.
 	pub path: std::path::PathBuf,
@@ -39,14 +49,223 @@ pub struct Options {
 	/// fsync/msync data to disk before removing logs. Provides crash resistance guarantee.
 	/// On by default.
 	pub sync_data: bool,
+	/// Apply commits to the tables directly, without writing them to the log first.
+	/// Only meant for disposable databases, e.g. in tests: **a crash or unclean
+	/// shutdown may leave the database corrupted.** While the database is open, it is
+	/// marked dirty, and opening it after an unclean shutdown fails with
+	/// `Error::UncleanNoWalShutdown` unless `force_open_dirty` is set. Opening fails if
+	/// the database has log records that still need to be replayed. Off by default.
+	pub no_wal: bool,
+	/// Open a database that was not shut down cleanly while running with `no_wal`.
+	/// Its contents may be corrupted.
+	pub force_open_dirty: bool,
 	/// Collect database statistics. May have effect on performance.
 	pub stats: bool,
+	/// When `stats` is enabled, also append a structured snapshot of the statistics
+	/// to `stats.ndjson` on shutdown, keeping a history across sessions.
+	pub stats_history: bool,
+	/// When `stats` is enabled, also write `stats.txt` at this interval rather than only
+	/// on shutdown, so that the statistics survive a crash. The file is replaced
+	/// atomically. Not written periodically by default.
+	pub stats_interval: Option<std::time::Duration>,
+	/// How much value table files are extended when they run out of space.
+	pub table_growth: TableGrowth,
+	/// Split value tables into files of at most this many bytes. A table that
+	/// would grow past it continues in a numbered file next to the first one, e.g.
+	/// `table_00_0a.1`. Must be at least the largest value table entry size. Stored
+	/// in the metadata; reopening with a different setting fails. Not split by default.
+	pub max_table_file_size: Option<u64>,
 	/// Override salt value. If `None` is specified salt is loaded from metadata
 	/// or randomly generated when creating a new database.
 	pub salt: Option<Salt>,
+	/// Allow opening an existing database with a different number of columns.
+	/// When `false` (the default), a mismatch fails with `Error::ColumnCountMismatch`.
+	/// When `true`, columns beyond the existing ones are created empty and recorded
+	/// in the metadata. Existing columns beyond those in `columns` are kept and can be
+	/// read, but committing to them fails. Settings of the columns present in both
+	/// must still match.
+	pub allow_column_count_change: bool,
+	/// Names and priority of the background worker threads.
+	pub thread_config: ThreadConfig,
+	/// Number of times a failed log fsync is retried before the database stops
+	/// with a background error. Defaults to 0. Note that some filesystems drop
+	/// unwritten pages on a failed fsync, so a successful retry may not guarantee
+	/// that the earlier writes are durable.
+	pub max_flush_retries: u32,
+	/// Delay before the first log fsync retry. The delay grows linearly with
+	/// each further attempt.
+	pub flush_retry_backoff: std::time::Duration,
+	/// Encrypt values with this key. Must be set when creating the database
+	/// and on every open afterwards. The key itself is never stored.
+	#[cfg(feature = "encryption")]
+	pub encryption_key: Option<[u8; crate::encrypt::KEY_LEN]>,
+	/// Reject commits with more operations than this with `Error::ChangesetTooLarge`.
+	/// Very large commits hold the commit overlay lock for a long time and are written
+	/// as a single log record, stalling other readers and writers. Not limited by default.
+	pub max_changeset_ops: Option<usize>,
+	/// Number of entries the commit overlay of each column is allocated for on open.
+	/// The overlay holds the changes of queued commits and grows while the commit
+	/// overlay lock is held, which stalls readers and writers during bursts of large
+	/// commits. Preallocating avoids growing it. Defaults to 0.
+	pub overlay_initial_capacity: usize,
+	/// Compress log records with this algorithm. Trades CPU time for less log I/O.
+	/// Records that don't get smaller are written uncompressed. Each record is
+	/// marked, so logs written with a different setting are still replayed. Not
+	/// compressed by default.
+	pub log_compression: Option<CompressionType>,
+	/// Map index files in windows of this many bytes, rather than as a whole. Only a
+	/// few windows of each index file are mapped at a time, which allows large
+	/// databases on 32-bit platforms where the address space can't fit the whole
+	/// index. Lookups are slower. Must be a power of two of at least 64 KiB. Value
+	/// tables are never mapped. Not set by default.
+	pub mmap_window: Option<usize>,
+	/// Reject commits with `Error::QuotaExceeded` if they could make the database
+	/// files use more disk space than this. Log and value table growth is counted as it
+	/// happens, and the database directory is rescanned every few seconds to account for
+	/// the index files. The queued data and the new commit are counted twice, since they are
+	/// written to the log first. Commits that only remove keys are always allowed.
+	/// Not limited by default.
+	pub max_total_bytes: Option<u64>,
+	/// Log a warning when a committer waits for the commit queue, the log worker waits
+	/// for the log queue, or a log fsync takes at least this long. Such stalls are
+	/// counted in `DbStats`. Defaults to 1 second.
+	pub stall_warn_threshold: std::time::Duration,
+	/// After each log record is enacted, read back every key changed by the commit and
+	/// check that the tables match the change, failing with `Error::Corruption`
+	/// otherwise. Values of append-only columns are not checked. Very slow and keeps a
+	/// copy of every commit until it is enacted; meant for soak tests. Off by default.
+	pub paranoid_checks: bool,
+	/// Called with the path of each log file right before it is cleaned up, e.g. to
+	/// archive it. All records in the file have been enacted by then, and the file
+	/// is not changed until the hook returns. Called from the background workers and
+	/// on shutdown, so it should not take long. Not set by default.
+	pub on_log_cleanup: Option<LogCleanupHook>,
+}
+
+// Written manually to keep the encryption key out of the logs.
+impl std::fmt::Debug for Options {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let mut s = f.debug_struct("Options");
+		s.field("path", &self.path)
+			.field("columns", &self.columns)
+			.field("sync_wal", &self.sync_wal)
+			.field("sync_data", &self.sync_data)
+			.field("stats", &self.stats)
+			.field("stats_history", &self.stats_history)
+			.field("stats_interval", &self.stats_interval)
+			.field("table_growth", &self.table_growth)
+			.field("max_table_file_size", &self.max_table_file_size)
+			.field("salt", &self.salt)
+			.field("allow_column_count_change", &self.allow_column_count_change)
+			.field("thread_config", &self.thread_config)
+			.field("max_flush_retries", &self.max_flush_retries)
+			.field("flush_retry_backoff", &self.flush_retry_backoff)
+			.field("max_changeset_ops", &self.max_changeset_ops)
+			.field("overlay_initial_capacity", &self.overlay_initial_capacity)
+			.field("log_compression", &self.log_compression)
+			.field("mmap_window", &self.mmap_window)
+			.field("max_total_bytes", &self.max_total_bytes)
+			.field("stall_warn_threshold", &self.stall_warn_threshold)
+			.field("paranoid_checks", &self.paranoid_checks)
+			.field("on_log_cleanup", &self.on_log_cleanup.is_some())
+			.field("no_wal", &self.no_wal)
+			.field("force_open_dirty", &self.force_open_dirty);
+		#[cfg(feature = "encryption")]
+		s.field("encryption_key", &self.encryption_key.map(|_| "<hidden>"));
+		s.finish()
+	}
 }
 
+/// Background worker thread settings.
 #[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ThreadConfig {
+	/// Worker threads are named `<name_prefix>-commit`, `<name_prefix>-flush`,
+	/// `<name_prefix>-log` and `<name_prefix>-cleanup`.
+	pub name_prefix: String,
+	/// Niceness to set for the worker threads, e.g. 10 to let other threads run first.
+	/// Only supported on Linux. Lowering the niceness usually requires privileges.
+	pub nice: Option<i32>,
+}
+
+impl Default for ThreadConfig {
+	fn default() -> Self {
+		ThreadConfig {
+			name_prefix: "axia-db".into(),
+			nice: None,
+		}
+	}
+}
+
+/// Value table file growth strategy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TableGrowth {
+	/// Extend the file by a fixed number of bytes. Keeps disk usage tight.
+	Fixed(u64),
+	/// Double the file size. Reduces the number of extensions during bulk import.
+	Doubling,
+}
+
+impl Default for TableGrowth {
+	fn default() -> Self {
+		TableGrowth::Fixed(DEFAULT_TABLE_GROW_BYTES)
+	}
+}
+
+impl TableGrowth {
+	/// Number of entries to add to a table with `capacity` entries.
+	pub(crate) fn grow_entries(&self, capacity: u64, entry_size: u16) -> u64 {
+		let fixed = |bytes: u64| std::cmp::max(bytes / entry_size as u64, 1);
+		match self {
+			TableGrowth::Fixed(bytes) => fixed(*bytes),
+			TableGrowth::Doubling => std::cmp::max(capacity, fixed(DEFAULT_TABLE_GROW_BYTES)),
+		}
+	}
+}
+
+/// How values of a column are indexed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IndexMode {
+	/// Values are indexed by key hash and can be queried with `Db::get`.
+	#[default]
+	Hash,
+	/// No index is maintained. Values are only written to the value tables and can
+	/// only be read by iterating the column. Every commit of a value appends a new
+	/// entry, even if the key was written before. Values can't be deleted.
+	AppendOnly,
+}
+
+/// Access allowed to a column, set with `Db::set_column_mode`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColumnMode {
+	/// Reads and commits are allowed.
+	#[default]
+	ReadWrite,
+	/// Commits to the column fail with `Error::ColumnReadOnly`.
+	ReadOnly,
+	/// Reads and commits fail with `Error::ColumnOffline`.
+	Offline,
+}
+
+impl ColumnMode {
+	fn as_str(&self) -> &'static str {
+		match self {
+			ColumnMode::ReadWrite => "read_write",
+			ColumnMode::ReadOnly => "read_only",
+			ColumnMode::Offline => "offline",
+		}
+	}
+
+	fn from_str(s: &str) -> Option<ColumnMode> {
+		match s {
+			"read_write" => Some(ColumnMode::ReadWrite),
+			"read_only" => Some(ColumnMode::ReadOnly),
+			"offline" => Some(ColumnMode::Offline),
+			_ => None,
+		}
+	}
+}
+
+#[derive(Clone, Debug)]
 pub struct ColumnOptions {
 	/// Indicates that the column value is the preimage of the key.
 	/// This implies that a given value always has the same key.
@@ -64,8 +283,67 @@ pub struct ColumnOptions {
 	pub compression: CompressionType,
 	/// Minimal value size threshold to attempt compressing a value.
 	pub compression_treshold: u32,
+	/// Number of index bits to use when the column is created. Index with `n` bits
+	/// holds `64 * 2^n` entries. Setting this for columns that are known to be large
+	/// avoids reindexing during initial import. Defaults to 16.
+	pub initial_index_bits: Option<u8>,
+	/// Fraction of an index chunk that may be occupied before the index is
+	/// grown. Must be in `0.5..=1.0`. Lower values trade disk space for shorter
+	/// probe sequences. Defaults to 1.0, i.e. reindex on a full chunk.
+	pub target_load_factor: f32,
+	/// Maximum fraction of the commit queue that may be taken by pending writes
+	/// to this column. Commits that mostly write to this column wait while the
+	/// limit is exceeded, other commits proceed. Must be in `0.0..=1.0`, excluding 0.
+	/// This is not stored in the metadata and may be changed between runs.
+	pub max_queue_share: Option<f32>,
+	/// Keep the keys sorted to allow range and prefix iteration with `Db::iter_range` and
+	/// `Db::iter_prefix`. Original keys are stored along with the values and kept in
+	/// memory. The sorted keys are saved on clean shutdown, after an unclean shutdown
+	/// they are rebuilt with a scan of the column on open. Makes writes more expensive.
+	/// Can't be combined with `ref_counted` or `preimage`.
+	pub ordered: bool,
+	/// Size in bytes to extend each value table file of the column to when the
+	/// database is created. The index file is created up front as well. Writes
+	/// don't grow the files until the preallocated space is used up. Use
+	/// `initial_index_bits` to avoid reindexing. Ignored for existing databases.
+	/// This is not stored in the metadata.
+	pub preallocate: Option<u64>,
+	/// How values are indexed. Defaults to `IndexMode::Hash`. `IndexMode::AppendOnly`
+	/// can't be combined with `ref_counted`, `preimage` or `ordered`.
+	pub index: IndexMode,
+	/// Number of recently missed keys to remember per column, so that repeated
+	/// queries for absent keys don't search the index. Disabled by default.
+	/// This is not stored in the metadata.
+	pub negative_cache: Option<usize>,
+	/// Store all values of the column with exactly this size. Values are kept in a
+	/// single value table without per-entry size headers and `sizes` is ignored.
+	/// Committing a value of a different size fails. Can't be combined with
+	/// `compression` or `ordered`.
+	pub fixed_value_len: Option<u32>,
 }
 
+// Compares the options that are stored in the metadata. `max_queue_share`, `preallocate`
+// and `negative_cache` are deliberately ignored: they are not a part of the column format
+// and may change between runs.
+impl PartialEq for ColumnOptions {
+	fn eq(&self, other: &Self) -> bool {
+		self.preimage == other.preimage
+			&& self.uniform == other.uniform
+			&& self.sizes == other.sizes
+			&& self.ref_counted == other.ref_counted
+			&& self.compression == other.compression
+			&& self.compression_treshold == other.compression_treshold
+			&& self.initial_index_bits == other.initial_index_bits
+			&& self.target_load_factor.to_bits() == other.target_load_factor.to_bits()
+			&& self.ordered == other.ordered
+			&& self.index == other.index
+			&& self.fixed_value_len == other.fixed_value_len
+	}
+}
+
+// The load factor is compared bitwise, so equality is reflexive.
+impl Eq for ColumnOptions {}
+
 
 /// Database metadata.
 #[derive(Clone, Debug)]
@@ -76,15 +354,36 @@ pub struct Metadata {
 	pub version: u32,
 	/// Column metadata.
 	pub columns: Vec<ColumnOptions>,
+	/// Keyed hash of the salt for encrypted databases. Used to check the encryption key.
+	pub encryption_check: Option<[u8; 32]>,
+	/// Column modes persisted with `Db::set_column_mode`. Columns not listed are `ReadWrite`.
+	pub column_modes: BTreeMap<u8, ColumnMode>,
+	/// Maximum size of value table files, see `Options::max_table_file_size`.
+	pub max_table_file_size: Option<u64>,
 }
 
 impl ColumnOptions {
 	fn as_string(&self) -> String {
-		format!("preimage: {}, uniform: {}, refc: {}, compression: {}, sizes: [{}]",
+		let index_bits = self.initial_index_bits.map(|bits| format!("index_bits: {}, ", bits)).unwrap_or_default();
+		let ordered = if self.ordered { "ordered: true, " } else { "" };
+		let append_only = if self.index == IndexMode::AppendOnly { "append_only: true, " } else { "" };
+		let fixed_len = self.fixed_value_len.map(|len| format!("fixed_len: {}, ", len)).unwrap_or_default();
+		// Only written if set, so that the metadata of existing databases doesn't change.
+		let load_factor = if self.target_load_factor != DEFAULT_LOAD_FACTOR {
+			format!("load_factor: {}, ", self.target_load_factor)
+		} else {
+			String::new()
+		};
+		format!("preimage: {}, uniform: {}, refc: {}, compression: {}, {}{}{}{}{}sizes: [{}]",
 			self.preimage,
 			self.uniform,
 			self.ref_counted,
 			self.compression as u8,
+			index_bits,
+			ordered,
+			append_only,
+			fixed_len,
+			load_factor,
 			self.sizes.iter().fold(String::new(), |mut r, s| {
 				if !r.is_empty() {
 					r.push_str(", ");
@@ -104,6 +403,33 @@ impl ColumnOptions {
 				return false;
 			}
 		}
+		if let Some(bits) = self.initial_index_bits {
+			if !(crate::column::START_BITS ..= MAX_INITIAL_INDEX_BITS).contains(&bits) {
+				return false;
+			}
+		}
+		if !(MIN_LOAD_FACTOR ..= 1.0).contains(&self.target_load_factor) {
+			return false;
+		}
+		if let Some(share) = self.max_queue_share {
+			if !(share > 0.0 && share <= 1.0) {
+				return false;
+			}
+		}
+		if self.ordered && (self.ref_counted || self.preimage) {
+			return false;
+		}
+		if self.index == IndexMode::AppendOnly && (self.ref_counted || self.preimage || self.ordered) {
+			return false;
+		}
+		if let Some(len) = self.fixed_value_len {
+			if len == 0 || len > crate::table::MAX_FIXED_VALUE_LEN {
+				return false;
+			}
+			if self.compression != CompressionType::NoCompression || self.ordered {
+				return false;
+			}
+		}
 		true
 	}
 
@@ -123,15 +449,48 @@ impl ColumnOptions {
 		let uniform = vals.get("uniform")?.parse().ok()?;
 		let ref_counted = vals.get("refc")?.parse().ok()?;
 		let compression: u8 = vals.get("compression").and_then(|c| c.parse().ok()).unwrap_or(0);
+		let compression = CompressionType::from_u8(compression)?;
+		let initial_index_bits = match vals.get("index_bits") {
+			Some(bits) => Some(bits.parse().ok()?),
+			None => None,
+		};
+		let target_load_factor = match vals.get("load_factor") {
+			Some(factor) => factor.parse().ok()?,
+			None => DEFAULT_LOAD_FACTOR,
+		};
+		let ordered = match vals.get("ordered") {
+			Some(ordered) => ordered.parse().ok()?,
+			None => false,
+		};
+		let index = match vals.get("append_only") {
+			Some(append_only) => if append_only.parse().ok()? { IndexMode::AppendOnly } else { IndexMode::Hash },
+			None => IndexMode::Hash,
+		};
+		let fixed_value_len = match vals.get("fixed_len") {
+			Some(len) => Some(len.parse().ok()?),
+			None => None,
+		};
 
-		Some(ColumnOptions {
+		let options = ColumnOptions {
 			preimage,
 			uniform,
 			ref_counted,
-			compression: compression.into(),
+			compression,
 			sizes,
 			compression_treshold: ColumnOptions::default().compression_treshold,
-		})
+			initial_index_bits,
+			target_load_factor,
+			max_queue_share: None,
+			ordered,
+			preallocate: None,
+			index,
+			negative_cache: None,
+			fixed_value_len,
+		};
+		if !options.is_valid() {
+			return None;
+		}
+		Some(options)
 	}
 }
 
@@ -156,50 +515,201 @@ impl Default for ColumnOptions {
 			ref_counted: false,
 			compression: CompressionType::NoCompression,
 			compression_treshold: 4096,
+			initial_index_bits: None,
+			target_load_factor: DEFAULT_LOAD_FACTOR,
+			max_queue_share: None,
+			ordered: false,
+			preallocate: None,
+			index: IndexMode::Hash,
+			negative_cache: None,
+			fixed_value_len: None,
 			sizes,
 		}
 	}
 }
 
 impl Options {
-	pub fn with_columns(path: &std::path::Path, num_columns: u8) -> Options {
+	pub fn with_columns(path: impl AsRef<Path>, num_columns: u8) -> Options {
 		Options {
-			path: path.into(),
+			path: path.as_ref().to_path_buf(),
 			sync_wal: true,
 			sync_data: true,
 			stats: true,
+			stats_history: false,
+			stats_interval: None,
+			table_growth: Default::default(),
+			max_table_file_size: None,
 			salt: None,
+			allow_column_count_change: false,
+			thread_config: Default::default(),
+			max_flush_retries: 0,
+			flush_retry_backoff: std::time::Duration::from_millis(100),
+			max_changeset_ops: None,
+			overlay_initial_capacity: 0,
+			log_compression: None,
+			mmap_window: None,
+			max_total_bytes: None,
+			stall_warn_threshold: std::time::Duration::from_secs(1),
+			paranoid_checks: false,
+			on_log_cleanup: None,
+			no_wal: false,
+			force_open_dirty: false,
+			// Run all tests on encrypted databases when the feature is enabled.
+			#[cfg(all(test, feature = "encryption"))]
+			encryption_key: Some([0x42; crate::encrypt::KEY_LEN]),
+			#[cfg(all(not(test), feature = "encryption"))]
+			encryption_key: None,
 			columns: (0..num_columns).map(|_| Default::default()).collect(),
 		}
 	}
 
+	/// Same as `with_columns`, but checks that `path` is a non-empty absolute path.
+	pub fn with_path_str(path: &str, num_columns: u8) -> Result<Options> {
+		if path.is_empty() {
+			return Err(Error::InvalidConfiguration("Database path is empty".into()));
+		}
+		if !Path::new(path).is_absolute() {
+			return Err(Error::InvalidConfiguration(format!("Database path {} is not absolute", path)));
+		}
+		Ok(Self::with_columns(path, num_columns))
+	}
+
+	/// Key check to store in the metadata, if the database is encrypted.
+	pub(crate) fn encryption_check(&self, salt: &Salt) -> Option<[u8; 32]> {
+		#[cfg(feature = "encryption")]
+		{
+			self.encryption_key.as_ref().map(|key| crate::encrypt::key_check(key, salt))
+		}
+		#[cfg(not(feature = "encryption"))]
+		{
+			let _ = salt;
+			None
+		}
+	}
+
 	pub fn write_metadata(&self, path: &std::path::Path, salt: &Salt) -> Result<()> {
+		Self::write_metadata_file(path, salt, CURRENT_VERSION, &self.columns, self.encryption_check(salt).as_ref(), &Default::default(), self.max_table_file_size)
+	}
+
+	fn write_metadata_file(
+		path: &std::path::Path,
+		salt: &Salt,
+		version: u32,
+		columns: &[ColumnOptions],
+		encryption_check: Option<&[u8; 32]>,
+		column_modes: &BTreeMap<u8, ColumnMode>,
+		max_table_file_size: Option<u64>,
+	) -> Result<()> {
 		let mut file = std::fs::File::create(path)?;
-		writeln!(file, "version={}", CURRENT_VERSION)?;
+		writeln!(file, "version={}", version)?;
 		writeln!(file, "salt={}", hex::encode(salt))?;
-		for i in 0..self.columns.len() {
-			writeln!(file, "col{}={}", i, self.columns[i].as_string())?;
+		if let Some(check) = encryption_check {
+			writeln!(file, "encryption={}", hex::encode(check))?;
+		}
+		if let Some(size) = max_table_file_size {
+			writeln!(file, "max_table_file_size={}", size)?;
+		}
+		for (i, column) in columns.iter().enumerate() {
+			writeln!(file, "col{}={}", i, column.as_string())?;
+		}
+		for (col, mode) in column_modes {
+			writeln!(file, "mode{}={}", col, mode.as_str())?;
+		}
+		Ok(())
+	}
+
+	/// Rewrite the metadata of the database at `path` with new column modes. The file
+	/// is replaced atomically, so that it is never left partially written.
+	pub(crate) fn write_column_modes(path: &Path, metadata: &Metadata, column_modes: &BTreeMap<u8, ColumnMode>) -> Result<()> {
+		let tmp_path = path.join("metadata.tmp");
+		Self::write_metadata_file(
+			&tmp_path,
+			&metadata.salt,
+			metadata.version,
+			&metadata.columns,
+			metadata.encryption_check.as_ref(),
+			column_modes,
+			metadata.max_table_file_size,
+		)?;
+		std::fs::rename(&tmp_path, path.join("metadata"))?;
+		Ok(())
+	}
+
+	/// Bump the version of a version 5 database to `CURRENT_VERSION`. Only the log format
+	/// changed since, so that older versions refuse to open the database once it may
+	/// contain log records they can't read. Databases older than that are left as is,
+	/// their tables are written differently.
+	pub(crate) fn upgrade_metadata(path: &Path, metadata: &mut Metadata) -> Result<()> {
+		if metadata.version != 5 {
+			return Ok(());
 		}
+		log::info!(target: "axia-db", "Upgrading database version {} to {}", metadata.version, CURRENT_VERSION);
+		let tmp_path = path.join("metadata.tmp");
+		Self::write_metadata_file(
+			&tmp_path,
+			&metadata.salt,
+			CURRENT_VERSION,
+			&metadata.columns,
+			metadata.encryption_check.as_ref(),
+			&metadata.column_modes,
+			metadata.max_table_file_size,
+		)?;
+		std::fs::rename(&tmp_path, path.join("metadata"))?;
+		metadata.version = CURRENT_VERSION;
 		Ok(())
 	}
 
 	pub fn load_and_validate_metadata(&self, create: bool) -> Result<Metadata> {
+		if let Some(size) = self.max_table_file_size {
+			if size < crate::table::MAX_ENTRY_SIZE as u64 {
+				return Err(Error::InvalidConfiguration(format!(
+					"max_table_file_size must be at least {} bytes",
+					crate::table::MAX_ENTRY_SIZE,
+				)));
+			}
+		}
 		let mut path: PathBuf = self.path.clone();
 		path.push("metadata");
-		let meta = Self::load_metadata(&path)?;
+		let meta = Self::load_metadata(&path).context(|| ErrorContext::new("load metadata").path(&path))?;
 
-		if let Some(meta) = meta {
-			if meta.columns.len() != self.columns.len() {
-				return Err(Error::InvalidConfiguration("Column config mismatch".into()));
+		if let Some(mut meta) = meta {
+			match (&meta.encryption_check, self.encryption_check(&meta.salt)) {
+				(Some(_), None) => return Err(Error::EncryptionKeyRequired),
+				(Some(expected), Some(check)) if *expected != check => return Err(Error::InvalidEncryptionKey),
+				(None, Some(_)) => return Err(Error::InvalidConfiguration(
+					"Encryption key specified for a database that is not encrypted".into()
+				)),
+				_ => (),
+			}
+			if meta.columns.len() != self.columns.len() && !self.allow_column_count_change {
+				return Err(Error::ColumnCountMismatch {
+					existing: meta.columns.len(),
+					requested: self.columns.len(),
+				});
+			}
+
+			if meta.max_table_file_size != self.max_table_file_size {
+				return Err(Error::InvalidConfiguration(format!(
+					"Table file size limit mismatch. Expected {:?}, got {:?}",
+					self.max_table_file_size, meta.max_table_file_size)));
 			}
 
-			for c in 0..meta.columns.len() {
+			for c in 0..std::cmp::min(meta.columns.len(), self.columns.len()) {
 				if meta.columns[c] != self.columns[c] {
 					return Err(Error::InvalidConfiguration(format!(
 								"Column config mismatch for column {}. Expected \"{}\", got \"{}\"",
 								c, self.columns[c].as_string(), meta.columns[c].as_string())));
 				}
 			}
+			if self.columns.len() > meta.columns.len() {
+				log::info!(
+					target: "axia-db",
+					"Adding {} columns to the database",
+					self.columns.len() - meta.columns.len(),
+				);
+				meta.columns.extend_from_slice(&self.columns[meta.columns.len()..]);
+				Self::write_metadata_file(&path, &meta.salt, meta.version, &meta.columns, meta.encryption_check.as_ref(), &meta.column_modes, meta.max_table_file_size)?;
+			}
 			Ok(meta)
 		} else if create {
 			let s: Salt = self.salt.unwrap_or(rand::thread_rng().gen());
@@ -208,6 +718,9 @@ impl Options {
 				version: CURRENT_VERSION,
 				columns: self.columns.clone(),
 				salt: s,
+				encryption_check: self.encryption_check(&s),
+				column_modes: Default::default(),
+				max_table_file_size: self.max_table_file_size,
 			})
 		} else {
 			Err(Error::InvalidConfiguration("Database does not exist. To create a new one, use open_or_create".into()))
@@ -225,6 +738,9 @@ impl Options {
 		let mut salt = None;
 		let mut columns = Vec::new();
 		let mut version = 0;
+		let mut encryption_check = None;
+		let mut column_modes = BTreeMap::new();
+		let mut max_table_file_size = None;
 		for l in file.lines() {
 			let l = l?;
 			let mut vals = l.split("=");
@@ -232,25 +748,45 @@ impl Options {
 			let v = vals.next().ok_or(Error::Corruption("Bad metadata".into()))?;
 			if k == "version" {
 				version = u32::from_str(v).map_err(|_| Error::Corruption("Bad version string".into()))?;
+				// Checked right away, newer versions may add settings this one can't parse.
+				if version > CURRENT_VERSION {
+					return Err(Error::UnsupportedVersion { found: version, supported: CURRENT_VERSION });
+				}
 			} else if k == "salt" {
 					let salt_slice = hex::decode(v).map_err(|_| Error::Corruption("Bad salt string".into()))?;
 					let mut s = Salt::default();
 					s.copy_from_slice(&salt_slice);
 					salt = Some(s);
+			} else if k == "encryption" {
+				let check = hex::decode(v).ok().filter(|c| c.len() == 32)
+					.ok_or_else(|| Error::Corruption("Bad encryption check string".into()))?;
+				let mut c = [0u8; 32];
+				c.copy_from_slice(&check);
+				encryption_check = Some(c);
+			} else if k == "max_table_file_size" {
+				let size = u64::from_str(v).map_err(|_| Error::Corruption("Bad table file size".into()))?;
+				max_table_file_size = Some(size);
 			} else if k.starts_with("col") {
 				let col = ColumnOptions::from_string(v).ok_or_else(|| Error::Corruption("Bad column metadata".into()))?;
 				columns.push(col);
+			} else if let Some(col) = k.strip_prefix("mode") {
+				let col = u8::from_str(col).map_err(|_| Error::Corruption("Bad column mode".into()))?;
+				let mode = ColumnMode::from_str(v).ok_or_else(|| Error::Corruption("Bad column mode".into()))?;
+				column_modes.insert(col, mode);
 			}
 		}
 		if version < LAST_SUPPORTED_VERSION  {
 			return Err(Error::InvalidConfiguration(format!(
-						"Unsupported database version {}. Expected {}", version, CURRENT_VERSION)));
+						"Unsupported database version {} in {}. Expected {}", version, path.display(), CURRENT_VERSION)));
 		}
-		let salt = salt.ok_or_else(|| Error::InvalidConfiguration("Missing salt value".into()))?;
+		let salt = salt.ok_or_else(|| Error::InvalidConfiguration(format!("Missing salt value in {}", path.display())))?;
 		Ok(Some(Metadata {
 			version,
 			columns,
 			salt,
+			encryption_check,
+			column_modes,
+			max_table_file_size,
 		}))
 	}
 
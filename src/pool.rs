@@ -0,0 +1,105 @@
+// Copyright 2015-2020 AXIA Technologies (UK) Ltd.
+// This file is part of AXIA.
+
+// AXIA is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// AXIA is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with AXIA.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Reusable buffers for value reads.
+
+use std::sync::Arc;
+use parking_lot::Mutex;
+
+struct Buffers {
+	free: Mutex<Vec<Vec<u8>>>,
+	max_buffers: usize,
+}
+
+/// Pool of buffers for `Db::get_with_pool`. Values returned by the pool give their
+/// buffer back when dropped, so that steady state reads don't allocate.
+pub struct BufferPool {
+	buffers: Arc<Buffers>,
+}
+
+impl BufferPool {
+	/// Create a pool that keeps at most `max_buffers` unused buffers.
+	pub fn new(max_buffers: usize) -> BufferPool {
+		BufferPool {
+			buffers: Arc::new(Buffers {
+				free: Mutex::new(Vec::with_capacity(max_buffers)),
+				max_buffers,
+			}),
+		}
+	}
+
+	/// Number of unused buffers in the pool.
+	pub fn available(&self) -> usize {
+		self.buffers.free.lock().len()
+	}
+
+	pub(crate) fn take(&mut self) -> PooledValue {
+		let buf = self.buffers.free.lock().pop().unwrap_or_default();
+		PooledValue { buf, buffers: self.buffers.clone() }
+	}
+}
+
+/// Value read with `Db::get_with_pool`. The buffer is returned to the pool on drop.
+pub struct PooledValue {
+	buf: Vec<u8>,
+	buffers: Arc<Buffers>,
+}
+
+impl PooledValue {
+	pub(crate) fn buffer(&mut self) -> &mut Vec<u8> {
+		&mut self.buf
+	}
+
+	/// Take the value out. The buffer is not returned to the pool.
+	pub fn into_vec(mut self) -> Vec<u8> {
+		std::mem::take(&mut self.buf)
+	}
+}
+
+impl std::ops::Deref for PooledValue {
+	type Target = [u8];
+
+	fn deref(&self) -> &[u8] {
+		&self.buf
+	}
+}
+
+impl AsRef<[u8]> for PooledValue {
+	fn as_ref(&self) -> &[u8] {
+		&self.buf
+	}
+}
+
+impl std::fmt::Debug for PooledValue {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		self.buf.fmt(f)
+	}
+}
+
+impl Drop for PooledValue {
+	fn drop(&mut self) {
+		// Buffers taken out with `into_vec` have no capacity.
+		if self.buf.capacity() == 0 {
+			return;
+		}
+		let mut free = self.buffers.free.lock();
+		if free.len() < self.buffers.max_buffers {
+			let mut buf = std::mem::take(&mut self.buf);
+			buf.clear();
+			free.push(buf);
+		}
+	}
+}
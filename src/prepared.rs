@@ -0,0 +1,166 @@
+// Copyright 2015-2020 AXIA Technologies (UK) Ltd.
+// This file is part of AXIA.
+
+// AXIA is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// AXIA is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with AXIA.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Prepared changesets of two-phase commits.
+//!
+//! Each prepared changeset is kept in its own file until it is committed or rolled
+//! back. The file holds the hashed changes, followed by a crc32 of the contents.
+//! It is written to a temporary file first and then renamed, so that it either
+//! exists in full or not at all. Values are encrypted if the database is, like
+//! they are in the value tables.
+
+use std::borrow::Cow;
+use std::convert::TryInto;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use crate::{
+	error::{Error, Result},
+	encrypt::Encryption,
+	column::ColId,
+	table::Key,
+	db::{ChangeSet, Value},
+};
+
+const FILE_PREFIX: &str = "prepared_";
+const TMP_SUFFIX: &str = ".tmp";
+
+fn file_path(path: &Path, id: u64) -> PathBuf {
+	path.join(format!("{}{:016x}", FILE_PREFIX, id))
+}
+
+/// Whether `name` is a changeset file that was not completely written.
+pub fn is_temp_file_name(name: &str) -> bool {
+	name.starts_with(FILE_PREFIX) && name.ends_with(TMP_SUFFIX)
+}
+
+/// Durably write the changeset `id`.
+pub fn write(
+	path: &Path,
+	id: u64,
+	changes: &[(ColId, Key, Option<Value>)],
+	encryption: Option<&Encryption>,
+) -> Result<()> {
+	let mut buf = Vec::new();
+	buf.extend_from_slice(&(changes.len() as u32).to_le_bytes());
+	for (c, k, v) in changes {
+		buf.push(*c);
+		buf.extend_from_slice(k);
+		match v {
+			Some(v) => {
+				let v = match encryption {
+					Some(encryption) => Cow::Owned(encryption.encrypt(v)),
+					None => Cow::Borrowed(v),
+				};
+				buf.push(1);
+				buf.extend_from_slice(&(v.len() as u32).to_le_bytes());
+				buf.extend_from_slice(&v);
+			}
+			None => buf.push(0),
+		}
+	}
+	let crc = crc32fast::hash(&buf);
+	buf.extend_from_slice(&crc.to_le_bytes());
+
+	let target = file_path(path, id);
+	let mut tmp = target.clone().into_os_string();
+	tmp.push(TMP_SUFFIX);
+	let mut file = std::fs::File::create(&tmp)?;
+	file.write_all(&buf)?;
+	file.sync_all()?;
+	std::fs::rename(&tmp, &target)?;
+	sync_dir(path)
+}
+
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> Result<()> {
+	std::fs::File::open(path)?.sync_all()?;
+	Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> Result<()> {
+	Ok(())
+}
+
+/// Read the changeset `id`. Returns `None` if there is no such changeset.
+pub fn read(path: &Path, id: u64, encryption: Option<&Encryption>) -> Result<Option<ChangeSet>> {
+	let buf = match std::fs::read(file_path(path, id)) {
+		Ok(buf) => buf,
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+		Err(e) => return Err(e.into()),
+	};
+	let corrupted = || Error::Corruption(format!("Bad prepared changeset {}", id));
+	if buf.len() < 8 {
+		return Err(corrupted());
+	}
+	let (data, crc) = buf.split_at(buf.len() - 4);
+	if crc32fast::hash(data) != u32::from_le_bytes(crc.try_into().unwrap()) {
+		return Err(corrupted());
+	}
+	let mut pos = 0;
+	let mut take = |len: usize| -> Result<&[u8]> {
+		let bytes = data.get(pos .. pos + len).ok_or_else(corrupted)?;
+		pos += len;
+		Ok(bytes)
+	};
+	let count = u32::from_le_bytes(take(4)?.try_into().unwrap());
+	let mut changes = Vec::new();
+	for _ in 0 .. count {
+		let col = take(1)?[0];
+		let key: Key = take(32)?.try_into().unwrap();
+		let value = match take(1)?[0] {
+			0 => None,
+			1 => {
+				let len = u32::from_le_bytes(take(4)?.try_into().unwrap());
+				let value = take(len as usize)?;
+				Some(match encryption {
+					Some(encryption) => encryption.decrypt(value)?,
+					None => value.to_vec(),
+				})
+			}
+			_ => return Err(corrupted()),
+		};
+		changes.push((col, key, value));
+	}
+	Ok(Some(changes))
+}
+
+/// Remove the changeset `id`. Returns `false` if there is no such changeset.
+pub fn remove(path: &Path, id: u64) -> Result<bool> {
+	match std::fs::remove_file(file_path(path, id)) {
+		Ok(()) => Ok(true),
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+		Err(e) => Err(e.into()),
+	}
+}
+
+/// Ids of all prepared changesets, in ascending order.
+pub fn list(path: &Path) -> Result<Vec<u64>> {
+	let mut ids = Vec::new();
+	for entry in std::fs::read_dir(path)? {
+		let entry = entry?;
+		let name = entry.file_name();
+		let name = match name.to_str() {
+			Some(name) if name.starts_with(FILE_PREFIX) => name,
+			_ => continue,
+		};
+		if let Ok(id) = u64::from_str_radix(&name[FILE_PREFIX.len() ..], 16) {
+			ids.push(id);
+		}
+	}
+	ids.sort_unstable();
+	Ok(ids)
+}
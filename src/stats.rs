@@ -18,15 +18,30 @@
 
 use std::sync::atomic::{AtomicU64, AtomicU32, AtomicI64, Ordering};
 use std::mem::MaybeUninit;
+use std::collections::VecDeque;
+use parking_lot::Mutex;
 use std::io::{Read, Write, Cursor};
-use crate::{error::Result, column::ColId, table::SIZE_TIERS};
+use crate::{error::{Error, Result}, column::ColId, table::SIZE_TIERS};
 
 // store up to value of size HISTOGRAM_BUCKETS * 2 ^ HISTOGRAM_BUCKET_BITS,
 // that is 32ko
 const HISTOGRAM_BUCKETS: usize = 1024;
 const HISTOGRAM_BUCKET_BITS: u8 = 5;
+// Bucket `n` holds values with size in `2^(n-1) .. 2^n`. The last one holds everything larger.
+const SIZE_LOG_BUCKETS: usize = 32;
+// Bucket `n` holds queries that examined `n` index entries. The last one holds longer chains.
+const PROBE_BUCKETS: usize = 16;
+// Share of free value table entries above which a column is worth compacting.
+const COMPACTION_FREE_RATIO: f64 = 0.3;
+// Number of completed reindex events kept.
+const REINDEX_HISTORY: usize = 16;
+const REINDEX_EVENT_SIZE: usize = 8 * 6;
 
-pub const TOTAL_SIZE: usize = 4 * HISTOGRAM_BUCKETS + 8 * HISTOGRAM_BUCKETS + 8 * SIZE_TIERS + 8 * 11;
+// Position of `total_values` in the serialized stats.
+const TOTAL_VALUES_OFFSET: usize = 4 * HISTOGRAM_BUCKETS + 8 * SIZE_TIERS + 8 * 2;
+
+pub const TOTAL_SIZE: usize = 4 * HISTOGRAM_BUCKETS + 8 * HISTOGRAM_BUCKETS + 8 * SIZE_TIERS + 8 * 11
+	+ 8 * SIZE_LOG_BUCKETS + 8 * PROBE_BUCKETS + 8 + REINDEX_HISTORY * REINDEX_EVENT_SIZE + 8 * 6;
 
 pub struct ColumnStats {
 	value_histogram: [AtomicU32; HISTOGRAM_BUCKETS],
@@ -43,6 +58,45 @@ pub struct ColumnStats {
 	queries_miss: AtomicU64,
 	uncompressed_bytes: AtomicU64,
 	compression_delta: [AtomicI64; HISTOGRAM_BUCKETS],
+	size_log_histogram: [AtomicU64; SIZE_LOG_BUCKETS],
+	probe_histogram: [AtomicU64; PROBE_BUCKETS],
+	reindex_history: Mutex<VecDeque<ReindexEvent>>,
+	// Index write plan outcomes.
+	writes_inserted: AtomicU64,
+	writes_replaced: AtomicU64,
+	writes_removed: AtomicU64,
+	write_collisions: AtomicU64,
+	writes_reindex: AtomicU64,
+	negative_cache_hits: AtomicU64,
+}
+
+/// Occupancy of the tables of a column. Not part of the saved stats, it is read from the
+/// tables when the stats are reported.
+#[derive(Default)]
+pub struct TableSlots {
+	/// Entries in use by each value table, including free ones.
+	pub value: Vec<u64>,
+	/// Entries on the free list of each value table.
+	pub free: Vec<u64>,
+	/// Entries in the current index table.
+	pub index: u64,
+}
+
+/// Completed index reallocation.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReindexEvent {
+	/// Log record that triggered the reindex. 0 if the reindex was resumed after reopening the database.
+	pub start_record: u64,
+	/// Log record that dropped the old index.
+	pub end_record: u64,
+	/// Wall-clock start time in milliseconds since the unix epoch.
+	pub started: u64,
+	/// Duration in milliseconds.
+	pub duration: u64,
+	/// Number of index entries moved to the new index.
+	pub entries: u64,
+	/// Log bytes written for the moved entries.
+	pub bytes: u64,
 }
 
 fn read_u32(cursor: &mut Cursor<&[u8]>) -> AtomicU32 {
@@ -75,6 +129,10 @@ fn write_i64(cursor: &mut Cursor<&mut [u8]>, val: &AtomicI64) {
 	cursor.write(&val.load(Ordering::Relaxed).to_le_bytes()).expect("Incorrent stats buffer");
 }
 
+fn size_log_histogram_index(size: u32) -> usize {
+	std::cmp::min((32 - size.leading_zeros()) as usize, SIZE_LOG_BUCKETS - 1)
+}
+
 fn value_histogram_index(size: u32) -> Option<usize> {
 	let bucket = size as usize >> HISTOGRAM_BUCKET_BITS;
 	if bucket < HISTOGRAM_BUCKETS {
@@ -84,6 +142,15 @@ fn value_histogram_index(size: u32) -> Option<usize> {
 	}
 }
 
+// Inclusive size range of a log histogram bucket.
+fn size_log_bucket_range(index: usize) -> (u64, u64) {
+	match index {
+		0 => (0, 0),
+		i if i == SIZE_LOG_BUCKETS - 1 => (1 << (i - 1), u32::MAX as u64),
+		i => (1 << (i - 1), (1 << i) - 1),
+	}
+}
+
 impl ColumnStats {
 	pub fn from_slice(data: &[u8]) -> ColumnStats {
 		let mut cursor = Cursor::new(data);
@@ -110,13 +177,57 @@ impl ColumnStats {
 			queries_miss: read_u64(&mut cursor),
 			uncompressed_bytes: read_u64(&mut cursor),
 			compression_delta: unsafe { MaybeUninit::uninit().assume_init() },
+			size_log_histogram: Default::default(),
+			probe_histogram: Default::default(),
+			reindex_history: Default::default(),
+			writes_inserted: Default::default(),
+			writes_replaced: Default::default(),
+			writes_removed: Default::default(),
+			write_collisions: Default::default(),
+			writes_reindex: Default::default(),
+			negative_cache_hits: Default::default(),
 		};
 		for n in 0 .. HISTOGRAM_BUCKETS {
 			stats.compression_delta[n] = read_i64(&mut cursor);
 		}
+		for n in 0 .. SIZE_LOG_BUCKETS {
+			stats.size_log_histogram[n] = read_u64(&mut cursor);
+		}
+		for n in 0 .. PROBE_BUCKETS {
+			stats.probe_histogram[n] = read_u64(&mut cursor);
+		}
+		let history_len = std::cmp::min(read_u64(&mut cursor).into_inner() as usize, REINDEX_HISTORY);
+		let mut history = VecDeque::with_capacity(REINDEX_HISTORY);
+		// History always occupies `REINDEX_HISTORY` slots, unused ones are zero.
+		for n in 0 .. REINDEX_HISTORY {
+			let event = ReindexEvent {
+				start_record: read_u64(&mut cursor).into_inner(),
+				end_record: read_u64(&mut cursor).into_inner(),
+				started: read_u64(&mut cursor).into_inner(),
+				duration: read_u64(&mut cursor).into_inner(),
+				entries: read_u64(&mut cursor).into_inner(),
+				bytes: read_u64(&mut cursor).into_inner(),
+			};
+			if n < history_len {
+				history.push_back(event);
+			}
+		}
+		stats.reindex_history = Mutex::new(history);
+		stats.writes_inserted = read_u64(&mut cursor);
+		stats.writes_replaced = read_u64(&mut cursor);
+		stats.writes_removed = read_u64(&mut cursor);
+		stats.write_collisions = read_u64(&mut cursor);
+		stats.writes_reindex = read_u64(&mut cursor);
+		stats.negative_cache_hits = read_u64(&mut cursor);
 		stats
 	}
 
+	/// Reads only the number of values from serialized stats.
+	pub fn total_values_from_slice(data: &[u8]) -> u64 {
+		let bytes = &data[TOTAL_VALUES_OFFSET .. TOTAL_VALUES_OFFSET + 8];
+		u64::from_le_bytes(bytes.try_into().expect("Incorrect stats buffer"))
+	}
+
 	pub fn empty() -> ColumnStats {
 		let value_histogram: [AtomicU32; HISTOGRAM_BUCKETS] = unsafe { std::mem::transmute([0u32; HISTOGRAM_BUCKETS]) };
 		let query_histogram: [AtomicU64; SIZE_TIERS] = unsafe { std::mem::transmute([0u64; SIZE_TIERS]) };
@@ -135,7 +246,51 @@ impl ColumnStats {
 			queries_miss: Default::default(),
 			uncompressed_bytes: Default::default(),
 			compression_delta: unsafe { std::mem::transmute([0i64; HISTOGRAM_BUCKETS]) },
+			size_log_histogram: Default::default(),
+			probe_histogram: Default::default(),
+			reindex_history: Default::default(),
+			writes_inserted: Default::default(),
+			writes_replaced: Default::default(),
+			writes_removed: Default::default(),
+			write_collisions: Default::default(),
+			writes_reindex: Default::default(),
+			negative_cache_hits: Default::default(),
+		}
+	}
+
+	/// Reset all counters to zero.
+	pub fn clear(&self) {
+		for v in self.value_histogram.iter() {
+			v.store(0, Ordering::Relaxed);
 		}
+		for v in self.query_histogram.iter().chain(self.size_log_histogram.iter()).chain(self.probe_histogram.iter()) {
+			v.store(0, Ordering::Relaxed);
+		}
+		for v in self.compression_delta.iter() {
+			v.store(0, Ordering::Relaxed);
+		}
+		for v in [
+			&self.oversized,
+			&self.oversized_bytes,
+			&self.total_values,
+			&self.total_bytes,
+			&self.commits,
+			&self.inserted_new,
+			&self.inserted_overwrite,
+			&self.removed_hit,
+			&self.removed_miss,
+			&self.queries_miss,
+			&self.uncompressed_bytes,
+			&self.writes_inserted,
+			&self.writes_replaced,
+			&self.writes_removed,
+			&self.write_collisions,
+			&self.writes_reindex,
+			&self.negative_cache_hits,
+		] {
+			v.store(0, Ordering::Relaxed);
+		}
+		self.reindex_history.lock().clear();
 	}
 
 	pub fn to_slice(&self, data: &mut [u8]) {
@@ -160,9 +315,36 @@ impl ColumnStats {
 		for n in 0 .. HISTOGRAM_BUCKETS {
 			write_i64(&mut cursor, &self.compression_delta[n]);
 		}
+		for n in 0 .. SIZE_LOG_BUCKETS {
+			write_u64(&mut cursor, &self.size_log_histogram[n]);
+		}
+		for n in 0 .. PROBE_BUCKETS {
+			write_u64(&mut cursor, &self.probe_histogram[n]);
+		}
+		let history = self.reindex_history.lock();
+		let mut write = |v: u64| {
+			cursor.write_all(&v.to_le_bytes()).expect("Incorrect stats buffer");
+		};
+		write(history.len() as u64);
+		let empty = ReindexEvent::default();
+		for n in 0 .. REINDEX_HISTORY {
+			let event = history.get(n).unwrap_or(&empty);
+			write(event.start_record);
+			write(event.end_record);
+			write(event.started);
+			write(event.duration);
+			write(event.entries);
+			write(event.bytes);
+		}
+		write_u64(&mut cursor, &self.writes_inserted);
+		write_u64(&mut cursor, &self.writes_replaced);
+		write_u64(&mut cursor, &self.writes_removed);
+		write_u64(&mut cursor, &self.write_collisions);
+		write_u64(&mut cursor, &self.writes_reindex);
+		write_u64(&mut cursor, &self.negative_cache_hits);
 	}
 
-	fn write_stats(&self, writer: &mut impl std::io::Write, col: ColId) -> Result<()> {
+	fn write_stats(&self, writer: &mut impl std::io::Write, col: ColId, slots: &TableSlots) -> Result<()> {
 		writeln!(writer, "Column {}", col)?;
 		writeln!(writer, "Total values: {}", self.total_values.load(Ordering::Relaxed))?;
 		writeln!(writer, "Total bytes: {}", self.total_bytes.load(Ordering::Relaxed))?;
@@ -174,6 +356,18 @@ impl ColumnStats {
 		writeln!(writer, "Removals: {}", self.removed_hit.load(Ordering::Relaxed))?;
 		writeln!(writer, "Missed removals: {}", self.removed_miss.load(Ordering::Relaxed))?;
 		writeln!(writer, "Uncompressed bytes: {}", self.uncompressed_bytes.load(Ordering::Relaxed))?;
+		writeln!(writer, "Index slots: {} occupied of {}", self.total_values.load(Ordering::Relaxed), slots.index)?;
+		writeln!(writer, "Value table slots (free/used): [{}]", slots.free.iter().zip(slots.value.iter())
+			.map(|(free, used)| format!("{}/{}", free, used))
+			.collect::<Vec<_>>()
+			.join(", "))?;
+		writeln!(writer, "Index writes: inserted {}, replaced {}, removed {}, collisions {}, reindex triggered {}",
+			self.writes_inserted.load(Ordering::Relaxed),
+			self.writes_replaced.load(Ordering::Relaxed),
+			self.writes_removed.load(Ordering::Relaxed),
+			self.write_collisions.load(Ordering::Relaxed),
+			self.writes_reindex.load(Ordering::Relaxed),
+		)?;
 		writeln!(writer, "Compression deltas:")?;
 		for i in 0 .. HISTOGRAM_BUCKETS {
 			let count = self.value_histogram[i].load(Ordering::Relaxed);
@@ -196,6 +390,7 @@ impl ColumnStats {
 			}
 		}
 		writeln!(writer, "Missed queries: {}", self.queries_miss.load(Ordering::Relaxed))?;
+		writeln!(writer, "Negative cache hits: {}", self.negative_cache_hits.load(Ordering::Relaxed))?;
 		writeln!(writer, "Value histogram:")?;
 		for i in 0 .. HISTOGRAM_BUCKETS {
 			let count = self.value_histogram[i].load(Ordering::Relaxed);
@@ -208,12 +403,40 @@ impl ColumnStats {
 				)?;
 			}
 		}
+		writeln!(writer, "Value size histogram (log2):")?;
+		for i in 0 .. SIZE_LOG_BUCKETS {
+			let count = self.size_log_histogram[i].load(Ordering::Relaxed);
+			if count != 0 {
+				let (start, end) = size_log_bucket_range(i);
+				writeln!(writer, "    {}-{}: {}", start, end, count)?;
+			}
+		}
+		writeln!(writer, "Index probes per query:")?;
+		for i in 0 .. PROBE_BUCKETS {
+			let count = self.probe_histogram[i].load(Ordering::Relaxed);
+			if count != 0 {
+				let more = if i == PROBE_BUCKETS - 1 { "+" } else { "" };
+				writeln!(writer, "    {}{}: {}", i, more, count)?;
+			}
+		}
+		writeln!(writer, "Reindex history:")?;
+		for event in self.reindex_history.lock().iter() {
+			writeln!(writer,
+				"    records {}-{}: started {}, {} ms, {} entries, {} bytes",
+				event.start_record,
+				event.end_record,
+				event.started,
+				event.duration,
+				event.entries,
+				event.bytes,
+			)?;
+		}
 		writeln!(writer, "")?;
 		Ok(())
 	}
 
-	pub fn write_summary(&self, writer: &mut impl std::io::Write, col: ColId) {
-		let _ = self.write_stats(writer, col);
+	pub fn write_summary(&self, writer: &mut impl std::io::Write, col: ColId, slots: &TableSlots) {
+		let _ = self.write_stats(writer, col, slots);
 	}
 
 	pub fn query_hit(&self, size_tier: u8) {
@@ -224,7 +447,17 @@ impl ColumnStats {
 		self.queries_miss.fetch_add(1, Ordering::Relaxed);
 	}
 
+	pub fn negative_cache_hit(&self) {
+		self.negative_cache_hits.fetch_add(1, Ordering::Relaxed);
+	}
+
+	pub fn query_probes(&self, probes: u32) {
+		let index = std::cmp::min(probes as usize, PROBE_BUCKETS - 1);
+		self.probe_histogram[index].fetch_add(1, Ordering::Relaxed);
+	}
+
 	pub fn insert(&self, size: u32, compressed: u32) {
+		self.size_log_histogram[size_log_histogram_index(size)].fetch_add(1, Ordering::Relaxed);
 		if let Some(index) = value_histogram_index(size) {
 			self.value_histogram[index].fetch_add(1, Ordering::Relaxed);
 			self.compression_delta[index].fetch_add(size as i64 - compressed as i64, Ordering::Relaxed);
@@ -238,6 +471,7 @@ impl ColumnStats {
 	}
 
 	pub fn remove(&self, size: u32, compressed: u32) {
+		self.size_log_histogram[size_log_histogram_index(size)].fetch_sub(1, Ordering::Relaxed);
 		if let Some(index) = value_histogram_index(size) {
 			self.value_histogram[index].fetch_sub(1, Ordering::Relaxed);
 			self.compression_delta[index].fetch_sub(size as i64 - compressed as i64, Ordering::Relaxed);
@@ -273,4 +507,708 @@ impl ColumnStats {
 	pub fn commit(&self) {
 		self.commits.fetch_add(1, Ordering::Relaxed);
 	}
+
+	pub fn write_inserted(&self) {
+		self.writes_inserted.fetch_add(1, Ordering::Relaxed);
+	}
+
+	pub fn write_replaced(&self) {
+		self.writes_replaced.fetch_add(1, Ordering::Relaxed);
+	}
+
+	pub fn write_removed(&self) {
+		self.writes_removed.fetch_add(1, Ordering::Relaxed);
+	}
+
+	pub fn write_collisions(&self, collisions: u64) {
+		if collisions != 0 {
+			self.write_collisions.fetch_add(collisions, Ordering::Relaxed);
+		}
+	}
+
+	pub fn write_reindex(&self) {
+		self.writes_reindex.fetch_add(1, Ordering::Relaxed);
+	}
+
+	pub fn reindex_completed(&self, event: ReindexEvent) {
+		let mut history = self.reindex_history.lock();
+		if history.len() == REINDEX_HISTORY {
+			history.pop_front();
+		}
+		history.push_back(event);
+	}
+
+	pub fn summary(&self, col: ColId, slots: &TableSlots) -> ColumnStatSummary {
+		ColumnStatSummary {
+			col,
+			total_values: self.total_values.load(Ordering::Relaxed),
+			total_bytes: self.total_bytes.load(Ordering::Relaxed),
+			oversized: self.oversized.load(Ordering::Relaxed),
+			oversized_bytes: self.oversized_bytes.load(Ordering::Relaxed),
+			commits: self.commits.load(Ordering::Relaxed),
+			inserted_new: self.inserted_new.load(Ordering::Relaxed),
+			inserted_overwrite: self.inserted_overwrite.load(Ordering::Relaxed),
+			removed_hit: self.removed_hit.load(Ordering::Relaxed),
+			removed_miss: self.removed_miss.load(Ordering::Relaxed),
+			queries_miss: self.queries_miss.load(Ordering::Relaxed),
+			uncompressed_bytes: self.uncompressed_bytes.load(Ordering::Relaxed),
+			query_histogram: self.query_histogram.iter().map(|q| q.load(Ordering::Relaxed)).collect(),
+			value_size_histogram: self.size_log_histogram.iter().map(|v| v.load(Ordering::Relaxed)).collect(),
+			probe_histogram: self.probe_histogram.iter().map(|p| p.load(Ordering::Relaxed)).collect(),
+			reindex_history: self.reindex_history.lock().iter().cloned().collect(),
+			writes_inserted: self.writes_inserted.load(Ordering::Relaxed),
+			writes_replaced: self.writes_replaced.load(Ordering::Relaxed),
+			writes_removed: self.writes_removed.load(Ordering::Relaxed),
+			write_collisions: self.write_collisions.load(Ordering::Relaxed),
+			writes_reindex: self.writes_reindex.load(Ordering::Relaxed),
+			negative_cache_hits: self.negative_cache_hits.load(Ordering::Relaxed),
+			value_slots: slots.value.clone(),
+			free_slots: slots.free.clone(),
+			index_slots: slots.index,
+		}
+	}
+}
+
+/// Reason a committer or background worker was held up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum StallReason {
+	/// A committer waited for the commit queue to drain.
+	CommitQueue,
+	/// The log worker waited for written log records to be enacted.
+	LogQueue,
+	/// The flush worker waited for the log to be synced to disk.
+	Fsync,
+}
+
+impl StallReason {
+	const COUNT: usize = 3;
+
+	// What the user can do about it, for the stall warning.
+	fn hint(self) -> &'static str {
+		match self {
+			StallReason::CommitQueue => "commit queue is full, commits arrive faster than the log is written",
+			StallReason::LogQueue => "log queue is full, the log is written faster than it is enacted into the tables; check disk throughput",
+			StallReason::Fsync => "log fsync is slow; check disk latency or disable `sync_wal`",
+		}
+	}
+}
+
+impl std::fmt::Display for StallReason {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}", self.hint())
+	}
+}
+
+// Number and total duration of stalls, by reason.
+#[derive(Default)]
+pub(crate) struct StallStats {
+	counts: [AtomicU64; StallReason::COUNT],
+	micros: [AtomicU64; StallReason::COUNT],
+}
+
+impl StallStats {
+	pub fn record(&self, reason: StallReason, duration: std::time::Duration) {
+		self.counts[reason as usize].fetch_add(1, Ordering::Relaxed);
+		self.micros[reason as usize].fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+	}
+
+	// Number of stalls and their total duration in microseconds.
+	pub fn get(&self, reason: StallReason) -> (u64, u64) {
+		(self.counts[reason as usize].load(Ordering::Relaxed), self.micros[reason as usize].load(Ordering::Relaxed))
+	}
+
+	pub fn clear(&self) {
+		for v in self.counts.iter().chain(self.micros.iter()) {
+			v.store(0, Ordering::Relaxed);
+		}
+	}
+}
+
+/// Structured snapshot of the database statistics.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DbStats {
+	/// Wall-clock time of the snapshot, in milliseconds since the unix epoch.
+	pub timestamp: u64,
+	/// Time since the database was opened, in milliseconds.
+	pub uptime: u64,
+	/// Id of the last log record enacted into the tables. This is a log record id,
+	/// not a commit id as returned by `Db::commit`.
+	pub last_enacted: u64,
+	/// Number of commits written to the log.
+	pub commits: u64,
+	/// Total size of the log records written for commits.
+	pub logged_bytes: u64,
+	/// Number of times committers waited at least `Options::stall_warn_threshold`
+	/// for the commit queue to drain.
+	pub commit_queue_stalls: u64,
+	/// Total duration of the commit queue stalls, in microseconds.
+	pub commit_queue_stall_time: u64,
+	/// Number of times the log worker waited at least `Options::stall_warn_threshold`
+	/// for log records to be enacted.
+	pub log_queue_stalls: u64,
+	/// Total duration of the log queue stalls, in microseconds.
+	pub log_queue_stall_time: u64,
+	/// Number of log fsyncs that took at least `Options::stall_warn_threshold`.
+	pub fsync_stalls: u64,
+	/// Total duration of the fsync stalls, in microseconds.
+	pub fsync_stall_time: u64,
+	/// Per-column statistics.
+	pub columns: Vec<ColumnStatSummary>,
+}
+
+/// Statistics for a single column.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ColumnStatSummary {
+	pub col: ColId,
+	pub total_values: u64,
+	pub total_bytes: u64,
+	pub oversized: u64,
+	pub oversized_bytes: u64,
+	pub commits: u64,
+	pub inserted_new: u64,
+	pub inserted_overwrite: u64,
+	pub removed_hit: u64,
+	pub removed_miss: u64,
+	pub queries_miss: u64,
+	pub uncompressed_bytes: u64,
+	/// Query hits per value size tier.
+	pub query_histogram: Vec<u64>,
+	/// Number of stored values by size. Entry `n` counts values with size
+	/// in `2^(n-1) .. 2^n` bytes, the last entry also counts all larger values.
+	pub value_size_histogram: Vec<u64>,
+	/// Number of queries by index entries examined. Entry `n` counts queries
+	/// that examined `n` entries, the last entry also counts longer chains.
+	pub probe_histogram: Vec<u64>,
+	/// Most recent completed reindexes, oldest first.
+	pub reindex_history: Vec<ReindexEvent>,
+	/// Commit writes that added a new index entry.
+	pub writes_inserted: u64,
+	/// Commit writes that updated an existing value, including reference count increments.
+	pub writes_replaced: u64,
+	/// Commit writes that removed or dereferenced an existing value.
+	pub writes_removed: u64,
+	/// Index entries that matched the key prefix but belonged to a different key,
+	/// encountered while planning commit and reindex writes.
+	pub write_collisions: u64,
+	/// Commit and reindex writes that found an index chunk full and triggered a reindex.
+	pub writes_reindex: u64,
+	/// Queries answered by the negative lookup cache. These are not included in `queries_miss`.
+	pub negative_cache_hits: u64,
+	/// Entries in use by each value table, by size tier, including free ones.
+	pub value_slots: Vec<u64>,
+	/// Entries left by removed values that are waiting to be reused, by size tier.
+	/// Counted since the database was opened with `Options::stats` set.
+	pub free_slots: Vec<u64>,
+	/// Total number of entries in the index. Occupied entries are counted by `total_values`.
+	pub index_slots: u64,
+}
+
+impl DbStats {
+	/// Encode as a single line JSON object, without the trailing newline.
+	pub fn to_json_line(&self) -> String {
+		let mut out = json::Writer::default();
+		out.begin_object();
+		out.field_u64("timestamp", self.timestamp);
+		out.field_u64("uptime", self.uptime);
+		out.field_u64("last_enacted", self.last_enacted);
+		out.field_u64("commits", self.commits);
+		out.field_u64("logged_bytes", self.logged_bytes);
+		out.field_u64("commit_queue_stalls", self.commit_queue_stalls);
+		out.field_u64("commit_queue_stall_time", self.commit_queue_stall_time);
+		out.field_u64("log_queue_stalls", self.log_queue_stalls);
+		out.field_u64("log_queue_stall_time", self.log_queue_stall_time);
+		out.field_u64("fsync_stalls", self.fsync_stalls);
+		out.field_u64("fsync_stall_time", self.fsync_stall_time);
+		out.key("columns");
+		out.begin_array();
+		for c in self.columns.iter() {
+			c.write_json(&mut out);
+		}
+		out.end_array();
+		out.end_object();
+		out.finish()
+	}
+
+	/// Decode a line produced by `to_json_line`. Unknown fields are ignored
+	/// and missing fields are set to zero, so that records written by other
+	/// versions can still be read.
+	pub fn from_json_line(line: &str) -> Result<DbStats> {
+		let value = json::parse(line)?;
+		let columns = match value.get("columns") {
+			Some(c) => c.as_array()?.iter().map(ColumnStatSummary::from_json).collect::<Result<_>>()?,
+			None => Vec::new(),
+		};
+		Ok(DbStats {
+			timestamp: value.field_u64("timestamp")?,
+			uptime: value.field_u64("uptime")?,
+			last_enacted: value.field_u64("last_enacted")?,
+			commits: value.field_u64("commits")?,
+			logged_bytes: value.field_u64("logged_bytes")?,
+			commit_queue_stalls: value.field_u64("commit_queue_stalls")?,
+			commit_queue_stall_time: value.field_u64("commit_queue_stall_time")?,
+			log_queue_stalls: value.field_u64("log_queue_stalls")?,
+			log_queue_stall_time: value.field_u64("log_queue_stall_time")?,
+			fsync_stalls: value.field_u64("fsync_stalls")?,
+			fsync_stall_time: value.field_u64("fsync_stall_time")?,
+			columns,
+		})
+	}
+
+	/// Read all records from a newline-delimited JSON stats history.
+	pub fn read_history(reader: impl std::io::BufRead) -> Result<Vec<DbStats>> {
+		let mut result = Vec::new();
+		for line in reader.lines() {
+			let line = line?;
+			if line.trim().is_empty() {
+				continue;
+			}
+			result.push(Self::from_json_line(&line)?);
+		}
+		Ok(result)
+	}
+}
+
+impl ColumnStatSummary {
+	fn write_json(&self, out: &mut json::Writer) {
+		out.begin_object();
+		out.field_u64("col", self.col as u64);
+		out.field_u64("total_values", self.total_values);
+		out.field_u64("total_bytes", self.total_bytes);
+		out.field_u64("oversized", self.oversized);
+		out.field_u64("oversized_bytes", self.oversized_bytes);
+		out.field_u64("commits", self.commits);
+		out.field_u64("inserted_new", self.inserted_new);
+		out.field_u64("inserted_overwrite", self.inserted_overwrite);
+		out.field_u64("removed_hit", self.removed_hit);
+		out.field_u64("removed_miss", self.removed_miss);
+		out.field_u64("queries_miss", self.queries_miss);
+		out.field_u64("uncompressed_bytes", self.uncompressed_bytes);
+		out.field_u64_array("query_histogram", &self.query_histogram);
+		out.field_u64_array("value_size_histogram", &self.value_size_histogram);
+		out.field_u64_array("probe_histogram", &self.probe_histogram);
+		out.key("reindex_history");
+		out.begin_array();
+		for event in self.reindex_history.iter() {
+			out.begin_object();
+			out.field_u64("start_record", event.start_record);
+			out.field_u64("end_record", event.end_record);
+			out.field_u64("started", event.started);
+			out.field_u64("duration", event.duration);
+			out.field_u64("entries", event.entries);
+			out.field_u64("bytes", event.bytes);
+			out.end_object();
+		}
+		out.end_array();
+		out.field_u64("writes_inserted", self.writes_inserted);
+		out.field_u64("writes_replaced", self.writes_replaced);
+		out.field_u64("writes_removed", self.writes_removed);
+		out.field_u64("write_collisions", self.write_collisions);
+		out.field_u64("writes_reindex", self.writes_reindex);
+		out.field_u64("negative_cache_hits", self.negative_cache_hits);
+		out.field_u64_array("value_slots", &self.value_slots);
+		out.field_u64_array("free_slots", &self.free_slots);
+		out.field_u64("index_slots", self.index_slots);
+		out.end_object();
+	}
+
+	fn from_json(value: &json::Value) -> Result<ColumnStatSummary> {
+		let col = value.field_u64("col")?;
+		if col > ColId::MAX as u64 {
+			return Err(Error::Corruption("Bad stats column".into()));
+		}
+		Ok(ColumnStatSummary {
+			col: col as ColId,
+			total_values: value.field_u64("total_values")?,
+			total_bytes: value.field_u64("total_bytes")?,
+			oversized: value.field_u64("oversized")?,
+			oversized_bytes: value.field_u64("oversized_bytes")?,
+			commits: value.field_u64("commits")?,
+			inserted_new: value.field_u64("inserted_new")?,
+			inserted_overwrite: value.field_u64("inserted_overwrite")?,
+			removed_hit: value.field_u64("removed_hit")?,
+			removed_miss: value.field_u64("removed_miss")?,
+			queries_miss: value.field_u64("queries_miss")?,
+			uncompressed_bytes: value.field_u64("uncompressed_bytes")?,
+			query_histogram: value.field_u64_array("query_histogram")?,
+			value_size_histogram: value.field_u64_array("value_size_histogram")?,
+			probe_histogram: value.field_u64_array("probe_histogram")?,
+			reindex_history: match value.get("reindex_history") {
+				Some(h) => h.as_array()?.iter().map(|e| Ok(ReindexEvent {
+					start_record: e.field_u64("start_record")?,
+					end_record: e.field_u64("end_record")?,
+					started: e.field_u64("started")?,
+					duration: e.field_u64("duration")?,
+					entries: e.field_u64("entries")?,
+					bytes: e.field_u64("bytes")?,
+				})).collect::<Result<_>>()?,
+				None => Vec::new(),
+			},
+			writes_inserted: value.field_u64("writes_inserted")?,
+			writes_replaced: value.field_u64("writes_replaced")?,
+			writes_removed: value.field_u64("writes_removed")?,
+			write_collisions: value.field_u64("write_collisions")?,
+			writes_reindex: value.field_u64("writes_reindex")?,
+			negative_cache_hits: value.field_u64("negative_cache_hits")?,
+			value_slots: value.field_u64_array("value_slots")?,
+			free_slots: value.field_u64_array("free_slots")?,
+			index_slots: value.field_u64("index_slots")?,
+		})
+	}
+
+	/// Share of the value table entries that are free, between 0 and 1.
+	pub fn free_slot_ratio(&self) -> f64 {
+		let used: u64 = self.value_slots.iter().sum();
+		if used == 0 {
+			return 0.0;
+		}
+		self.free_slots.iter().sum::<u64>() as f64 / used as f64
+	}
+
+	/// Returns `true` if more than 30% of the value table entries are free, so that
+	/// compacting the column would reclaim a significant amount of space.
+	pub fn needs_compaction(&self) -> bool {
+		self.free_slot_ratio() > COMPACTION_FREE_RATIO
+	}
+}
+
+/// Minimal JSON support for the stats history file.
+/// Only handles what `DbStats` needs: objects, arrays, strings and integers.
+mod json {
+	use crate::error::{Error, Result};
+
+	#[derive(Default)]
+	pub struct Writer {
+		out: String,
+		// Whether the current object or array needs a separator before the next item.
+		need_comma: Vec<bool>,
+	}
+
+	impl Writer {
+		fn separator(&mut self) {
+			if let Some(need_comma) = self.need_comma.last_mut() {
+				if *need_comma {
+					self.out.push(',');
+				}
+				*need_comma = true;
+			}
+		}
+
+		pub fn begin_object(&mut self) {
+			self.separator();
+			self.out.push('{');
+			self.need_comma.push(false);
+		}
+
+		pub fn end_object(&mut self) {
+			self.need_comma.pop();
+			self.out.push('}');
+		}
+
+		pub fn begin_array(&mut self) {
+			self.separator();
+			self.out.push('[');
+			self.need_comma.push(false);
+		}
+
+		pub fn end_array(&mut self) {
+			self.need_comma.pop();
+			self.out.push(']');
+		}
+
+		// Keys are plain identifiers, so no escaping is required.
+		pub fn key(&mut self, key: &str) {
+			self.separator();
+			self.out.push('"');
+			self.out.push_str(key);
+			self.out.push_str("\":");
+			// The value that follows the key does not need another separator.
+			if let Some(need_comma) = self.need_comma.last_mut() {
+				*need_comma = false;
+			}
+		}
+
+		pub fn u64(&mut self, value: u64) {
+			self.separator();
+			self.out.push_str(&value.to_string());
+		}
+
+		pub fn field_u64(&mut self, key: &str, value: u64) {
+			self.key(key);
+			self.u64(value);
+		}
+
+		pub fn field_u64_array(&mut self, key: &str, values: &[u64]) {
+			self.key(key);
+			self.begin_array();
+			for v in values {
+				self.u64(*v);
+			}
+			self.end_array();
+		}
+
+		pub fn finish(self) -> String {
+			self.out
+		}
+	}
+
+	#[derive(Debug, PartialEq)]
+	pub enum Value {
+		Int(i128),
+		Str(String),
+		Array(Vec<Value>),
+		Object(Vec<(String, Value)>),
+	}
+
+	fn bad(what: &str) -> Error {
+		Error::Corruption(format!("Bad stats record: {}", what))
+	}
+
+	impl Value {
+		pub fn get(&self, key: &str) -> Option<&Value> {
+			match self {
+				Value::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+				_ => None,
+			}
+		}
+
+		pub fn as_u64(&self) -> Result<u64> {
+			match self {
+				Value::Int(v) if *v >= 0 && *v <= u64::MAX as i128 => Ok(*v as u64),
+				_ => Err(bad("expected unsigned integer")),
+			}
+		}
+
+		pub fn as_array(&self) -> Result<&[Value]> {
+			match self {
+				Value::Array(items) => Ok(items),
+				_ => Err(bad("expected array")),
+			}
+		}
+
+		pub fn field_u64(&self, key: &str) -> Result<u64> {
+			self.get(key).map_or(Ok(0), |v| v.as_u64())
+		}
+
+		pub fn field_u64_array(&self, key: &str) -> Result<Vec<u64>> {
+			match self.get(key) {
+				Some(v) => v.as_array()?.iter().map(|v| v.as_u64()).collect(),
+				None => Ok(Vec::new()),
+			}
+		}
+	}
+
+	pub fn parse(s: &str) -> Result<Value> {
+		let mut parser = Parser { data: s.as_bytes(), pos: 0 };
+		let value = parser.value()?;
+		parser.skip_whitespace();
+		if parser.pos != parser.data.len() {
+			return Err(bad("trailing data"));
+		}
+		Ok(value)
+	}
+
+	struct Parser<'a> {
+		data: &'a [u8],
+		pos: usize,
+	}
+
+	impl<'a> Parser<'a> {
+		fn skip_whitespace(&mut self) {
+			while self.pos < self.data.len() && self.data[self.pos].is_ascii_whitespace() {
+				self.pos += 1;
+			}
+		}
+
+		fn peek(&mut self) -> Option<u8> {
+			self.skip_whitespace();
+			self.data.get(self.pos).cloned()
+		}
+
+		fn expect(&mut self, c: u8) -> Result<()> {
+			if self.peek() != Some(c) {
+				return Err(bad(&format!("expected '{}' at {}", c as char, self.pos)));
+			}
+			self.pos += 1;
+			Ok(())
+		}
+
+		fn value(&mut self) -> Result<Value> {
+			match self.peek() {
+				Some(b'{') => self.object(),
+				Some(b'[') => self.array(),
+				Some(b'"') => Ok(Value::Str(self.string()?)),
+				Some(c) if c == b'-' || c.is_ascii_digit() => self.number(),
+				_ => Err(bad(&format!("unexpected input at {}", self.pos))),
+			}
+		}
+
+		fn object(&mut self) -> Result<Value> {
+			self.expect(b'{')?;
+			let mut fields = Vec::new();
+			if self.peek() == Some(b'}') {
+				self.pos += 1;
+				return Ok(Value::Object(fields));
+			}
+			loop {
+				let key = self.string()?;
+				self.expect(b':')?;
+				fields.push((key, self.value()?));
+				match self.peek() {
+					Some(b',') => self.pos += 1,
+					Some(b'}') => {
+						self.pos += 1;
+						return Ok(Value::Object(fields));
+					},
+					_ => return Err(bad("unterminated object")),
+				}
+			}
+		}
+
+		fn array(&mut self) -> Result<Value> {
+			self.expect(b'[')?;
+			let mut items = Vec::new();
+			if self.peek() == Some(b']') {
+				self.pos += 1;
+				return Ok(Value::Array(items));
+			}
+			loop {
+				items.push(self.value()?);
+				match self.peek() {
+					Some(b',') => self.pos += 1,
+					Some(b']') => {
+						self.pos += 1;
+						return Ok(Value::Array(items));
+					},
+					_ => return Err(bad("unterminated array")),
+				}
+			}
+		}
+
+		fn string(&mut self) -> Result<String> {
+			self.expect(b'"')?;
+			let mut result = Vec::new();
+			while let Some(c) = self.data.get(self.pos).cloned() {
+				self.pos += 1;
+				match c {
+					b'"' => return String::from_utf8(result).map_err(|_| bad("invalid utf-8")),
+					b'\\' => {
+						let escaped = self.data.get(self.pos).cloned().ok_or_else(|| bad("unterminated string"))?;
+						self.pos += 1;
+						result.push(escaped);
+					},
+					c => result.push(c),
+				}
+			}
+			Err(bad("unterminated string"))
+		}
+
+		fn number(&mut self) -> Result<Value> {
+			let start = self.pos;
+			if self.data[self.pos] == b'-' {
+				self.pos += 1;
+			}
+			while self.pos < self.data.len() && self.data[self.pos].is_ascii_digit() {
+				self.pos += 1;
+			}
+			let s = std::str::from_utf8(&self.data[start..self.pos]).map_err(|_| bad("invalid number"))?;
+			s.parse().map(Value::Int).map_err(|_| bad("invalid number"))
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::{ColumnStats, DbStats, TableSlots, ReindexEvent, json, TOTAL_SIZE, REINDEX_HISTORY};
+
+	#[test]
+	fn stats_json_round_trip() {
+		let column = ColumnStats::empty();
+		column.insert_val(100, 50);
+		column.insert_val(5000, 5000);
+		column.query_hit(3);
+		column.query_miss();
+		column.commit();
+		column.reindex_completed(ReindexEvent { start_record: 1, end_record: 5, started: 7, duration: 10, entries: 100, bytes: 4000 });
+		let stats = DbStats {
+			timestamp: 1_600_000_000_000,
+			uptime: 42,
+			last_enacted: u64::MAX,
+			commits: 3,
+			logged_bytes: 1024,
+			commit_queue_stalls: 2,
+			commit_queue_stall_time: 3_000_000,
+			log_queue_stalls: 1,
+			log_queue_stall_time: 1_500_000,
+			fsync_stalls: 4,
+			fsync_stall_time: 8_000_000,
+			columns: vec![
+				column.summary(0, &TableSlots { value: vec![10, 0, 4], free: vec![3, 0, 1], index: 1 << 16 }),
+				ColumnStats::empty().summary(1, &TableSlots::default()),
+			],
+		};
+		let line = stats.to_json_line();
+		assert!(!line.contains('\n'));
+		assert_eq!(DbStats::from_json_line(&line).unwrap(), stats);
+
+		let history = format!("{}\n\n{}\n", line, line);
+		let parsed = DbStats::read_history(std::io::Cursor::new(history)).unwrap();
+		assert_eq!(parsed, vec![stats.clone(), stats]);
+	}
+
+	#[test]
+	fn reindex_history_persisted() {
+		let column = ColumnStats::empty();
+		for i in 0 .. REINDEX_HISTORY as u64 + 1 {
+			column.reindex_completed(ReindexEvent { start_record: i, end_record: i + 1, entries: i * 10, ..Default::default() });
+		}
+		let mut data = vec![0u8; TOTAL_SIZE];
+		column.to_slice(&mut data);
+		let history = ColumnStats::from_slice(&data).summary(0, &TableSlots::default()).reindex_history;
+		assert_eq!(history.len(), REINDEX_HISTORY);
+		assert_eq!(history[0].start_record, 1);
+		assert_eq!(history[REINDEX_HISTORY - 1].entries, REINDEX_HISTORY as u64 * 10);
+
+		column.clear();
+		column.to_slice(&mut data);
+		assert!(ColumnStats::from_slice(&data).summary(0, &TableSlots::default()).reindex_history.is_empty());
+	}
+
+	#[test]
+	fn write_counters_persisted() {
+		let column = ColumnStats::empty();
+		column.reindex_completed(ReindexEvent { start_record: 1, ..Default::default() });
+		column.write_inserted();
+		column.write_inserted();
+		column.write_removed();
+		column.write_collisions(3);
+		column.negative_cache_hit();
+		let mut data = vec![0u8; TOTAL_SIZE];
+		column.to_slice(&mut data);
+		let summary = ColumnStats::from_slice(&data).summary(0, &TableSlots::default());
+		assert_eq!(summary.reindex_history.len(), 1);
+		assert_eq!(summary.writes_inserted, 2);
+		assert_eq!(summary.writes_replaced, 0);
+		assert_eq!(summary.writes_removed, 1);
+		assert_eq!(summary.write_collisions, 3);
+		assert_eq!(summary.negative_cache_hits, 1);
+	}
+
+	#[test]
+	fn stats_json_missing_and_unknown_fields() {
+		let stats = DbStats::from_json_line(r#"{"uptime": 7, "extra": ["a\"b", -1, {}], "columns": [{"col": 2}]}"#).unwrap();
+		assert_eq!(stats.uptime, 7);
+		assert_eq!(stats.timestamp, 0);
+		assert_eq!(stats.columns.len(), 1);
+		assert_eq!(stats.columns[0].col, 2);
+		assert!(stats.columns[0].query_histogram.is_empty());
+
+		assert!(DbStats::from_json_line(r#"{"uptime": -7}"#).is_err());
+		assert!(DbStats::from_json_line(r#"{"uptime": 7"#).is_err());
+		assert!(DbStats::from_json_line(r#"{"columns": [{"col": 256}]}"#).is_err());
+		assert_eq!(json::parse("[1, [], \"x\"]").unwrap(), json::Value::Array(vec![
+			json::Value::Int(1),
+			json::Value::Array(Vec::new()),
+			json::Value::Str("x".into()),
+		]));
+	}
 }
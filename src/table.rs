@@ -51,6 +51,13 @@
 // if value is compressed.
 // VALUE: SIZE payload bytes.
 //
+// Fixed size entry (columns with `fixed_value_len`):
+// [LIVE: 1][REFS: 4][KEY: 26][VALUE]
+// LIVE - Live entry marker. 0x00.
+// REF: 32-bit reference counter (optional).
+// KEY: lower 26 bytes of the key.
+// VALUE: payload bytes, always the same size.
+//
 // Deleted entry
 // [TOMBSTONE: 2][NEXT: 8]
 // TOMBSTONE - Deleted entry marker. 0xffff
@@ -59,15 +66,15 @@
 
 use std::convert::TryInto;
 use std::mem::MaybeUninit;
-use std::io::Read;
 use std::sync::atomic::{AtomicU64, AtomicBool, Ordering};
 use std::sync::Arc;
 use crate::{
-	error::Result,
+	error::{Error, Result},
 	column::ColId,
 	log::{LogQuery, LogReader, LogWriter},
 	display::hex,
 	options::ColumnOptions as Options,
+	file::TableLayout,
 };
 
 pub const KEY_LEN: usize = 32;
@@ -78,6 +85,7 @@ pub const MAX_ENTRY_SIZE: usize = 0x7ff8; // Actual max size in V4 was 0x7dfe
 pub const MIN_ENTRY_SIZE: usize = 32;
 const REFS_SIZE: usize = 4;
 const SIZE_SIZE: usize = 2;
+const LIVE_SIZE: usize = 1;
 const PARTIAL_SIZE: usize = 26;
 const INDEX_SIZE: usize = 8;
 const MAX_ENTRY_BUF_SIZE: usize = 0x8000;
@@ -87,8 +95,11 @@ const MULTIPART_V4: &[u8] = &[0xff, 0xfe];
 const MULTIHEAD_V4: &[u8] = &[0xff, 0xfd];
 const MULTIPART: &[u8] = &[0xfe, 0xff];
 const MULTIHEAD: &[u8] = &[0xfd, 0xff];
+const LIVE: &[u8] = &[0x00];
 // When a rc reach locked ref, it is locked in db.
 const LOCKED_REF: u32 = u32::MAX;
+/// Largest value that fits a fixed size entry.
+pub const MAX_FIXED_VALUE_LEN: u32 = (MAX_ENTRY_SIZE - LIVE_SIZE - REFS_SIZE - PARTIAL_SIZE) as u32;
 
 
 pub type Key = [u8; KEY_LEN];
@@ -126,6 +137,11 @@ impl TableId {
 		name.starts_with(&format!("table_{:02}_", col))
 	}
 
+	/// Same as `is_file_name`, for any column.
+	pub fn is_any_file_name(name: &str) -> bool {
+		name.starts_with("table_")
+	}
+
 	pub fn as_u16(&self) -> u16 {
 		self.0
 	}
@@ -143,9 +159,13 @@ pub struct ValueTable {
 	file: crate::file::TableFile,
 	filled: AtomicU64,
 	last_removed: AtomicU64,
+	// Number of entries on the free list. Not stored, see `count_free_entries`.
+	free_entries: AtomicU64,
 	dirty_header: AtomicBool,
 	multipart: bool,
 	ref_counted: bool,
+	// Size of every stored value for tables without size headers.
+	fixed_len: Option<u16>,
 	db_version: u32,
 }
 
@@ -235,6 +255,14 @@ impl<B: AsRef<[u8]> + AsMut<[u8]>> Entry<B> {
 			(db_version <= 4 && (self.is_multipart_v4() || self.is_multihead_v4()))
 	}
 
+	fn write_live(&mut self) {
+		self.write_slice(LIVE);
+	}
+
+	fn skip_live(&mut self) {
+		self.0 += LIVE_SIZE;
+	}
+
 	fn read_size(&mut self) -> (u16, bool) {
 		let size = u16::from_le_bytes(self.read_slice(SIZE_SIZE).try_into().unwrap());
 		let compressed = (size & COMPRESSED_MASK) > 0;
@@ -316,12 +344,19 @@ impl ValueTable {
 		path: Arc<std::path::PathBuf>,
 		id: TableId,
 		entry_size: Option<u16>,
+		fixed_len: Option<u16>,
 		options: &Options,
+		layout: TableLayout,
 		db_version: u32,
 	) -> Result<ValueTable> {
-		let (multipart, entry_size) = match entry_size {
-			Some(s) => (false, s),
-			None => (true, 4096),
+		let ref_size = if options.ref_counted { REFS_SIZE } else { 0 };
+		let (multipart, entry_size) = match (fixed_len, entry_size) {
+			(Some(len), _) => {
+				let size = LIVE_SIZE + ref_size + PARTIAL_SIZE + len as usize;
+				(false, std::cmp::max(size, MIN_ENTRY_SIZE) as u16)
+			},
+			(None, Some(s)) => (false, s),
+			(None, None) => (true, 4096),
 		};
 		assert!(entry_size >= MIN_ENTRY_SIZE as u16);
 		if db_version >= 4 {
@@ -330,12 +365,12 @@ impl ValueTable {
 
 		let mut filepath: std::path::PathBuf = std::path::PathBuf::clone(&*path);
 		filepath.push(id.file_name());
-		let file = crate::file::TableFile::open(filepath, entry_size, id)?;
+		let file = crate::file::TableFile::open(filepath, entry_size, id, layout)?;
 		let mut filled = 1;
 		let mut last_removed = 0;
-		if let Some(file) = &mut *file.file.write() {
+		if file.exists() {
 			let mut header = Header::default();
-			file.read_exact(&mut header.0)?;
+			file.read_at(&mut header.0, 0)?;
 			last_removed = header.last_removed();
 			filled = header.filled();
 			if filled == 0 {
@@ -350,14 +385,29 @@ impl ValueTable {
 			file,
 			filled: AtomicU64::new(filled),
 			last_removed: AtomicU64::new(last_removed),
+			free_entries: AtomicU64::new(0),
 			dirty_header: AtomicBool::new(false),
 			multipart,
 			ref_counted: options.ref_counted,
+			fixed_len,
 			db_version,
 		})
 	}
 
+	/// Extend the table file to at least `bytes`.
+	pub fn preallocate(&self, bytes: u64) -> Result<()> {
+		self.file.preallocate(bytes, self.entry_size)
+	}
+
+	/// Hint that the entry at `index` will be read soon.
+	pub fn prefetch(&self, index: u64) {
+		self.file.prefetch(index * self.entry_size as u64, self.entry_size as u64);
+	}
+
 	pub fn value_size(&self) -> u16 {
+		if let Some(len) = self.fixed_len {
+			return len;
+		}
 		self.entry_size - SIZE_SIZE as u16 - self.ref_size() as u16 - PARTIAL_SIZE as u16
 	}
 
@@ -396,7 +446,10 @@ impl ValueTable {
 				return Ok((0, Default::default(), false));
 			}
 
-			let (entry_end, next) = if self.multipart && buf.is_multi(self.db_version) {
+			let (entry_end, next) = if let Some(len) = self.fixed_len {
+				buf.skip_live();
+				(self.fixed_entry_len(len), 0)
+			} else if self.multipart && buf.is_multi(self.db_version) {
 				buf.skip_size();
 				let next = buf.read_next();
 				(entry_size, next)
@@ -439,9 +492,16 @@ impl ValueTable {
 
 	pub fn get(&self, key: &Key, index: u64, log: &impl LogQuery) -> Result<Option<(Value, bool)>> {
 		let mut result = Vec::new();
-		let (rc, _, compressed) = self.for_parts(Some(key), index, log, |buf| result.extend_from_slice(buf))?;
+		Ok(self.get_into(key, index, log, &mut result)?.map(|compressed| (result, compressed)))
+	}
+
+	/// Same as `get`, but reads the value into `value`, replacing its contents.
+	/// Returns whether the value is compressed, or `None` if it is not found.
+	pub fn get_into(&self, key: &Key, index: u64, log: &impl LogQuery, value: &mut Vec<u8>) -> Result<Option<bool>> {
+		value.clear();
+		let (rc, _, compressed) = self.for_parts(Some(key), index, log, |buf| value.extend_from_slice(buf))?;
 		if rc > 0 {
-			return Ok(Some((result, compressed)));
+			return Ok(Some(compressed));
 		}
 		Ok(None)
 	}
@@ -484,9 +544,13 @@ impl ValueTable {
 		if buf.is_tombstone() {
 			return Ok(None);
 		}
-		buf.skip_size();
-		if self.multipart && buf.is_multi(self.db_version) {
-			buf.skip_next();
+		if self.fixed_len.is_some() {
+			buf.skip_live();
+		} else {
+			buf.skip_size();
+			if self.multipart && buf.is_multi(self.db_version) {
+				buf.skip_next();
+			}
 		}
 		if self.ref_counted {
 			buf.skip_rc();
@@ -506,7 +570,7 @@ impl ValueTable {
 		return Ok(next);
 	}
 
-	pub fn read_next_part(&self, index: u64, log: &LogWriter) -> Result<Option<u64>> {
+	pub fn read_next_part(&self, index: u64, log: &impl LogQuery) -> Result<Option<u64>> {
 		let mut buf = PartialEntry::new_uninit();
 		if !log.value(self.id, index, buf.as_mut()) {
 			self.file.read_at(buf.as_mut(), index * self.entry_size as u64)?;
@@ -531,6 +595,7 @@ impl ValueTable {
 				last_removed,
 			);
 			self.last_removed.store(next_removed, Ordering::Relaxed);
+			let _ = self.free_entries.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
 			last_removed
 		} else {
 			log::trace!(
@@ -547,6 +612,9 @@ impl ValueTable {
 	}
 
 	fn overwrite_chain(&self, key: &Key, value: &[u8], log: &mut LogWriter, at: Option<u64>, compressed: bool) -> Result<u64> {
+		if let Some(len) = self.fixed_len {
+			return self.write_fixed(key, value, len, log, at);
+		}
 		let mut remainder = value.len() + self.ref_size() + PARTIAL_SIZE;
 		let mut offset = 0;
 		let mut start = 0;
@@ -621,6 +689,30 @@ impl ValueTable {
 		Ok(start)
 	}
 
+	fn write_fixed(&self, key: &Key, value: &[u8], len: u16, log: &mut LogWriter, at: Option<u64>) -> Result<u64> {
+		assert_eq!(value.len(), len as usize);
+		let index = match at {
+			Some(index) => index,
+			None => self.next_free(log)?,
+		};
+		log::trace!(
+			target: "axia-db",
+			"{}: Writing fixed slot {}: {}",
+			self.id,
+			index,
+			hex(key),
+		);
+		let mut buf = FullEntry::new_uninit();
+		buf.write_live();
+		if self.ref_counted {
+			buf.write_rc(1u32);
+		}
+		buf.write_slice(partial_key(key));
+		buf.write_slice(value);
+		log.insert_value(self.id, index, buf[0..buf.offset()].to_vec());
+		Ok(index)
+	}
+
 	fn clear_chain(&self, mut index: u64, log: &mut LogWriter) -> Result<()> {
 		loop {
 			match self.read_next_part(index, log)? {
@@ -651,6 +743,7 @@ impl ValueTable {
 
 		log.insert_value(self.id, index, buf[0..buf.offset()].to_vec());
 		self.last_removed.store(index, Ordering::Relaxed);
+		self.free_entries.fetch_add(1, Ordering::Relaxed);
 		self.dirty_header.store(true, Ordering::Relaxed);
 		Ok(())
 	}
@@ -699,7 +792,10 @@ impl ValueTable {
 			return Ok(false);
 		}
 
-		let size = if self.multipart && buf.is_multi(self.db_version) {
+		let size = if let Some(len) = self.fixed_len {
+			buf.skip_live();
+			self.fixed_entry_len(len)
+		} else if self.multipart && buf.is_multi(self.db_version) {
 			buf.skip_size();
 			buf.skip_next();
 			self.entry_size as usize
@@ -749,6 +845,11 @@ impl ValueTable {
 			log.read(&mut buf[SIZE_SIZE..SIZE_SIZE + INDEX_SIZE])?;
 			self.file.write_at(&buf[0..SIZE_SIZE + INDEX_SIZE], index * (self.entry_size as u64))?;
 			log::trace!(target: "axia-db", "{}: Enacted tombstone in slot {}", self.id, index);
+		} else if let Some(len) = self.fixed_len {
+			let entry_len = self.fixed_entry_len(len);
+			log.read(&mut buf[SIZE_SIZE..entry_len])?;
+			self.file.write_at(&buf[0..entry_len], index * (self.entry_size as u64))?;
+			log::trace!(target: "axia-db", "{}: Enacted fixed {}, {} bytes", self.id, index, len);
 		} else if self.multipart && buf.is_multi(self.db_version) {
 				let entry_size = self.entry_size as usize;
 				log.read(&mut buf[SIZE_SIZE..entry_size])?;
@@ -775,6 +876,10 @@ impl ValueTable {
 		if buf.is_tombstone() {
 			log.read(&mut buf[SIZE_SIZE..SIZE_SIZE + INDEX_SIZE])?;
 			log::trace!(target: "axia-db", "{}: Validated tombstone in slot {}", self.id, index);
+		} else if let Some(len) = self.fixed_len {
+			let entry_len = self.fixed_entry_len(len);
+			log.read(&mut buf[SIZE_SIZE..entry_len])?;
+			log::trace!(target: "axia-db", "{}: Validated fixed {}, {} bytes", self.id, index, len);
 		} else if self.multipart && buf.is_multi(self.db_version) {
 			let entry_size = self.entry_size as usize;
 			log.read(&mut buf[SIZE_SIZE..entry_size])?;
@@ -789,7 +894,7 @@ impl ValueTable {
 	}
 
 	pub fn refresh_metadata(&self) -> Result<()> {
-		if self.file.file.read().is_none() {
+		if !self.file.exists() {
 			return Ok(());
 		}
 		let mut header = Header::default();
@@ -829,26 +934,107 @@ impl ValueTable {
 		}
 	}
 
-	pub fn iter_while(&self, log: &impl LogQuery, mut f: impl FnMut (u64, u32, Vec<u8>, bool) -> bool) -> Result<()> {
-		let filled = self.filled.load(Ordering::Relaxed);
-		for index in 1 .. filled {
+	fn fixed_entry_len(&self, len: u16) -> usize {
+		LIVE_SIZE + self.ref_size() + PARTIAL_SIZE + len as usize
+	}
+
+	/// Bytes taken by the entries in use. Files are grown sparsely, so the space past
+	/// the filled entries is not allocated yet.
+	pub fn used_bytes(&self) -> u64 {
+		if !self.file.exists() {
+			return 0;
+		}
+		self.filled() * self.entry_size as u64
+	}
+
+	/// Number of entries in use, including free and removed ones. Entry 0 is the header.
+	pub fn filled(&self) -> u64 {
+		self.filled.load(Ordering::Relaxed)
+	}
+
+	/// Same as `filled`, as stored in the table file. Entries allocated by commits that
+	/// are not enacted yet are not included.
+	pub fn enacted_filled(&self) -> Result<u64> {
+		if !self.file.exists() {
+			return Ok(1);
+		}
+		let mut header = Header::default();
+		self.file.read_at(&mut header.0, 0)?;
+		Ok(std::cmp::max(header.filled(), 1))
+	}
+
+	/// Number of removed entries waiting to be reused. Only known after `count_free_entries`,
+	/// it is kept up to date as entries are freed and reused after that.
+	pub fn free_entries(&self) -> u64 {
+		self.free_entries.load(Ordering::Relaxed)
+	}
+
+	/// Count the entries on the free list by following it. Reads one entry per free slot,
+	/// so this is only done once, when the database is opened.
+	pub fn count_free_entries(&self) -> Result<()> {
+		let filled = self.filled();
+		let mut count = 0;
+		let mut index = self.last_removed.load(Ordering::Relaxed);
+		while index != 0 {
+			if index >= filled || count >= filled {
+				return Err(Error::Corruption(format!("{}: Bad free list entry {}", self.id, index)));
+			}
+			let mut buf = PartialEntry::new_uninit();
+			self.file.read_at(buf.as_mut(), index * self.entry_size as u64)?;
+			buf.skip_size();
+			index = buf.read_next();
+			count += 1;
+		}
+		self.free_entries.store(count, Ordering::Relaxed);
+		Ok(())
+	}
+
+	/// Indexes of the entries that hold the second and later parts of split values.
+	/// These look like complete entries and must be skipped when scanning the table.
+	pub fn continuation_parts(&self, log: &impl LogQuery) -> Result<std::collections::HashSet<u64>> {
+		let mut parts = std::collections::HashSet::new();
+		if self.multipart {
+			for index in 1 .. self.filled() {
+				if let Some(next) = self.read_next_part(index, log)? {
+					parts.insert(next);
+				}
+			}
+		}
+		Ok(parts)
+	}
+
+	/// Iterate over the values stored at `range`, skipping the continuation parts of
+	/// multipart values listed in `parts`, see `continuation_parts`. The callback gets the
+	/// entry index, reference count, partial key, value and whether the value is compressed.
+	/// Returns the index after the last entry visited.
+	pub fn iter_range_while(
+		&self,
+		parts: &std::collections::HashSet<u64>,
+		range: std::ops::Range<u64>,
+		log: &impl LogQuery,
+		mut f: impl FnMut (u64, u32, &[u8], Vec<u8>, bool) -> bool,
+	) -> Result<u64> {
+		for index in range.clone() {
+			if parts.contains(&index) {
+				continue;
+			}
 			let mut result = Vec::new();
-			let (rc, _, compressed) = self.for_parts(None, index, log, |buf| result.extend_from_slice(buf))?;
+			let (rc, pk, compressed) = self.for_parts(None, index, log, |buf| result.extend_from_slice(buf))?;
 			if rc > 0 {
-				if !f(index, rc, result, compressed) {
-					break;
+				if !f(index, rc, &pk, result, compressed) {
+					return Ok(index + 1);
 				}
 			}
 		}
-		Ok(())
+		Ok(range.end)
 	}
 }
 
 #[cfg(test)]
 mod test {
 	const ENTRY_SIZE: u16 = 64;
-	use super::{ValueTable, TableId, Key, Value};
-	use crate::{log::{Log, LogWriter, LogAction}, options::{Options, ColumnOptions, CURRENT_VERSION}};
+	use super::{ValueTable, TableId, TableLayout, Key, Value};
+	use crate::{log::{Log, LogWriter, LogAction}, options::{Options, ColumnOptions, TableGrowth, CURRENT_VERSION}};
 
 	struct TempDir(std::sync::Arc<std::path::PathBuf>);
 
@@ -868,13 +1054,17 @@ mod test {
 		}
 
 		fn table(&self, size: Option<u16>, options: &ColumnOptions) -> ValueTable {
+			self.table_with_growth(size, options, Default::default())
+		}
+
+		fn table_with_growth(&self, size: Option<u16>, options: &ColumnOptions, growth: TableGrowth) -> ValueTable {
 			let id = TableId::new(0, 0);
-			ValueTable::open(self.0.clone(), id, size, options, CURRENT_VERSION).unwrap()
+			ValueTable::open(self.0.clone(), id, size, None, options, TableLayout { growth, max_file_size: None }, CURRENT_VERSION).unwrap()
 		}
 
 		fn log(&self) -> Log {
 			let options = Options::with_columns(&*self.0, 1);
-			Log::open(&options).unwrap()
+			Log::open(&options, CURRENT_VERSION).unwrap()
 		}
 	}
 
@@ -889,25 +1079,29 @@ mod test {
 	fn write_ops<F: FnOnce(&mut LogWriter)>(table: &ValueTable, log: &Log, f: F) {
 		let mut writer = log.begin_record();
 		f(&mut writer);
-		let bytes_written = log.end_record(writer.drain()).unwrap();
+		let stats = log.end_record(writer.drain()).unwrap();
 		// Cycle through 2 log files
 		let _ = log.read_next(false);
 		log.flush_one(0).unwrap();
 		let _ = log.read_next(false);
 		log.flush_one(0).unwrap();
 		let mut reader = log.read_next(false).unwrap().unwrap();
+		let mut value_insertions = 0;
 		loop {
 			match reader.next().unwrap() {
-				LogAction::BeginRecord | LogAction::InsertIndex { .. } | LogAction::DropTable { .. } => {
+				LogAction::BeginRecord | LogAction::InsertIndex { .. } | LogAction::DropTable { .. } | LogAction::RecordMeta(_) | LogAction::CommitPrepared(_) => {
 					panic!("Unexpected log entry");
 				},
 				LogAction::EndRecord => {
 					let bytes_read = reader.read_bytes();
-					assert_eq!(bytes_written, bytes_read);
+					assert_eq!(stats.bytes_written, bytes_read);
+					assert_eq!(stats.num_value_insertions, value_insertions);
+					assert_eq!(stats.num_index_insertions, 0);
 					break;
 				},
 				LogAction::InsertValue(insertion) => {
 					table.enact_plan(insertion.index, &mut reader).unwrap();
+					value_insertions += 1;
 				},
 			}
 		}
@@ -956,6 +1150,38 @@ mod test {
 		assert_eq!(table.filled.load(std::sync::atomic::Ordering::Relaxed), 2);
 	}
 
+	#[test]
+	fn table_growth() {
+		assert_eq!(TableGrowth::Fixed(256 * 1024).grow_entries(0, 64), 4096);
+		assert_eq!(TableGrowth::Fixed(10).grow_entries(100, 64), 1);
+		assert_eq!(TableGrowth::Doubling.grow_entries(0, 64), 4096);
+		assert_eq!(TableGrowth::Doubling.grow_entries(10000, 64), 10000);
+
+		let dir = TempDir::new("table_growth");
+		let growth = TableGrowth::Fixed(ENTRY_SIZE as u64 * 3);
+		let table = dir.table_with_growth(Some(ENTRY_SIZE), &Default::default(), growth);
+		let log = dir.log();
+		write_ops(&table, &log, |writer| {
+			table.write_insert_plan(&key(1), &value(19), writer, false).unwrap();
+		});
+		assert_eq!(table.file.capacity.load(std::sync::atomic::Ordering::Relaxed), 3);
+		let len = std::fs::metadata(&table.file.path).unwrap().len();
+		assert_eq!(len, ENTRY_SIZE as u64 * 3);
+	}
+
+	#[test]
+	fn split_files_bad_address() {
+		let dir = TempDir::new("split_files_bad_address");
+		let layout = TableLayout { growth: TableGrowth::Fixed(ENTRY_SIZE as u64 * 6), max_file_size: Some(ENTRY_SIZE as u64 * 4) };
+		let table = ValueTable::open(dir.0.clone(), TableId::new(0, 0), Some(ENTRY_SIZE), None, &Default::default(), layout, CURRENT_VERSION).unwrap();
+		let log = dir.log();
+		write_ops(&table, &log, |writer| {
+			table.write_insert_plan(&key(1), &value(19), writer, false).unwrap();
+		});
+		// An address past the last file can only come from corrupted data.
+		assert!(matches!(table.get(&key(1), 100, log.overlays()), Err(crate::error::Error::Corruption(_))));
+	}
+
 	#[test]
 	#[should_panic(expected = "assertion failed: entry_size <= MAX_ENTRY_SIZE as u16")]
 	fn oversized_into_fixed_panics() {
@@ -1127,6 +1353,40 @@ mod test {
 		}
 	}
 
+	#[test]
+	fn fixed_size_entries() {
+		fixed_size_entries_inner(&Default::default());
+		fixed_size_entries_inner(&rc_options());
+	}
+	fn fixed_size_entries_inner(options: &ColumnOptions) {
+		let dir = TempDir::new("fixed_size_entries");
+		let table = ValueTable::open(dir.0.clone(), TableId::new(0, 0), None, Some(40), options, Default::default(), CURRENT_VERSION).unwrap();
+		let log = dir.log();
+		assert_eq!(table.value_size(), 40);
+		assert_eq!(table.entry_size as usize, 1 + table.ref_size() + 26 + 40);
+
+		let key1 = key(1);
+		let key2 = key(2);
+		let val1 = value(40);
+		let val2 = value(40);
+
+		write_ops(&table, &log, |writer| {
+			table.write_insert_plan(&key1, &val1, writer, false).unwrap();
+			table.write_insert_plan(&key2, &val2, writer, false).unwrap();
+		});
+		assert_eq!(table.get(&key1, 1, log.overlays()).unwrap(), Some((val1.clone(), false)));
+		assert_eq!(table.get(&key2, 2, log.overlays()).unwrap(), Some((val2.clone(), false)));
+		assert!(table.has_key_at(2, &key2, &log.begin_record()).unwrap());
+
+		write_ops(&table, &log, |writer| {
+			table.write_replace_plan(1, &key1, &val2, writer, false).unwrap();
+			table.write_remove_plan(2, writer).unwrap();
+		});
+		assert_eq!(table.get(&key1, 1, log.overlays()).unwrap(), Some((val2, false)));
+		assert_eq!(table.get(&key2, 2, log.overlays()).unwrap(), None);
+		assert_eq!(table.last_removed.load(std::sync::atomic::Ordering::Relaxed), 2);
+	}
+
 	#[test]
 	fn ref_underflow() {
 		let dir = TempDir::new("ref_underflow");
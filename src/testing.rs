@@ -0,0 +1,159 @@
+// Copyright 2015-2020 AXIA Technologies (UK) Ltd.
+// This file is part of AXIA.
+
+// AXIA is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// AXIA is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with AXIA.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Deterministic database fixtures for tests.
+//!
+//! `DbBuilder` creates a database, commits the fixtures and runs the commit pipeline
+//! synchronously until they reach the requested stage. No background workers are
+//! started, so the database stays in that state until it is advanced with
+//! `Db::step` or `Db::advance_to`.
+//!
+//! The crate's own tests use it where they need no background workers. Tests of the
+//! background pipeline stages, of reopening an open database or of recovery with the
+//! lock held still use the internal options.
+
+use std::path::Path;
+use crate::{
+	column::ColId,
+	db::{Db, EnableCommitPipelineStages, InternalOptions, Value},
+	error::Result,
+	options::Options,
+};
+
+pub struct DbBuilder {
+	options: Options,
+	fixtures: Vec<(ColId, Vec<u8>, Option<Value>)>,
+	stage: EnableCommitPipelineStages,
+	max_commit_queue_bytes: Option<usize>,
+}
+
+impl DbBuilder {
+	/// Create a database with the given options. Fixtures are written all the way to
+	/// the tables unless another stage is set.
+	pub fn new(options: Options) -> DbBuilder {
+		DbBuilder {
+			options,
+			fixtures: Vec::new(),
+			stage: EnableCommitPipelineStages::Standard,
+			max_commit_queue_bytes: None,
+		}
+	}
+
+	/// Create a database with `num_columns` default columns.
+	pub fn with_columns(path: &Path, num_columns: u8) -> DbBuilder {
+		Self::new(Options::with_columns(path, num_columns))
+	}
+
+	/// Add a change to the initial commit. `None` removes the key.
+	pub fn fixture(mut self, col: ColId, key: impl AsRef<[u8]>, value: Option<Value>) -> DbBuilder {
+		self.fixtures.push((col, key.as_ref().to_vec(), value));
+		self
+	}
+
+	pub fn fixtures<I, K>(mut self, fixtures: I) -> DbBuilder
+	where
+		I: IntoIterator<Item=(ColId, K, Option<Value>)>,
+		K: AsRef<[u8]>,
+	{
+		self.fixtures.extend(fixtures.into_iter().map(|(c, k, v)| (c, k.as_ref().to_vec(), v)));
+		self
+	}
+
+	/// Pipeline stage that the fixtures reach before the database is returned. See
+	/// `Db::advance_to`.
+	pub fn stage(mut self, stage: EnableCommitPipelineStages) -> DbBuilder {
+		self.stage = stage;
+		self
+	}
+
+	/// Make commits wait once the commit queue holds more than `bytes`. The queue is only
+	/// drained by `Db::step` and `Db::advance_to`, so that commits stay blocked until then.
+	pub fn max_commit_queue_bytes(mut self, bytes: usize) -> DbBuilder {
+		self.max_commit_queue_bytes = Some(bytes);
+		self
+	}
+
+	pub fn build(self) -> Result<Db> {
+		let inner_options = InternalOptions {
+			create: true,
+			commit_stages: EnableCommitPipelineStages::CommitOverlay,
+			max_commit_queue_bytes: self.max_commit_queue_bytes,
+			..Default::default()
+		};
+		let db = Db::open_for_testing(&self.options, &inner_options)?;
+		if !self.fixtures.is_empty() {
+			db.commit(self.fixtures)?;
+		}
+		db.advance_to(self.stage)?;
+		Ok(db)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::DbBuilder;
+	use crate::db::EnableCommitPipelineStages;
+	use tempfile::tempdir;
+
+	#[test]
+	fn fixture_stages() {
+		for stage in [
+			EnableCommitPipelineStages::CommitOverlay,
+			EnableCommitPipelineStages::LogOverlay,
+			EnableCommitPipelineStages::DbFile,
+			EnableCommitPipelineStages::Standard,
+		] {
+			let tmp = tempdir().unwrap();
+			let db = DbBuilder::with_columns(tmp.path(), 2)
+				.fixture(0, b"key1", Some(b"value1".to_vec()))
+				.fixtures(vec![(1, b"key2", Some(b"value2".to_vec())), (1, b"key3", None)])
+				.stage(stage)
+				.build()
+				.unwrap();
+			let in_commit_overlay = matches!(stage, EnableCommitPipelineStages::CommitOverlay);
+			let in_log_overlay = matches!(stage, EnableCommitPipelineStages::LogOverlay);
+			assert_eq!(db.commit_overlay_is_empty(0).unwrap(), !in_commit_overlay);
+			assert_eq!(db.commit_overlay_is_empty(1).unwrap(), !in_commit_overlay);
+			assert_eq!(db.log_overlay_is_empty(), !in_log_overlay);
+			assert_eq!(db.get(0, b"key1").unwrap(), Some(b"value1".to_vec()));
+			assert_eq!(db.get(1, b"key2").unwrap(), Some(b"value2".to_vec()));
+			assert_eq!(db.get(1, b"key3").unwrap(), None);
+
+			db.advance_to(EnableCommitPipelineStages::Standard).unwrap();
+			assert!(db.commit_overlay_is_empty(0).unwrap());
+			assert!(db.log_overlay_is_empty());
+			assert!(!db.step().unwrap());
+			assert_eq!(db.get(1, b"key2").unwrap(), Some(b"value2".to_vec()));
+		}
+	}
+
+	#[test]
+	fn force_reindex() {
+		let tmp = tempdir().unwrap();
+		let db = DbBuilder::with_columns(tmp.path(), 1)
+			.fixtures((0 .. 100u32).map(|i| (0, i.to_le_bytes(), Some(vec![1; 8]))))
+			.build()
+			.unwrap();
+		assert!(tmp.path().join("index_00_16").exists());
+		db.force_reindex(0).unwrap();
+		db.advance_to(EnableCommitPipelineStages::Standard).unwrap();
+		assert!(tmp.path().join("index_00_17").exists());
+		assert!(!tmp.path().join("index_00_16").exists());
+		for i in 0 .. 100u32 {
+			assert_eq!(db.get(0, &i.to_le_bytes()).unwrap(), Some(vec![1; 8]));
+		}
+	}
+}